@@ -0,0 +1,93 @@
+//! 4K random writes across a 2 GiB file, like a disk image used by a loop device.
+//!
+//! Run it with `cargo bench --bench random_write_4k`. The file is in a temp dir, set `RENCFS_BENCH_DIR` to put it
+//! on the disk you want to measure, and `RENCFS_BENCH_WRITES` for how many writes to do, 10000 by default.
+
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::Result;
+use rand::Rng;
+use shush_rs::SecretString;
+
+use rencfs::crypto::Cipher;
+use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
+
+const ROOT_INODE: u64 = 1;
+const FILE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+const WRITE_SIZE: usize = 4096;
+
+struct PasswordProviderImpl {}
+
+impl PasswordProvider for PasswordProviderImpl {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(SecretString::from_str("bench").unwrap())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let writes: usize =
+        env::var("RENCFS_BENCH_WRITES").map_or(Ok(10_000), |writes| writes.parse())?;
+    let dir = match env::var("RENCFS_BENCH_DIR") {
+        Ok(dir) => tempfile::tempdir_in(PathBuf::from(dir))?,
+        Err(_) => tempfile::tempdir()?,
+    };
+    let fs = EncryptedFs::new(
+        dir.path().join("data"),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+    )
+    .await?;
+    let attr = CreateFileAttr {
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    };
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("disk.img").unwrap(),
+            attr,
+            false,
+            false,
+        )
+        .await?;
+    fs.release(fh).await?;
+    let ino = attr.ino;
+
+    let start = Instant::now();
+    fs.set_len(ino, FILE_SIZE).await?;
+    println!("allocated {FILE_SIZE} bytes in {:?}", start.elapsed());
+
+    let mut rng = rand::thread_rng();
+    let mut data = [0_u8; WRITE_SIZE];
+    let fh = fs.open(ino, false, true).await?;
+    let start = Instant::now();
+    for _ in 0..writes {
+        let offset = rng.gen_range(0..FILE_SIZE / WRITE_SIZE as u64) * WRITE_SIZE as u64;
+        rng.fill(&mut data[..]);
+        let mut pos = 0;
+        while pos < data.len() {
+            pos += fs.write(ino, offset + pos as u64, &data[pos..], fh).await?;
+        }
+    }
+    fs.release(fh).await?;
+    let elapsed = start.elapsed();
+    #[allow(clippy::cast_precision_loss)]
+    let bytes = (writes * WRITE_SIZE) as f64;
+    println!(
+        "{writes} random {WRITE_SIZE} bytes writes in {elapsed:?}, {:.0} IOPS, {:.2} MiB/s",
+        writes as f64 / elapsed.as_secs_f64(),
+        bytes / elapsed.as_secs_f64() / 1024.0 / 1024.0
+    );
+    assert_eq!(FILE_SIZE, fs.get_attr(ino).await?.size);
+
+    Ok(())
+}
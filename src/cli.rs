@@ -0,0 +1,782 @@
+//! The commands of the `rencfs` binary, so other binaries can embed them.
+//!
+//! Use [`command`] to get the arguments definition, [`parse_command`] to turn the matches into a [`Command`] and
+//! [`run_command`] to execute it. [`run`] does all of these from the process arguments, like the `rencfs` binary.
+
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{env, io, panic, process};
+
+use anyhow::Result;
+use clap::{crate_authors, crate_name, crate_version, Arg, ArgAction, ArgMatches};
+use ctrlc::set_handler;
+use rpassword::read_password;
+use shush_rs::{ExposeSecret, SecretString};
+use strum::IntoEnumIterator;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tokio::{fs, task};
+use tracing::{error, info, warn, Level};
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    EncryptedFs, FsError, FsOptions, FsResult, PasswordProvider, PasswordSource,
+};
+use crate::mount::MountPoint;
+use crate::{fs_util, keyring, log, mount};
+
+static mut PASS: Option<SecretString> = None;
+
+#[derive(Debug, Error)]
+pub enum ExitStatusError {
+    #[error("exit with status {0}")]
+    Failure(i32),
+}
+
+/// A command with its arguments.
+#[derive(Debug)]
+pub enum Command {
+    /// Mount the filesystem exposing decrypted content from data dir.
+    Mount(MountArgs),
+    /// Change password for the master key used to encrypt the data.
+    ChangePassword(ChangePasswordArgs),
+    /// Save the header of the data dir to a file, as a backup, see [`EncryptedFs::export_header`].
+    ExportHeader(HeaderArgs),
+    /// Restore the header of the data dir from a backup, see [`EncryptedFs::import_header`].
+    ImportHeader(HeaderArgs),
+}
+
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct MountArgs {
+    pub mount_point: PathBuf,
+    pub data_dir: PathBuf,
+    pub cipher: Cipher,
+    /// Try to umount the mountpoint before mounting, useful when the previous run crashed.
+    pub umount_on_start: bool,
+    pub allow_root: bool,
+    pub allow_other: bool,
+    /// Let the kernel enforce the permissions of files, see [`FsOptions::default_permissions`].
+    pub default_permissions: bool,
+    pub read_only: bool,
+    /// Mount even if the mount point has files, see [`FsOptions::allow_nonempty_mountpoint`].
+    pub allow_nonempty: bool,
+    /// Use a data dir on a FUSE mount, see [`FsOptions::allow_fuse_data_dir`].
+    pub allow_fuse_data_dir: bool,
+    /// Paths in the vault that can't be changed, see [`FsOptions::read_only_paths`].
+    pub read_only_paths: Vec<PathBuf>,
+    /// Threads handling the FUSE requests, see [`FsOptions::fuse_worker_threads`].
+    pub fuse_worker_threads: Option<usize>,
+    /// Let the kernel cache the writes, see [`FsOptions::writeback_cache`].
+    pub writeback_cache: bool,
+    /// Where to read the password from, if not set we ask for it and keep it in the keyring.
+    pub password_source: Option<PasswordArg>,
+}
+
+/// Where to read the password from, from `--password-env`, `--password-file` or `--password-fd`, see
+/// [`PasswordSource`].
+#[derive(Debug, Clone)]
+pub enum PasswordArg {
+    Env(String),
+    File(PathBuf),
+    /// Opened file descriptor, it's duplicated when reading the password so this stays only a number, it doesn't
+    /// own it.
+    #[cfg(unix)]
+    Fd(i32),
+    /// The password of [`PasswordArg::Env`] after [`take_env_password`].
+    Value(SecretString),
+}
+
+impl PasswordArg {
+    /// The [`PasswordSource`] to read the password from.
+    ///
+    /// # Errors
+    ///
+    /// If the file descriptor is not opened.
+    pub fn into_source(self) -> io::Result<PasswordSource> {
+        Ok(match self {
+            Self::Env(name) => PasswordSource::Env(name),
+            Self::File(path) => PasswordSource::File(path),
+            #[cfg(unix)]
+            Self::Fd(fd) => PasswordSource::Fd(fs_util::dup_fd(fd)?),
+            Self::Value(password) => PasswordSource::Value(password),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangePasswordArgs {
+    pub data_dir: PathBuf,
+    pub cipher: Cipher,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderArgs {
+    pub data_dir: PathBuf,
+    /// The backup of the header.
+    pub file: PathBuf,
+}
+
+/// Parses the process arguments and runs the command on a tokio runtime it starts, like the `rencfs` binary, so
+/// it must not be called from a runtime.
+///
+/// It initializes the logging, if you embed the commands in your binary you probably want [`run_command`].
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::missing_panics_doc)]
+pub fn run() -> Result<()> {
+    let matches = command().get_matches();
+    // before any thread starts, even the one of the logging
+    let env_password = take_env_password(&matches);
+
+    let str = matches.get_one::<String>("log-level").unwrap().as_str();
+    let log_level = Level::from_str(str);
+    assert!(log_level.is_ok(), "Invalid log level");
+    let log_level = log_level.unwrap();
+    let guard = log::log_init(log_level);
+
+    let mut command = match parse_command(&matches) {
+        Ok(command) => command,
+        Err(err) => {
+            if let Some(ExitStatusError::Failure(code)) = err.downcast_ref::<ExitStatusError>() {
+                info!("Bye!");
+                drop(guard);
+                process::exit(*code);
+            }
+            return Err(err);
+        }
+    };
+    if let (Command::Mount(args), Some(password)) = (&mut command, env_password) {
+        match password {
+            Ok(password) => args.password_source = Some(PasswordArg::Value(password)),
+            Err(err) => {
+                error!(err = %err, "cannot read password");
+                drop(guard);
+                process::exit(1);
+            }
+        }
+    }
+    let mount_point = match &command {
+        Command::Mount(args) => Some(args.mount_point.clone()),
+        Command::ChangePassword(_) | Command::ExportHeader(_) | Command::ImportHeader(_) => None,
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let res = runtime.block_on(async {
+        task::spawn_blocking(|| {
+            panic::catch_unwind(|| {
+                let handle = tokio::runtime::Handle::current();
+                handle.block_on(async { run_command(command).await })
+            })
+        })
+        .await
+    });
+    match res {
+        Ok(Ok(Ok(()))) => Ok(()),
+        Ok(Ok(Err(err))) => {
+            let err2 = err.downcast_ref::<ExitStatusError>();
+            if let Some(ExitStatusError::Failure(code)) = err2 {
+                info!("Bye!");
+                drop(guard);
+                process::exit(*code);
+            }
+            error!("{err}");
+            if let Some(mount_point) = mount_point {
+                umount_after_error(&mount_point);
+            }
+            Err(err)
+        }
+        Ok(Err(err)) => {
+            error!("{err:#?}");
+            if let Some(mount_point) = mount_point {
+                umount_after_error(&mount_point);
+            }
+            drop(guard);
+            panic!("{err:#?}");
+        }
+        Err(err) => {
+            error!("{err}");
+            if let Some(mount_point) = mount_point {
+                umount_after_error(&mount_point);
+            }
+            drop(guard);
+            panic!("{err}");
+        }
+    }
+}
+
+/// Reads the password from the variable of `--password-env`, if it's given, and removes it from the environment so
+/// child processes don't get it.
+///
+/// Changing the environment races with other threads reading it, so call it before starting any, like [`run`] does
+/// before starting the runtime.
+pub fn take_env_password(matches: &ArgMatches) -> Option<FsResult<SecretString>> {
+    let (_, matches) = matches.subcommand().filter(|(name, _)| *name == "mount")?;
+    let name = matches.get_one::<String>("password-env")?;
+    let password = PasswordSource::Env(name.clone()).read();
+    env::remove_var(name);
+    Some(password)
+}
+
+fn umount_after_error(mount_point: &Path) {
+    let _ = mount::umount(&mount_point.to_string_lossy()).map_err(|err| {
+        warn!("Cannot umount, maybe it was not mounted: {err}");
+        err
+    });
+}
+
+/// The arguments definition of all commands, you can add it as a subcommand of your own.
+#[allow(clippy::too_many_lines)]
+#[must_use]
+pub fn command() -> clap::Command {
+    clap::Command::new(crate_name!())
+        .version(crate_version!())
+        .author(crate_authors!())
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .short('l')
+                .value_name("log-level")
+                .default_value("INFO")
+                .global(true)
+                .help("Log level, possible values: TRACE, DEBUG, INFO, WARN, ERROR"),
+        )
+        .arg(
+            Arg::new("cipher")
+                .long("cipher")
+                .short('c')
+                .value_name("cipher")
+                .default_value("ChaCha20Poly1305")
+                .global(true)
+                .help(format!("Cipher used for encryption, possible values: {}",
+                              Cipher::iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+                )
+        )
+        .subcommand_required(true)
+        .subcommand(
+            clap::Command::new("mount")
+                .about("Mount the filesystem exposing decrypted content from data dir")
+                .arg(
+                    Arg::new("mount-point")
+                        .long("mount-point")
+                        .short('m')
+                        .required(true)
+                        .value_name("MOUNT_POINT")
+                        .requires("data-dir")
+                        .help("Act as a client, and mount FUSE at given path"),
+                )
+                .arg(
+                    Arg::new("data-dir")
+                        .long("data-dir")
+                        .short('d')
+                        .required(true)
+                        .value_name("DATA_DIR")
+                        .requires("mount-point")
+                        .help("Where to store the encrypted data"),
+                )
+                .arg(
+                    Arg::new("umount-on-start")
+                        .long("umount-on-start")
+                        .short('u')
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("If we should try to umount the mountpoint before starting the FUSE server. This can be useful when the previous run crashed or was forced kll and the mountpoint is still mounted."),
+                )
+                .arg(
+                    Arg::new("allow-root")
+                        .long("allow-root")
+                        .short('s')
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Allow root user to access filesystem"),
+                )
+                .arg(
+                    Arg::new("allow-other")
+                        .long("allow-other")
+                        .short('o')
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Allow other user to access filesystem"),
+                )
+                .arg(
+                    Arg::new("default-permissions")
+                        .long("default-permissions")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Let the kernel enforce the permissions of files"),
+                )
+                .arg(
+                    Arg::new("read-only")
+                        .long("read-only")
+                        .short('r')
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Set FUSE filesystem read-only mount option, default is disabled.")
+                )
+                .arg(
+                    Arg::new("allow-nonempty")
+                        .long("allow-nonempty")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Mount even if the mount point is not empty, the files in it are hidden until unmounted")
+                )
+                .arg(
+                    Arg::new("allow-fuse-data-dir")
+                        .long("allow-fuse-data-dir")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Use a data dir on a FUSE mount, like another rencfs mount, by default it refuses to")
+                )
+                .arg(
+                    Arg::new("read-only-path")
+                        .long("read-only-path")
+                        .value_name("PATH")
+                        .action(ArgAction::Append)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Path in the vault, like /templates, that can't be changed while the rest is writable, can be repeated")
+                )
+                .arg(
+                    Arg::new("fuse-worker-threads")
+                        .long("fuse-worker-threads")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Threads handling the requests in parallel, default is one for each core")
+                )
+                .arg(
+                    Arg::new("writeback-cache")
+                        .long("writeback-cache")
+                        .action(ArgAction::SetTrue)
+                        .requires("mount-point")
+                        .requires("data-dir")
+                        .help("Let the kernel cache the writes and send them in larger chunks, faster for small writes")
+                )
+                .arg(
+                    Arg::new("password-env")
+                        .long("password-env")
+                        .value_name("VAR")
+                        .conflicts_with_all(["password-file", "password-fd"])
+                        .help("Read the password from this environment variable instead of asking for it"),
+                )
+                .arg(
+                    Arg::new("password-file")
+                        .long("password-file")
+                        .value_name("FILE")
+                        .conflicts_with("password-fd")
+                        .help("Read the password from this file instead of asking for it"),
+                )
+                .arg(
+                    Arg::new("password-fd")
+                        .long("password-fd")
+                        .value_name("FD")
+                        .value_parser(clap::value_parser!(i32).range(0..))
+                        .help("Read the password from this opened file descriptor instead of asking for it, useful with systemd credentials"),
+                )
+        ).subcommand(
+        clap::Command::new("passwd")
+            .about("Change password for the master key used to encrypt the data")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where to store the encrypted data"),
+            )
+    ).subcommand(
+        clap::Command::new("export-header")
+            .about("Save the encrypted key and the params of the data dir to a file, as a backup. Without them the data can't be decrypted even with the password")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("file")
+                    .long("file")
+                    .short('f')
+                    .required(true)
+                    .value_name("FILE")
+                    .help("Where to save the backup"),
+            )
+    ).subcommand(
+        clap::Command::new("import-header")
+            .about("Restore the encrypted key and the params of the data dir from a backup made with export-header")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("file")
+                    .long("file")
+                    .short('f')
+                    .required(true)
+                    .value_name("FILE")
+                    .help("The backup to restore"),
+            )
+    )
+}
+
+/// Creates the [`Command`] from the matches of [`command`].
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::missing_panics_doc)]
+pub fn parse_command(matches: &ArgMatches) -> Result<Command> {
+    let cipher: String = matches.get_one::<String>("cipher").unwrap().to_string();
+    let cipher = Cipher::from_str(cipher.as_str());
+    if cipher.is_err() {
+        error!("Invalid cipher");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let cipher = cipher.unwrap();
+
+    match matches.subcommand() {
+        Some(("passwd", matches)) => Ok(Command::ChangePassword(ChangePasswordArgs {
+            data_dir: matches.get_one::<String>("data-dir").unwrap().into(),
+            cipher,
+        })),
+        Some(("export-header", matches)) => Ok(Command::ExportHeader(HeaderArgs {
+            data_dir: matches.get_one::<String>("data-dir").unwrap().into(),
+            file: matches.get_one::<String>("file").unwrap().into(),
+        })),
+        Some(("import-header", matches)) => Ok(Command::ImportHeader(HeaderArgs {
+            data_dir: matches.get_one::<String>("data-dir").unwrap().into(),
+            file: matches.get_one::<String>("file").unwrap().into(),
+        })),
+        Some(("mount", matches)) => Ok(Command::Mount(MountArgs {
+            mount_point: matches.get_one::<String>("mount-point").unwrap().into(),
+            data_dir: matches.get_one::<String>("data-dir").unwrap().into(),
+            cipher,
+            umount_on_start: matches.get_flag("umount-on-start"),
+            allow_root: matches.get_flag("allow-root"),
+            allow_other: matches.get_flag("allow-other"),
+            default_permissions: matches.get_flag("default-permissions"),
+            read_only: matches.get_flag("read-only"),
+            allow_nonempty: matches.get_flag("allow-nonempty"),
+            allow_fuse_data_dir: matches.get_flag("allow-fuse-data-dir"),
+            read_only_paths: matches
+                .get_many::<String>("read-only-path")
+                .unwrap_or_default()
+                .map(PathBuf::from)
+                .collect(),
+            fuse_worker_threads: matches.get_one::<usize>("fuse-worker-threads").copied(),
+            writeback_cache: matches.get_flag("writeback-cache"),
+            password_source: parse_password_source(matches),
+        })),
+        None => {
+            error!("No subcommand provided");
+            Err(ExitStatusError::Failure(1).into())
+        }
+        _ => {
+            error!("Invalid subcommand");
+            Err(ExitStatusError::Failure(1).into())
+        }
+    }
+}
+
+fn parse_password_source(matches: &ArgMatches) -> Option<PasswordArg> {
+    if let Some(name) = matches.get_one::<String>("password-env") {
+        return Some(PasswordArg::Env(name.clone()));
+    }
+    if let Some(path) = matches.get_one::<String>("password-file") {
+        return Some(PasswordArg::File(path.into()));
+    }
+    #[cfg(unix)]
+    if let Some(fd) = matches.get_one::<i32>("password-fd") {
+        return Some(PasswordArg::Fd(*fd));
+    }
+    None
+}
+
+/// Runs the command, reading passwords from stdin if they don't have other source.
+///
+/// [`Command::Mount`] runs until the process receives a signal to exit, when it umounts and exits the process.
+#[allow(clippy::missing_errors_doc)]
+pub async fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::ChangePassword(args) => run_change_password(args).await,
+        Command::Mount(args) => run_mount(args).await,
+        Command::ExportHeader(args) => run_export_header(&args).await,
+        Command::ImportHeader(args) => run_import_header(&args).await,
+    }
+}
+
+async fn run_export_header(args: &HeaderArgs) -> Result<()> {
+    let header = EncryptedFs::export_header(&args.data_dir).map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    fs::write(&args.file, header).await?;
+    println!("Header saved to {}", args.file.display());
+    Ok(())
+}
+
+async fn run_import_header(args: &HeaderArgs) -> Result<()> {
+    let header = fs::read(&args.file).await?;
+    EncryptedFs::import_header(&args.data_dir, &header).map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    println!("Header restored");
+    Ok(())
+}
+
+async fn run_change_password(args: ChangePasswordArgs) -> Result<()> {
+    // read password from stdin
+    print!("Enter old password: ");
+    io::stdout().flush().unwrap();
+    let password = SecretString::from_str(&read_password().unwrap()).unwrap();
+    print!("Enter new password: ");
+    io::stdout().flush().unwrap();
+    let new_password = SecretString::from_str(&read_password().unwrap()).unwrap();
+    print!("Confirm new password: ");
+    io::stdout().flush().unwrap();
+    let new_password2 = SecretString::from_str(&read_password().unwrap()).unwrap();
+    if new_password.expose_secret() != new_password2.expose_secret() {
+        println!("Passwords do not match");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    println!("Changing password...");
+    EncryptedFs::passwd(&args.data_dir, password, new_password, args.cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid old password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Password changed successfully");
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run_mount(mut args: MountArgs) -> Result<()> {
+    let mountpoint = args.mount_point.to_string_lossy().to_string();
+    let data_dir = args.data_dir;
+
+    // when running from IDE we can't read from stdin with rpassword, get it from env var
+    let mut password = SecretString::from_str(
+        env::var("RENCFS_PASSWORD")
+            .unwrap_or_else(|_| String::new())
+            .as_str(),
+    )
+    .unwrap();
+    let has_password_source = args.password_source.is_some();
+    if let Some(source) = args.password_source.take() {
+        // keep it only in memory
+        let password = source
+            .into_source()
+            .map_err(FsError::from)
+            .and_then(PasswordSource::read)
+            .map_err(|err| {
+                error!(err = %err, "cannot read password");
+                ExitStatusError::Failure(1)
+            })?;
+        unsafe {
+            PASS = Some(password);
+        }
+    } else if password.expose_secret().is_empty() {
+        if !io::stdin().is_terminal() {
+            error!("No terminal to ask for the password, use --password-env, --password-file or --password-fd");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+        // read password from stdin
+        print!("Enter password: ");
+        io::stdout().flush().unwrap();
+        password = SecretString::from_str(read_password().unwrap().as_str()).unwrap();
+
+        if !data_dir.is_dir()
+            || fs::read_dir(&data_dir)
+                .await
+                .unwrap()
+                .next_entry()
+                .await
+                .unwrap()
+                .is_none()
+        {
+            // first run, ask to confirm password
+            print!("Confirm password: ");
+            io::stdout().flush().unwrap();
+            let confirm_password =
+                SecretString::from_str(read_password().unwrap().as_str()).unwrap();
+            if password.expose_secret() != confirm_password.expose_secret() {
+                error!("Passwords do not match");
+                return Err(ExitStatusError::Failure(1).into());
+            }
+        }
+    }
+    if !has_password_source {
+        // save password in keyring
+        info!("Save password in keyring");
+        let res = keyring::save(&password, "password").map_err(|err| match err {
+            FsError::KeyringUnavailable { .. } => info!(err = %err),
+            _ => warn!(err = %err),
+        });
+        if res.is_err() {
+            // maybe we don't have a security manager, keep it in mem
+            unsafe {
+                warn!("Cannot save password in keyring, keep it in memory");
+                PASS = Some(password.clone());
+            }
+        }
+    }
+
+    if args.umount_on_start {
+        let _ = mount::umount(mountpoint.as_str()).map_err(|err| {
+            warn!("Cannot umount, maybe it was not mounted: {err}");
+            err
+        });
+    }
+
+    struct PasswordProviderImpl {}
+    #[allow(clippy::items_after_statements)]
+    #[allow(static_mut_refs)]
+    impl PasswordProvider for PasswordProviderImpl {
+        fn get_password(&self) -> Option<SecretString> {
+            unsafe {
+                if PASS.is_some() {
+                    info!("Get password from memory");
+                    PASS.clone()
+                } else {
+                    info!("Get password from keyring");
+                    match keyring::get("password") {
+                        Ok(password) => Some(password),
+                        Err(err @ FsError::KeyringUnavailable { .. })
+                            if io::stdin().is_terminal() =>
+                        {
+                            // the secret service went away, ask for it again and keep it in memory
+                            warn!(err = %err, "keyring is not available");
+                            print!("Enter password: ");
+                            io::stdout().flush().unwrap();
+                            let password = SecretString::from_str(&read_password().ok()?).unwrap();
+                            PASS = Some(password.clone());
+                            Some(password)
+                        }
+                        Err(err) => {
+                            error!(err = %err, "cannot get password from keyring");
+                            None
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let mut options = FsOptions::default()
+        .with_default_permissions(args.default_permissions)
+        .with_allow_nonempty_mountpoint(args.allow_nonempty)
+        .with_allow_fuse_data_dir(args.allow_fuse_data_dir)
+        .with_read_only_paths(args.read_only_paths)
+        .with_writeback_cache(args.writeback_cache);
+    if let Some(fuse_worker_threads) = args.fuse_worker_threads {
+        options = options.with_fuse_worker_threads(fuse_worker_threads);
+    }
+    let mount_point = mount::create_mount_point_with_options(
+        Path::new(&mountpoint),
+        &data_dir,
+        Box::new(PasswordProviderImpl {}),
+        args.cipher,
+        args.allow_root,
+        args.allow_other,
+        args.read_only,
+        options,
+    );
+    let mount_handle = mount_point.mount().await.map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    let mount_handle = Arc::new(Mutex::new(Some(Some(mount_handle))));
+    let mount_handle_clone = mount_handle.clone();
+    // cleanup on process kill
+    set_handler(move || {
+        // can't use tracing methods here as guard cannot be dropper to flush content before we exit
+        eprintln!("Received signal to exit");
+        let mut status: Option<ExitStatusError> = None;
+        remove_pass();
+        eprintln!("Unmounting {mountpoint}");
+        // create new tokio runtime
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _ = rt
+            .block_on(async {
+                let res = mount_handle_clone
+                    .lock()
+                    .await
+                    .replace(None)
+                    .unwrap()
+                    .unwrap()
+                    .umount()
+                    .await;
+                if res.is_err() {
+                    mount::umount(mountpoint.as_str())?;
+                }
+                Ok::<(), io::Error>(())
+            })
+            .map_err(|err| {
+                eprintln!("Error: {err}");
+                status.replace(ExitStatusError::Failure(1));
+                err
+            });
+        eprintln!("Bye!");
+        process::exit(status.map_or(0, |x| match x {
+            ExitStatusError::Failure(status) => status,
+        }));
+    })?;
+
+    task::spawn_blocking(|| {
+        let rt = tokio::runtime::Handle::current();
+        rt.block_on(async {
+            tokio::time::sleep(tokio::time::Duration::from_secs(u64::MAX)).await;
+        });
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[allow(static_mut_refs)]
+fn remove_pass() {
+    unsafe {
+        if PASS.is_none() {
+            info!("Delete password from keyring");
+            keyring::remove("password")
+                .map_err(|err| match err {
+                    FsError::KeyringUnavailable { .. } => warn!(err = %err),
+                    _ => error!(err = %err),
+                })
+                .ok();
+        } else {
+            info!("Remove password from memory");
+            PASS = None;
+        }
+    }
+}
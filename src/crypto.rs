@@ -1,10 +1,12 @@
+use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Seek, Write};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::sync::Arc;
 
+use aes_kw::KekAes256;
 use argon2::Argon2;
 use base64::alphabet::STANDARD;
 use base64::engine::general_purpose::NO_PAD;
@@ -14,19 +16,24 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
+use ring::aead::{
+    Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN,
+};
+use ring::hmac;
 use serde::{Deserialize, Serialize};
 use shush_rs::{ExposeSecret, SecretString, SecretVec};
 use strum_macros::{Display, EnumIter, EnumString};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
 use tracing::{debug, error, instrument};
 use write::CryptoInnerWriter;
 
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
-use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite};
+use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite, BLOCK_SIZE};
 use crate::encryptedfs::FsResult;
 use crate::{fs_util, stream_util};
 
+mod bench;
 pub mod buf_mut;
 pub mod read;
 pub mod write;
@@ -41,6 +48,117 @@ pub enum Cipher {
     Aes256Gcm,
 }
 
+/// How file names are encrypted, chosen apart from the [`Cipher`] of the content, see
+/// [`FsOptions::name_cipher`](crate::encryptedfs::FsOptions::name_cipher).
+///
+/// Besides [`NameCipher::Content`] they use a subkey derived from the key for names only.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    EnumIter,
+    EnumString,
+    Display,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+)]
+pub enum NameCipher {
+    /// The cipher of the content with the key, with a random nonce so the same name encrypts differently each time.
+    #[default]
+    Content,
+    ChaCha20Poly1305,
+    Aes256Gcm,
+    /// Deterministic, the nonce is the HMAC of the name with a subkey, a synthetic nonce like SIV does but not the
+    /// RFC 8452 AES-GCM-SIV. The same name always encrypts the same, it shows which names are equal, not what they
+    /// are. The same keyed HMAC names the entries in the hash dir, instead of the plain hash of the name.
+    ChaCha20Poly1305Synthetic,
+    Aes256GcmSynthetic,
+}
+
+impl NameCipher {
+    #[must_use]
+    pub const fn is_deterministic(self) -> bool {
+        matches!(
+            self,
+            Self::ChaCha20Poly1305Synthetic | Self::Aes256GcmSynthetic
+        )
+    }
+
+    /// The AEAD it uses, `content` for [`NameCipher::Content`].
+    #[must_use]
+    pub const fn cipher(self, content: Cipher) -> Cipher {
+        match self {
+            Self::Content => content,
+            Self::ChaCha20Poly1305 | Self::ChaCha20Poly1305Synthetic => Cipher::ChaCha20Poly1305,
+            Self::Aes256Gcm | Self::Aes256GcmSynthetic => Cipher::Aes256Gcm,
+        }
+    }
+}
+
+/// Label of the subkey encrypting names, see [`derive_subkey`].
+const NAME_KEY_LABEL: &[u8] = b"rencfs-name-key";
+/// Label of the subkey hashing names into the nonce of [`NameCipher::ChaCha20Poly1305Synthetic`] and
+/// [`NameCipher::Aes256GcmSynthetic`].
+const NAME_SYNTHETIC_LABEL: &[u8] = b"rencfs-name-siv";
+
+/// The subkeys for names, derived once from the key and not for each name.
+pub struct NameKeys {
+    cipher_key: SecretVec<u8>,
+    synthetic_key: hmac::Key,
+}
+
+impl NameKeys {
+    #[must_use]
+    pub fn new(key: &SecretVec<u8>) -> Self {
+        Self {
+            cipher_key: derive_subkey(key, NAME_KEY_LABEL),
+            synthetic_key: hmac::Key::new(
+                hmac::HMAC_SHA256,
+                &derive_subkey(key, NAME_SYNTHETIC_LABEL).expose_secret(),
+            ),
+        }
+    }
+
+    /// Nonce of a name for [`NameCipher::ChaCha20Poly1305Synthetic`] and [`NameCipher::Aes256GcmSynthetic`].
+    fn synthetic_nonce(&self, name: &[u8]) -> [u8; NONCE_LEN] {
+        let tag = hmac::sign(&self.synthetic_key, name);
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&tag.as_ref()[..NONCE_LEN]);
+        nonce
+    }
+
+    /// Like [`hash_file_name`], with the HMAC the synthetic nonce is taken from instead of a plain hash, so the names
+    /// can't be guessed from the hash without the key.
+    #[must_use]
+    pub fn hash_file_name(&self, name: &SecretString) -> String {
+        special_file_name(&name.expose_secret()).unwrap_or_else(|| {
+            hex::encode(hmac::sign(
+                &self.synthetic_key,
+                name.expose_secret().as_bytes(),
+            ))
+        })
+    }
+}
+
+/// `.` and `..`, or the names we keep for them, as they are saved, `None` for the other names.
+fn special_file_name(name: &str) -> Option<String> {
+    match name {
+        "$." | "$.." => Some(name.to_string()),
+        "." | ".." => Some(format!("${name}")),
+        _ => None,
+    }
+}
+
+/// A key for one use derived from `key`, the HMAC-SHA256 of `label`, so uses don't share a key.
+#[must_use]
+pub fn derive_subkey(key: &SecretVec<u8>, label: &[u8]) -> SecretVec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &key.expose_secret());
+    SecretVec::new(Box::new(hmac::sign(&key, label).as_ref().to_vec()))
+}
+
 impl Cipher {
     /// In bytes.
     #[must_use]
@@ -52,6 +170,26 @@ impl Cipher {
         }
     }
 
+    /// Length (in bytes) of an encrypted block, the nonce, the encrypted data and the tag.
+    ///
+    /// Block `i` of a file starts at offset `i * ciphertext_block_len()`, the last one might be shorter.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn ciphertext_block_len(&self) -> usize {
+        self.ciphertext_block_len_for(BLOCK_SIZE)
+    }
+
+    /// Like [`Self::ciphertext_block_len`] but for plaintext blocks of `block_size` bytes.
+    #[must_use]
+    #[allow(clippy::use_self)]
+    pub fn ciphertext_block_len_for(&self, block_size: usize) -> usize {
+        let tag_len = match self {
+            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+            Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+        };
+        NONCE_LEN + block_size + tag_len
+    }
+
     /// Max length (in bytes) of the plaintext that can be encrypted before becoming unsafe.
     #[must_use]
     #[allow(clippy::use_self)]
@@ -115,7 +253,7 @@ pub fn create_write<W: CryptoInnerWriter + Send + Sync + 'static>(
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoWrite<W> {
-    create_ring_write(writer, cipher, key)
+    create_ring_write(writer, cipher, key, BLOCK_SIZE)
 }
 
 /// Creates an encrypted writer with seek
@@ -124,73 +262,357 @@ pub fn create_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync + 'sta
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> impl CryptoWriteSeek<W> {
-    create_ring_write_seek(writer, cipher, key)
+    create_ring_write_seek(writer, cipher, key, BLOCK_SIZE)
+}
+
+/// Creates an encrypted writer which splits the content in blocks of `block_size`
+pub fn create_write_with_block_size<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key, block_size)
+}
+
+/// Creates an encrypted writer with seek which splits the content in blocks of `block_size`
+pub fn create_write_seek_with_block_size<
+    W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static,
+>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> impl CryptoWriteSeek<W> {
+    create_ring_write_seek(writer, cipher, key, block_size)
+}
+
+/// Creates an encrypted writer which passes each block through `transforms` before encrypting it, see
+/// [`BlockTransform`], and tells `counter` about each block it encrypts
+pub fn create_write_with_transforms<W: CryptoInnerWriter + Send + Sync + 'static>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+    transforms: Vec<Arc<dyn BlockTransform>>,
+    counter: Option<Arc<dyn BlockCounter>>,
+) -> impl CryptoWrite<W> {
+    create_ring_write(writer, cipher, key, block_size)
+        .with_transforms(transforms)
+        .with_counter(counter)
+}
+
+/// Creates an encrypted writer with seek which passes each block through `transforms` before encrypting it, see
+/// [`BlockTransform`], and tells `counter` about each block it encrypts
+pub fn create_write_seek_with_transforms<
+    W: CryptoInnerWriter + Seek + Read + Send + Sync + 'static,
+>(
+    writer: W,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+    transforms: Vec<Arc<dyn BlockTransform>>,
+    counter: Option<Arc<dyn BlockCounter>>,
+) -> impl CryptoWriteSeek<W> {
+    create_ring_write_seek(writer, cipher, key, block_size)
+        .with_transforms(transforms)
+        .with_counter(counter)
 }
 
 fn create_ring_write<W: CryptoInnerWriter + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
 ) -> RingCryptoWrite<W> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoWrite::new(writer, false, algorithm, key)
+    RingCryptoWrite::new_with_block_size(writer, false, algorithm(cipher), key, block_size)
 }
 
 fn create_ring_write_seek<W: CryptoInnerWriter + Seek + Read + Send + Sync>(
     writer: W,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
 ) -> RingCryptoWrite<W> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoWrite::new(writer, true, algorithm, key)
+    RingCryptoWrite::new_with_block_size(writer, true, algorithm(cipher), key, block_size)
 }
 
 fn create_ring_read<R: Read + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
 ) -> RingCryptoRead<R> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoRead::new(reader, algorithm, key)
+    RingCryptoRead::new_with_block_size(reader, algorithm(cipher), key, block_size)
 }
 
-fn create_ring_read_seek<R: Read + Seek + Send + Sync>(
+/// Creates an encrypted reader
+pub fn create_read<R: Read + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
-) -> RingCryptoRead<R> {
-    let algorithm = match cipher {
-        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
-        Cipher::Aes256Gcm => &AES_256_GCM,
-    };
-    RingCryptoRead::new_seek(reader, algorithm, key)
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key, BLOCK_SIZE)
 }
 
-/// Creates an encrypted reader
-pub fn create_read<R: Read + Send + Sync>(
+/// Creates an encrypted reader with seek
+pub fn create_read_seek<R: Read + Seek + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read(reader, cipher, key, BLOCK_SIZE)
+}
+
+/// Creates an encrypted reader for content written in blocks of `block_size`
+pub fn create_read_with_block_size<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
 ) -> impl CryptoRead<R> {
-    create_ring_read(reader, cipher, key)
+    create_ring_read(reader, cipher, key, block_size)
 }
 
-/// Creates an encrypted reader with seek
-pub fn create_read_seek<R: Read + Seek + Send + Sync>(
+/// Creates an encrypted reader with seek for content written in blocks of `block_size`
+pub fn create_read_seek_with_block_size<R: Read + Seek + Send + Sync>(
     reader: R,
     cipher: Cipher,
     key: &SecretVec<u8>,
+    block_size: usize,
 ) -> impl CryptoReadSeek<R> {
-    create_ring_read_seek(reader, cipher, key)
+    create_ring_read(reader, cipher, key, block_size)
+}
+
+/// Creates an encrypted reader which passes each block through `transforms` after decrypting it, see
+/// [`BlockTransform`]
+pub fn create_read_with_transforms<R: Read + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+    transforms: Vec<Arc<dyn BlockTransform>>,
+) -> impl CryptoRead<R> {
+    create_ring_read(reader, cipher, key, block_size).with_transforms(transforms)
+}
+
+/// Creates an encrypted reader with seek which passes each block through `transforms` after decrypting it, see
+/// [`BlockTransform`]
+pub fn create_read_seek_with_transforms<R: Read + Seek + Send + Sync>(
+    reader: R,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+    transforms: Vec<Arc<dyn BlockTransform>>,
+) -> impl CryptoReadSeek<R> {
+    create_ring_read(reader, cipher, key, block_size).with_transforms(transforms)
+}
+
+/// A step the content of files goes through, around the encryption of each block.
+///
+/// [`BlockTransform::on_write`] gets the plaintext of each block before it's encrypted and
+/// [`BlockTransform::on_read`] after it's decrypted. Like to scan for secrets, add a watermark or enforce a content
+/// policy, failing in `on_write` rejects the write.
+///
+/// When there are more, `on_write` is called in order and `on_read` in reverse order. It must be deterministic and
+/// `on_read` must undo `on_write`, else the content can't be read back. The blocks are at fixed offsets in the
+/// encrypted file, so a transform must keep the length of the block, that's why compression doesn't fit yet.
+/// The last block of a file can be shorter than the others.
+pub trait BlockTransform: Debug + Send + Sync + 'static {
+    #[allow(clippy::missing_errors_doc)]
+    fn on_write(&self, block: &mut Vec<u8>) -> io::Result<()>;
+    #[allow(clippy::missing_errors_doc)]
+    fn on_read(&self, block: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Called with the nonce each block was encrypted with, after [`BlockTransform::on_write`].
+    fn on_sealed(&self, _nonce: &[u8]) {}
+}
+
+/// Counts the blocks encrypted with a key, like to know when the nonces might start to repeat.
+///
+/// Unlike a [`BlockTransform`] it doesn't see the content, so the writer doesn't need to copy the blocks for it.
+pub trait BlockCounter: Debug + Send + Sync + 'static {
+    fn add(&self, blocks: u64);
+}
+
+/// Passes the plaintext of a block through [`BlockTransform::on_write`] of each transform.
+pub(crate) fn transform_on_write(
+    transforms: &[Arc<dyn BlockTransform>],
+    block: &mut [u8],
+) -> io::Result<()> {
+    if transforms.is_empty() {
+        return Ok(());
+    }
+    let mut buf = block.to_vec();
+    for transform in transforms {
+        transform.on_write(&mut buf)?;
+    }
+    copy_transformed(block, &buf)
+}
+
+/// Tells each transform the nonce a block was encrypted with, see [`BlockTransform::on_sealed`].
+pub(crate) fn transform_on_sealed(transforms: &[Arc<dyn BlockTransform>], nonce: &[u8]) {
+    for transform in transforms {
+        transform.on_sealed(nonce);
+    }
+}
+
+/// Passes the plaintext of a block through [`BlockTransform::on_read`] of each transform, in reverse order.
+pub(crate) fn transform_on_read(
+    transforms: &[Arc<dyn BlockTransform>],
+    block: &mut [u8],
+) -> io::Result<()> {
+    if transforms.is_empty() {
+        return Ok(());
+    }
+    let mut buf = block.to_vec();
+    for transform in transforms.iter().rev() {
+        transform.on_read(&mut buf)?;
+    }
+    copy_transformed(block, &buf)
+}
+
+fn copy_transformed(block: &mut [u8], transformed: &[u8]) -> io::Result<()> {
+    if transformed.len() != block.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "a block transform changed the length of the block",
+        ));
+    }
+    block.copy_from_slice(transformed);
+    Ok(())
+}
+
+fn algorithm(cipher: Cipher) -> &'static Algorithm {
+    match cipher {
+        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        Cipher::Aes256Gcm => &AES_256_GCM,
+    }
+}
+
+/// Encrypts one block like [`create_write`] does for block `block_index` of a file, with a new random nonce.
+///
+/// Returns the nonce, the encrypted data and the tag, what [`decrypt_block`] takes.
+#[allow(clippy::missing_errors_doc)]
+pub fn encrypt_block(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_index: u64,
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let algorithm = algorithm(cipher);
+    let key = LessSafeKey::new(
+        UnboundKey::new(algorithm, &key.expose_secret())
+            .map_err(|_| Error::Generic("invalid key"))?,
+    );
+    let mut nonce = [0; NONCE_LEN];
+    create_rng().fill_bytes(&mut nonce);
+    let mut block = Vec::with_capacity(NONCE_LEN + plaintext.len() + algorithm.tag_len());
+    block.extend_from_slice(&nonce);
+    block.extend_from_slice(plaintext);
+    let tag = key
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(block_index.to_le_bytes()),
+            &mut block[NONCE_LEN..],
+        )
+        .map_err(|_| Error::Generic("cannot encrypt"))?;
+    block.extend_from_slice(tag.as_ref());
+    Ok(block)
+}
+
+/// Decrypts in place one block written by [`create_write`], verifying its tag.
+///
+/// `block` is the whole encrypted block, the nonce, the encrypted data and the tag, at position `block_index`
+/// in the file. Returns the plaintext, which is inside `block`.
+#[allow(clippy::missing_errors_doc)]
+pub fn decrypt_block<'a>(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_index: u64,
+    block: &'a mut [u8],
+) -> Result<&'a mut [u8]> {
+    let algorithm = algorithm(cipher);
+    if block.len() < NONCE_LEN + algorithm.tag_len() {
+        return Err(Error::Generic("block too short"));
+    }
+    let key = LessSafeKey::new(
+        UnboundKey::new(algorithm, &key.expose_secret())
+            .map_err(|_| Error::Generic("invalid key"))?,
+    );
+    let (nonce, data) = block.split_at_mut(NONCE_LEN);
+    let nonce =
+        Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Generic("invalid nonce"))?;
+    key.open_in_place(nonce, Aad::from(block_index.to_le_bytes()), data)
+        .map_err(|_| Error::Generic("invalid block"))
+}
+
+/// Version of the format of [`seal`], the first byte.
+const SEAL_VERSION: u8 = 1;
+/// Associated data of [`seal`], so a sealed buffer can't be mistaken for a block of a file.
+const SEAL_AAD: &[u8] = b"rencfs-seal";
+
+/// Encrypts a standalone buffer. The result has everything needed by [`unseal`] besides the key: the version of the
+/// format, the cipher, the nonce, the encrypted data and the tag.
+#[allow(clippy::missing_errors_doc)]
+pub fn seal(cipher: Cipher, key: &SecretVec<u8>, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let algorithm = algorithm(cipher);
+    let key = LessSafeKey::new(
+        UnboundKey::new(algorithm, &key.expose_secret())
+            .map_err(|_| Error::Generic("invalid key"))?,
+    );
+    let mut nonce = [0; NONCE_LEN];
+    create_rng().fill_bytes(&mut nonce);
+    let cipher_id = match cipher {
+        Cipher::ChaCha20Poly1305 => 0,
+        Cipher::Aes256Gcm => 1,
+    };
+    let mut sealed = Vec::with_capacity(2 + NONCE_LEN + plaintext.len() + algorithm.tag_len());
+    sealed.extend_from_slice(&[SEAL_VERSION, cipher_id]);
+    sealed.extend_from_slice(&nonce);
+    let mut data = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(SEAL_AAD),
+        &mut data,
+    )
+    .map_err(|_| Error::Generic("cannot encrypt"))?;
+    sealed.extend_from_slice(&data);
+    Ok(sealed)
+}
+
+/// Decrypts a buffer from [`seal`], verifying its tag.
+#[allow(clippy::missing_errors_doc)]
+pub fn unseal(key: &SecretVec<u8>, sealed: &[u8]) -> Result<Vec<u8>> {
+    let [version, cipher_id, rest @ ..] = sealed else {
+        return Err(Error::Generic("sealed data too short"));
+    };
+    if *version != SEAL_VERSION {
+        return Err(Error::Generic("unknown version of sealed data"));
+    }
+    let cipher = match cipher_id {
+        0 => Cipher::ChaCha20Poly1305,
+        1 => Cipher::Aes256Gcm,
+        _ => return Err(Error::Generic("unknown cipher of sealed data")),
+    };
+    let algorithm = algorithm(cipher);
+    if rest.len() < NONCE_LEN + algorithm.tag_len() {
+        return Err(Error::Generic("sealed data too short"));
+    }
+    let key = LessSafeKey::new(
+        UnboundKey::new(algorithm, &key.expose_secret())
+            .map_err(|_| Error::Generic("invalid key"))?,
+    );
+    let (nonce, data) = rest.split_at(NONCE_LEN);
+    let nonce =
+        Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Generic("invalid nonce"))?;
+    let mut data = data.to_vec();
+    let len = key
+        .open_in_place(nonce, Aad::from(SEAL_AAD), &mut data)
+        .map_err(|_| Error::Generic("invalid sealed data"))?
+        .len();
+    data.truncate(len);
+    Ok(data)
 }
 
 #[allow(clippy::missing_errors_doc)]
@@ -221,50 +643,301 @@ pub fn decrypt_file_name(name: &str, cipher: Cipher, key: &SecretVec<u8>) -> Res
     decrypt(&name, cipher, key)
 }
 
+/// Like [`decrypt_file_name`] for names encrypted with `name_cipher`, `cipher` is the one of the content.
+///
+/// # Errors
+///
+/// If `name` is not one we encrypted with these keys.
+pub fn decrypt_file_name_with(
+    name: &str,
+    name_cipher: NameCipher,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    name_keys: &NameKeys,
+) -> Result<SecretString> {
+    let name = String::from(name).replace('|', "/");
+    match name_cipher {
+        NameCipher::Content => decrypt(&name, cipher, key),
+        NameCipher::ChaCha20Poly1305 | NameCipher::Aes256Gcm => {
+            decrypt(&name, name_cipher.cipher(cipher), &name_keys.cipher_key)
+        }
+        NameCipher::ChaCha20Poly1305Synthetic | NameCipher::Aes256GcmSynthetic => {
+            let data = BASE64.decode(name)?;
+            if data.len() < NONCE_LEN {
+                return Err(Error::Generic("encrypted name too short"));
+            }
+            let (nonce, data) = data.split_at(NONCE_LEN);
+            let aead_key = LessSafeKey::new(
+                UnboundKey::new(
+                    algorithm(name_cipher.cipher(cipher)),
+                    &name_keys.cipher_key.expose_secret(),
+                )
+                .map_err(|_| Error::Generic("invalid key"))?,
+            );
+            let mut data = data.to_vec();
+            let len = aead_key
+                .open_in_place(
+                    Nonce::try_assume_unique_for_key(nonce)
+                        .map_err(|_| Error::Generic("invalid nonce"))?,
+                    Aad::empty(),
+                    &mut data,
+                )
+                .map_err(|_| Error::Generic("invalid encrypted name"))?
+                .len();
+            data.truncate(len);
+            // the nonce must be the one of this name, or it's not what we encrypted
+            if !bool::from(name_keys.synthetic_nonce(&data).as_slice().ct_eq(nonce)) {
+                return Err(Error::Generic("invalid encrypted name"));
+            }
+            let name = String::from_utf8(data).map_err(|_| Error::Generic("invalid name"))?;
+            Ok(SecretString::new(Box::new(name)))
+        }
+    }
+}
+
 #[instrument(skip(password, salt))]
 #[allow(clippy::missing_errors_doc)]
 pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<SecretVec<u8>> {
+    derive_key_with(password, cipher, salt, KdfParams::default())
+}
+
+/// Cost parameters of the Argon2id we derive keys from passwords with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KdfParams {
+    /// In KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    /// The ones [`derive_key`] uses.
+    fn default() -> Self {
+        Self {
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// The most we accept from data we didn't write, like an envelope, so it can't have us take gigabytes of memory
+    /// or hours of CPU to derive a key.
+    pub const MAX: Self = Self {
+        m_cost: 256 * 1024,
+        t_cost: 16,
+        p_cost: 16,
+    };
+
+    /// If none of them is more than in `max`.
+    #[must_use]
+    pub const fn within(self, max: Self) -> bool {
+        self.m_cost <= max.m_cost && self.t_cost <= max.t_cost && self.p_cost <= max.p_cost
+    }
+}
+
+/// Like [`derive_key`] with the cost parameters in `params`.
+#[instrument(skip(password, salt))]
+#[allow(clippy::missing_errors_doc)]
+pub fn derive_key_with(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<SecretVec<u8>> {
     let mut dk = vec![];
     let key_len = cipher.key_len();
     dk.resize(key_len, 0);
-    Argon2::default()
+    let params = argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, None)
+        .map_err(|err| Error::GenericString(err.to_string()))?;
+    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
         .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
         .map_err(|err| Error::GenericString(err.to_string()))?;
     Ok(SecretVec::new(Box::new(dk)))
 }
 
+/// How the key of the data dir is encrypted with the key derived from the password, apart from the cipher of the
+/// content, see [`FsOptions::key_wrap`](crate::encryptedfs::FsOptions::key_wrap).
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    EnumIter,
+    EnumString,
+    Display,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+)]
+pub enum KeyWrapAlgorithm {
+    /// Like the content of the files, with their cipher, what data dirs created before we had it use.
+    #[default]
+    Content,
+    /// AES Key Wrap with a 256-bit key, RFC 3394. Deterministic, the integrity check is its 64-bit IV.
+    Aes256Kw,
+    /// The key encrypted at once with a random nonce, followed by the tag.
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+/// Associated data of the keys wrapped with [`KeyWrapAlgorithm::ChaCha20Poly1305`] and
+/// [`KeyWrapAlgorithm::Aes256Gcm`].
+const KEY_WRAP_AAD: &[u8] = b"rencfs-key-wrap";
+
+/// Encrypts `key` with `kek`, the key derived from the password, `cipher` is the one of the content.
+#[allow(clippy::missing_errors_doc)]
+pub fn wrap_key(
+    algorithm: KeyWrapAlgorithm,
+    cipher: Cipher,
+    kek: &SecretVec<u8>,
+    key: &[u8],
+) -> Result<Vec<u8>> {
+    match algorithm {
+        KeyWrapAlgorithm::Content => {
+            Ok(serialize_encrypt_into(io::Cursor::new(vec![]), key, cipher, kek)?.into_inner())
+        }
+        KeyWrapAlgorithm::Aes256Kw => aes_kw(kek)?
+            .wrap_vec(key)
+            .map_err(|_| Error::Generic("cannot wrap key")),
+        KeyWrapAlgorithm::ChaCha20Poly1305 | KeyWrapAlgorithm::Aes256Gcm => {
+            let aead = if algorithm == KeyWrapAlgorithm::ChaCha20Poly1305 {
+                &CHACHA20_POLY1305
+            } else {
+                &AES_256_GCM
+            };
+            let aead_key = LessSafeKey::new(
+                UnboundKey::new(aead, &kek.expose_secret())
+                    .map_err(|_| Error::Generic("invalid key"))?,
+            );
+            let mut nonce = [0; NONCE_LEN];
+            create_rng().fill_bytes(&mut nonce);
+            let mut wrapped = Vec::with_capacity(NONCE_LEN + key.len() + aead.tag_len());
+            wrapped.extend_from_slice(&nonce);
+            wrapped.extend_from_slice(key);
+            let tag = aead_key
+                .seal_in_place_separate_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::from(KEY_WRAP_AAD),
+                    &mut wrapped[NONCE_LEN..],
+                )
+                .map_err(|_| Error::Generic("cannot wrap key"))?;
+            wrapped.extend_from_slice(tag.as_ref());
+            Ok(wrapped)
+        }
+    }
+}
+
+/// Decrypts what [`wrap_key`] encrypted, it fails if `kek` is not the one it was encrypted with.
+#[allow(clippy::missing_errors_doc)]
+pub fn unwrap_key(
+    algorithm: KeyWrapAlgorithm,
+    cipher: Cipher,
+    kek: &SecretVec<u8>,
+    wrapped: &[u8],
+) -> Result<SecretVec<u8>> {
+    let key = match algorithm {
+        KeyWrapAlgorithm::Content => bincode::deserialize_from(create_read(wrapped, cipher, kek))?,
+        KeyWrapAlgorithm::Aes256Kw => aes_kw(kek)?
+            .unwrap_vec(wrapped)
+            .map_err(|_| Error::Generic("cannot unwrap key"))?,
+        KeyWrapAlgorithm::ChaCha20Poly1305 | KeyWrapAlgorithm::Aes256Gcm => {
+            let aead = if algorithm == KeyWrapAlgorithm::ChaCha20Poly1305 {
+                &CHACHA20_POLY1305
+            } else {
+                &AES_256_GCM
+            };
+            if wrapped.len() < NONCE_LEN + aead.tag_len() {
+                return Err(Error::Generic("wrapped key too short"));
+            }
+            let aead_key = LessSafeKey::new(
+                UnboundKey::new(aead, &kek.expose_secret())
+                    .map_err(|_| Error::Generic("invalid key"))?,
+            );
+            let (nonce, data) = wrapped.split_at(NONCE_LEN);
+            let mut key = data.to_vec();
+            let len = aead_key
+                .open_in_place(
+                    Nonce::try_assume_unique_for_key(nonce)
+                        .map_err(|_| Error::Generic("invalid nonce"))?,
+                    Aad::from(KEY_WRAP_AAD),
+                    &mut key,
+                )
+                .map_err(|_| Error::Generic("cannot unwrap key"))?
+                .len();
+            key.truncate(len);
+            key
+        }
+    };
+    Ok(SecretVec::new(Box::new(key)))
+}
+
+fn aes_kw(kek: &SecretVec<u8>) -> Result<KekAes256> {
+    KekAes256::try_from(kek.expose_secret().as_slice()).map_err(|_| Error::Generic("invalid key"))
+}
+
 #[allow(clippy::missing_errors_doc)]
 pub fn encrypt_file_name(
     name: &SecretString,
     cipher: Cipher,
     key: &SecretVec<u8>,
 ) -> FsResult<String> {
-    let secret_string = name.expose_secret();
-
-    match secret_string.as_str() {
-        "$." | "$.." => Ok(secret_string.clone()),
-        "." | ".." => Ok(format!("${secret_string}")),
-        _ => {
-            let secret = SecretString::from_str(&secret_string)
-                .map_err(|err| Error::GenericString(err.to_string()))?;
-            let mut encrypted = encrypt(&secret, cipher, key)?;
-            encrypted = encrypted.replace('/', "|");
-
-            Ok(encrypted)
-        }
+    if let Some(name) = special_file_name(&name.expose_secret()) {
+        return Ok(name);
     }
+    Ok(encrypt(name, cipher, key)?.replace('/', "|"))
+}
+
+/// Like [`encrypt_file_name`] with `name_cipher`, `cipher` is the one of the content.
+///
+/// # Errors
+///
+/// If the name cannot be encrypted.
+pub fn encrypt_file_name_with(
+    name: &SecretString,
+    name_cipher: NameCipher,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    name_keys: &NameKeys,
+) -> FsResult<String> {
+    if let Some(name) = special_file_name(&name.expose_secret()) {
+        return Ok(name);
+    }
+    let encrypted = match name_cipher {
+        NameCipher::Content => encrypt(name, cipher, key)?,
+        NameCipher::ChaCha20Poly1305 | NameCipher::Aes256Gcm => {
+            encrypt(name, name_cipher.cipher(cipher), &name_keys.cipher_key)?
+        }
+        NameCipher::ChaCha20Poly1305Synthetic | NameCipher::Aes256GcmSynthetic => {
+            let mut data = name.expose_secret().as_bytes().to_vec();
+            let nonce = name_keys.synthetic_nonce(&data);
+            let aead_key = LessSafeKey::new(
+                UnboundKey::new(
+                    algorithm(name_cipher.cipher(cipher)),
+                    &name_keys.cipher_key.expose_secret(),
+                )
+                .map_err(|_| Error::Generic("invalid key"))?,
+            );
+            aead_key
+                .seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::empty(),
+                    &mut data,
+                )
+                .map_err(|_| Error::Generic("cannot encrypt"))?;
+            BASE64.encode([&nonce[..], &data].concat())
+        }
+    };
+    Ok(encrypted.replace('/', "|"))
 }
 
 #[allow(clippy::missing_errors_doc)]
 #[must_use]
 pub fn hash_file_name(name: &SecretString) -> String {
-    if *name.expose_secret() == "$." || *name.expose_secret() == "$.." {
-        name.expose_secret().clone()
-    } else if *name.expose_secret() == "." || *name.expose_secret() == ".." {
-        format!("${}", name.expose_secret())
-    } else {
-        hex::encode(hash_secret_string(name))
-    }
+    special_file_name(&name.expose_secret())
+        .unwrap_or_else(|| hex::encode(hash_secret_string(name)))
 }
 
 #[must_use]
@@ -356,9 +1029,25 @@ pub fn serialize_encrypt_into<W, T>(
 where
     W: CryptoInnerWriter + Send + Sync + 'static,
     T: serde::Serialize + ?Sized,
+{
+    serialize_encrypt_into_with(writer, value, fixint_encoding(), cipher, key)
+}
+
+/// Like [`serialize_encrypt_into`] with the `options` of bincode, like [`varint_encoding`].
+pub fn serialize_encrypt_into_with<W, T, O>(
+    writer: W,
+    value: &T,
+    options: O,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> Result<W>
+where
+    W: CryptoInnerWriter + Send + Sync + 'static,
+    T: serde::Serialize + ?Sized,
+    O: bincode::Options,
 {
     let mut writer = create_write(writer, cipher, key);
-    bincode::serialize_into(&mut writer, value)?;
+    options.serialize_into(&mut writer, value)?;
     let writer = writer.finish()?;
     Ok(writer)
 }
@@ -371,16 +1060,50 @@ pub fn atomic_serialize_encrypt_into<T>(
 ) -> Result<()>
 where
     T: serde::Serialize + ?Sized,
+{
+    atomic_serialize_encrypt_into_with(file, value, fixint_encoding(), cipher, key)
+}
+
+/// Like [`atomic_serialize_encrypt_into`] with the `options` of bincode, like [`varint_encoding`].
+pub fn atomic_serialize_encrypt_into_with<T, O>(
+    file: &Path,
+    value: &T,
+    options: O,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+) -> Result<()>
+where
+    T: serde::Serialize + ?Sized,
+    O: bincode::Options,
 {
     let parent = file.parent().ok_or(Error::Generic("file has no parent"))?;
     let mut file = fs_util::open_atomic_write(file)?;
     // println!("file: {:#?}", file.as_file_mut().metadata()?);
-    file = serialize_encrypt_into(file, value, cipher, key)?;
+    file = serialize_encrypt_into_with(file, value, options, cipher, key)?;
     file.commit()?;
     File::open(parent)?.sync_all()?;
     Ok(())
 }
 
+/// The encoding of `bincode::serialize`, integers take their full size.
+#[must_use]
+pub fn fixint_encoding() -> impl bincode::Options + Copy {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// A compact encoding, small integers take fewer bytes, which shrinks structs of mostly small numbers, like the
+/// attributes of files, to about half.
+#[must_use]
+pub fn varint_encoding() -> impl bincode::Options + Copy {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_varint_encoding()
+        .allow_trailing_bytes()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -391,6 +1114,7 @@ mod tests {
         fs::File,
         io::{self, Write},
         path::{Path, PathBuf},
+        str::FromStr,
     };
     use tempfile::{tempdir, TempDir};
 
@@ -473,6 +1197,50 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_name_cipher() {
+        use strum::IntoEnumIterator;
+
+        for cipher in Cipher::iter() {
+            let key = secret_key(cipher);
+            let name = SecretString::from_str("testfile.txt").unwrap();
+            let name_keys = NameKeys::new(&key);
+            for name_cipher in NameCipher::iter() {
+                let encrypted =
+                    encrypt_file_name_with(&name, name_cipher, cipher, &key, &name_keys).unwrap();
+                let again =
+                    encrypt_file_name_with(&name, name_cipher, cipher, &key, &name_keys).unwrap();
+                assert_eq!(name_cipher.is_deterministic(), encrypted == again);
+                let decrypted =
+                    decrypt_file_name_with(&encrypted, name_cipher, cipher, &key, &name_keys)
+                        .unwrap();
+                assert_eq!(name.expose_secret(), decrypted.expose_secret());
+                if name_cipher != NameCipher::Content {
+                    // with its own subkey, not the key of the content
+                    assert!(
+                        decrypt_file_name(&encrypted, name_cipher.cipher(cipher), &key).is_err()
+                    );
+                }
+            }
+            // another name doesn't have the same nonce
+            let other = SecretString::from_str("testfile.txu").unwrap();
+            let synthetic = NameCipher::Aes256GcmSynthetic;
+            let a = encrypt_file_name_with(&name, synthetic, cipher, &key, &name_keys).unwrap();
+            let b = encrypt_file_name_with(&other, synthetic, cipher, &key, &name_keys).unwrap();
+            assert_ne!(a[..16], b[..16]);
+            // the hash is keyed
+            assert_ne!(name_keys.hash_file_name(&name), hash_file_name(&name));
+            assert_ne!(
+                name_keys.hash_file_name(&name),
+                NameKeys::new(&secret_key(cipher)).hash_file_name(&name)
+            );
+            assert_eq!(
+                name_keys.hash_file_name(&SecretString::from_str("..").unwrap()),
+                "$.."
+            );
+        }
+    }
+
     #[test]
     fn test_derive_key() {
         let password = SecretString::from_str("password").unwrap();
@@ -537,6 +1305,111 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decrypt_block() {
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let key = secret_key(cipher);
+            let data = "a".repeat(write::BLOCK_SIZE + 42);
+            let mut writer = create_write(io::Cursor::new(vec![]), cipher, &key);
+            writer.write_all(data.as_bytes()).unwrap();
+            let mut encrypted = writer.finish().unwrap().into_inner();
+            let block_len = cipher.ciphertext_block_len();
+
+            let (first, last) = encrypted.split_at_mut(block_len);
+            assert_eq!(
+                &data.as_bytes()[..write::BLOCK_SIZE],
+                decrypt_block(cipher, &key, 0, first).unwrap()
+            );
+            // wrong index
+            assert!(decrypt_block(cipher, &key, 0, &mut last.to_vec()).is_err());
+            assert_eq!(
+                &data.as_bytes()[write::BLOCK_SIZE..],
+                decrypt_block(cipher, &key, 1, &mut last.to_vec()).unwrap()
+            );
+            // corrupted
+            last[NONCE_LEN + 1] ^= 1;
+            assert!(decrypt_block(cipher, &key, 1, last).is_err());
+            assert!(decrypt_block(cipher, &key, 0, &mut [0; 3]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_key_wrap() {
+        // RFC 3394 4.6, 256 bits of key data with a 256-bit KEK
+        let kek = SecretVec::new(Box::new((0..32).collect()));
+        let key = hex::decode("00112233445566778899AABBCCDDEEFF000102030405060708090A0B0C0D0E0F")
+            .unwrap();
+        let wrapped = hex::decode(
+            "28C9F404C4B810F4CBCCB35CFB87F8263F5786E2D80ED326CBC7F0E71A99F43BFB988B9B7A02DD21",
+        )
+        .unwrap();
+        let cipher = Cipher::ChaCha20Poly1305;
+        assert_eq!(
+            wrapped,
+            wrap_key(KeyWrapAlgorithm::Aes256Kw, cipher, &kek, &key).unwrap()
+        );
+        assert_eq!(
+            key,
+            *unwrap_key(KeyWrapAlgorithm::Aes256Kw, cipher, &kek, &wrapped)
+                .unwrap()
+                .expose_secret()
+        );
+
+        for cipher in [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            for algorithm in [
+                KeyWrapAlgorithm::Content,
+                KeyWrapAlgorithm::Aes256Kw,
+                KeyWrapAlgorithm::ChaCha20Poly1305,
+                KeyWrapAlgorithm::Aes256Gcm,
+            ] {
+                let kek = secret_key(cipher);
+                let key = secret_key(cipher);
+                let mut wrapped = wrap_key(algorithm, cipher, &kek, &key.expose_secret()).unwrap();
+                assert_eq!(
+                    *key.expose_secret(),
+                    *unwrap_key(algorithm, cipher, &kek, &wrapped)
+                        .unwrap()
+                        .expose_secret()
+                );
+                assert!(unwrap_key(algorithm, cipher, &secret_key(cipher), &wrapped).is_err());
+                *wrapped.last_mut().unwrap() ^= 1;
+                assert!(unwrap_key(algorithm, cipher, &kek, &wrapped).is_err());
+                assert!(unwrap_key(algorithm, cipher, &kek, &wrapped[..7]).is_err());
+            }
+        }
+        assert!(wrap_key(KeyWrapAlgorithm::Aes256Kw, cipher, &kek, &[0; 12]).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_block() {
+        for &cipher in &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let key = secret_key(cipher);
+            let data = "a".repeat(write::BLOCK_SIZE);
+            let mut first = encrypt_block(cipher, &key, 0, data.as_bytes()).unwrap();
+            let mut last = encrypt_block(cipher, &key, 1, b"bcd").unwrap();
+            assert_eq!(cipher.ciphertext_block_len(), first.len());
+            assert_ne!(
+                first,
+                encrypt_block(cipher, &key, 0, data.as_bytes()).unwrap()
+            );
+
+            // what create_read reads
+            let mut file = first.clone();
+            file.extend_from_slice(&last);
+            let mut reader = create_read(io::Cursor::new(file), cipher, &key);
+            let mut decrypted = String::new();
+            reader.read_to_string(&mut decrypted).unwrap();
+            assert_eq!(format!("{data}bcd"), decrypted);
+
+            assert_eq!(
+                data.as_bytes(),
+                decrypt_block(cipher, &key, 0, &mut first).unwrap()
+            );
+            assert!(decrypt_block(cipher, &key, 0, &mut last.clone()).is_err());
+            assert_eq!(b"bcd", decrypt_block(cipher, &key, 1, &mut last).unwrap());
+        }
+    }
+
     #[test]
     fn test_encrypt_decrypt_empty_string() {
         let key = SecretVec::from(vec![0; 32]);
@@ -0,0 +1,273 @@
+//! Throughput of each [`Cipher`] and block size, in memory so the disk doesn't count.
+//!
+//! Run with `cargo bench crypto::bench`, `MB/s` is of plaintext.
+
+#[allow(unused_imports)]
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[allow(unused_imports)]
+use rand::{Rng, RngCore};
+#[allow(unused_imports)]
+use shush_rs::SecretVec;
+#[allow(unused_imports)]
+use test::{black_box, Bencher};
+
+#[allow(unused_imports)]
+use crate::crypto;
+#[allow(unused_imports)]
+use crate::crypto::read::CryptoRead;
+#[allow(unused_imports)]
+use crate::crypto::write::CryptoWrite;
+#[allow(unused_imports)]
+use crate::crypto::Cipher;
+
+/// Size of the file for the sequential and random benches.
+#[allow(dead_code)]
+const FILE_LEN: usize = 1024 * 1024;
+/// Size of each random read and write, like a page.
+#[allow(dead_code)]
+const RANDOM_IO_LEN: usize = 4 * 1024;
+
+#[allow(dead_code)]
+fn key(cipher: Cipher) -> SecretVec<u8> {
+    let mut key = vec![0; cipher.key_len()];
+    rand::thread_rng().fill_bytes(&mut key);
+    SecretVec::new(Box::new(key))
+}
+
+#[allow(dead_code)]
+fn random_data(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut data);
+    data
+}
+
+/// An encrypted file of [`FILE_LEN`] in memory.
+#[allow(dead_code)]
+fn encrypted_file(cipher: Cipher, key: &SecretVec<u8>, block_size: usize) -> Vec<u8> {
+    let mut writer =
+        crypto::create_write_with_block_size(io::Cursor::new(vec![]), cipher, key, block_size);
+    writer.write_all(&random_data(FILE_LEN)).unwrap();
+    writer.finish().unwrap().into_inner()
+}
+
+#[allow(dead_code)]
+fn encrypt_block(b: &mut Bencher, cipher: Cipher, block_size: usize) {
+    let key = key(cipher);
+    let block = random_data(block_size);
+    b.bytes = block_size as u64;
+    b.iter(|| black_box(crypto::encrypt_block(cipher, &key, 0, &block).unwrap()));
+}
+
+#[allow(dead_code)]
+fn decrypt_block(b: &mut Bencher, cipher: Cipher, block_size: usize) {
+    let key = key(cipher);
+    let block = crypto::encrypt_block(cipher, &key, 0, &random_data(block_size)).unwrap();
+    b.bytes = block_size as u64;
+    b.iter(|| {
+        let mut block = block.clone();
+        black_box(
+            crypto::decrypt_block(cipher, &key, 0, &mut block)
+                .unwrap()
+                .len(),
+        )
+    });
+}
+
+#[allow(dead_code)]
+fn sequential_write(b: &mut Bencher, cipher: Cipher, block_size: usize) {
+    let key = key(cipher);
+    let data = random_data(FILE_LEN);
+    b.bytes = FILE_LEN as u64;
+    b.iter(|| {
+        let mut writer =
+            crypto::create_write_with_block_size(io::Cursor::new(vec![]), cipher, &key, block_size);
+        writer.write_all(&data).unwrap();
+        black_box(writer.finish().unwrap())
+    });
+}
+
+#[allow(dead_code)]
+fn sequential_read(b: &mut Bencher, cipher: Cipher, block_size: usize) {
+    let key = key(cipher);
+    let file = encrypted_file(cipher, &key, block_size);
+    b.bytes = FILE_LEN as u64;
+    b.iter(|| {
+        let mut reader = crypto::create_read_with_block_size(&file[..], cipher, &key, block_size);
+        black_box(io::copy(&mut reader, &mut io::sink()).unwrap())
+    });
+}
+
+#[allow(dead_code)]
+fn random_write(b: &mut Bencher, cipher: Cipher, block_size: usize) {
+    let key = key(cipher);
+    let file = encrypted_file(cipher, &key, block_size);
+    let data = random_data(RANDOM_IO_LEN);
+    let mut writer =
+        crypto::create_write_seek_with_block_size(io::Cursor::new(file), cipher, &key, block_size);
+    b.bytes = RANDOM_IO_LEN as u64;
+    b.iter(|| {
+        let offset = rand::thread_rng().gen_range(0..(FILE_LEN - RANDOM_IO_LEN) as u64);
+        writer.seek(SeekFrom::Start(offset)).unwrap();
+        writer.write_all(&data).unwrap();
+        // so each write pays for its blocks
+        writer.flush().unwrap();
+        black_box(offset)
+    });
+}
+
+#[allow(dead_code)]
+fn random_read(b: &mut Bencher, cipher: Cipher, block_size: usize) {
+    let key = key(cipher);
+    let file = encrypted_file(cipher, &key, block_size);
+    let mut reader =
+        crypto::create_read_seek_with_block_size(io::Cursor::new(file), cipher, &key, block_size);
+    let mut buf = vec![0; RANDOM_IO_LEN];
+    b.bytes = RANDOM_IO_LEN as u64;
+    b.iter(|| {
+        let offset = rand::thread_rng().gen_range(0..(FILE_LEN - RANDOM_IO_LEN) as u64);
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        black_box(buf[0])
+    });
+}
+
+#[bench]
+fn bench_encrypt_block_chacha_4k(b: &mut Bencher) {
+    encrypt_block(b, Cipher::ChaCha20Poly1305, 4 * 1024);
+}
+
+#[bench]
+fn bench_encrypt_block_chacha_64k(b: &mut Bencher) {
+    encrypt_block(b, Cipher::ChaCha20Poly1305, 64 * 1024);
+}
+
+#[bench]
+fn bench_encrypt_block_chacha_1m(b: &mut Bencher) {
+    encrypt_block(b, Cipher::ChaCha20Poly1305, 1024 * 1024);
+}
+
+#[bench]
+fn bench_encrypt_block_aes_4k(b: &mut Bencher) {
+    encrypt_block(b, Cipher::Aes256Gcm, 4 * 1024);
+}
+
+#[bench]
+fn bench_encrypt_block_aes_64k(b: &mut Bencher) {
+    encrypt_block(b, Cipher::Aes256Gcm, 64 * 1024);
+}
+
+#[bench]
+fn bench_encrypt_block_aes_1m(b: &mut Bencher) {
+    encrypt_block(b, Cipher::Aes256Gcm, 1024 * 1024);
+}
+
+#[bench]
+fn bench_decrypt_block_chacha_4k(b: &mut Bencher) {
+    decrypt_block(b, Cipher::ChaCha20Poly1305, 4 * 1024);
+}
+
+#[bench]
+fn bench_decrypt_block_chacha_64k(b: &mut Bencher) {
+    decrypt_block(b, Cipher::ChaCha20Poly1305, 64 * 1024);
+}
+
+#[bench]
+fn bench_decrypt_block_chacha_1m(b: &mut Bencher) {
+    decrypt_block(b, Cipher::ChaCha20Poly1305, 1024 * 1024);
+}
+
+#[bench]
+fn bench_decrypt_block_aes_4k(b: &mut Bencher) {
+    decrypt_block(b, Cipher::Aes256Gcm, 4 * 1024);
+}
+
+#[bench]
+fn bench_decrypt_block_aes_64k(b: &mut Bencher) {
+    decrypt_block(b, Cipher::Aes256Gcm, 64 * 1024);
+}
+
+#[bench]
+fn bench_decrypt_block_aes_1m(b: &mut Bencher) {
+    decrypt_block(b, Cipher::Aes256Gcm, 1024 * 1024);
+}
+
+#[bench]
+fn bench_sequential_write_chacha_4k(b: &mut Bencher) {
+    sequential_write(b, Cipher::ChaCha20Poly1305, 4 * 1024);
+}
+
+#[bench]
+fn bench_sequential_write_chacha_64k(b: &mut Bencher) {
+    sequential_write(b, Cipher::ChaCha20Poly1305, 64 * 1024);
+}
+
+#[bench]
+fn bench_sequential_write_aes_4k(b: &mut Bencher) {
+    sequential_write(b, Cipher::Aes256Gcm, 4 * 1024);
+}
+
+#[bench]
+fn bench_sequential_write_aes_64k(b: &mut Bencher) {
+    sequential_write(b, Cipher::Aes256Gcm, 64 * 1024);
+}
+
+#[bench]
+fn bench_sequential_read_chacha_4k(b: &mut Bencher) {
+    sequential_read(b, Cipher::ChaCha20Poly1305, 4 * 1024);
+}
+
+#[bench]
+fn bench_sequential_read_chacha_64k(b: &mut Bencher) {
+    sequential_read(b, Cipher::ChaCha20Poly1305, 64 * 1024);
+}
+
+#[bench]
+fn bench_sequential_read_aes_4k(b: &mut Bencher) {
+    sequential_read(b, Cipher::Aes256Gcm, 4 * 1024);
+}
+
+#[bench]
+fn bench_sequential_read_aes_64k(b: &mut Bencher) {
+    sequential_read(b, Cipher::Aes256Gcm, 64 * 1024);
+}
+
+#[bench]
+fn bench_random_write_chacha_4k(b: &mut Bencher) {
+    random_write(b, Cipher::ChaCha20Poly1305, 4 * 1024);
+}
+
+#[bench]
+fn bench_random_write_chacha_64k(b: &mut Bencher) {
+    random_write(b, Cipher::ChaCha20Poly1305, 64 * 1024);
+}
+
+#[bench]
+fn bench_random_write_aes_4k(b: &mut Bencher) {
+    random_write(b, Cipher::Aes256Gcm, 4 * 1024);
+}
+
+#[bench]
+fn bench_random_write_aes_64k(b: &mut Bencher) {
+    random_write(b, Cipher::Aes256Gcm, 64 * 1024);
+}
+
+#[bench]
+fn bench_random_read_chacha_4k(b: &mut Bencher) {
+    random_read(b, Cipher::ChaCha20Poly1305, 4 * 1024);
+}
+
+#[bench]
+fn bench_random_read_chacha_64k(b: &mut Bencher) {
+    random_read(b, Cipher::ChaCha20Poly1305, 64 * 1024);
+}
+
+#[bench]
+fn bench_random_read_aes_4k(b: &mut Bencher) {
+    random_read(b, Cipher::Aes256Gcm, 4 * 1024);
+}
+
+#[bench]
+fn bench_random_read_aes_64k(b: &mut Bencher) {
+    random_read(b, Cipher::Aes256Gcm, 64 * 1024);
+}
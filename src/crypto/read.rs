@@ -1,5 +1,5 @@
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{BufRead, Read, Seek, SeekFrom};
 use std::sync::{Arc, Mutex};
 
 use ring::aead::{
@@ -11,6 +11,7 @@ use tracing::{error, instrument, warn};
 
 use crate::crypto::buf_mut::BufMut;
 use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::BlockTransform;
 use crate::stream_util;
 
 mod bench;
@@ -26,7 +27,7 @@ pub trait CryptoRead<R: Read + Send + Sync>: Read + Send + Sync {
 /// ring
 #[macro_export]
 macro_rules! decrypt_block {
-    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr) => {{
+    ($block_index:expr, $buf:expr, $input:expr, $last_nonce:expr, $opening_key:expr, $transforms:expr) => {{
         let len = {
             $buf.clear();
             let buffer = $buf.as_mut_remaining();
@@ -46,6 +47,12 @@ macro_rules! decrypt_block {
                 pos
             };
             if len != 0 {
+                if len < NONCE_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "block is too short",
+                    ));
+                }
                 let data = &mut buffer[..len];
                 let aad = Aad::from(($block_index).to_le_bytes());
                 // extract nonce
@@ -58,6 +65,7 @@ macro_rules! decrypt_block {
                     error!("error opening within: {}", err);
                     io::Error::new(io::ErrorKind::Other, "error opening within")
                 })?;
+                $crate::crypto::transform_on_read(&$transforms, plaintext)?;
                 len = plaintext.len();
             }
             len
@@ -83,12 +91,24 @@ pub struct RingCryptoRead<R: Read> {
     ciphertext_block_size: usize,
     plaintext_block_size: usize,
     block_index: u64,
+    transforms: Vec<Arc<dyn BlockTransform>>,
 }
 
 impl<R: Read> RingCryptoRead<R> {
     #[allow(clippy::missing_panics_doc)]
     pub fn new(reader: R, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
-        let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+        Self::new_with_block_size(reader, algorithm, key, BLOCK_SIZE)
+    }
+
+    /// Like [`Self::new`] but for content written in blocks of `block_size` bytes instead of [`BLOCK_SIZE`].
+    #[allow(clippy::missing_panics_doc)]
+    pub fn new_with_block_size(
+        reader: R,
+        algorithm: &'static Algorithm,
+        key: &SecretVec<u8>,
+        block_size: usize,
+    ) -> Self {
+        let ciphertext_block_size = NONCE_LEN + block_size + algorithm.tag_len();
         let buf = BufMut::new(vec![0; ciphertext_block_size]);
         let last_nonce = Arc::new(Mutex::new(None));
         let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
@@ -100,10 +120,18 @@ impl<R: Read> RingCryptoRead<R> {
             buf,
             last_nonce,
             ciphertext_block_size,
-            plaintext_block_size: BLOCK_SIZE,
+            plaintext_block_size: block_size,
             block_index: 0,
+            transforms: vec![],
         }
     }
+
+    /// Pass each block through `transforms` after decrypting it, see [`BlockTransform`].
+    #[must_use]
+    pub fn with_transforms(mut self, transforms: Vec<Arc<dyn BlockTransform>>) -> Self {
+        self.transforms = transforms;
+        self
+    }
 }
 
 impl<R: Read> Read for RingCryptoRead<R> {
@@ -120,13 +148,39 @@ impl<R: Read> Read for RingCryptoRead<R> {
             self.buf,
             self.input.as_mut().unwrap(),
             self.last_nonce,
-            self.opening_key
+            self.opening_key,
+            self.transforms
         );
         let len = self.buf.read(buf)?;
         Ok(len)
     }
 }
 
+/// Gives the decrypted block from the internal buffer, without copying it.
+impl<R: Read> BufRead for RingCryptoRead<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buf.available_read() == 0 {
+            decrypt_block!(
+                self.block_index,
+                self.buf,
+                self.input.as_mut().unwrap(),
+                self.last_nonce,
+                self.opening_key,
+                self.transforms
+            );
+        }
+        Ok(self.buf.as_ref_read_available())
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buf.available_read());
+        self.buf
+            .seek_read(SeekFrom::Current(amt as i64))
+            .expect("in bounds");
+    }
+}
+
 pub(crate) struct ExistingNonceSequence {
     last_nonce: Arc<Mutex<Option<Vec<u8>>>>,
 }
@@ -151,7 +205,7 @@ impl<R: Read + Send + Sync> CryptoRead<R> for RingCryptoRead<R> {
 
 /// Read with Seek
 pub trait CryptoReadSeek<R: Read + Seek + Send + Sync>:
-    CryptoRead<R> + Read + Seek + Send + Sync
+    CryptoRead<R> + Read + BufRead + Seek + Send + Sync
 {
 }
 
@@ -170,10 +224,16 @@ impl<R: Read + Seek> RingCryptoRead<R> {
         if ciphertext_len == 0 {
             return Ok(0);
         }
-        let plaintext_len = ciphertext_len
-            - ((ciphertext_len / self.ciphertext_block_size as u64) + 1)
-                * (self.ciphertext_block_size - self.plaintext_block_size) as u64;
-        Ok(plaintext_len)
+        // each block, including the last one if it's full, has the nonce and the tag
+        ciphertext_len
+            .checked_sub(
+                ciphertext_len.div_ceil(self.ciphertext_block_size as u64)
+                    * (self.ciphertext_block_size - self.plaintext_block_size) as u64,
+            )
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "last block is too short",
+            ))
     }
 }
 
@@ -220,12 +280,22 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
             }
         } else {
             // change block
+            let (new_block_index, offset_in_block) = if new_pos > 0
+                && new_pos == plaintext_len
+                && new_pos.is_multiple_of(self.plaintext_block_size as u64)
+            {
+                // the end of a file with only full blocks, there is no next block to start, go at the end of the
+                // last one
+                (new_block_index - 1, self.plaintext_block_size as u64)
+            } else {
+                (new_block_index, new_pos % self.plaintext_block_size as u64)
+            };
             self.input.as_mut().unwrap().seek(SeekFrom::Start(
                 new_block_index * self.ciphertext_block_size as u64,
             ))?;
             self.buf.clear();
             self.block_index = new_block_index;
-            if new_pos % self.plaintext_block_size as u64 == 0 {
+            if offset_in_block == 0 {
                 // in case we need to seek at the start of the new block, we need to decrypt here, because we altered
                 // the block_index but the seek seek_forward from below will not decrypt anything
                 // as the offset in new block is 0. In that case the po()
@@ -235,12 +305,12 @@ impl<R: Read + Seek> Seek for RingCryptoRead<R> {
                     self.buf,
                     self.input.as_mut().unwrap(),
                     self.last_nonce,
-                    self.opening_key
+                    self.opening_key,
+                    self.transforms
                 );
             }
             // seek inside new block
-            let plaintext_block_size = self.plaintext_block_size;
-            stream_util::seek_forward(self, new_pos % plaintext_block_size as u64, true)?;
+            stream_util::seek_forward(self, offset_in_block, true)?;
         }
         Ok(self.pos())
     }
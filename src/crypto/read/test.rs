@@ -362,6 +362,36 @@ fn test_ring_crypto_read_seek_blocks_aes() {
     assert_eq!(&buffer, &data[42..BLOCK_SIZE + 42]);
 }
 
+#[test]
+#[traced_test]
+fn test_ring_crypto_read_seek_end_of_full_blocks() {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use rand::Rng;
+    use ring::aead::CHACHA20_POLY1305;
+
+    use crate::crypto::read::RingCryptoRead;
+    use crate::crypto::write::{CryptoWrite, RingCryptoWrite, BLOCK_SIZE};
+
+    // the last block is full, so there is no partial block at the end
+    let mut data = vec![0u8; 2 * BLOCK_SIZE];
+    rand::thread_rng().fill(&mut data[..]);
+    let algorithm = &CHACHA20_POLY1305;
+    let key = SecretVec::new(Box::new(vec![0; algorithm.key_len()]));
+    let mut writer = RingCryptoWrite::new(Cursor::new(vec![]), false, algorithm, &key);
+    writer.write_all(&data).unwrap();
+    let mut cursor = writer.finish().unwrap();
+
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = RingCryptoRead::new_seek(&mut cursor, algorithm, &key);
+    assert_eq!(data.len() as u64, reader.seek(SeekFrom::End(0)).unwrap());
+    let pos = data.len() as u64 - 20;
+    assert_eq!(pos, reader.seek(SeekFrom::Start(pos)).unwrap());
+    let mut buffer = vec![];
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(&buffer, &data[data.len() - 20..]);
+}
+
 #[test]
 #[traced_test]
 fn test_ring_crypto_read_seek_blocks_boundary_chacha() {
@@ -638,3 +668,95 @@ fn reader_with_seeks() {
     reader.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(reader.stream_position().unwrap(), 42);
 }
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct XorTransform(u8);
+
+impl crate::crypto::BlockTransform for XorTransform {
+    fn on_write(&self, block: &mut Vec<u8>) -> io::Result<()> {
+        block.iter_mut().for_each(|b| *b ^= self.0);
+        Ok(())
+    }
+
+    fn on_read(&self, block: &mut Vec<u8>) -> io::Result<()> {
+        self.on_write(block)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct TruncateTransform;
+
+impl crate::crypto::BlockTransform for TruncateTransform {
+    fn on_write(&self, block: &mut Vec<u8>) -> io::Result<()> {
+        block.pop();
+        Ok(())
+    }
+
+    fn on_read(&self, _block: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+#[traced_test]
+fn test_block_transforms() {
+    use std::io::{Cursor, Read, SeekFrom, Write};
+    use std::sync::Arc;
+
+    use crate::crypto;
+    use crate::crypto::read::BLOCK_SIZE;
+    use crate::crypto::write::CryptoWrite;
+    use crate::crypto::{BlockTransform, Cipher};
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let transforms: Vec<Arc<dyn BlockTransform>> =
+        vec![Arc::new(XorTransform(0x5a)), Arc::new(XorTransform(0x0f))];
+    let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 42).map(|i| (i % 251) as u8).collect();
+
+    let mut writer = crypto::create_write_with_transforms(
+        Cursor::new(vec![]),
+        cipher,
+        &key,
+        BLOCK_SIZE,
+        transforms.clone(),
+        None,
+    );
+    writer.write_all(&data).unwrap();
+    let mut cursor = writer.finish().unwrap();
+
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader =
+        crypto::create_read_seek_with_transforms(cursor, cipher, &key, BLOCK_SIZE, transforms);
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(data, read);
+    reader.seek(SeekFrom::Start(BLOCK_SIZE as u64 + 1)).unwrap();
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(data[BLOCK_SIZE + 1], buf[0]);
+
+    // without them we get what they wrote
+    let mut cursor = reader.into_inner();
+    cursor.seek(SeekFrom::Start(0)).unwrap();
+    let mut reader = crypto::create_read(cursor, cipher, &key);
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(
+        data.iter().map(|b| b ^ 0x5a ^ 0x0f).collect::<Vec<_>>(),
+        read
+    );
+
+    // the length of the blocks must stay the same
+    let mut writer = crypto::create_write_with_transforms(
+        Cursor::new(vec![]),
+        cipher,
+        &key,
+        BLOCK_SIZE,
+        vec![Arc::new(TruncateTransform)],
+        None,
+    );
+    assert!(writer.write_all(&data).is_err());
+}
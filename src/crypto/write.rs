@@ -14,6 +14,7 @@ use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
 use crate::crypto::read::ExistingNonceSequence;
+use crate::crypto::{BlockCounter, BlockTransform};
 use crate::{crypto, decrypt_block, stream_util};
 
 mod bench;
@@ -78,29 +79,42 @@ pub struct RingCryptoWrite<W: CryptoInnerWriter + Send + Sync> {
     opening_key: Option<OpeningKey<ExistingNonceSequence>>,
     last_nonce: Option<Arc<Mutex<Option<Vec<u8>>>>>,
     decrypt_buf: Option<BufMut>,
+    /// We seeked to the start of an existing block but didn't decrypt it yet, if the next write replaces all of it
+    /// we don't need to.
+    pending_decrypt: bool,
+    transforms: Vec<Arc<dyn BlockTransform>>,
+    counter: Option<Arc<dyn BlockCounter>>,
 }
 
 impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::needless_pass_by_value)]
-    pub fn new(
+    pub fn new(writer: W, seek: bool, algorithm: &'static Algorithm, key: &SecretVec<u8>) -> Self {
+        Self::new_with_block_size(writer, seek, algorithm, key, BLOCK_SIZE)
+    }
+
+    /// Like [`Self::new`] but the plaintext is split in blocks of `block_size` bytes instead of [`BLOCK_SIZE`].
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn new_with_block_size(
         mut writer: W,
         seek: bool,
         algorithm: &'static Algorithm,
         key: &SecretVec<u8>,
+        block_size: usize,
     ) -> Self {
         let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).expect("unbound key");
         let nonce_sequence = Arc::new(Mutex::new(RandomNonceSequence::default()));
         let wrapping_nonce_sequence = RandomNonceSequenceWrapper::new(nonce_sequence.clone());
         let sealing_key = SealingKey::new(unbound_key, wrapping_nonce_sequence);
-        let buf = BufMut::new(vec![0; BLOCK_SIZE]);
+        let buf = BufMut::new(vec![0; block_size]);
 
         let (last_nonce, opening_key, decrypt_buf) = if writer.as_write_seek_read().is_some() {
             let last_nonce = Arc::new(Mutex::new(None));
             let unbound_key = UnboundKey::new(algorithm, &key.expose_secret()).unwrap();
             let nonce_sequence2 = ExistingNonceSequence::new(last_nonce.clone());
             let opening_key = OpeningKey::new(unbound_key, nonce_sequence2);
-            let ciphertext_block_size = NONCE_LEN + BLOCK_SIZE + algorithm.tag_len();
+            let ciphertext_block_size = NONCE_LEN + block_size + algorithm.tag_len();
             let decrypt_buf = BufMut::new(vec![0; ciphertext_block_size]);
 
             (Some(last_nonce), Some(opening_key), Some(decrypt_buf))
@@ -113,17 +127,35 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             sealing_key,
             buf,
             nonce_sequence,
-            ciphertext_block_size: NONCE_LEN + BLOCK_SIZE + algorithm.tag_len(),
-            plaintext_block_size: BLOCK_SIZE,
+            ciphertext_block_size: NONCE_LEN + block_size + algorithm.tag_len(),
+            plaintext_block_size: block_size,
             block_index: 0,
             opening_key,
             last_nonce,
             decrypt_buf,
+            pending_decrypt: false,
+            transforms: vec![],
+            counter: None,
         }
     }
 
+    /// Pass each block through `transforms` before encrypting it, see [`BlockTransform`].
+    #[must_use]
+    pub fn with_transforms(mut self, transforms: Vec<Arc<dyn BlockTransform>>) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Tell `counter` about each block we encrypt, see [`BlockCounter`].
+    #[must_use]
+    pub fn with_counter(mut self, counter: Option<Arc<dyn BlockCounter>>) -> Self {
+        self.counter = counter;
+        self
+    }
+
     fn encrypt_and_write(&mut self) -> io::Result<()> {
         let data = self.buf.as_mut();
+        crypto::transform_on_write(&self.transforms, data)?;
         let aad = Aad::from(self.block_index.to_le_bytes());
         let tag = self
             .sealing_key
@@ -137,6 +169,10 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             })?;
         let nonce_sequence = self.nonce_sequence.lock().unwrap();
         let nonce = &nonce_sequence.last_nonce;
+        crypto::transform_on_sealed(&self.transforms, nonce);
+        if let Some(counter) = &self.counter {
+            counter.add(1);
+        }
         let writer = self
             .writer
             .as_mut()
@@ -155,6 +191,7 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
     }
 
     fn decrypt_block(&mut self) -> io::Result<bool> {
+        self.pending_decrypt = false;
         let old_block_index = self.block_index;
         let writer = self
             .writer
@@ -170,7 +207,8 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.decrypt_buf.as_mut().unwrap(),
             writer,
             self.last_nonce.as_ref().unwrap(),
-            self.opening_key.as_mut().unwrap()
+            self.opening_key.as_mut().unwrap(),
+            self.transforms
         );
         if old_block_index == self.block_index {
             // no decryption happened
@@ -217,7 +255,14 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
                 "write called on already finished writer",
             ));
         }
-        if self.pos() == 0 && self.buf.available() == 0 {
+        // we are at the start of a block when we load it, if we replace all of it there's no need to decrypt it
+        let whole_block = buf.len() >= self.plaintext_block_size;
+        if self.pending_decrypt {
+            self.pending_decrypt = false;
+            if !whole_block {
+                self.decrypt_block()?;
+            }
+        } else if self.pos() == 0 && self.buf.available() == 0 {
             if self.seek {
                 // first write since we opened the writer, try to load the first block
                 let writer = self
@@ -231,7 +276,9 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
                     ))?;
                 writer.seek(SeekFrom::Start(0))?;
                 self.block_index = 0;
-                self.decrypt_block()?;
+                if !whole_block {
+                    self.decrypt_block()?;
+                }
             }
         } else if self.buf.is_dirty() && self.buf.remaining() == 0 {
             self.flush()?;
@@ -247,7 +294,7 @@ impl<W: CryptoInnerWriter + Send + Sync> Write for RingCryptoWrite<W> {
                     "downcast failed",
                 ))?;
             let stream_len = writer.stream_len()?;
-            if stream_len > block_index * self.ciphertext_block_size as u64 {
+            if stream_len > block_index * self.ciphertext_block_size as u64 && !whole_block {
                 self.decrypt_block()?;
             }
         }
@@ -348,8 +395,14 @@ impl<W: CryptoInnerWriter + Send + Sync> RingCryptoWrite<W> {
             self.block_index * self.plaintext_block_size as u64 + self.buf.available() as u64
         } else {
             ciphertext_len
-                - ((ciphertext_len / self.ciphertext_block_size as u64) + 1)
-                    * (self.ciphertext_block_size - self.plaintext_block_size) as u64
+                .checked_sub(
+                    ciphertext_len.div_ceil(self.ciphertext_block_size as u64)
+                        * (self.ciphertext_block_size - self.plaintext_block_size) as u64,
+                )
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "last block is too short",
+                ))?
         };
         Ok(plaintext_len)
     }
@@ -431,8 +484,16 @@ impl<W: CryptoInnerWriter + Send + Sync> Seek for RingCryptoWrite<W> {
             writer.seek(SeekFrom::Start(
                 target_block_index * self.ciphertext_block_size as u64,
             ))?;
-            // try to decrypt target block
             self.block_index = target_block_index;
+            if target_block_index == new_block_index
+                && new_pos.is_multiple_of(self.plaintext_block_size as u64)
+            {
+                // decrypt it only if the next write doesn't replace all of it
+                self.buf.clear();
+                self.pending_decrypt = true;
+                return Ok(self.pos());
+            }
+            // try to decrypt target block
             self.decrypt_block()?;
             if self.block_index == new_block_index {
                 // seek inside new block as much as we can
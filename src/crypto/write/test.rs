@@ -1381,3 +1381,75 @@ fn writer_with_seeks() {
     writer.seek(SeekFrom::Start(42)).unwrap();
     assert_eq!(writer.stream_position().unwrap(), 42);
 }
+
+#[test]
+#[traced_test]
+fn test_aligned_overwrite_doesnt_read() {
+    use std::io::{Cursor, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+
+    /// Counts the bytes read from the encrypted content.
+    struct CountingCursor {
+        inner: Cursor<Vec<u8>>,
+        read: Arc<AtomicUsize>,
+    }
+    impl Read for CountingCursor {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.inner.read(buf)?;
+            self.read.fetch_add(len, Ordering::SeqCst);
+            Ok(len)
+        }
+    }
+    impl Write for CountingCursor {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for CountingCursor {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    let cipher = Cipher::ChaCha20Poly1305;
+    let key = create_secret_key(cipher.key_len());
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 42).map(|i| (i % 251) as u8).collect();
+    let mut writer = crypto::create_write(Cursor::new(vec![]), cipher, &key);
+    writer.write_all(&data).unwrap();
+    let encrypted = writer.finish().unwrap().into_inner();
+
+    let read = Arc::new(AtomicUsize::new(0));
+    let cursor = CountingCursor {
+        inner: Cursor::new(encrypted),
+        read: read.clone(),
+    };
+    let mut writer = crypto::create_write_seek(cursor, cipher, &key);
+    let new_block = vec![7; BLOCK_SIZE];
+    // the first block and one in the middle
+    for block in [0, 2] {
+        writer
+            .seek(SeekFrom::Start((block * BLOCK_SIZE) as u64))
+            .unwrap();
+        writer.write_all(&new_block).unwrap();
+    }
+    assert_eq!(0, read.load(Ordering::SeqCst));
+    // a partial write still needs the rest of the block
+    writer.seek(SeekFrom::Start(BLOCK_SIZE as u64)).unwrap();
+    writer.write_all(&[9; 10]).unwrap();
+    assert!(read.load(Ordering::SeqCst) > 0);
+    let encrypted = writer.finish().unwrap().inner.into_inner();
+
+    let mut expected = data;
+    expected[..BLOCK_SIZE].fill(7);
+    expected[BLOCK_SIZE..BLOCK_SIZE + 10].fill(9);
+    expected[BLOCK_SIZE * 2..BLOCK_SIZE * 3].fill(7);
+    let mut reader = crypto::create_read(Cursor::new(encrypted), cipher, &key);
+    let mut decrypted = vec![];
+    reader.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(expected, decrypted);
+}
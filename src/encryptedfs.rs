@@ -1,38 +1,45 @@
 use argon2::password_hash::rand_core::RngCore;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::{stream, Stream, TryStreamExt};
+use icu_normalizer::ComposingNormalizerBorrowed;
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
 use serde::{Deserialize, Serialize};
+use shush_rs::zeroize::{Zeroize, Zeroizing};
 use shush_rs::{ExposeSecret, SecretBox, SecretString, SecretVec};
 use std::backtrace::Backtrace;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 use std::fs::{DirEntry, File, OpenOptions, ReadDir};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, LazyLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock, Weak};
 use std::time::{Duration, SystemTime};
 use std::{fs, io};
 use thiserror::Error;
 use tokio::runtime::Runtime;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{broadcast, Mutex, RwLock};
 use tokio::task::{JoinError, JoinSet};
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::{debug, error, info, instrument, warn, Level};
 
 use crate::arc_hashmap::ArcHashMap;
 use crate::crypto::read::{CryptoRead, CryptoReadSeek};
-use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek};
-use crate::crypto::Cipher;
+use crate::crypto::write::{CryptoInnerWriter, CryptoWrite, CryptoWriteSeek, BLOCK_SIZE};
+use crate::crypto::{BlockCounter, BlockTransform, Cipher, KeyWrapAlgorithm, NameCipher, NameKeys};
 use crate::expire_value::{ExpireValue, ValueProvider};
-use crate::{crypto, fs_util, stream_util};
+use crate::storage::StorageBackend;
+use crate::{async_util, crypto, fs_util, stream_util};
+use acl::Acl;
 use bon::bon;
+use metadata_db::MetadataDb;
 
+mod acl;
 mod bench;
+mod metadata_db;
 #[cfg(test)]
 mod test;
 
@@ -41,12 +48,59 @@ pub(crate) const CONTENTS_DIR: &str = "contents";
 pub(crate) const SECURITY_DIR: &str = "security";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+pub(crate) const PARAMS_FILENAME: &str = "params";
+pub(crate) const JOURNAL_DIR: &str = "journal";
+pub(crate) const JOURNAL_LEN_FILENAME: &str = "len";
+/// In the journal of a file, the size it has after [`EncryptedFs::replace_contents`].
+pub(crate) const JOURNAL_SIZE_FILENAME: &str = "size";
+pub(crate) const PARITY_DIR: &str = "parity";
+pub(crate) const XATTR_DIR: &str = "xattr";
+/// In [`INODES_DIR`], used with [`MetadataStore::EmbeddedDb`].
+pub(crate) const METADATA_DB_FILENAME: &str = "metadata.redb";
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
+/// Hashes of names deleted in the upper layer of an overlay, see [`EncryptedFs::new_overlay`].
+pub(crate) const WHITEOUT_DIR: &str = "whiteout";
 
 pub(crate) const ROOT_INODE: u64 = 1;
 
+/// Smallest block size accepted by [`EncryptedFs::change_block_size`].
+pub const MIN_BLOCK_SIZE: usize = 512;
+/// Largest block size accepted by [`EncryptedFs::change_block_size`].
+pub const MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Symlinks followed resolving a path before failing with [`FsError::TooManySymlinks`], the same as Linux.
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Default for [`FsOptions::max_encryptions_per_key`], the limit NIST gives for random 96-bit nonces.
+pub const DEFAULT_MAX_ENCRYPTIONS_PER_KEY: u64 = 1 << 32;
+/// We warn when this percent of [`FsOptions::max_encryptions_per_key`] is reached.
+const ENCRYPTIONS_WARN_PERCENT: u64 = 90;
+/// The count of encryptions is saved after this many, what we lose on a crash is small compared to the limit.
+const ENCRYPTIONS_SAVE_EVERY: u64 = 1 << 16;
+/// How many of the last nonces [`FsOptions::detect_nonce_reuse`] remembers.
+pub const NONCE_REUSE_WINDOW: usize = 1 << 20;
+/// Entries in each of the caches of attributes and directory entries.
+const CACHE_CAPACITY: usize = 2000;
+/// Fewest entries the caches keep however low [`FsOptions::max_memory_bytes`] is.
+const MIN_CACHE_CAPACITY: usize = 16;
+/// About how much an entry of the caches takes, with the name and what the LRU keeps for it.
+const CACHE_ENTRY_BYTES: u64 = 512;
+/// The caches get this part of [`FsOptions::max_memory_bytes`], the buffers of the open handles the rest.
+const MEMORY_CACHES_DIVISOR: u64 = 4;
+
+/// The file can't be written, truncated, renamed or removed, like `chattr +i`. Same value as in Linux.
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+/// The file can only be appended to, it can't be truncated, renamed or removed, like `chattr +a`. Same value as in
+/// Linux.
+pub const FS_APPEND_FL: u32 = 0x20;
+
+/// The xattr with the access ACL of a file, see [`EncryptedFs::set_xattr`].
+pub const XATTR_ACL_ACCESS: &str = "system.posix_acl_access";
+/// The xattr with the default ACL of a directory, applied to what is created in it.
+pub const XATTR_ACL_DEFAULT: &str = "system.posix_acl_default";
+
 fn spawn_runtime() -> Runtime {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -58,6 +112,19 @@ fn spawn_runtime() -> Runtime {
 static DIR_ENTRIES_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
 static NOD_RT: LazyLock<Runtime> = LazyLock::new(spawn_runtime);
 
+/// Tag of the password and of the header it was checked against, see [`CHECKED_PASSWORDS`].
+type CheckedPassword = (Vec<u8>, Vec<u8>);
+
+/// Data dirs [`EncryptedFs::check_password`] found the password right for.
+static CHECKED_PASSWORDS: LazyLock<std::sync::Mutex<HashMap<PathBuf, CheckedPassword>>> =
+    LazyLock::new(std::sync::Mutex::default);
+/// Key of the tags in [`CHECKED_PASSWORDS`], new for each process so they can't be used outside it.
+static CHECKED_PASSWORDS_KEY: LazyLock<SecretVec<u8>> = LazyLock::new(|| {
+    let mut key = vec![0; 32];
+    crypto::create_rng().fill_bytes(&mut key);
+    SecretVec::new(Box::new(key))
+});
+
 /// File attributes.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct FileAttr {
@@ -89,7 +156,7 @@ pub struct FileAttr {
     pub rdev: u32,
     /// Block size
     pub blksize: u32,
-    /// Flags (macOS only, see chflags(2))
+    /// Flags, like [`FS_IMMUTABLE_FL`] and [`FS_APPEND_FL`], or on macOS the ones of chflags(2)
     pub flags: u32,
 }
 
@@ -106,8 +173,8 @@ pub enum FileType {
     Directory,
     /// Regular file (`S_IFREG`)
     RegularFile,
-    // /// Symbolic link (S_IFLNK)
-    // Symlink,
+    /// Symbolic link (`S_IFLNK`), the content is the path it points to
+    Symlink,
     // /// Unix domain socket (S_IFSOCK)
     // Socket,
 }
@@ -132,7 +199,7 @@ pub struct SetFileAttr {
     pub gid: Option<u32>,
     /// Rdev
     pub rdev: Option<u32>,
-    /// Flags (macOS only, see chflags(2))
+    /// Flags, like [`FS_IMMUTABLE_FL`] and [`FS_APPEND_FL`], or on macOS the ones of chflags(2)
     pub flags: Option<u32>,
 }
 
@@ -193,7 +260,7 @@ impl SetFileAttr {
 
     #[must_use]
     pub const fn with_flags(mut self, flags: u32) -> Self {
-        self.rdev = Some(flags);
+        self.flags = Some(flags);
         self
     }
 }
@@ -210,7 +277,7 @@ pub struct CreateFileAttr {
     pub gid: u32,
     /// Rdev
     pub rdev: u32,
-    /// Flags (macOS only, see chflags(2))
+    /// Flags, like [`FS_IMMUTABLE_FL`] and [`FS_APPEND_FL`], or on macOS the ones of chflags(2)
     pub flags: u32,
 }
 
@@ -241,423 +308,3334 @@ impl From<CreateFileAttr> for FileAttr {
     }
 }
 
-#[derive(Error, Debug)]
-pub enum FsError {
-    #[error("IO error: {source}")]
-    Io {
-        #[from]
-        source: io::Error,
-        backtrace: Backtrace,
-    },
-    #[error("serialize error: {source}")]
-    SerializeError {
-        #[from]
-        source: bincode::Error,
-        backtrace: Backtrace,
-    },
-    #[error("item not found: {0}")]
-    NotFound(&'static str),
-    #[error("inode not found")]
-    InodeNotFound,
-    #[error("invalid input")]
-    InvalidInput(&'static str),
-    #[error("invalid node type")]
-    InvalidInodeType,
-    #[error("invalid file handle")]
-    InvalidFileHandle,
-    #[error("already exists")]
-    AlreadyExists,
-    #[error("already open for write")]
-    AlreadyOpenForWrite,
-    #[error("not empty")]
-    NotEmpty,
-    #[error("other: {0}")]
-    Other(&'static str),
-    #[error("invalid password")]
-    InvalidPassword,
-    #[error("invalid structure of data directory")]
-    InvalidDataDirStructure,
-    #[error("crypto error: {source}")]
-    Crypto {
-        #[from]
-        source: crypto::Error,
-        backtrace: Backtrace,
-    },
-    #[error("keyring error: {source}")]
-    Keyring {
-        #[from]
-        source: keyring::Error,
-        backtrace: Backtrace,
-    },
-    #[error("parse int error: {source}")]
-    ParseIntError {
-        #[from]
-        source: ParseIntError,
-        backtrace: Backtrace,
-    },
-    #[error("tokio join error: {source}")]
-    JoinError {
-        #[from]
-        source: JoinError,
-        backtrace: Backtrace,
-    },
-    #[error("max filesize exceeded, max allowed {0}")]
-    MaxFilesizeExceeded(usize),
-    #[error("Read only mode is active.")]
-    ReadOnly,
+/// Where the attributes of inodes and the directory entries are kept, see [`FsOptions::metadata_store`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MetadataStore {
+    /// One encrypted file for each inode and for each directory entry.
+    #[default]
+    Files,
+    /// Encrypted values in an embedded key-value database in the data dir, lookups don't need to touch the
+    /// filesystem and there are far fewer small files, for vaults with millions of files.
+    ///
+    /// It can't be used with overlays or a [`Storage`] other than [`Storage::Directory`] and the blocks of an inode can't be pushed to a
+    /// [`StorageBackend`](crate::storage::StorageBackend).
+    EmbeddedDb,
 }
 
-#[derive(Debug, Clone)]
-struct TimesAndSizeFileAttr {
-    atime: SystemTime,
-    mtime: SystemTime,
-    ctime: SystemTime,
-    crtime: SystemTime,
-    size: u64,
+/// Order of the entries returned by [`EncryptedFs::read_dir`] and [`EncryptedFs::read_dir_plus`], see
+/// [`FsOptions::readdir_order`]. "." and ".." are always first when sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReaddirOrder {
+    /// As they are stored, which with encrypted names looks random. The fastest.
+    #[default]
+    Natural,
+    /// By the plaintext name, comparing the bytes, like `LC_COLLATE=C`.
+    SortedByName,
+    /// By the time the entries were created, oldest first, then by name. Needs the attributes of each entry.
+    InsertionOrder,
 }
 
-impl TimesAndSizeFileAttr {
-    #[allow(dead_code)]
-    const fn new(
-        atime: SystemTime,
-        mtime: SystemTime,
-        ctime: SystemTime,
-        crtime: SystemTime,
-        size: u64,
-    ) -> Self {
-        Self {
-            atime,
-            mtime,
-            ctime,
-            crtime,
-            size,
-        }
-    }
+/// How to recover when the FUSE session ends with an error without us unmounting, see [`FsOptions::reconnect`].
+///
+/// When it ends cleanly, like after `fusermount -u`, it stays unmounted.
+///
+/// We flush the opened files and mount again with the same unlocked filesystem, waiting `initial_backoff` before the
+/// first try and doubling it after each failure, up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How many times to try to mount again after a disconnect, `0` disables it.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
 }
 
-impl From<FileAttr> for TimesAndSizeFileAttr {
-    fn from(value: FileAttr) -> Self {
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
         Self {
-            atime: value.atime,
-            mtime: value.mtime,
-            ctime: value.ctime,
-            crtime: value.crtime,
-            size: value.size,
+            max_retries: 0,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
         }
     }
 }
 
-impl From<TimesAndSizeFileAttr> for SetFileAttr {
-    fn from(value: TimesAndSizeFileAttr) -> Self {
-        Self::default()
-            .with_atime(value.atime)
-            .with_mtime(value.mtime)
-            .with_ctime(value.ctime)
-            .with_crtime(value.crtime)
-            .with_size(value.size)
+impl ReconnectPolicy {
+    #[must_use]
+    pub const fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// How long to wait before the `attempt`-th (starting from 0) try to mount again.
+    #[must_use]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_backoff)
     }
 }
 
-#[derive(Debug, Clone)]
-struct TimesFileAttr {
-    atime: SystemTime,
-    mtime: SystemTime,
-    ctime: SystemTime,
-    crtime: SystemTime,
+/// Where the encrypted content of files is kept, see [`FsOptions::storage`].
+#[derive(Clone, Default)]
+pub enum Storage {
+    /// Only in the data dir.
+    #[default]
+    Directory,
+    /// In the data dir and mirrored to a [`StorageBackend`], like object storage. It's a sync of the content, not
+    /// remote storage: reads and writes only go to the data dir, a file is downloaded whole when it's opened and
+    /// missing there and uploaded when a handle which wrote to it is released. Directories, attributes, the key and
+    /// the params are only kept in the data dir.
+    Mirror(Arc<dyn StorageBackend>),
 }
 
-impl TimesFileAttr {
-    #[allow(dead_code)]
-    const fn new(
-        atime: SystemTime,
-        mtime: SystemTime,
-        ctime: SystemTime,
-        crtime: SystemTime,
-    ) -> Self {
-        Self {
-            atime,
-            mtime,
-            ctime,
-            crtime,
+impl Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Directory => write!(f, "Directory"),
+            Self::Mirror(_) => write!(f, "Mirror"),
         }
     }
 }
 
-impl From<FileAttr> for TimesFileAttr {
-    fn from(value: FileAttr) -> Self {
-        Self {
-            atime: value.atime,
-            mtime: value.mtime,
-            ctime: value.ctime,
-            crtime: value.crtime,
-        }
-    }
+/// Where the times of files come from, see [`FsOptions::timestamp_source`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampSource {
+    /// The system clock as it is, which can go back when it's adjusted, like by an NTP step.
+    #[default]
+    SystemClock,
+    /// The system clock, but if it goes back the time keeps going on from the last one with a monotonic clock,
+    /// until the system clock catches up. So times set while mounted never go back, though they can be ahead of the
+    /// system clock for a while.
+    MonotonicAdjusted,
 }
 
-impl From<TimesFileAttr> for SetFileAttr {
-    fn from(value: TimesFileAttr) -> Self {
-        Self::default()
-            .with_atime(value.atime)
-            .with_mtime(value.mtime)
-            .with_ctime(value.ctime)
-            .with_crtime(value.crtime)
-    }
+/// How much of a file [`EncryptedFs::open`] verifies, see [`FsOptions::open_verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyLevel {
+    /// Nothing when opening, each block is verified when it's read. The fastest.
+    #[default]
+    None,
+    /// That the encrypted file is long enough for the size of the file and the tags of its first and last blocks.
+    /// Catches a truncated or replaced file reading at most two blocks. There is no tag over the whole file, the
+    /// blocks in between are verified when they are read.
+    Header,
+    /// The tag of each block, like [`EncryptedFs::quick_verify`], opening takes about as long as reading the file.
+    Full,
 }
 
+/// Options for [`EncryptedFs::new_with_options`], defaults are used by [`EncryptedFs::new`].
 #[derive(Debug, Clone)]
-pub struct DirectoryEntry {
-    pub ino: u64,
-    pub name: SecretBox<String>,
-    pub kind: FileType,
+pub struct FsOptions {
+    /// How often to verify a sample of blocks in background, `None` disables it.
+    pub scrub_interval: Option<Duration>,
+    /// How many blocks to verify on each scrub.
+    pub scrub_blocks: usize,
+    /// What to do when the FUSE session ends with an error, only used when mounting.
+    pub reconnect: ReconnectPolicy,
+    /// New files and directories are limited to the group and others permissions of the parent directory, like a
+    /// umask taken from it. Only used when mounting.
+    pub inherit_perm: bool,
+    /// Larger reads are clamped to this, so a misbehaving client can't make us allocate gigabytes. Only used when
+    /// mounting, it can't be 0.
+    pub max_read_size: usize,
+    /// Let the kernel check access with the `perm`, `uid` and `gid` of the files, without it anyone who can see the
+    /// mount can access all files. Only used when mounting.
+    pub default_permissions: bool,
+    /// Encrypt the names of files and directories. Without it the names and the directory structure are visible in
+    /// the data dir and only the content is encrypted, so use it only when that metadata is not a secret.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub encrypt_names: bool,
+    /// Keep a parity block for every `n` blocks of the content of files, so one corrupted block in each `n` can be
+    /// reconstructed when reading it fails, see [`EncryptedFs::repair`]. `None` disables it.
+    ///
+    /// It takes `1/n` more space and the parity of the groups a write changed is computed again on flush, so it's
+    /// meant for archival vaults on unreliable media. It's saved in the data dir when creating it, opening it with
+    /// another value fails with [`FsError::InvalidInput`].
+    pub redundancy: Option<usize>,
+    /// Sync the directories changed by `create`, `rename`, `remove_file` and `remove_dir` before returning, so the
+    /// change is not lost if we crash or lose power right after. Slower, but what mail spools and databases expect.
+    pub sync_metadata: bool,
+    /// Sync the content of a file, with its directory, when a write handle is released, so apps that close without
+    /// `fsync` don't lose what they wrote if we crash right after. Slower, off by default, and with
+    /// [`FsOptions::write_journal`] it's synced anyway when blocks were overwritten, before dropping their journal.
+    pub fsync_on_release: bool,
+    /// Save the original of each block, encrypted, before a write changes it, until the new content is synced, so if
+    /// we crash in the middle of a write the file is brought back to how it was when the data dir is opened again,
+    /// instead of having some blocks from the old content and some from the new one. It costs a copy of each changed
+    /// block and a sync when releasing a handle which overwrote blocks. Off by default, a journal left by a crash is
+    /// recovered even when it's off. [`EncryptedFs::close_handles_for`] needs it to drop the writes.
+    pub write_journal: bool,
+    /// Where to keep the attributes of inodes and the directory entries.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub metadata_store: MetadataStore,
+    /// When a directory is opened load the attributes of all its entries in background, so the burst of `getattr`
+    /// that follows, like from `ls -l` or a file manager, hits the cache. Only used when mounting.
+    pub prefetch_dir_metadata: bool,
+    /// Give up with [`FsError::Timeout`] on a block operation on a [`StorageBackend`] or a read from the mount that
+    /// takes longer than this, so one slow network request doesn't hang a FUSE worker and eventually the whole mount.
+    /// With it reads from the mount run on the blocking pool, where a hung one is left to finish. Writes are not
+    /// interrupted halfway, that would leave the file inconsistent. `None` waits forever, fine for local disks.
+    pub op_timeout: Option<Duration>,
+    /// Match names ignoring the case, like Windows and macOS do, keeping the case they were created with. Creating
+    /// `File` when `file` exists fails with [`FsError::AlreadyExists`]. Uses Unicode case folding, not only ASCII.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub case_insensitive: bool,
+    /// Write out what a handle buffered once this many bytes were written with it since the last time, making the
+    /// writer wait for the writes instead of letting a fast producer pile up dirty data in memory. It's not synced,
+    /// that's still done on flush, fsync and release. Non-blocking writers on the mount get `EAGAIN` while it's
+    /// written in background. The kernel's write-back cache, when enabled, writes with the handles the same way, so
+    /// it's limited too. `None` has no limit.
+    pub max_dirty_per_handle: Option<u64>,
+    /// Normalize names to Unicode NFC, so a name typed on macOS, which usually sends NFD, and the same name from Linux
+    /// are one entry instead of two that look the same. Names are also stored in NFC.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub normalize_names: bool,
+    /// How the encryption key saved in the data dir is protected besides the password. It must be the same each time
+    /// the data dir is opened.
+    pub header_protection: Arc<dyn HeaderProtection>,
+    /// Lock the memory holding the encryption key with `mlock`, so it's never written to swap.
+    pub lock_memory: bool,
+    /// Fail opening the data dir if [`FsOptions::lock_memory`] can't lock it, like when `RLIMIT_MEMLOCK` is too low,
+    /// instead of only logging a warning.
+    pub lock_memory_strict: bool,
+    /// Disable core dumps of the process and leave the memory holding the encryption key out of them, so a crash
+    /// doesn't leak the key or the plaintext of the files. Only supported on Linux, ignored elsewhere.
+    pub protect_from_coredump: bool,
+    /// Sort the entries when listing a directory, so tools like `tar` produce the same output each time. Sorting is
+    /// done on each listing, so it's slower for big directories.
+    pub readdir_order: ReaddirOrder,
+    /// Where the times set on files come from. With [`TimestampSource::MonotonicAdjusted`] an mtime or ctime we set
+    /// is never older than one set before, even if the system clock goes back. They are saved as usual.
+    pub timestamp_source: TimestampSource,
+    /// Set the mtime of the encrypted content of each file in the data dir to the mtime of the file, so a backup of
+    /// the data dir by mtime, like with `rsync`, copies only the files that changed, without hashing the content.
+    ///
+    /// It leaks when each file was changed to anyone who can see the data dir, by default they show only when the
+    /// content was last written there.
+    pub mirror_mtime_to_backing: bool,
+    /// Mount even if the mount point has files, they are hidden until unmounted. Only used when mounting.
+    pub allow_nonempty_mountpoint: bool,
+    /// Use a data dir on a FUSE mount, by default it fails with [`FsError::DataDirOnFuse`]. If it's on another rencfs
+    /// mount, or on this one, each write goes through FUSE twice and unmounting the lower one while this is mounted
+    /// loses data. Only checked on Linux.
+    pub allow_fuse_data_dir: bool,
+    /// Paths in the vault, like `/templates`, that can't be changed while the rest of the vault is writable. Writing,
+    /// creating, renaming or removing anything under them fails with [`FsError::ReadOnly`].
+    ///
+    /// They are resolved when the data dir is opened, the ones that don't exist then are ignored. A file under them
+    /// which also has a hard link elsewhere can't be changed from there either.
+    pub read_only_paths: Vec<PathBuf>,
+    /// Transforms the plaintext of each block of the content of files goes through, before encrypting it on write and
+    /// after decrypting it on read, see [`BlockTransform`]. The metadata doesn't go through them.
+    ///
+    /// They must be the same each time the data dir is opened, else the content can't be read back.
+    /// [`EncryptedFs::change_block_size`] copies the blocks as they are, without them.
+    pub block_transforms: Vec<Arc<dyn BlockTransform>>,
+    /// With random nonces of 96 bits, like both ciphers use, the chance two blocks get the same nonce stops being
+    /// negligible after about this many blocks are encrypted with the same key. We count them in the data dir and
+    /// warn, also with [`FsEvent::RekeyRecommended`], when 90% of it is reached. `None` disables the warning.
+    ///
+    /// The count is saved every 65536 encryptions and on [`EncryptedFs::flush_all`], so a crash loses at most that.
+    pub max_encryptions_per_key: Option<u64>,
+    /// Let the kernel remember for this long that a name doesn't exist, so tools probing many paths that aren't there,
+    /// like a compiler searching the include paths, don't make us look them up each time. `None` doesn't cache them.
+    /// Only used when mounting.
+    ///
+    /// Creating the name through the mount replaces the cached miss right away, but changes done with the
+    /// [`EncryptedFs`] directly while mounted stay hidden until it expires, or until
+    /// [`MountHandle::invalidate_entry`](crate::mount::MountHandle::invalidate_entry) is called.
+    pub negative_lookup_ttl: Option<Duration>,
+    /// How long the kernel can use the attributes we gave it before asking again, each time we are asked they are
+    /// decrypted, or read from the cache. Only used when mounting.
+    ///
+    /// Changes done through the mount are seen right away, but ones done with the [`EncryptedFs`] directly while
+    /// mounted, like the size after a write, are seen only after it expires, so keep it short if you do that, or call
+    /// [`MountHandle::invalidate_inode`](crate::mount::MountHandle::invalidate_inode).
+    pub attr_ttl: Duration,
+    /// How long the kernel can use what a name resolved to before looking it up again. Only used when mounting.
+    ///
+    /// Like with [`FsOptions::attr_ttl`], renames and removals done with the [`EncryptedFs`] directly while mounted
+    /// are seen only after it expires. It's also the TTL of the attributes which come with the entry.
+    pub entry_ttl: Duration,
+    /// Writes which grow a file fail with [`FsError::InsufficientSpace`], seen as `ENOSPC` when mounted, once the
+    /// disk of the data dir has less than this free. What's left is for the metadata and for flushing what was already
+    /// written, so the vault can still be unmounted cleanly when the disk fills. `None` keeps nothing.
+    pub reserved_space_bytes: Option<u64>,
+    /// Threads handling the FUSE requests, which run in parallel on them. Only used when mounting.
+    ///
+    /// `None` uses the tokio runtime `mount` is called from, the default one has a thread for each core as
+    /// [`std::thread::available_parallelism`] gives, which suits most machines. Else the session gets its own runtime
+    /// with this many, more for a server with many cores and busy clients, 1 or 2 on a small device.
+    pub fuse_worker_threads: Option<usize>,
+    /// Let the kernel keep the writes in its page cache and send them to us later in larger chunks, which is much
+    /// faster for small writes. Only used when mounting.
+    ///
+    /// The kernel then keeps the size and times of the files while it has writes pending, so changes done with the
+    /// [`EncryptedFs`] directly while mounted might be overwritten. Files opened only for writing are opened for
+    /// reading too, as the kernel reads the pages it writes partially.
+    pub writeback_cache: bool,
+    /// In a directory with the sticky bit, like `/tmp`, only the owner of an entry, the owner of the directory and
+    /// root can remove or rename it, others get `EPERM`. Without it anyone with write access to the directory can.
+    /// Only used when mounting, with [`FsOptions::default_permissions`] the kernel checks it anyway.
+    pub sticky_bit: bool,
+    /// Entries whose name matches one of these globs are left out of [`EncryptedFs::read_dir`] and
+    /// [`EncryptedFs::read_dir_plus`], like `.trash` or `*.lock`. They can still be looked up and used by name, they
+    /// are only not listed. `*` matches any characters and `?` a single one, the plaintext name is matched.
+    pub hidden_patterns: Vec<String>,
+    /// How deep directories can be nested, the ones in the root are at depth 1. Creating one deeper fails with
+    /// [`FsError::TooDeep`], so a runaway script can't make a tree too deep for the tools walking it. `None` has no
+    /// limit. It's checked only when creating directories, moving a tree under another can still go deeper.
+    pub max_dir_depth: Option<usize>,
+    /// How many entries a directory can have, creating more fails with [`FsError::TooManyEntries`]. `None` has no
+    /// limit.
+    pub max_dir_entries: Option<usize>,
+    /// Like mounting with `nosuid`, the setuid and setgid bits can't be set on files, they are cleared when creating
+    /// them or changing their permissions. Enforced by us however the [`EncryptedFs`] is used, and when mounting the
+    /// mount is `nosuid` too, so the files which already have them don't run with them.
+    pub nosuid: bool,
+    /// Like mounting with `nodev`, device numbers can't be set, creating a device node fails with
+    /// [`FsError::NotPermitted`]. When mounting the mount is `nodev` too.
+    pub nodev: bool,
+    /// Like mounting with `noexec`, files can't be executed, opening them for exec and checking them for execute
+    /// access fail with `EACCES`. The execute bits are kept. Only used when mounting, the mount is `noexec` too.
+    pub noexec: bool,
+    /// Store the attributes of files and the directory entries with a compact encoding before encrypting them, which
+    /// takes about half the space. Worth it with [`MetadataStore::EmbeddedDb`] and many small files, with
+    /// [`MetadataStore::Files`] each one is in its own file which takes a disk block anyway.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub compress_metadata: bool,
+    /// Remember the nonces of the last [`NONCE_REUSE_WINDOW`] blocks of content we encrypted and log an error if
+    /// one comes again, which with random nonces means a bug. It takes a few tens of MB, meant for tests and for
+    /// who wants to be sure, see [`EncryptedFs::nonce_reuses`].
+    pub detect_nonce_reuse: bool,
+    /// What to verify when opening a file, opening fails with [`FsError::CorruptFile`] if it's corrupted. Files
+    /// already open for write are not verified, as not all of what was written is flushed.
+    pub open_verify: VerifyLevel,
+    /// How to encrypt the file names, apart from the cipher of the content, with [`FsOptions::encrypt_names`].
+    /// A deterministic one, like [`NameCipher::ChaCha20Poly1305Synthetic`], encrypts the same name the same each time.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub name_cipher: NameCipher,
+    /// About how much memory the caches and the buffers of the open handles can take together, so the instance fits
+    /// in a small container or many vaults fit on one host. `None` has no limit.
+    ///
+    /// A quarter goes to the caches, which keep fewer entries so there are more misses. The rest is for the data
+    /// written but not written out yet and the blocks of the handles holding it, a write which would go over it waits
+    /// until what its handle buffered is written out, like with [`FsOptions::max_dirty_per_handle`]. Readers aren't
+    /// counted, as writing out can't release what they keep.
+    pub max_memory_bytes: Option<u64>,
+    /// How the encryption key is encrypted with the key derived from the password, apart from the cipher of the
+    /// content, so it can be pinned to a known primitive like [`KeyWrapAlgorithm::Aes256Kw`]. It's saved in the params
+    /// of the data dir and opening it uses the saved one.
+    /// Only used when creating the data dir, after that the value saved in it is used.
+    pub key_wrap: KeyWrapAlgorithm,
+    /// Where the encrypted content of files is kept.
+    ///
+    /// With [`Storage::Mirror`] the data files of a file are uploaded, like with [`EncryptedFs::push_blocks`], when
+    /// it's truncated or replaced, and deleted with the file. When a handle which wrote to it is released only the
+    /// blocks it wrote are uploaded, with the attributes. Opening a file whose content is missing from the data dir
+    /// downloads all of it first. Reads and writes only go to the data dir, so the latency of the backend is paid on
+    /// open and release, not on each block, and directories, the key and the params are only kept there, so the vault
+    /// can't be opened from the backend alone. It needs [`MetadataStore::Files`].
+    pub storage: Storage,
 }
 
-impl PartialEq for DirectoryEntry {
-    fn eq(&self, other: &Self) -> bool {
-        self.ino == other.ino
-            && self.name.expose_secret() == other.name.expose_secret()
-            && self.kind == other.kind
+impl Default for FsOptions {
+    fn default() -> Self {
+        Self {
+            scrub_interval: None,
+            scrub_blocks: 64,
+            reconnect: ReconnectPolicy::default(),
+            inherit_perm: false,
+            max_read_size: 1024 * 1024,
+            default_permissions: false,
+            encrypt_names: true,
+            redundancy: None,
+            sync_metadata: false,
+            fsync_on_release: false,
+            write_journal: false,
+            metadata_store: MetadataStore::Files,
+            prefetch_dir_metadata: false,
+            op_timeout: None,
+            case_insensitive: false,
+            max_dirty_per_handle: None,
+            normalize_names: false,
+            header_protection: Arc::new(PasswordOnly),
+            lock_memory: false,
+            lock_memory_strict: false,
+            protect_from_coredump: false,
+            readdir_order: ReaddirOrder::Natural,
+            timestamp_source: TimestampSource::SystemClock,
+            mirror_mtime_to_backing: false,
+            allow_nonempty_mountpoint: false,
+            allow_fuse_data_dir: false,
+            read_only_paths: vec![],
+            block_transforms: vec![],
+            max_encryptions_per_key: Some(DEFAULT_MAX_ENCRYPTIONS_PER_KEY),
+            negative_lookup_ttl: None,
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            reserved_space_bytes: None,
+            fuse_worker_threads: None,
+            writeback_cache: false,
+            sticky_bit: true,
+            hidden_patterns: vec![],
+            max_dir_depth: None,
+            max_dir_entries: None,
+            nosuid: false,
+            nodev: false,
+            noexec: false,
+            compress_metadata: false,
+            detect_nonce_reuse: false,
+            open_verify: VerifyLevel::None,
+            name_cipher: NameCipher::Content,
+            max_memory_bytes: None,
+            key_wrap: KeyWrapAlgorithm::Content,
+            storage: Storage::Directory,
+        }
     }
 }
 
-/// Like [`DirectoryEntry`] but with [`FileAttr`].
-#[derive(Debug)]
-pub struct DirectoryEntryPlus {
-    pub ino: u64,
-    pub name: SecretBox<String>,
-    pub kind: FileType,
-    pub attr: FileAttr,
-}
+impl FsOptions {
+    #[must_use]
+    pub const fn with_scrub_interval(mut self, scrub_interval: Duration) -> Self {
+        self.scrub_interval = Some(scrub_interval);
+        self
+    }
 
-impl PartialEq for DirectoryEntryPlus {
-    fn eq(&self, other: &Self) -> bool {
-        self.ino == other.ino
-            && self.name.expose_secret() == other.name.expose_secret()
-            && self.kind == other.kind
-            && self.attr == other.attr
+    #[must_use]
+    pub const fn with_scrub_blocks(mut self, scrub_blocks: usize) -> Self {
+        self.scrub_blocks = scrub_blocks;
+        self
     }
-}
 
-pub type FsResult<T> = Result<T, FsError>;
+    #[must_use]
+    pub const fn with_reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
 
-pub struct DirectoryEntryIterator(VecDeque<FsResult<DirectoryEntry>>);
+    #[must_use]
+    pub const fn with_inherit_perm(mut self, inherit_perm: bool) -> Self {
+        self.inherit_perm = inherit_perm;
+        self
+    }
 
-impl Iterator for DirectoryEntryIterator {
-    type Item = FsResult<DirectoryEntry>;
+    #[must_use]
+    pub const fn with_max_read_size(mut self, max_read_size: usize) -> Self {
+        self.max_read_size = max_read_size;
+        self
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
+    #[must_use]
+    pub const fn with_default_permissions(mut self, default_permissions: bool) -> Self {
+        self.default_permissions = default_permissions;
+        self
     }
-}
 
-pub struct DirectoryEntryPlusIterator(VecDeque<FsResult<DirectoryEntryPlus>>);
+    #[must_use]
+    pub const fn with_encrypt_names(mut self, encrypt_names: bool) -> Self {
+        self.encrypt_names = encrypt_names;
+        self
+    }
 
-impl Iterator for DirectoryEntryPlusIterator {
-    type Item = FsResult<DirectoryEntryPlus>;
+    #[must_use]
+    pub const fn with_redundancy(mut self, redundancy: usize) -> Self {
+        self.redundancy = Some(redundancy);
+        self
+    }
 
-    #[instrument(name = "DirectoryEntryPlusIterator::next", skip(self))]
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_front()
+    #[must_use]
+    pub const fn with_sync_metadata(mut self, sync_metadata: bool) -> Self {
+        self.sync_metadata = sync_metadata;
+        self
     }
-}
 
-struct ReadHandleContext {
-    ino: u64,
-    attr: TimesFileAttr,
-    reader: Option<Box<dyn CryptoReadSeek<File>>>,
-}
+    #[must_use]
+    pub const fn with_fsync_on_release(mut self, fsync_on_release: bool) -> Self {
+        self.fsync_on_release = fsync_on_release;
+        self
+    }
 
-enum ReadHandleContextOperation {
-    Create { ino: u64 },
-}
+    #[must_use]
+    pub const fn with_write_journal(mut self, write_journal: bool) -> Self {
+        self.write_journal = write_journal;
+        self
+    }
 
-impl ReadHandleContextOperation {
-    const fn get_ino(&self) -> u64 {
-        match *self {
-            Self::Create { ino, .. } => ino,
-        }
+    #[must_use]
+    pub const fn with_metadata_store(mut self, metadata_store: MetadataStore) -> Self {
+        self.metadata_store = metadata_store;
+        self
     }
-}
 
-enum WriteHandleContextOperation {
-    Create { ino: u64 },
-}
+    #[must_use]
+    pub const fn with_prefetch_dir_metadata(mut self, prefetch_dir_metadata: bool) -> Self {
+        self.prefetch_dir_metadata = prefetch_dir_metadata;
+        self
+    }
 
-impl WriteHandleContextOperation {
-    const fn get_ino(&self) -> u64 {
-        match *self {
-            Self::Create { ino, .. } => ino,
-        }
+    #[must_use]
+    pub const fn with_op_timeout(mut self, op_timeout: Duration) -> Self {
+        self.op_timeout = Some(op_timeout);
+        self
     }
-}
 
-struct WriteHandleContext {
-    ino: u64,
-    attr: TimesAndSizeFileAttr,
-    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    #[must_use]
+    pub const fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_dirty_per_handle(mut self, max_dirty_per_handle: u64) -> Self {
+        self.max_dirty_per_handle = Some(max_dirty_per_handle);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_memory_bytes(mut self, max_memory_bytes: u64) -> Self {
+        self.max_memory_bytes = Some(max_memory_bytes);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_normalize_names(mut self, normalize_names: bool) -> Self {
+        self.normalize_names = normalize_names;
+        self
+    }
+
+    #[must_use]
+    pub fn with_header_protection(mut self, header_protection: Arc<dyn HeaderProtection>) -> Self {
+        self.header_protection = header_protection;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_lock_memory(mut self, lock_memory: bool) -> Self {
+        self.lock_memory = lock_memory;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_lock_memory_strict(mut self, lock_memory_strict: bool) -> Self {
+        self.lock_memory_strict = lock_memory_strict;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_protect_from_coredump(mut self, protect_from_coredump: bool) -> Self {
+        self.protect_from_coredump = protect_from_coredump;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_readdir_order(mut self, readdir_order: ReaddirOrder) -> Self {
+        self.readdir_order = readdir_order;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_timestamp_source(mut self, timestamp_source: TimestampSource) -> Self {
+        self.timestamp_source = timestamp_source;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_mirror_mtime_to_backing(mut self, mirror_mtime_to_backing: bool) -> Self {
+        self.mirror_mtime_to_backing = mirror_mtime_to_backing;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_allow_nonempty_mountpoint(mut self, allow_nonempty_mountpoint: bool) -> Self {
+        self.allow_nonempty_mountpoint = allow_nonempty_mountpoint;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_allow_fuse_data_dir(mut self, allow_fuse_data_dir: bool) -> Self {
+        self.allow_fuse_data_dir = allow_fuse_data_dir;
+        self
+    }
+
+    #[must_use]
+    pub fn with_read_only_paths(mut self, read_only_paths: Vec<PathBuf>) -> Self {
+        self.read_only_paths = read_only_paths;
+        self
+    }
+
+    #[must_use]
+    pub fn with_block_transforms(mut self, block_transforms: Vec<Arc<dyn BlockTransform>>) -> Self {
+        self.block_transforms = block_transforms;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_encryptions_per_key(mut self, max_encryptions_per_key: u64) -> Self {
+        self.max_encryptions_per_key = Some(max_encryptions_per_key);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_negative_lookup_ttl(mut self, negative_lookup_ttl: Duration) -> Self {
+        self.negative_lookup_ttl = Some(negative_lookup_ttl);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_attr_ttl(mut self, attr_ttl: Duration) -> Self {
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_entry_ttl(mut self, entry_ttl: Duration) -> Self {
+        self.entry_ttl = entry_ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_reserved_space_bytes(mut self, reserved_space_bytes: u64) -> Self {
+        self.reserved_space_bytes = Some(reserved_space_bytes);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_fuse_worker_threads(mut self, fuse_worker_threads: usize) -> Self {
+        self.fuse_worker_threads = Some(fuse_worker_threads);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_writeback_cache(mut self, writeback_cache: bool) -> Self {
+        self.writeback_cache = writeback_cache;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_sticky_bit(mut self, sticky_bit: bool) -> Self {
+        self.sticky_bit = sticky_bit;
+        self
+    }
+
+    #[must_use]
+    pub fn with_hidden_patterns(mut self, hidden_patterns: Vec<String>) -> Self {
+        self.hidden_patterns = hidden_patterns;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_dir_depth(mut self, max_dir_depth: usize) -> Self {
+        self.max_dir_depth = Some(max_dir_depth);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_dir_entries(mut self, max_dir_entries: usize) -> Self {
+        self.max_dir_entries = Some(max_dir_entries);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_nosuid(mut self, nosuid: bool) -> Self {
+        self.nosuid = nosuid;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_nodev(mut self, nodev: bool) -> Self {
+        self.nodev = nodev;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_noexec(mut self, noexec: bool) -> Self {
+        self.noexec = noexec;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_compress_metadata(mut self, compress_metadata: bool) -> Self {
+        self.compress_metadata = compress_metadata;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_detect_nonce_reuse(mut self, detect_nonce_reuse: bool) -> Self {
+        self.detect_nonce_reuse = detect_nonce_reuse;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_open_verify(mut self, open_verify: VerifyLevel) -> Self {
+        self.open_verify = open_verify;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_name_cipher(mut self, name_cipher: NameCipher) -> Self {
+        self.name_cipher = name_cipher;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_key_wrap(mut self, key_wrap: KeyWrapAlgorithm) -> Self {
+        self.key_wrap = key_wrap;
+        self
+    }
+
+    #[must_use]
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
 }
 
-struct KeyProvider {
-    key_path: PathBuf,
-    salt_path: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
-    cipher: Cipher,
+const HEADER_BACKUP_MAGIC: [u8; 8] = *b"rencfshd";
+
+/// What [`EncryptedFs::export_header`] returns, the files from `security` as they are.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HeaderBackup {
+    magic: [u8; 8],
+    key_enc: Vec<u8>,
+    key_salt: Vec<u8>,
+    pub(crate) params: Option<Vec<u8>>,
 }
 
-#[async_trait]
-impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
-    async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
-        let password = self
-            .password_provider
-            .get_password()
-            .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+impl HeaderBackup {
+    /// Fails with [`FsError::InvalidInput`] if it's not what [`EncryptedFs::export_header`] returns.
+    pub(crate) fn parse(data: &[u8]) -> FsResult<Self> {
+        use bincode::Options;
+
+        // the lengths in it can't ask for more than we have
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(data.len() as u64)
+            .deserialize(data)
+            .ok()
+            .filter(|header: &Self| header.magic == HEADER_BACKUP_MAGIC)
+            .ok_or(FsError::InvalidInput("not a header backup"))
     }
 }
 
-pub trait PasswordProvider: Send + Sync + 'static {
-    fn get_password(&self) -> Option<SecretString>;
+const FILE_ENVELOPE_MAGIC: [u8; 8] = *b"rencfsfe";
+
+/// What [`EncryptedFs::export_file_envelope`] returns, the content of a file sealed with a key derived from the
+/// password with its own salt, so [`decrypt_file_envelope`] needs nothing from the data dir.
+#[derive(Serialize, Deserialize)]
+struct FileEnvelope {
+    magic: [u8; 8],
+    cipher: Cipher,
+    kdf: crypto::KdfParams,
+    salt: Vec<u8>,
+    /// From [`crypto::seal`].
+    sealed: Vec<u8>,
 }
 
-struct DirEntryNameCacheProvider {}
-#[async_trait]
-impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
-    async fn provide(&self) -> Result<Mutex<LruCache<String, SecretString>>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+/// Decrypt what [`EncryptedFs::export_file_envelope`] returns with the password of the data dir it came from.
+///
+/// Fails with [`FsError::InvalidInput`] if it's not an envelope or its key derivation costs more than
+/// [`crypto::KdfParams::MAX`], and with [`FsError::InvalidPassword`] if the password is not the one or the envelope
+/// was changed.
+#[allow(clippy::missing_errors_doc)]
+pub fn decrypt_file_envelope(data: &[u8], password: &SecretString) -> FsResult<Vec<u8>> {
+    use bincode::Options;
+
+    // the lengths in it can't ask for more than we have
+    let envelope: FileEnvelope = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(data.len() as u64)
+        .deserialize(data)
+        .ok()
+        .filter(|envelope: &FileEnvelope| envelope.magic == FILE_ENVELOPE_MAGIC)
+        .ok_or(FsError::InvalidInput("not a file envelope"))?;
+    if !envelope.kdf.within(crypto::KdfParams::MAX) {
+        return Err(FsError::InvalidInput(
+            "the key derivation of the envelope costs too much",
+        ));
     }
+    let key = crypto::derive_key_with(password, envelope.cipher, &envelope.salt, envelope.kdf)?;
+    crypto::unseal(&key, &envelope.sealed).map_err(|_| FsError::InvalidPassword)
 }
 
-struct DirEntryMetaCacheProvider {}
-#[async_trait]
-impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvider {
-    async fn provide(&self) -> Result<Mutex<DirEntryMetaCache>, FsError> {
-        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
-    }
+/// Result of [`EncryptedFs::du`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Sum of the sizes of the files.
+    pub logical_bytes: u64,
+    /// Space the encrypted content of the files takes on disk, with the overhead of each block and the parity,
+    /// holes are not counted. The metadata is not included.
+    pub physical_bytes: u64,
+    pub files: u64,
+    /// Including the one `du` was called on.
+    pub dirs: u64,
 }
 
-struct AttrCacheProvider {}
-#[async_trait]
-impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvider {
-    async fn provide(&self) -> Result<RwLock<LruCache<u64, FileAttr>>, FsError> {
-        Ok(RwLock::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+/// Result of [`EncryptedFs::overhead`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OverheadInfo {
+    /// Sum of the sizes of the files.
+    pub plaintext_bytes: u64,
+    /// Length of their encrypted content, the nonce and tag of each block included.
+    pub ciphertext_bytes: u64,
+    /// Length of their parity, see [`FsOptions::redundancy`].
+    pub parity_bytes: u64,
+    pub blocks: u64,
+    /// Bytes each block takes more than its plaintext, for the nonce and the tag.
+    pub per_block_overhead: u64,
+}
+
+impl OverheadInfo {
+    /// How many times larger the content is on disk than the plaintext, parity included, 1.0 for empty files.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn expansion_ratio(&self) -> f64 {
+        if self.plaintext_bytes == 0 {
+            return 1.0;
+        }
+        (self.ciphertext_bytes + self.parity_bytes) as f64 / self.plaintext_bytes as f64
     }
 }
 
-type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
+/// Digest of the ciphertext of a block, see [`EncryptedFs::block_digests`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockDigest {
+    pub index: u64,
+    /// BLAKE3 of the ciphertext of the block, including the nonce and the tag.
+    pub digest: [u8; 32],
+}
 
-/// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
-pub struct EncryptedFs {
-    pub(crate) data_dir: PathBuf,
-    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
-    read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
-    current_handle: AtomicU64,
-    cipher: Cipher,
-    // (ino, fh)
-    opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
-    opened_files_for_write: RwLock<HashMap<u64, u64>>,
-    // used for rw ops of actual serialization
-    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
-    serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
-    // used for the update op
-    serialize_update_inode_locks: ArcHashMap<u64, Mutex<bool>>,
-    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
-    serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
-    serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
-    read_write_locks: ArcHashMap<u64, RwLock<bool>>,
-    key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
-    self_weak: std::sync::Mutex<Option<Weak<Self>>>,
-    attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
-    dir_entries_name_cache:
-        ExpireValue<Mutex<LruCache<String, SecretBox<String>>>, FsError, DirEntryNameCacheProvider>,
-    dir_entries_meta_cache:
-        ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
-    sizes_write: Mutex<HashMap<u64, AtomicU64>>,
-    sizes_read: Mutex<HashMap<u64, AtomicU64>>,
-    requested_read: Mutex<HashMap<u64, AtomicU64>>,
-    read_only: bool,
+/// Events sent on the channel from [`EncryptedFs::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEvent {
+    /// A block failed the integrity check.
+    CorruptBlock { ino: u64, block: u64 },
+    /// 90% of [`FsOptions::max_encryptions_per_key`] blocks were encrypted with the key, copy the content to a new
+    /// data dir, which gets a new key.
+    RekeyRecommended { encryptions: u64, max: u64 },
 }
 
-impl EncryptedFs {
-    #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::missing_errors_doc)]
-    pub async fn new(
-        data_dir: PathBuf,
-        password_provider: Box<dyn PasswordProvider>,
-        cipher: Cipher,
-        read_only: bool,
-    ) -> FsResult<Arc<Self>> {
-        let key_provider = KeyProvider {
-            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-            password_provider,
-            cipher,
+#[derive(Error, Debug)]
+pub enum FsError {
+    #[error("IO error: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+        backtrace: Backtrace,
+    },
+    #[error("serialize error: {source}")]
+    SerializeError {
+        #[from]
+        source: bincode::Error,
+        backtrace: Backtrace,
+    },
+    #[error("item not found: {0}")]
+    NotFound(&'static str),
+    #[error("inode not found")]
+    InodeNotFound,
+    #[error("invalid input")]
+    InvalidInput(&'static str),
+    #[error("invalid node type")]
+    InvalidInodeType,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
+    #[error("no such attribute")]
+    XattrNotFound,
+    #[error("invalid file handle")]
+    InvalidFileHandle,
+    #[error("already exists")]
+    AlreadyExists,
+    #[error("already open for write")]
+    AlreadyOpenForWrite,
+    #[error("not empty")]
+    NotEmpty,
+    #[error("operation not permitted")]
+    NotPermitted,
+    #[error("other: {0}")]
+    Other(&'static str),
+    #[error("invalid password")]
+    InvalidPassword,
+    #[error("second factor required, insert the security key the data dir was enrolled with")]
+    SecondFactorRequired,
+    #[error("invalid structure of data directory")]
+    InvalidDataDirStructure,
+    #[error("crypto error: {source}")]
+    Crypto {
+        #[from]
+        source: crypto::Error,
+        backtrace: Backtrace,
+    },
+    #[error("keyring error: {source}")]
+    Keyring {
+        #[from]
+        source: keyring::Error,
+        backtrace: Backtrace,
+    },
+    /// There is no secret service to keep the password in, the password should be asked for or read from a
+    /// [`PasswordSource`] instead.
+    #[error("keyring is not available: {source}")]
+    KeyringUnavailable { source: keyring::Error },
+    #[error("parse int error: {source}")]
+    ParseIntError {
+        #[from]
+        source: ParseIntError,
+        backtrace: Backtrace,
+    },
+    #[error("tokio join error: {source}")]
+    JoinError {
+        #[from]
+        source: JoinError,
+        backtrace: Backtrace,
+    },
+    #[error("max filesize exceeded, max allowed {0}")]
+    MaxFilesizeExceeded(usize),
+    #[error("Read only mode is active.")]
+    ReadOnly,
+    #[error("not enough free space, need {needed} bytes, available {available}")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("block size change to {0} not finished, run it again")]
+    BlockSizeChangeUnfinished(usize),
+    #[error("operation timed out")]
+    Timeout,
+    #[error("invalid mount point: {0}")]
+    InvalidMountPoint(&'static str),
+    #[error("data dir is on the {fs_type} FUSE mount {mount_point}, like another rencfs mount, use allow_fuse_data_dir if that's intended")]
+    DataDirOnFuse {
+        mount_point: PathBuf,
+        fs_type: String,
+    },
+    #[error("directories can't be nested deeper than {max}")]
+    TooDeep { max: usize },
+    #[error("directory can't have more than {max} entries")]
+    TooManyEntries { max: usize },
+    #[error("too many levels of symbolic links")]
+    TooManySymlinks,
+    #[error("file {ino} is corrupted, blocks {blocks:?}")]
+    CorruptFile { ino: u64, blocks: Vec<u64> },
+    #[error("cannot remove inode {ino}: {source}")]
+    RemoveFailed { ino: u64, source: Box<FsError> },
+}
+
+impl FsError {
+    /// If it failed because the disk is full.
+    #[must_use]
+    pub fn is_no_space(&self) -> bool {
+        match self {
+            Self::InsufficientSpace { .. } => true,
+            Self::Io { source, .. } => source.kind() == io::ErrorKind::StorageFull,
+            _ => false,
+        }
+    }
+}
+
+/// Parameters of the filesystem, stored in plaintext in `security/params` as we need them before reading anything.
+/// Data dirs created before we had it use the defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct VaultParams {
+    /// Size of the plaintext blocks of the files content.
+    pub(crate) block_size: usize,
+    /// Set while [`EncryptedFs::change_block_size`] is converting the files to this block size.
+    pub(crate) pending_block_size: Option<usize>,
+    /// See [`FsOptions::encrypt_names`].
+    pub(crate) encrypt_names: bool,
+    /// See [`FsOptions::metadata_store`].
+    pub(crate) metadata_store: MetadataStore,
+    /// See [`FsOptions::case_insensitive`].
+    pub(crate) case_insensitive: bool,
+    /// See [`FsOptions::normalize_names`].
+    pub(crate) normalize_names: bool,
+    /// Blocks encrypted with the key so far, see [`FsOptions::max_encryptions_per_key`].
+    pub(crate) encryptions: u64,
+    /// See [`FsOptions::compress_metadata`].
+    pub(crate) compress_metadata: bool,
+    /// See [`FsOptions::name_cipher`].
+    pub(crate) name_cipher: NameCipher,
+    /// See [`FsOptions::key_wrap`].
+    pub(crate) key_wrap: KeyWrapAlgorithm,
+    /// Blocks in a parity group, see [`FsOptions::redundancy`].
+    pub(crate) redundancy: Option<usize>,
+}
+
+impl Default for VaultParams {
+    fn default() -> Self {
+        Self {
+            block_size: BLOCK_SIZE,
+            pending_block_size: None,
+            encrypt_names: true,
+            metadata_store: MetadataStore::Files,
+            case_insensitive: false,
+            normalize_names: false,
+            encryptions: 0,
+            compress_metadata: false,
+            name_cipher: NameCipher::Content,
+            key_wrap: KeyWrapAlgorithm::Content,
+            redundancy: None,
+        }
+    }
+}
+
+impl VaultParams {
+    pub(crate) fn load(data_dir: &Path) -> FsResult<Self> {
+        let path = data_dir.join(SECURITY_DIR).join(PARAMS_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Reads the content of the params file, also in the formats used by older versions.
+    pub(crate) fn parse(data: &[u8]) -> FsResult<Self> {
+        Ok(bincode::deserialize(data)
+            .or_else(|_| {
+                // saved before we had `redundancy`
+                bincode::deserialize::<(
+                    usize,
+                    Option<usize>,
+                    bool,
+                    MetadataStore,
+                    bool,
+                    bool,
+                    u64,
+                    bool,
+                    NameCipher,
+                    KeyWrapAlgorithm,
+                )>(data)
+                .map(
+                    |(
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        encryptions,
+                        compress_metadata,
+                        name_cipher,
+                        key_wrap,
+                    )| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        encryptions,
+                        compress_metadata,
+                        name_cipher,
+                        key_wrap,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `key_wrap`
+                bincode::deserialize::<(
+                    usize,
+                    Option<usize>,
+                    bool,
+                    MetadataStore,
+                    bool,
+                    bool,
+                    u64,
+                    bool,
+                    NameCipher,
+                )>(data)
+                .map(
+                    |(
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        encryptions,
+                        compress_metadata,
+                        name_cipher,
+                    )| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        encryptions,
+                        compress_metadata,
+                        name_cipher,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `name_cipher`
+                bincode::deserialize::<(
+                    usize,
+                    Option<usize>,
+                    bool,
+                    MetadataStore,
+                    bool,
+                    bool,
+                    u64,
+                    bool,
+                )>(data)
+                .map(
+                    |(
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        encryptions,
+                        compress_metadata,
+                    )| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        encryptions,
+                        compress_metadata,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `compress_metadata`
+                bincode::deserialize::<(
+                        usize,
+                        Option<usize>,
+                        bool,
+                        MetadataStore,
+                        bool,
+                        bool,
+                        u64,
+                    )>(data)
+                    .map(
+                        |(
+                            block_size,
+                            pending_block_size,
+                            encrypt_names,
+                            metadata_store,
+                            case_insensitive,
+                            normalize_names,
+                            encryptions,
+                        )| Self {
+                            block_size,
+                            pending_block_size,
+                            encrypt_names,
+                            metadata_store,
+                            case_insensitive,
+                            normalize_names,
+                            encryptions,
+                            ..Self::default()
+                        },
+                    )
+            })
+            .or_else(|_| {
+                // saved before we had `encryptions`
+                bincode::deserialize::<(usize, Option<usize>, bool, MetadataStore, bool, bool)>(
+                    data,
+                )
+                .map(
+                    |(
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                    )| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        normalize_names,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `normalize_names`
+                bincode::deserialize::<(usize, Option<usize>, bool, MetadataStore, bool)>(data).map(
+                    |(
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                    )| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        case_insensitive,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `case_insensitive`
+                bincode::deserialize::<(usize, Option<usize>, bool, MetadataStore)>(data).map(
+                    |(block_size, pending_block_size, encrypt_names, metadata_store)| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        metadata_store,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `metadata_store`
+                bincode::deserialize::<(usize, Option<usize>, bool)>(data).map(
+                    |(block_size, pending_block_size, encrypt_names)| Self {
+                        block_size,
+                        pending_block_size,
+                        encrypt_names,
+                        ..Self::default()
+                    },
+                )
+            })
+            .or_else(|_| {
+                // saved before we had `encrypt_names`
+                bincode::deserialize::<(usize, Option<usize>)>(data).map(
+                    |(block_size, pending_block_size)| Self {
+                        block_size,
+                        pending_block_size,
+                        ..Self::default()
+                    },
+                )
+            })?)
+    }
+
+    pub(crate) fn save(&self, data_dir: &Path) -> FsResult<()> {
+        let path = data_dir.join(SECURITY_DIR).join(PARAMS_FILENAME);
+        let mut file = fs_util::open_atomic_write(&path)?;
+        bincode::serialize_into(&mut file, self)?;
+        file.commit()?;
+        File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TimesAndSizeFileAttr {
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    size: u64,
+}
+
+impl TimesAndSizeFileAttr {
+    #[allow(dead_code)]
+    const fn new(
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
+        size: u64,
+    ) -> Self {
+        Self {
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            size,
+        }
+    }
+}
+
+impl From<FileAttr> for TimesAndSizeFileAttr {
+    fn from(value: FileAttr) -> Self {
+        Self {
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+            size: value.size,
+        }
+    }
+}
+
+impl From<TimesAndSizeFileAttr> for SetFileAttr {
+    fn from(value: TimesAndSizeFileAttr) -> Self {
+        Self::default()
+            .with_atime(value.atime)
+            .with_mtime(value.mtime)
+            .with_ctime(value.ctime)
+            .with_crtime(value.crtime)
+            .with_size(value.size)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TimesFileAttr {
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+}
+
+impl TimesFileAttr {
+    #[allow(dead_code)]
+    const fn new(
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
+    ) -> Self {
+        Self {
+            atime,
+            mtime,
+            ctime,
+            crtime,
+        }
+    }
+}
+
+impl From<FileAttr> for TimesFileAttr {
+    fn from(value: FileAttr) -> Self {
+        Self {
+            atime: value.atime,
+            mtime: value.mtime,
+            ctime: value.ctime,
+            crtime: value.crtime,
+        }
+    }
+}
+
+impl From<TimesFileAttr> for SetFileAttr {
+    fn from(value: TimesFileAttr) -> Self {
+        Self::default()
+            .with_atime(value.atime)
+            .with_mtime(value.mtime)
+            .with_ctime(value.ctime)
+            .with_crtime(value.crtime)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub ino: u64,
+    pub name: SecretBox<String>,
+    pub kind: FileType,
+}
+
+impl PartialEq for DirectoryEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.ino == other.ino
+            && self.name.expose_secret() == other.name.expose_secret()
+            && self.kind == other.kind
+    }
+}
+
+/// Like [`DirectoryEntry`] but with [`FileAttr`].
+#[derive(Debug, Clone)]
+pub struct DirectoryEntryPlus {
+    pub ino: u64,
+    pub name: SecretBox<String>,
+    pub kind: FileType,
+    pub attr: FileAttr,
+}
+
+impl PartialEq for DirectoryEntryPlus {
+    fn eq(&self, other: &Self) -> bool {
+        self.ino == other.ino
+            && self.name.expose_secret() == other.name.expose_secret()
+            && self.kind == other.kind
+            && self.attr == other.attr
+    }
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+pub struct DirectoryEntryIterator(VecDeque<FsResult<DirectoryEntry>>);
+
+impl Iterator for DirectoryEntryIterator {
+    type Item = FsResult<DirectoryEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop_front()
+    }
+}
+
+pub struct DirectoryEntryPlusIterator(VecDeque<FsResult<DirectoryEntryPlus>>);
+
+impl Iterator for DirectoryEntryPlusIterator {
+    type Item = FsResult<DirectoryEntryPlus>;
+
+    #[instrument(name = "DirectoryEntryPlusIterator::next", skip(self))]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.pop_front()
+    }
+}
+
+/// An open directory, see [`EncryptedFs::open_dir`].
+struct DirHandle {
+    ino: u64,
+    snapshot: Option<DirSnapshot>,
+}
+
+/// The entries of a [`DirHandle`], read once when it's read from the start, so reading it in chunks doesn't read the
+/// directory again each time and gives the entries as they were then, even if it changes meanwhile. `None` is an
+/// entry we couldn't read.
+///
+/// The kernel might read the first chunk with attributes and the rest without, so each kind can give the other.
+#[derive(Clone)]
+enum DirSnapshot {
+    Names(Arc<Vec<Option<DirectoryEntry>>>),
+    Plus(Arc<Vec<Option<DirectoryEntryPlus>>>),
+}
+
+fn snapshot_ok<T>(entry: FsResult<T>) -> Option<T> {
+    entry
+        .map_err(|err| error!(err = %err, "reading directory entry"))
+        .ok()
+}
+
+fn snapshot_entry<T>(entry: Option<T>) -> FsResult<T> {
+    entry.ok_or(FsError::Other("cannot read the directory entry"))
+}
+
+/// Health of a mounted filesystem, see [`EncryptedFs::status`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    /// The key is not in memory, the next operation needing it asks for the password again.
+    pub locked: bool,
+    pub open_handles: usize,
+    /// Bytes written with the open handles not flushed yet.
+    pub dirty_bytes: u64,
+    /// Hits out of all lookups in the attributes cache, `None` before the first one.
+    pub cache_hit_rate: Option<f64>,
+    /// When and what was the last error reading, writing or flushing, from the storage or the crypto, not from a bad
+    /// request.
+    pub last_error: Option<(SystemTime, String)>,
+    pub uptime: Duration,
+}
+
+/// State of an open file handle, see [`EncryptedFs::open_handles`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandleInfo {
+    pub fh: u64,
+    pub ino: u64,
+    pub read: bool,
+    pub write: bool,
+    /// Has writes which were not flushed yet.
+    pub dirty: bool,
+    /// Bytes written with this handle since it was opened.
+    pub bytes_written: u64,
+    /// Bytes written with this handle since what it buffered was last written out, see
+    /// [`FsOptions::max_dirty_per_handle`].
+    pub dirty_bytes: u64,
+    pub opened_at: SystemTime,
+}
+
+struct ReadHandleContext {
+    ino: u64,
+    attr: TimesFileAttr,
+    reader: Option<Box<dyn CryptoReadSeek<File>>>,
+}
+
+enum ReadHandleContextOperation {
+    Create { ino: u64 },
+}
+
+impl ReadHandleContextOperation {
+    const fn get_ino(&self) -> u64 {
+        match *self {
+            Self::Create { ino, .. } => ino,
+        }
+    }
+}
+
+enum WriteHandleContextOperation {
+    Create { ino: u64 },
+}
+
+impl WriteHandleContextOperation {
+    const fn get_ino(&self) -> u64 {
+        match *self {
+            Self::Create { ino, .. } => ino,
+        }
+    }
+}
+
+struct WriteHandleContext {
+    ino: u64,
+    attr: TimesAndSizeFileAttr,
+    writer: Option<Box<dyn CryptoWriteSeek<File>>>,
+    tail: AppendBuffer,
+    /// Length of the content file when we started changing it and the blocks we saved in the journal since then.
+    journaled: Option<(u64, HashSet<u64>)>,
+    dirty: DirtyBlocks,
+    /// Blocks changed since the parity was last updated, see [`FsOptions::redundancy`].
+    parity_dirty: DirtyBlocks,
+}
+
+impl WriteHandleContext {
+    /// Writes the pending appends to the writer.
+    fn drain_tail(&mut self) -> io::Result<()> {
+        if self.tail.buf.is_empty() {
+            return Ok(());
+        }
+        let writer = self.writer.as_mut().expect("writer is missing");
+        writer.seek(SeekFrom::Start(self.tail.offset))?;
+        writer.write_all(&self.tail.buf)?;
+        self.tail.buf.clear();
+        Ok(())
+    }
+}
+
+/// Holds appends at the end of the file in memory until we have a full block.
+///
+/// Many small appends would otherwise go through the writer one by one and reset the other handles
+/// on every call, this way the block is encrypted once, when it fills, on flush or on release.
+#[derive(Debug, Clone, Default)]
+struct AppendBuffer {
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+impl AppendBuffer {
+    fn end(&self) -> u64 {
+        self.offset + self.buf.len() as u64
+    }
+
+    /// If a write at `offset` continues the buffered data, or starts it at the end of the file.
+    fn accepts(&self, offset: u64, size: u64) -> bool {
+        if self.buf.is_empty() {
+            offset == size
+        } else {
+            offset == self.end()
+        }
+    }
+
+    /// Copies the buffered data that falls in `buf` over what we read from the file.
+    ///
+    /// `len` is how much we read from the file and `file_end` the position where the file content ended.
+    /// The buffered data is used only if it continues the file content.
+    #[allow(clippy::cast_possible_truncation)]
+    fn read_into(&self, offset: u64, file_end: u64, buf: &mut [u8], len: usize) -> usize {
+        if file_end < self.offset {
+            return len;
+        }
+        let start = offset.max(self.offset);
+        let end = (offset + buf.len() as u64).min(self.end());
+        if start >= end {
+            return len;
+        }
+        buf[(start - offset) as usize..(end - offset) as usize].copy_from_slice(
+            &self.buf[(start - self.offset) as usize..(end - self.offset) as usize],
+        );
+        len.max((end - offset) as usize)
+    }
+}
+
+/// Blocks of the content written with a handle, as merged ranges `start -> end`, so only they are uploaded to the
+/// [`FsOptions::storage`] on release and only their parity is computed again.
+#[derive(Debug, Clone, Default)]
+struct DirtyBlocks(BTreeMap<u64, u64>);
+
+impl DirtyBlocks {
+    /// Adds the blocks in `[start, end)`.
+    fn insert(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+        // the ranges don't overlap, so going back from `end` the ones touching it come first
+        let touching: Vec<_> = self
+            .0
+            .range(..=end)
+            .rev()
+            .take_while(|(_, &range_end)| range_end >= start)
+            .map(|(&range_start, _)| range_start)
+            .collect();
+        for range_start in touching {
+            let range_end = self.0.remove(&range_start).unwrap();
+            start = start.min(range_start);
+            end = end.max(range_end);
+        }
+        self.0.insert(start, end);
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.0.iter().flat_map(|(&start, &end)| start..end)
+    }
+}
+
+struct KeyProvider {
+    key_path: PathBuf,
+    salt_path: PathBuf,
+    data_dir: PathBuf,
+    /// For a new data dir, else the saved one is used.
+    key_wrap: KeyWrapAlgorithm,
+    password_provider: Box<dyn PasswordProvider>,
+    cipher: Cipher,
+    header_protection: Arc<dyn HeaderProtection>,
+    lock_memory: bool,
+    lock_memory_strict: bool,
+    protect_from_coredump: bool,
+}
+
+#[async_trait]
+impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
+    async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
+        // the token is asked before the password, without it there is nothing to try the password on
+        let header = if self.key_path.exists() {
+            Some(
+                self.header_protection
+                    .unprotect(&fs::read(&self.key_path)?)?,
+            )
+        } else {
+            None
+        };
+        let password = self
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        let key_wrap = if header.is_some() {
+            stored_key_wrap(&self.data_dir)
+        } else {
+            self.key_wrap
+        };
+        let key = read_or_create_key(
+            &self.key_path,
+            &self.salt_path,
+            header,
+            &password,
+            self.cipher,
+            key_wrap,
+            &*self.header_protection,
+        )?;
+        // `SecretVec` locks only the `Vec` itself, not the buffer with the key
+        if self.lock_memory {
+            if let Err(err) = fs_util::lock_memory(key.expose_secret().as_slice()) {
+                if self.lock_memory_strict {
+                    return Err(err.into());
+                }
+                warn!(err = %err, "cannot lock the key in memory, it might be written to swap");
+            }
+        }
+        if self.protect_from_coredump {
+            if let Err(err) = fs_util::exclude_from_core_dump(key.expose_secret().as_slice()) {
+                warn!(err = %err, "cannot exclude the key from core dumps");
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// Extra protection of the encryption key saved in the data dir, on top of the password, like with a key kept in a
+/// hardware token (PKCS#11) or a TPM, see [`FsOptions::header_protection`].
+///
+/// The key encrypted with the password is passed through [`HeaderProtection::protect`] before saving it and through
+/// [`HeaderProtection::unprotect`], before asking for the password, when reading it. Without the token the password
+/// can't even be tried. The params of the data dir, like the block size, stay in plaintext, they are not secret.
+///
+/// A token which can give a secret, like the `hmac-secret` of a FIDO2 authenticator, can also return it from
+/// [`HeaderProtection::kdf_secret`]. It's mixed into the key derived from the password, so even a header unwrapped
+/// from the token, like in an old backup, can't be opened with the password alone.
+pub trait HeaderProtection: Debug + Send + Sync + 'static {
+    #[allow(clippy::missing_errors_doc)]
+    fn protect(&self, header: &[u8]) -> FsResult<Vec<u8>>;
+    /// Fails, like with [`FsError::InvalidPassword`], if the token is missing or it's not the one that protected it.
+    #[allow(clippy::missing_errors_doc)]
+    fn unprotect(&self, data: &[u8]) -> FsResult<Vec<u8>>;
+    /// The secret mixed into the key derived from the password, `None` by default.
+    ///
+    /// It's asked for after [`HeaderProtection::unprotect`] when opening the data dir, and before
+    /// [`HeaderProtection::protect`] when creating it.
+    ///
+    /// # Errors
+    ///
+    /// Like [`HeaderProtection::unprotect`], if the token is missing.
+    fn kdf_secret(&self) -> FsResult<Option<SecretVec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// The default [`HeaderProtection`], only the password protects the key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PasswordOnly;
+
+impl HeaderProtection for PasswordOnly {
+    fn protect(&self, header: &[u8]) -> FsResult<Vec<u8>> {
+        Ok(header.to_vec())
+    }
+
+    fn unprotect(&self, data: &[u8]) -> FsResult<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+pub trait PasswordProvider: Send + Sync + 'static {
+    fn get_password(&self) -> Option<SecretString>;
+}
+
+/// Where to read the password from in non-interactive setups, instead of asking for it or using the keyring.
+#[derive(Debug)]
+pub enum PasswordSource {
+    /// Name of the environment variable holding the password. It's left in the environment, as changing it races
+    /// with other threads reading it, the `rencfs` binary removes it before starting its runtime.
+    Env(String),
+    /// File with the password, a trailing newline is ignored.
+    File(PathBuf),
+    /// Opened file descriptor to read the password from, like the one systemd gives with `LoadCredential`.
+    /// It's read until EOF and then closed, a trailing newline is ignored.
+    #[cfg(unix)]
+    Fd(std::os::fd::OwnedFd),
+    /// The password itself, like one already taken from the environment.
+    Value(SecretString),
+}
+
+impl PasswordSource {
+    /// Reads the password, consuming the source as a file descriptor can be read only once.
+    ///
+    /// # Errors
+    ///
+    /// [`FsError::NotFound`] if the environment variable is not set, [`FsError::Io`] if reading the file or the
+    /// descriptor fails.
+    pub fn read(self) -> FsResult<SecretString> {
+        let mut password = match self {
+            Self::Env(name) => {
+                // moved into the secret, not copied
+                return std::env::var(name)
+                    .map(|password| SecretString::new(Box::new(password)))
+                    .map_err(|_| FsError::NotFound("password environment variable"));
+            }
+            Self::File(path) => read_zeroizing(File::open(path)?)?,
+            // closed when it's dropped
+            #[cfg(unix)]
+            Self::Fd(fd) => read_zeroizing(File::from(fd))?,
+            Self::Value(password) => return Ok(password),
+        };
+        let len = password.len()
+            - password
+                .iter()
+                .rev()
+                .take_while(|b| matches!(b, b'\n' | b'\r'))
+                .count();
+        password.truncate(len);
+        let password = String::from_utf8(std::mem::take(&mut *password)).map_err(|err| {
+            err.into_bytes().zeroize();
+            io::Error::new(io::ErrorKind::InvalidData, "the password is not UTF-8")
+        })?;
+        Ok(SecretString::new(Box::new(password)))
+    }
+
+    /// Reads the password once and keeps it in memory to provide it when needed.
+    ///
+    /// # Errors
+    ///
+    /// Like [`PasswordSource::read`].
+    pub fn into_provider(self) -> FsResult<Box<dyn PasswordProvider>> {
+        struct Provider(SecretString);
+        impl PasswordProvider for Provider {
+            fn get_password(&self) -> Option<SecretString> {
+                Some(self.0.clone())
+            }
+        }
+        Ok(Box::new(Provider(self.read()?)))
+    }
+}
+
+/// Reads until the end into a buffer which is zeroed when dropped, like the smaller ones it leaves behind while
+/// growing, so the password doesn't stay in memory.
+fn read_zeroizing(mut reader: impl Read) -> io::Result<Zeroizing<Vec<u8>>> {
+    let mut buf = Zeroizing::new(vec![0; 256]);
+    let mut len = 0;
+    loop {
+        if len == buf.len() {
+            let mut bigger = Zeroizing::new(vec![0; buf.len() * 2]);
+            bigger[..len].copy_from_slice(&buf[..len]);
+            buf = bigger;
+        }
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Counts the blocks encrypted with the key, see [`FsOptions::max_encryptions_per_key`]. It's a [`BlockCounter`] so
+/// we can pass it to the writers of the content of files.
+#[derive(Debug)]
+struct EncryptionCounter {
+    count: AtomicU64,
+    saved: AtomicU64,
+    max: Option<u64>,
+    warned: AtomicBool,
+    events: broadcast::Sender<FsEvent>,
+}
+
+impl BlockCounter for EncryptionCounter {
+    fn add(&self, n: u64) {
+        let count = self.count.fetch_add(n, Ordering::SeqCst) + n;
+        let Some(max) = self.max else {
+            return;
+        };
+        if count >= max / 100 * ENCRYPTIONS_WARN_PERCENT
+            && !self.warned.swap(true, Ordering::SeqCst)
+        {
+            warn!(
+                encryptions = count,
+                max, "many blocks were encrypted with the key, nonces might repeat soon, copy the content to a new data dir"
+            );
+            let _ = self.events.send(FsEvent::RekeyRecommended {
+                encryptions: count,
+                max,
+            });
+        }
+    }
+}
+
+/// Remembers the last nonces blocks were encrypted with, see [`FsOptions::detect_nonce_reuse`].
+#[derive(Debug, Default)]
+struct NonceReuseDetector {
+    seen: std::sync::Mutex<SeenNonces>,
+    reuses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct SeenNonces {
+    set: HashSet<Vec<u8>>,
+    // oldest first, to forget them
+    order: VecDeque<Vec<u8>>,
+}
+
+impl BlockTransform for NonceReuseDetector {
+    fn on_write(&self, _block: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_read(&self, _block: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_sealed(&self, nonce: &[u8]) {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.set.insert(nonce.to_vec()) {
+            self.reuses.fetch_add(1, Ordering::SeqCst);
+            error!(
+                nonce = hex::encode(nonce),
+                "NONCE REUSED with the same key, this is a bug which breaks the encryption, please report it"
+            );
+            return;
+        }
+        seen.order.push_back(nonce.to_vec());
+        if seen.order.len() > NONCE_REUSE_WINDOW {
+            let oldest = seen.order.pop_front().unwrap();
+            seen.set.remove(&oldest);
+        }
+    }
+}
+
+/// Derives the [`NameKeys`] from the key, they expire like it.
+struct NameKeysProvider {
+    key: Arc<ExpireValue<SecretVec<u8>, FsError, KeyProvider>>,
+}
+#[async_trait]
+impl ValueProvider<NameKeys, FsError> for NameKeysProvider {
+    async fn provide(&self) -> Result<NameKeys, FsError> {
+        Ok(NameKeys::new(&*self.key.get().await?))
+    }
+}
+
+struct DirEntryNameCacheProvider {
+    capacity: NonZeroUsize,
+}
+#[async_trait]
+impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryNameCacheProvider {
+    async fn provide(&self) -> Result<Mutex<LruCache<String, SecretString>>, FsError> {
+        Ok(Mutex::new(LruCache::new(self.capacity)))
+    }
+}
+
+struct DirEntryMetaCacheProvider {
+    capacity: NonZeroUsize,
+}
+#[async_trait]
+impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvider {
+    async fn provide(&self) -> Result<Mutex<DirEntryMetaCache>, FsError> {
+        Ok(Mutex::new(LruCache::new(self.capacity)))
+    }
+}
+
+struct AttrCacheProvider {
+    capacity: NonZeroUsize,
+}
+#[async_trait]
+impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvider {
+    async fn provide(&self) -> Result<RwLock<LruCache<u64, FileAttr>>, FsError> {
+        Ok(RwLock::new(LruCache::new(self.capacity)))
+    }
+}
+
+/// Entries each cache can have, fewer with [`FsOptions::max_memory_bytes`] so together they fit in their part of it.
+fn cache_capacity(options: &FsOptions) -> NonZeroUsize {
+    let capacity = options.max_memory_bytes.map_or(CACHE_CAPACITY, |max| {
+        usize::try_from(max / MEMORY_CACHES_DIVISOR / 3 / CACHE_ENTRY_BYTES)
+            .unwrap_or(usize::MAX)
+            .clamp(MIN_CACHE_CAPACITY, CACHE_CAPACITY)
+    });
+    NonZeroUsize::new(capacity).unwrap()
+}
+
+type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
+
+/// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
+pub struct EncryptedFs {
+    pub(crate) data_dir: PathBuf,
+    write_handles: RwLock<HashMap<u64, Mutex<WriteHandleContext>>>,
+    read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
+    current_handle: AtomicU64,
+    cipher: Cipher,
+    // (ino, fh)
+    opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
+    opened_files_for_write: RwLock<HashMap<u64, u64>>,
+    // used for rw ops of actual serialization
+    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
+    serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
+    // used for the update op
+    serialize_update_inode_locks: ArcHashMap<u64, Mutex<bool>>,
+    // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
+    serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
+    serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
+    read_write_locks: ArcHashMap<u64, RwLock<bool>>,
+    // used to update the xattrs
+    xattr_locks: ArcHashMap<u64, Mutex<bool>>,
+    key: Arc<ExpireValue<SecretVec<u8>, FsError, KeyProvider>>,
+    name_keys: ExpireValue<NameKeys, FsError, NameKeysProvider>,
+    self_weak: std::sync::Mutex<Option<Weak<Self>>>,
+    attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
+    dir_entries_name_cache:
+        ExpireValue<Mutex<LruCache<String, SecretBox<String>>>, FsError, DirEntryNameCacheProvider>,
+    dir_entries_meta_cache:
+        ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
+    sizes_write: Mutex<HashMap<u64, AtomicU64>>,
+    sizes_read: Mutex<HashMap<u64, AtomicU64>>,
+    requested_read: Mutex<HashMap<u64, AtomicU64>>,
+    read_only: bool,
+    options: FsOptions,
+    events: broadcast::Sender<FsEvent>,
+    block_size: usize,
+    encrypt_names: bool,
+    name_cipher: NameCipher,
+    case_insensitive: bool,
+    normalize_names: bool,
+    compress_metadata: bool,
+    // the read-only base of an overlay, see [`EncryptedFs::new_overlay`]
+    lower: Option<Arc<EncryptedFs>>,
+    // read handles of files only in `lower`, (fh, lower fh)
+    lower_handles: RwLock<HashMap<u64, u64>>,
+    // kept apart from the handle contexts so we can read it without waiting on I/O
+    // use std::sync::Mutex as it's never held across an await
+    handle_infos: std::sync::Mutex<HashMap<u64, HandleInfo>>,
+    dir_handles: std::sync::Mutex<HashMap<u64, DirHandle>>,
+    // `Some` with [`MetadataStore::EmbeddedDb`]
+    metadata_db: Option<MetadataDb>,
+    // the backend of [`Storage::Mirror`], `None` with [`Storage::Directory`]
+    storage: Option<Arc<dyn StorageBackend>>,
+    // inodes under [`FsOptions::read_only_paths`], nothing can be added or removed in them so it's computed once
+    read_only_inos: OnceLock<HashSet<u64>>,
+    encryptions: Arc<EncryptionCounter>,
+    nonce_reuse: Option<Arc<NonceReuseDetector>>,
+    // so we don't save the params concurrently
+    params_lock: std::sync::Mutex<()>,
+    // for [`EncryptedFs::status`]
+    started_at: std::time::Instant,
+    attr_cache_hits: AtomicU64,
+    attr_cache_misses: AtomicU64,
+    // releases of write handles which synced the content, for the tests
+    #[cfg(test)]
+    release_syncs: AtomicU64,
+    last_error: std::sync::Mutex<Option<(SystemTime, String)>>,
+    // the last time from the system clock and when we got it, for [`TimestampSource::MonotonicAdjusted`]
+    clock: std::sync::Mutex<(SystemTime, std::time::Instant)>,
+}
+
+impl EncryptedFs {
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        read_only: bool,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_with_options(
+            data_dir,
+            password_provider,
+            cipher,
+            read_only,
+            FsOptions::default(),
+        )
+        .await
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_with_options(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        read_only: bool,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_layered(
+            data_dir,
+            password_provider,
+            cipher,
+            read_only,
+            options,
+            None,
+            false,
+        )
+        .await
+    }
+
+    /// Opens a damaged vault read-only to rescue what can still be read.
+    ///
+    /// A corrupted params file, an unfinished [`EncryptedFs::change_block_size`] or a missing root only log a
+    /// warning instead of failing to open. Damaged inodes, entries and blocks fail with an error when accessed,
+    /// so the readable files can be copied out and the ones that fail are the damaged ones.
+    ///
+    /// Nothing is written to the data dir, not even recovering the journal or creating missing directories.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_forgiving(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_layered(
+            data_dir,
+            password_provider,
+            cipher,
+            true,
+            FsOptions::default(),
+            None,
+            true,
+        )
+        .await
+    }
+
+    /// Layer a writable vault in `upper` over the `lower` one, like overlayfs.
+    ///
+    /// Reads fall through to `lower` for anything not in `upper`. Changing something from `lower` first copies it up,
+    /// encrypted with the key of `upper`, and deleting it leaves a whiteout in `upper` that hides it. `lower` is never
+    /// changed, so it can be opened read-only and shared by many overlays.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn new_overlay(
+        upper: PathBuf,
+        lower: Arc<Self>,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_layered(
+            upper,
+            password_provider,
+            cipher,
+            false,
+            options,
+            Some(lower),
+            false,
+        )
+        .await
+    }
+
+    async fn new_layered(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        read_only: bool,
+        options: FsOptions,
+        lower: Option<Arc<Self>>,
+        forgiving: bool,
+    ) -> FsResult<Arc<Self>> {
+        let read_only = read_only || backing_read_only(&data_dir);
+        let key_provider = KeyProvider {
+            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
+            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+            data_dir: data_dir.clone(),
+            key_wrap: options.key_wrap,
+            password_provider,
+            cipher,
+            header_protection: options.header_protection.clone(),
+            lock_memory: options.lock_memory,
+            lock_memory_strict: options.lock_memory_strict,
+            protect_from_coredump: options.protect_from_coredump,
+        };
+        if options.protect_from_coredump {
+            fs_util::disable_core_dumps()?;
+        }
+        let key = Arc::new(ExpireValue::new(key_provider, Duration::from_secs(10 * 60)));
+        if options.redundancy == Some(0) {
+            return Err(FsError::InvalidInput("redundancy must be greater than 0"));
+        }
+        if options.max_read_size == 0 {
+            // every read would look like the end of the file
+            return Err(FsError::InvalidInput(
+                "max_read_size must be greater than 0",
+            ));
+        }
+
+        if !options.allow_fuse_data_dir {
+            check_not_on_fuse(&data_dir)?;
+        }
+        let new_data_dir = !data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).exists();
+        if forgiving {
+            // we need at least the key, the rest is checked when used
+            if new_data_dir {
+                return Err(FsError::InvalidDataDirStructure);
+            }
+        } else if read_only && !new_data_dir {
+            // don't add what older versions didn't create, like the journal dir
+            check_structure(&data_dir, false).await?;
+        } else {
+            ensure_structure_created(&data_dir.clone()).await?;
+        }
+        key.get().await?; // this will check the password
+        let mut params = match VaultParams::load(&data_dir) {
+            Err(err) if forgiving => {
+                warn!(err = %err, "cannot read the params, using the defaults");
+                VaultParams::default()
+            }
+            params => params?,
+        };
+        if new_data_dir
+            && (!options.encrypt_names
+                || options.metadata_store != MetadataStore::Files
+                || options.case_insensitive
+                || options.normalize_names
+                || options.compress_metadata
+                || options.name_cipher != NameCipher::Content
+                || options.key_wrap != KeyWrapAlgorithm::Content
+                || options.redundancy.is_some())
+        {
+            if !options.encrypt_names && options.name_cipher != NameCipher::Content {
+                return Err(FsError::InvalidInput("name_cipher needs encrypt_names"));
+            }
+            params.encrypt_names = options.encrypt_names;
+            params.metadata_store = options.metadata_store;
+            params.case_insensitive = options.case_insensitive;
+            params.normalize_names = options.normalize_names;
+            params.compress_metadata = options.compress_metadata;
+            params.name_cipher = options.name_cipher;
+            params.key_wrap = options.key_wrap;
+            params.redundancy = options.redundancy;
+            params.save(&data_dir)?;
+        } else {
+            if params.redundancy != options.redundancy {
+                // the parity was computed over other groups, repairing with it would write garbage
+                return Err(FsError::InvalidInput(
+                    "redundancy differs from the one the data dir was created with",
+                ));
+            }
+            if params.encrypt_names != options.encrypt_names {
+                warn!(
+                    encrypt_names = params.encrypt_names,
+                    "encrypt_names differs from the one the data dir was created with, using that one"
+                );
+            }
+            if params.metadata_store != options.metadata_store {
+                warn!(
+                    metadata_store = ?params.metadata_store,
+                    "metadata_store differs from the one the data dir was created with, using that one"
+                );
+            }
+            if params.case_insensitive != options.case_insensitive {
+                warn!(
+                    case_insensitive = params.case_insensitive,
+                    "case_insensitive differs from the one the data dir was created with, using that one"
+                );
+            }
+            if params.normalize_names != options.normalize_names {
+                warn!(
+                    normalize_names = params.normalize_names,
+                    "normalize_names differs from the one the data dir was created with, using that one"
+                );
+            }
+            if params.compress_metadata != options.compress_metadata {
+                warn!(
+                    compress_metadata = params.compress_metadata,
+                    "compress_metadata differs from the one the data dir was created with, using that one"
+                );
+            }
+            if params.name_cipher != options.name_cipher {
+                warn!(
+                    name_cipher = %params.name_cipher,
+                    "name_cipher differs from the one the data dir was created with, using that one"
+                );
+            }
+            if params.key_wrap != options.key_wrap {
+                warn!(
+                    key_wrap = %params.key_wrap,
+                    "key_wrap differs from the one the data dir was created with, using that one"
+                );
+            }
+        }
+        if let Some(pending) = params.pending_block_size {
+            if !forgiving {
+                return Err(FsError::BlockSizeChangeUnfinished(pending));
+            }
+            warn!(
+                pending,
+                "block size change is unfinished, files already converted can't be read"
+            );
+        }
+        if lower
+            .as_ref()
+            .is_some_and(|lower| lower.metadata_db.is_some())
+            || (lower.is_some() && params.metadata_store != MetadataStore::Files)
+        {
+            return Err(FsError::InvalidInput(
+                "overlays need the files metadata store",
+            ));
+        }
+        if !matches!(options.storage, Storage::Directory)
+            && params.metadata_store != MetadataStore::Files
+        {
+            return Err(FsError::InvalidInput(
+                "a storage mirror needs the files metadata store",
+            ));
+        }
+        if lower.as_ref().is_some_and(|lower| {
+            lower.case_insensitive != params.case_insensitive
+                || lower.normalize_names != params.normalize_names
+                || lower.compress_metadata != params.compress_metadata
+        }) {
+            return Err(FsError::InvalidInput(
+                "overlay layers need the same case_insensitive, normalize_names and compress_metadata",
+            ));
+        }
+        let storage = match &options.storage {
+            Storage::Directory => None,
+            Storage::Mirror(backend) => Some(backend.clone()),
+        };
+        let metadata_db = match params.metadata_store {
+            MetadataStore::Files => None,
+            MetadataStore::EmbeddedDb => Some(MetadataDb::open(
+                &data_dir.join(INODES_DIR).join(METADATA_DB_FILENAME),
+                read_only,
+            )?),
+        };
+
+        let events = broadcast::channel(100).0;
+        let encryptions = Arc::new(EncryptionCounter {
+            count: AtomicU64::new(params.encryptions),
+            saved: AtomicU64::new(params.encryptions),
+            max: options.max_encryptions_per_key,
+            warned: AtomicBool::new(false),
+            events: events.clone(),
+        });
+        let nonce_reuse = options
+            .detect_nonce_reuse
+            .then(|| Arc::new(NonceReuseDetector::default()));
+        let capacity = cache_capacity(&options);
+        let fs = Self {
+            data_dir,
+            write_handles: RwLock::new(HashMap::new()),
+            read_handles: RwLock::new(HashMap::new()),
+            current_handle: AtomicU64::new(1),
+            cipher,
+            opened_files_for_read: RwLock::new(HashMap::new()),
+            opened_files_for_write: RwLock::new(HashMap::new()),
+            serialize_inode_locks: Arc::new(ArcHashMap::default()),
+            serialize_update_inode_locks: ArcHashMap::default(),
+            serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
+            serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
+            name_keys: ExpireValue::new(
+                NameKeysProvider { key: key.clone() },
+                Duration::from_secs(10 * 60),
+            ),
+            key,
+            self_weak: std::sync::Mutex::new(None),
+            read_write_locks: ArcHashMap::default(),
+            xattr_locks: ArcHashMap::default(),
+            // todo: take duration from param
+            attr_cache: ExpireValue::new(
+                AttrCacheProvider { capacity },
+                Duration::from_secs(10 * 60),
+            ),
+            // todo: take duration from param
+            dir_entries_name_cache: ExpireValue::new(
+                DirEntryNameCacheProvider { capacity },
+                Duration::from_secs(10 * 60),
+            ),
+            // todo: take duration from param
+            dir_entries_meta_cache: ExpireValue::new(
+                DirEntryMetaCacheProvider { capacity },
+                Duration::from_secs(10 * 60),
+            ),
+            sizes_write: Mutex::default(),
+            sizes_read: Mutex::default(),
+            requested_read: Mutex::default(),
+            read_only,
+            options,
+            events,
+            block_size: params.block_size,
+            encrypt_names: params.encrypt_names,
+            name_cipher: params.name_cipher,
+            case_insensitive: params.case_insensitive,
+            normalize_names: params.normalize_names,
+            compress_metadata: params.compress_metadata,
+            lower,
+            lower_handles: RwLock::new(HashMap::new()),
+            handle_infos: std::sync::Mutex::new(HashMap::new()),
+            dir_handles: std::sync::Mutex::new(HashMap::new()),
+            metadata_db,
+            storage,
+            read_only_inos: OnceLock::new(),
+            encryptions,
+            nonce_reuse,
+            params_lock: std::sync::Mutex::new(()),
+            started_at: std::time::Instant::now(),
+            attr_cache_hits: AtomicU64::new(0),
+            attr_cache_misses: AtomicU64::new(0),
+            #[cfg(test)]
+            release_syncs: AtomicU64::new(0),
+            last_error: std::sync::Mutex::new(None),
+            clock: std::sync::Mutex::new((SystemTime::now(), std::time::Instant::now())),
+        };
+
+        let arc = Arc::new(fs);
+        arc.self_weak
+            .lock()
+            .expect("cannot obtain lock")
+            .replace(Arc::downgrade(&arc));
+
+        if forgiving {
+            if !arc.exists(ROOT_INODE) {
+                warn!("root directory is missing");
+            }
+        } else {
+            arc.ensure_root_exists().await?;
+        }
+        arc.recover_journal().await?;
+        let read_only_inos = arc.resolve_read_only_paths().await?;
+        arc.read_only_inos.get_or_init(|| read_only_inos);
+        // warns if we are already close to the limit
+        arc.encryptions.add(0);
+
+        if let Some(interval) = arc.options.scrub_interval {
+            let fs = Arc::downgrade(&arc);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                // first tick is immediate
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let Some(fs) = fs.upgrade() else {
+                        break;
+                    };
+                    if let Err(err) = fs.scrub_now().await {
+                        error!(err = %err, "scrubbing");
+                    }
+                }
+            });
+        }
+
+        Ok(arc)
+    }
+
+    pub const fn options(&self) -> &FsOptions {
+        &self.options
+    }
+
+    /// Size of the plaintext blocks the content of files is split into before encrypting.
+    pub const fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// If the names of files and directories are encrypted, see [`FsOptions::encrypt_names`].
+    pub const fn encrypt_names(&self) -> bool {
+        self.encrypt_names
+    }
+
+    /// If names are matched ignoring the case, see [`FsOptions::case_insensitive`].
+    pub const fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// If names are normalized to NFC, see [`FsOptions::normalize_names`].
+    pub const fn normalize_names(&self) -> bool {
+        self.normalize_names
+    }
+
+    /// Where the metadata is kept, see [`FsOptions::metadata_store`].
+    pub const fn metadata_store(&self) -> MetadataStore {
+        if self.metadata_db.is_some() {
+            MetadataStore::EmbeddedDb
+        } else {
+            MetadataStore::Files
+        }
+    }
+
+    /// Blocks encrypted with the key of the data dir so far, see [`FsOptions::max_encryptions_per_key`].
+    pub fn encryptions(&self) -> u64 {
+        self.encryptions.count.load(Ordering::SeqCst)
+    }
+
+    /// Times a nonce was used again, with [`FsOptions::detect_nonce_reuse`], it's always 0 without it. Anything else
+    /// is a bug.
+    pub fn nonce_reuses(&self) -> u64 {
+        self.nonce_reuse
+            .as_ref()
+            .map_or(0, |detector| detector.reuses.load(Ordering::SeqCst))
+    }
+
+    /// Saves [`EncryptedFs::encryptions`] in the data dir if it changed, or with `only_if_many` if it changed by at
+    /// least [`ENCRYPTIONS_SAVE_EVERY`].
+    fn save_encryptions(&self, only_if_many: bool) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let count = self.encryptions();
+        let saved = self.encryptions.saved.load(Ordering::SeqCst);
+        if count == saved || (only_if_many && count - saved < ENCRYPTIONS_SAVE_EVERY) {
+            return Ok(());
+        }
+        let _guard = self.params_lock.lock().unwrap();
+        let mut params = VaultParams::load(&self.data_dir)?;
+        // another call might have saved a bigger one meanwhile
+        let count = self.encryptions();
+        params.encryptions = count;
+        params.save(&self.data_dir)?;
+        self.encryptions.saved.fetch_max(count, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The [`FsOptions::block_transforms`] with the nonce reuse detector, if any, for writers.
+    fn write_transforms(&self) -> Vec<Arc<dyn BlockTransform>> {
+        let mut transforms = self.options.block_transforms.clone();
+        if let Some(detector) = &self.nonce_reuse {
+            transforms.push(detector.clone());
+        }
+        transforms
+    }
+
+    /// Length (in bytes) of an encrypted block of the content of files.
+    fn ciphertext_block_len(&self) -> usize {
+        self.cipher.ciphertext_block_len_for(self.block_size)
+    }
+
+    /// Subscribe to events, like corrupted blocks found by the scrubber.
+    pub fn events(&self) -> broadcast::Receiver<FsEvent> {
+        self.events.subscribe()
+    }
+
+    /// Reconstruct from parity the blocks of a file which fail the integrity check, see [`FsOptions::redundancy`].
+    ///
+    /// Returns the repaired blocks. Blocks can't be repaired if there is more than one corrupted in the same group or
+    /// if the parity is older than the content, like after a crash while writing.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn repair(&self, ino: u64) -> FsResult<Vec<u64>> {
+        let Some(group) = self.options.redundancy else {
+            return Ok(vec![]);
+        };
+        let parity_path = self.parity_path(ino);
+        if !parity_path.is_file() {
+            return Ok(vec![]);
+        }
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        let key = self.key.get().await?;
+        let block_len = self.ciphertext_block_len() as u64;
+        let mut contents = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.contents_path(ino))?;
+        let blocks = contents.metadata()?.len().div_ceil(block_len);
+        let mut parity_file = File::open(parity_path)?;
+        let mut repaired = vec![];
+        for first in (0..blocks).step_by(group) {
+            let mut group_blocks = vec![];
+            contents.seek(SeekFrom::Start(first * block_len))?;
+            for _ in first..blocks.min(first + group as u64) {
+                let mut block = vec![];
+                (&mut contents).take(block_len).read_to_end(&mut block)?;
+                group_blocks.push(block);
+            }
+            let corrupted: Vec<_> = (0..group_blocks.len())
+                .filter(|i| {
+                    let mut block = group_blocks[*i].clone();
+                    crypto::decrypt_block(self.cipher, &key, first + *i as u64, &mut block).is_err()
+                })
+                .collect();
+            let [bad] = corrupted[..] else {
+                if corrupted.len() > 1 {
+                    warn!(ino, first, "too many corrupted blocks in group to repair");
+                }
+                continue;
+            };
+            #[allow(clippy::cast_possible_truncation)]
+            let mut block = vec![0; block_len as usize];
+            parity_file.seek(SeekFrom::Start(first / group as u64 * block_len))?;
+            if parity_file.read_exact(&mut block).is_err() {
+                warn!(ino, "parity is missing blocks");
+                break;
+            }
+            for (i, other) in group_blocks.iter().enumerate() {
+                if i != bad {
+                    block.iter_mut().zip(other).for_each(|(b, o)| *b ^= o);
+                }
+            }
+            block.truncate(group_blocks[bad].len());
+            let index = first + bad as u64;
+            if crypto::decrypt_block(self.cipher, &key, index, &mut block.clone()).is_err() {
+                warn!(
+                    ino,
+                    block = index,
+                    "cannot repair block, parity is outdated"
+                );
+                continue;
+            }
+            contents.seek(SeekFrom::Start(index * block_len))?;
+            contents.write_all(&block)?;
+            info!(ino, block = index, "repaired block from parity");
+            repaired.push(index);
+        }
+        if !repaired.is_empty() {
+            contents.sync_all()?;
+        }
+        Ok(repaired)
+    }
+
+    /// Compute the parity of a file again after it changed, see [`FsOptions::redundancy`].
+    ///
+    /// With `dirty` only the groups of those blocks are computed, else the whole file.
+    fn update_parity(&self, ino: u64, dirty: Option<&DirtyBlocks>) -> FsResult<()> {
+        let Some(group) = self.options.redundancy else {
+            return Ok(());
+        };
+        let parity_path = self.parity_path(ino);
+        let (Some(dirty), true) = (dirty, parity_path.is_file()) else {
+            return self.compute_parity(ino, group);
+        };
+        let block_len = self.ciphertext_block_len() as u64;
+        let group = group as u64;
+        let mut contents = File::open(self.contents_path(ino))?;
+        let groups = contents
+            .metadata()?
+            .len()
+            .div_ceil(block_len)
+            .div_ceil(group);
+        let touched: BTreeSet<u64> = dirty
+            .blocks()
+            .map(|block| block / group)
+            .filter(|g| *g < groups)
+            .collect();
+        let mut parity_file = OpenOptions::new().write(true).open(&parity_path)?;
+        #[allow(clippy::cast_possible_truncation)]
+        let mut parity = vec![0; block_len as usize];
+        let mut block = Vec::with_capacity(parity.len());
+        for g in touched {
+            parity.fill(0);
+            contents.seek(SeekFrom::Start(g * group * block_len))?;
+            for _ in 0..group {
+                block.clear();
+                (&mut contents).take(block_len).read_to_end(&mut block)?;
+                if block.is_empty() {
+                    break;
+                }
+                parity.iter_mut().zip(&block).for_each(|(p, b)| *p ^= b);
+            }
+            parity_file.seek(SeekFrom::Start(g * block_len))?;
+            parity_file.write_all(&parity)?;
+        }
+        parity_file.set_len(groups * block_len)?;
+        Ok(())
+    }
+
+    /// Compute the parity of the whole file, in groups of `group` blocks.
+    fn compute_parity(&self, ino: u64, group: usize) -> FsResult<()> {
+        let block_len = self.ciphertext_block_len();
+        fs::create_dir_all(self.data_dir.join(PARITY_DIR))?;
+        let mut contents = io::BufReader::new(File::open(self.contents_path(ino))?);
+        let mut parity_file = fs_util::open_atomic_write(&self.parity_path(ino))?;
+        let mut parity = vec![0; block_len];
+        let mut block = Vec::with_capacity(block_len);
+        let mut in_group = 0;
+        loop {
+            block.clear();
+            (&mut contents)
+                .take(block_len as u64)
+                .read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            // shorter blocks are like padded with zeros
+            parity.iter_mut().zip(&block).for_each(|(p, b)| *p ^= b);
+            in_group += 1;
+            if in_group == group {
+                parity_file.write_all(&parity)?;
+                parity.fill(0);
+                in_group = 0;
+            }
+        }
+        if in_group > 0 {
+            parity_file.write_all(&parity)?;
+        }
+        parity_file.commit()?;
+        Ok(())
+    }
+
+    /// Verify the tag of a random sample of blocks, of size [`FsOptions::scrub_blocks`], to catch silent disk
+    /// corruption early.
+    ///
+    /// Returns the corrupted blocks as `(ino, block)`, they are also logged and sent as [`FsEvent::CorruptBlock`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn scrub_now(&self) -> FsResult<Vec<(u64, u64)>> {
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        // (ino, blocks) for all files
+        let mut files = vec![];
+        for entry in fs::read_dir(self.data_dir.join(CONTENTS_DIR))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let Ok(ino) = entry.file_name().to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let blocks = entry.metadata()?.len().div_ceil(ciphertext_block_len);
+            if blocks > 0 {
+                files.push((ino, blocks));
+            }
+        }
+        let total: u64 = files.iter().map(|(_, blocks)| blocks).sum();
+        let sample = if total <= self.options.scrub_blocks as u64 {
+            files
+                .iter()
+                .flat_map(|(ino, blocks)| (0..*blocks).map(|block| (*ino, block)))
+                .collect::<Vec<_>>()
+        } else {
+            let mut rng = crypto::create_rng();
+            (0..self.options.scrub_blocks)
+                .map(|_| {
+                    #[allow(clippy::cast_possible_truncation)]
+                    let (ino, blocks) = files[(rng.next_u64() % files.len() as u64) as usize];
+                    (ino, rng.next_u64() % blocks)
+                })
+                .collect()
+        };
+
+        let key = self.key.get().await?;
+        let mut corrupted = vec![];
+        for (ino, block) in sample {
+            // don't read while it's being written
+            let lock = self
+                .read_write_locks
+                .get_or_insert_with(ino, || RwLock::new(false));
+            let _guard = lock.read().await;
+            let Ok(mut file) = File::open(self.contents_path(ino)) else {
+                // deleted in the meantime
+                continue;
+            };
+            let mut buf = vec![];
+            file.seek(SeekFrom::Start(block * ciphertext_block_len))?;
+            file.take(ciphertext_block_len).read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                // truncated in the meantime
+                continue;
+            }
+            if crypto::decrypt_block(self.cipher, &key, block, &mut buf).is_err() {
+                error!(ino, block, "corrupted block");
+                let _ = self.events.send(FsEvent::CorruptBlock { ino, block });
+                corrupted.push((ino, block));
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Check that a file is intact without reading it, for a backup verification pass over a whole vault.
+    ///
+    /// The attributes and the tag of each block are verified, the plaintext is dropped right away and nothing is
+    /// cached, no handle is needed. Blocks missing for the size of the file are reported too. Changes not yet flushed
+    /// from open handles are not included.
+    ///
+    /// Returns the corrupted blocks, they are also logged and sent as [`FsEvent::CorruptBlock`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn quick_verify(&self, ino: u64) -> FsResult<Vec<u64>> {
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.quick_verify(ino)).await;
+        }
+        let attr = self.get_inode_from_storage(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(wrong_file_type(attr.kind));
+        }
+        // don't read while it's being written
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let key = self.key.get().await?;
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        let mut file = File::open(self.contents_path(ino))?;
+        let blocks = file
+            .metadata()?
+            .len()
+            .div_ceil(ciphertext_block_len)
+            .max(attr.size.div_ceil(self.block_size as u64));
+        let mut corrupted = vec![];
+        let mut buf = vec![];
+        for block in 0..blocks {
+            buf.clear();
+            (&mut file)
+                .take(ciphertext_block_len)
+                .read_to_end(&mut buf)?;
+            if buf.is_empty() || crypto::decrypt_block(self.cipher, &key, block, &mut buf).is_err()
+            {
+                error!(ino, block, "corrupted block");
+                let _ = self.events.send(FsEvent::CorruptBlock { ino, block });
+                corrupted.push(block);
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// See [`FsOptions::open_verify`].
+    async fn verify_on_open(&self, ino: u64) -> FsResult<()> {
+        if self.options.open_verify == VerifyLevel::None
+            || self.get_attr(ino).await?.kind != FileType::RegularFile
+            || self.opened_files_for_write.read().await.contains_key(&ino)
+        {
+            return Ok(());
+        }
+        let corrupted = match self.options.open_verify {
+            VerifyLevel::None => vec![],
+            VerifyLevel::Header => self.verify_header(ino).await?,
+            VerifyLevel::Full => self.quick_verify(ino).await?,
+        };
+        if corrupted.is_empty() {
+            Ok(())
+        } else {
+            Err(FsError::CorruptFile {
+                ino,
+                blocks: corrupted,
+            })
+        }
+    }
+
+    /// Like [`EncryptedFs::quick_verify`] for only what [`VerifyLevel::Header`] checks.
+    async fn verify_header(&self, ino: u64) -> FsResult<Vec<u64>> {
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.verify_header(ino)).await;
+        }
+        let attr = self.get_inode_from_storage(ino).await?;
+        let blocks = attr.size.div_ceil(self.block_size as u64);
+        if blocks == 0 {
+            return Ok(vec![]);
+        }
+        // don't read while it's being written
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let key = self.key.get().await?;
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        let mut file = File::open(self.contents_path(ino))?;
+        let mut corrupted = vec![];
+        let last = blocks - 1;
+        for block in if last == 0 { vec![0] } else { vec![0, last] } {
+            let mut buf = vec![];
+            file.seek(SeekFrom::Start(block * ciphertext_block_len))?;
+            (&mut file)
+                .take(ciphertext_block_len)
+                .read_to_end(&mut buf)?;
+            // the last block can be shorter, the ones before it are whole
+            let short = block < last && (buf.len() as u64) < ciphertext_block_len;
+            if short
+                || buf.is_empty()
+                || crypto::decrypt_block(self.cipher, &key, block, &mut buf).is_err()
+            {
+                error!(ino, block, "corrupted block");
+                let _ = self.events.send(FsEvent::CorruptBlock { ino, block });
+                corrupted.push(block);
+            }
+        }
+        let needed = ciphertext_len(attr.size, self.cipher, self.block_size);
+        if file.metadata()?.len() < needed && !corrupted.contains(&last) {
+            error!(ino, "encrypted file is shorter than the size of the file");
+            let _ = self.events.send(FsEvent::CorruptBlock { ino, block: last });
+            corrupted.push(last);
+        }
+        Ok(corrupted)
+    }
+
+    /// Digest of the ciphertext of each block of a file, for incremental backups which copy only the changed blocks.
+    ///
+    /// The digest is over the ciphertext so it can be compared without the key, it changes only when the block is
+    /// rewritten. As each write uses a new nonce, writing the same data again changes it too. Changes not yet flushed
+    /// from open handles are not included.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn block_digests(&self, ino: u64) -> FsResult<Vec<BlockDigest>> {
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.block_digests(ino)).await;
+        }
+        let attr = self.get_inode_from_storage(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(wrong_file_type(attr.kind));
+        }
+        // don't read while it's being written
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        let mut file = io::BufReader::new(File::open(self.contents_path(ino))?);
+        let mut digests = vec![];
+        let mut buf = vec![];
+        for index in 0.. {
+            buf.clear();
+            (&mut file)
+                .take(ciphertext_block_len)
+                .read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+            digests.push(BlockDigest {
+                index,
+                digest: crypto::hash(&buf),
+            });
+        }
+        Ok(digests)
+    }
+
+    /// Encrypt a buffer, like a config of an app, with the key of the vault, so it's protected by the same password.
+    /// It's not saved anywhere, get it back with [`EncryptedFs::unseal`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn seal(&self, plaintext: &[u8]) -> FsResult<Vec<u8>> {
+        Ok(crypto::seal(
+            self.cipher,
+            &*self.key.get().await?,
+            plaintext,
+        )?)
+    }
+
+    /// Decrypt a buffer from [`EncryptedFs::seal`].
+    ///
+    /// Fails with [`FsError::Crypto`] if it was changed or sealed with the key of another vault.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn unseal(&self, sealed: &[u8]) -> FsResult<Vec<u8>> {
+        Ok(crypto::unseal(&*self.key.get().await?, sealed)?)
+    }
+
+    /// Check the tag of only one block of a file, like one reported by [`EncryptedFs::quick_verify`] after trying to
+    /// recover it. The other blocks are not read.
+    ///
+    /// Returns `false` if it's corrupted or missing, it's also logged and sent as [`FsEvent::CorruptBlock`]. Fails
+    /// with [`FsError::InvalidInput`] if the file doesn't have that block.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn verify_block(&self, ino: u64, block: u64) -> FsResult<bool> {
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.verify_block(ino, block)).await;
+        }
+        let attr = self.get_inode_from_storage(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(wrong_file_type(attr.kind));
+        }
+        // don't read while it's being written
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        let mut file = File::open(self.contents_path(ino))?;
+        let blocks = file
+            .metadata()?
+            .len()
+            .div_ceil(ciphertext_block_len)
+            .max(attr.size.div_ceil(self.block_size as u64));
+        if block >= blocks {
+            return Err(FsError::InvalidInput("block past the end of the file"));
+        }
+        let key = self.key.get().await?;
+        let mut buf = vec![];
+        file.seek(SeekFrom::Start(block * ciphertext_block_len))?;
+        file.take(ciphertext_block_len).read_to_end(&mut buf)?;
+        if buf.is_empty() || crypto::decrypt_block(self.cipher, &key, block, &mut buf).is_err() {
+            error!(ino, block, "corrupted block");
+            let _ = self.events.send(FsEvent::CorruptBlock { ino, block });
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub fn exists(&self, ino: u64) -> bool {
+        self.inode_stored(ino) || self.lower.as_ref().is_some_and(|lower| lower.exists(ino))
+    }
+
+    /// If the attributes of `ino` are in this layer.
+    fn inode_stored(&self, ino: u64) -> bool {
+        match &self.metadata_db {
+            Some(db) => db.contains_inode(ino).unwrap_or_else(|err| {
+                error!(err = %err, "reading inode from metadata db");
+                false
+            }),
+            None => self.ino_file(ino).is_file(),
+        }
+    }
+
+    pub fn is_dir(&self, ino: u64) -> bool {
+        self.contents_path(ino).is_dir()
+            || self.lower.as_ref().is_some_and(|lower| lower.is_dir(ino))
+    }
+
+    pub fn is_file(&self, ino: u64) -> bool {
+        self.contents_path(ino).is_file()
+            || self.lower.as_ref().is_some_and(|lower| lower.is_file(ino))
+    }
+
+    /// The error for `ino` when we need a regular file and it's not one.
+    fn wrong_file_type_of(&self, ino: u64) -> FsError {
+        if self.is_dir(ino) {
+            FsError::IsADirectory
+        } else {
+            FsError::InvalidInodeType
+        }
+    }
+
+    /// The lower layer of an overlay, if `ino` is there and wasn't copied up yet.
+    fn lower_only(&self, ino: u64) -> Option<&Arc<Self>> {
+        self.lower
+            .as_ref()
+            .filter(|lower| !self.inode_stored(ino) && lower.exists(ino))
+    }
+
+    /// The lower layer of an overlay, if it has `name` in `parent` and it wasn't deleted in upper.
+    async fn lower_with_name(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<&Arc<Self>>> {
+        let Some(lower) = &self.lower else {
+            return Ok(None);
+        };
+        if self.whiteout_path(parent, name).await?.is_file() || !lower.is_dir(parent) {
+            return Ok(None);
+        }
+        Ok(Box::pin(lower.exists_by_name_async(parent, name))
+            .await?
+            .then_some(lower))
+    }
+
+    async fn whiteout_path(&self, parent: u64, name: &SecretString) -> FsResult<PathBuf> {
+        Ok(self
+            .contents_path(parent)
+            .join(WHITEOUT_DIR)
+            .join(self.name_hash(name).await?))
+    }
+
+    /// Copy `ino` from the lower layer of an overlay, if it's not in upper already, so it can be changed.
+    ///
+    /// For directories only the inode is copied, the entries are still merged from both layers.
+    async fn copy_up(&self, ino: u64) -> FsResult<()> {
+        if self.lower_only(ino).is_none() {
+            return Ok(());
+        }
+        let lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _guard = lock.lock().await;
+        let Some(lower) = self.lower_only(ino) else {
+            // copied up in the meantime
+            return Ok(());
+        };
+        let attr = Box::pin(lower.get_inode_from_storage(ino)).await?;
+        let contents = self.contents_path(ino);
+        if attr.kind == FileType::Directory {
+            fs::create_dir_all(contents.join(LS_DIR))?;
+            fs::create_dir_all(contents.join(HASH_DIR))?;
+        } else {
+            let mut reader = lower
+                .create_read(File::open(lower.contents_path(ino))?)
+                .await?;
+            let mut writer = self.create_write(File::create(&contents)?).await?;
+            io::copy(&mut reader, &mut writer)?;
+            writer.finish()?.sync_all()?;
+            self.update_parity(ino, None)?;
+        }
+        let xattrs = Box::pin(lower.read_xattrs(ino)).await?;
+        self.write_xattrs(ino, &xattrs).await?;
+        // last, if we crash before this the next try overwrites what we copied so far
+        self.write_inode_to_storage(&attr).await?;
+        Ok(())
+    }
+
+    /// Files in the data dir backing an inode, useful for incremental backups.
+    ///
+    /// The layout is:
+    /// - `inodes/<ino>` the encrypted attributes
+    /// - `contents/<ino>` for files, the encrypted content split in blocks, see [`EncryptedFs::data_file_blocks`]
+    /// - `contents/<ino>/ls/*` and `contents/<ino>/hash/*` for directories, one file for each entry,
+    ///   named by the encrypted name and by the hash of the name
+    /// - `parity/<ino>` for files, if [`FsOptions::redundancy`] is enabled
+    /// - `xattr/<ino>` the encrypted extended attributes, if it has any
+    ///
+    /// Only the paths are returned, nothing is decrypted.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn data_files_for(&self, ino: u64) -> FsResult<Vec<PathBuf>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if self.metadata_db.is_some() {
+            return Err(FsError::InvalidInput(
+                "the metadata is not in files with the embedded db metadata store",
+            ));
+        }
+        let mut files = vec![self.ino_file(ino)];
+        let contents = self.contents_path(ino);
+        if contents.is_dir() {
+            for dir in [LS_DIR, HASH_DIR] {
+                for entry in fs::read_dir(contents.join(dir))? {
+                    files.push(entry?.path());
+                }
+            }
+        } else {
+            files.push(contents);
+            if self.parity_path(ino).is_file() {
+                files.push(self.parity_path(ino));
+            }
+        }
+        if self.xattr_path(ino).is_file() {
+            files.push(self.xattr_path(ino));
+        }
+        Ok(files)
+    }
+
+    /// Upload the data files of `ino`, as returned by [`EncryptedFs::data_files_for`], to `backend`.
+    ///
+    /// Each file is saved under its path relative to the data dir followed by the block index, the content of files
+    /// split in encrypted blocks and the others in one block. Changes not yet flushed from open handles are not included.
+    ///
+    /// Returns the number of blocks uploaded.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn push_blocks(&self, ino: u64, backend: &dyn StorageBackend) -> FsResult<usize> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let (blocks, lens) = self.blocks_to_push(ino, None)?;
+        let stale = self.stale_blocks(backend, &lens).await?;
+        let uploaded = blocks.len();
+        self.put_blocks(backend, blocks, stale).await?;
+        Ok(uploaded)
+    }
+
+    /// Puts `blocks` in `backend` and deletes the `stale` keys, one at a time.
+    async fn put_blocks(
+        &self,
+        backend: &dyn StorageBackend,
+        blocks: Vec<(String, Vec<u8>)>,
+        stale: Vec<String>,
+    ) -> FsResult<()> {
+        for (key, block) in blocks {
+            self.with_timeout(backend.put_block(&key, &block)).await?;
+        }
+        for key in stale {
+            self.with_timeout(backend.delete_block(&key)).await?;
+        }
+        Ok(())
+    }
+
+    /// The blocks of the data files of `ino` to upload, as `(key, block)`, and the key of each file with how many
+    /// blocks it has, see [`EncryptedFs::push_blocks`].
+    ///
+    /// With `dirty` only those blocks of the content, and the parity of their groups, are taken instead of all of
+    /// them. The content can't get shorter from writes, so nothing is left after the end.
+    #[allow(clippy::type_complexity)]
+    fn blocks_to_push(
+        &self,
+        ino: u64,
+        dirty: Option<&DirtyBlocks>,
+    ) -> FsResult<(Vec<(String, Vec<u8>)>, Vec<(String, usize)>)> {
+        let block_len = self.ciphertext_block_len();
+        let (contents, parity) = (self.contents_path(ino), self.parity_path(ino));
+        let mut blocks = vec![];
+        let mut lens = vec![];
+        for path in self.data_files_for(ino)? {
+            let key = self.block_key(&path);
+            let indexes: Option<BTreeSet<u64>> = match (dirty, self.options.redundancy) {
+                (Some(dirty), _) if path == contents => Some(dirty.blocks().collect()),
+                (Some(dirty), Some(group)) if path == parity => {
+                    Some(dirty.blocks().map(|block| block / group as u64).collect())
+                }
+                _ => None,
+            };
+            if let Some(indexes) = indexes {
+                let mut file = File::open(&path)?;
+                for index in indexes {
+                    let mut block = vec![];
+                    file.seek(SeekFrom::Start(index * block_len as u64))?;
+                    (&mut file).take(block_len as u64).read_to_end(&mut block)?;
+                    if !block.is_empty() {
+                        blocks.push((format!("{key}/{index}"), block));
+                    }
+                }
+                continue;
+            }
+            let data = fs::read(&path)?;
+            let chunks: Vec<&[u8]> = if path == contents || path == parity {
+                data.chunks(block_len).collect()
+            } else {
+                vec![&data]
+            };
+            for (index, block) in chunks.iter().enumerate() {
+                blocks.push((format!("{key}/{index}"), block.to_vec()));
+            }
+            lens.push((key, chunks.len()));
+        }
+        Ok((blocks, lens))
+    }
+
+    /// Keys of the blocks in `backend` after the end of the files, as `(key, blocks)`, like when they got shorter.
+    async fn stale_blocks(
+        &self,
+        backend: &dyn StorageBackend,
+        lens: &[(String, usize)],
+    ) -> FsResult<Vec<String>> {
+        let mut stale = vec![];
+        for (key, len) in lens {
+            for old in self.with_timeout(backend.list(&format!("{key}/"))).await? {
+                let index = old
+                    .rsplit_once('/')
+                    .and_then(|(_, i)| i.parse::<usize>().ok());
+                if index.is_some_and(|i| i >= *len) {
+                    stale.push(old);
+                }
+            }
+        }
+        Ok(stale)
+    }
+
+    /// Download the data files of `ino` saved with [`EncryptedFs::push_blocks`], replacing the local ones.
+    ///
+    /// Meant for restoring inodes which are not opened.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn pull_blocks(&self, ino: u64, backend: &dyn StorageBackend) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if self.metadata_db.is_some() {
+            return Err(FsError::InvalidInput(
+                "the metadata is not in files with the embedded db metadata store",
+            ));
+        }
+        // (path, blocks)
+        let mut files: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+        for prefix in [INODES_DIR, CONTENTS_DIR, PARITY_DIR, XATTR_DIR] {
+            for key in self
+                .with_timeout(backend.list(&format!("{prefix}/{ino}/")))
+                .await?
+            {
+                let Some((path, index)) = key.rsplit_once('/') else {
+                    continue;
+                };
+                let Ok(index) = index.parse::<usize>() else {
+                    continue;
+                };
+                files
+                    .entry(path.to_string())
+                    .or_default()
+                    .push((index, key.clone()));
+            }
+        }
+        if files.is_empty() {
+            return Err(FsError::InodeNotFound);
+        }
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        for (path, mut blocks) in files {
+            blocks.sort_unstable();
+            let path = self.data_dir.join(path);
+            fs::create_dir_all(path.parent().expect("oops, we don't have a parent"))?;
+            let mut file = fs_util::open_atomic_write(&path)?;
+            for (_, key) in blocks {
+                let block = self
+                    .with_timeout(backend.get_block(&key))
+                    .await?
+                    .ok_or(FsError::NotFound("block removed while downloading"))?;
+                file.write_all(&block)?;
+            }
+            file.commit()?;
+        }
+        self.attr_cache.get().await?.write().await.pop(&ino);
+        Ok(())
+    }
+
+    /// Upload the data files of `ino` to the backend of [`FsOptions::storage`], if there is one. With `dirty` only
+    /// the blocks of the content written since the last time, see [`EncryptedFs::blocks_to_push`].
+    async fn upload_to_storage(&self, ino: u64, dirty: Option<&DirtyBlocks>) -> FsResult<()> {
+        let Some(backend) = self.storage.as_deref() else {
+            return Ok(());
         };
-        let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
+        if self.lower_only(ino).is_some() {
+            return Ok(());
+        }
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.read().await;
+        let (blocks, lens) = self.blocks_to_push(ino, dirty)?;
+        let stale = self.stale_blocks(backend, &lens).await?;
+        self.put_blocks(backend, blocks, stale).await
+    }
 
-        ensure_structure_created(&data_dir.clone()).await?;
-        key.get().await?; // this will check the password
+    /// Download the data files of `ino` from the backend of [`FsOptions::storage`], if the content is not in the
+    /// data dir.
+    async fn download_from_storage(&self, ino: u64) -> FsResult<()> {
+        let Some(backend) = self.storage.as_deref() else {
+            return Ok(());
+        };
+        if self.read_only
+            || self.lower_only(ino).is_some()
+            || !self.ino_file(ino).is_file()
+            || self.contents_path(ino).exists()
+        {
+            return Ok(());
+        }
+        self.pull_blocks(ino, backend).await
+    }
 
-        let fs = Self {
-            data_dir,
-            write_handles: RwLock::new(HashMap::new()),
-            read_handles: RwLock::new(HashMap::new()),
-            current_handle: AtomicU64::new(1),
-            cipher,
-            opened_files_for_read: RwLock::new(HashMap::new()),
-            opened_files_for_write: RwLock::new(HashMap::new()),
-            serialize_inode_locks: Arc::new(ArcHashMap::default()),
-            serialize_update_inode_locks: ArcHashMap::default(),
-            serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
-            serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
-            key,
-            self_weak: std::sync::Mutex::new(None),
-            read_write_locks: ArcHashMap::default(),
-            // todo: take duration from param
-            attr_cache: ExpireValue::new(AttrCacheProvider {}, Duration::from_secs(10 * 60)),
-            // todo: take duration from param
-            dir_entries_name_cache: ExpireValue::new(
-                DirEntryNameCacheProvider {},
-                Duration::from_secs(10 * 60),
-            ),
-            // todo: take duration from param
-            dir_entries_meta_cache: ExpireValue::new(
-                DirEntryMetaCacheProvider {},
-                Duration::from_secs(10 * 60),
-            ),
-            sizes_write: Mutex::default(),
-            sizes_read: Mutex::default(),
-            requested_read: Mutex::default(),
-            read_only,
+    async fn delete_from_storage(&self, ino: u64) -> FsResult<()> {
+        let Some(backend) = self.storage.as_deref() else {
+            return Ok(());
         };
+        for prefix in [INODES_DIR, CONTENTS_DIR, PARITY_DIR, XATTR_DIR] {
+            for key in self
+                .with_timeout(backend.list(&format!("{prefix}/{ino}/")))
+                .await?
+            {
+                self.with_timeout(backend.delete_block(&key)).await?;
+            }
+        }
+        Ok(())
+    }
 
-        let arc = Arc::new(fs);
-        arc.self_weak
-            .lock()
-            .expect("cannot obtain lock")
-            .replace(Arc::downgrade(&arc));
+    /// Read up to `len` bytes like [`EncryptedFs::read`], giving up after [`FsOptions::op_timeout`].
+    ///
+    /// The read does blocking I/O, which a timeout on the executor couldn't interrupt, so with a timeout it runs on
+    /// the blocking pool. If it times out it's left to finish there, keeping the handle busy until then.
+    pub(crate) async fn read_with_timeout(
+        self: &Arc<Self>,
+        ino: u64,
+        offset: u64,
+        len: usize,
+        handle: u64,
+    ) -> FsResult<Vec<u8>> {
+        if self.options.op_timeout.is_none() {
+            let mut buf = vec![0; len];
+            let len = self.read(ino, offset, &mut buf, handle).await?;
+            buf.truncate(len);
+            return Ok(buf);
+        }
+        let fs = self.clone();
+        let rt = tokio::runtime::Handle::current();
+        self.with_timeout_blocking(move || {
+            let mut buf = vec![0; len];
+            let len = rt.block_on(fs.read(ino, offset, &mut buf, handle))?;
+            buf.truncate(len);
+            Ok(buf)
+        })
+        .await
+    }
 
-        arc.ensure_root_exists().await?;
+    /// Like [`EncryptedFs::with_timeout`] for `f` which blocks the thread, it runs on the blocking pool so the timeout
+    /// can fire while it's blocked.
+    pub(crate) async fn with_timeout_blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> FsResult<T> + Send + 'static,
+    ) -> FsResult<T> {
+        let task = tokio::task::spawn_blocking(f);
+        self.with_timeout(async { task.await? }).await
+    }
 
-        Ok(arc)
+    /// Give up on `f` after [`FsOptions::op_timeout`].
+    pub(crate) async fn with_timeout<T>(
+        &self,
+        f: impl std::future::Future<Output = FsResult<T>>,
+    ) -> FsResult<T> {
+        match self.options.op_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, f)
+                .await
+                .map_err(|_| FsError::Timeout)?,
+            None => f.await,
+        }
     }
 
-    pub fn exists(&self, ino: u64) -> bool {
-        self.ino_file(ino).is_file()
+    /// Path relative to the data dir, with `/` as separator, used as key in a [`StorageBackend`].
+    fn block_key(&self, path: &Path) -> String {
+        path.strip_prefix(&self.data_dir)
+            .expect("not in data dir")
+            .to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "/")
     }
 
-    pub fn is_dir(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_dir()
+    /// All files in the data dir, including the encrypted key.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn all_data_files(&self) -> FsResult<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut dirs = vec![self.data_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
     }
 
-    pub fn is_file(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_file()
+    /// Number of encrypted blocks in a data file.
+    ///
+    /// Block `i` starts at offset `i * ` [`Cipher::ciphertext_block_len`], so when a backup tool sees a file changed
+    /// it can compare only the blocks.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn data_file_blocks(&self, path: &Path) -> FsResult<u64> {
+        let len = fs::metadata(path)?.len();
+        Ok(len.div_ceil(self.ciphertext_block_len() as u64))
     }
 
-    #[allow(dead_code)]
-    async fn is_read_only(&self) -> bool {
+    /// If it was opened read-only, as asked or because the data dir is on a read-only filesystem.
+    pub const fn read_only(&self) -> bool {
         self.read_only
     }
 
-    /// Create a new node in the filesystem
+    /// Create a new node in the filesystem.
+    ///
+    /// The name can't be `.` or `..`, nor `$.` or `$..` which we keep for them.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     #[allow(clippy::too_many_lines)]
@@ -669,18 +3647,22 @@ impl EncryptedFs {
         read: bool,
         write: bool,
     ) -> FsResult<(u64, FileAttr)> {
-        if *name.expose_secret() == "." || *name.expose_secret() == ".." {
-            return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
-        }
+        check_entry_name(name)?;
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
-        if self.exists_by_name(parent, name)? {
+        if self.exists_by_name_async(parent, name).await? {
             return Err(FsError::AlreadyExists);
         }
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        self.check_not_read_only_path(parent)?;
+        if self.options.nodev && create_attr.rdev != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        let mut create_attr = create_attr;
+        create_attr.perm = self.allowed_perm(create_attr.kind, create_attr.perm);
 
         // spawn on a dedicated runtime to not interfere with other higher priority tasks
         let self_clone = self
@@ -694,8 +3676,15 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
+                self_clone
+                    .check_dir_limits(parent, create_attr.kind)
+                    .await?;
+                self_clone.copy_up(parent).await?;
                 let mut attr: FileAttr = create_attr.into();
                 attr.ino = self_clone.generate_next_inode();
+                let now = self_clone.now();
+                (attr.atime, attr.mtime, attr.ctime, attr.crtime) = (now, now, now, now);
+                self_clone.inherit_default_acl(parent, &mut attr).await?;
 
                 let fs = self_clone;
                 let mut join_set = JoinSet::new();
@@ -705,7 +3694,7 @@ impl EncryptedFs {
                 self_clone.write_inode_to_storage(&attr).await?;
 
                 match attr.kind {
-                    FileType::RegularFile => {
+                    FileType::RegularFile | FileType::Symlink => {
                         let self_clone = fs.clone();
                         join_set.spawn(async move {
                             // create in contents directory
@@ -736,6 +3725,10 @@ impl EncryptedFs {
                             // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
                             // this optimizes the search process as we don't need to decrypt all file names and search
                             fs::create_dir(contents_dir.join(HASH_DIR))?;
+                            self_clone.sync_dirs(&[
+                                contents_dir.clone(),
+                                self_clone.data_dir.join(CONTENTS_DIR),
+                            ])?;
 
                             // add "." and ".." entries
                             self_clone
@@ -782,7 +3775,7 @@ impl EncryptedFs {
 
                 let self_clone = fs.clone();
                 join_set.spawn(async move {
-                    let now = SystemTime::now();
+                    let now = self_clone.now();
                     self_clone
                         .set_attr(
                             parent,
@@ -822,50 +3815,411 @@ impl EncryptedFs {
     #[allow(clippy::missing_errors_doc)]
     pub async fn find_by_name(
         &self,
-        parent: u64,
-        name: &SecretString,
-    ) -> FsResult<Option<FileAttr>> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<FileAttr>> {
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(parent) {
+            return Err(FsError::NotADirectory);
+        }
+        let hash = self.name_hash(name).await?;
+        if let Some(db) = &self.metadata_db {
+            let Some(data) = db.get_entry(parent, &hash)? else {
+                return Ok(None);
+            };
+            let (ino, _, _): (u64, FileType, String) = self.decrypt_db_value(&data).await?;
+            return self.get_inode_from_cache_or_storage(ino).await.map(Some);
+        }
+        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
+        if !hash_path.is_file() {
+            let Some(lower) = self.lower_with_name(parent, name).await? else {
+                return Ok(None);
+            };
+            let Some(attr) = Box::pin(lower.find_by_name(parent, name)).await? else {
+                return Ok(None);
+            };
+            // it might have been copied up
+            return self
+                .get_inode_from_cache_or_storage(attr.ino)
+                .await
+                .map(Some);
+        }
+        let lock = self
+            .serialize_dir_entries_hash_locks
+            .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
+                RwLock::new(false)
+            });
+        let guard = lock.read().await;
+        let (ino, _, _): (u64, FileType, String) = self.deserialize_metadata(
+            crypto::create_read(File::open(hash_path)?, self.cipher, &*self.key.get().await?),
+        )?;
+        drop(guard);
+        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+    }
+
+    /// See [`FsOptions::max_dir_depth`] and [`FsOptions::max_dir_entries`].
+    async fn check_dir_limits(&self, parent: u64, kind: FileType) -> FsResult<()> {
+        if let Some(max) = self.options.max_dir_entries {
+            if self.len(parent)? >= max {
+                return Err(FsError::TooManyEntries { max });
+            }
+        }
+        if let (Some(max), FileType::Directory) = (self.options.max_dir_depth, kind) {
+            // follow ".." up to the root, stopping once it's too deep so a loop can't keep us here
+            let dot_dot = SecretString::from_str("..").unwrap();
+            let mut ino = parent;
+            let mut depth = 1;
+            loop {
+                if depth > max {
+                    return Err(FsError::TooDeep { max });
+                }
+                if ino == ROOT_INODE {
+                    break;
+                }
+                ino = self
+                    .find_by_name(ino, &dot_dot)
+                    .await?
+                    .ok_or(FsError::InvalidDataDirStructure)?
+                    .ino;
+                depth += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count children of a directory. This **EXCLUDES** "." and "..".
+    #[allow(clippy::missing_errors_doc)]
+    pub fn len(&self, ino: u64) -> FsResult<usize> {
+        if !self.is_dir(ino) {
+            return Err(FsError::NotADirectory);
+        }
+        let mut count = if let Some(db) = &self.metadata_db {
+            db.count_entries(ino)?
+        } else if self.lower.is_some() {
+            self.merged_hashes(ino)?.len()
+        } else {
+            fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count()
+        };
+        if ino == ROOT_INODE {
+            // we don't count "."
+            count -= 1;
+        } else {
+            // we don't count "." and ".."
+            count -= 2;
+        }
+        Ok(count)
+    }
+
+    /// Hashes of the names in a directory, merged from all layers of an overlay.
+    fn merged_hashes(&self, ino: u64) -> FsResult<HashSet<String>> {
+        let mut hashes = HashSet::new();
+        if let Some(lower) = &self.lower {
+            if lower.is_dir(ino) {
+                hashes = lower.merged_hashes(ino)?;
+            }
+        }
+        let contents = self.contents_path(ino);
+        if contents.join(WHITEOUT_DIR).is_dir() {
+            for entry in fs::read_dir(contents.join(WHITEOUT_DIR))? {
+                hashes.remove(entry?.file_name().to_string_lossy().as_ref());
+            }
+        }
+        if contents.join(HASH_DIR).is_dir() {
+            for entry in fs::read_dir(contents.join(HASH_DIR))? {
+                hashes.insert(entry?.file_name().to_string_lossy().to_string());
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Attributes of what `path` points to, a `/` separated path from the root, like `docs/notes.txt`. Like `stat`,
+    /// if it's a symlink we get what it points to, see [`EncryptedFs::resolve_path`].
+    ///
+    /// Fails with [`FsError::NotFound`] if a component doesn't exist and with [`FsError::NotADirectory`] if one
+    /// before the last is not a directory. `.` and `..` are followed like in a shell.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn getattr_path(&self, path: &str) -> FsResult<FileAttr> {
+        self.resolve_path(path, true).await
+    }
+
+    /// Like [`EncryptedFs::getattr_path`], with `follow_last` false we get the symlink itself if the last component
+    /// is one, like `lstat` does. Symlinks before the last are always followed.
+    ///
+    /// After [`MAX_SYMLINKS`] symlinks it fails with [`FsError::TooManySymlinks`], like when they make a loop.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn resolve_path(&self, path: &str, follow_last: bool) -> FsResult<FileAttr> {
+        let root = self.get_attr(ROOT_INODE).await?;
+        let components = self.path_components(path)?;
+        self.resolve_from(root, components, follow_last, &mut 0)
+            .await
+    }
+
+    /// Walk `components` from `dir`, counting the symlinks followed in `links`.
+    async fn resolve_from(
+        &self,
+        dir: FileAttr,
+        components: Vec<&str>,
+        follow_last: bool,
+        links: &mut usize,
+    ) -> FsResult<FileAttr> {
+        let mut pending: VecDeque<String> = components.into_iter().map(str::to_string).collect();
+        let mut attr = dir;
+        while let Some(name) = pending.pop_front() {
+            let next = self.lookup_component(attr.ino, &name).await?;
+            if next.kind == FileType::Symlink && (follow_last || !pending.is_empty()) {
+                *links += 1;
+                if *links > MAX_SYMLINKS {
+                    return Err(FsError::TooManySymlinks);
+                }
+                let target = self.read_link(next.ino).await?;
+                if target.expose_secret().starts_with('/') {
+                    attr = self.get_attr(ROOT_INODE).await?;
+                }
+                // relative to the directory it's in, which `attr` still is
+                for component in target
+                    .expose_secret()
+                    .split('/')
+                    .filter(|name| !name.is_empty())
+                    .rev()
+                {
+                    pending.push_front(component.to_string());
+                }
+                continue;
+            }
+            attr = next;
+        }
+        Ok(attr)
+    }
+
+    /// Like [`EncryptedFs::open`] for the file at `path`, see [`EncryptedFs::getattr_path`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn open_path(&self, path: &str, read: bool, write: bool) -> FsResult<u64> {
+        let attr = self.getattr_path(path).await?;
+        self.open(attr.ino, read, write).await
+    }
+
+    /// Like [`EncryptedFs::create`] at `path`, its parent directory must exist, see [`EncryptedFs::getattr_path`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_path(
+        &self,
+        path: &str,
+        create_attr: CreateFileAttr,
+        read: bool,
+        write: bool,
+    ) -> FsResult<(u64, FileAttr)> {
+        let (parent, name) = self.resolve_parent(path).await?;
+        self.create(parent, &name, create_attr, read, write).await
+    }
+
+    /// Remove the file or the empty directory at `path`, see [`EncryptedFs::getattr_path`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_path(&self, path: &str) -> FsResult<()> {
+        let (parent, name) = self.resolve_parent(path).await?;
+        // the symlink itself, not what it points to
+        match self
+            .lookup_component(parent, name.expose_secret().as_str())
+            .await?
+            .kind
+        {
+            FileType::RegularFile | FileType::Symlink => self.remove_file(parent, &name).await,
+            FileType::Directory => self.remove_dir(parent, &name).await,
+        }
+    }
+
+    /// Create the symlink `name` in `parent` pointing to `target`, owned by the user running us.
+    ///
+    /// `target` is kept encrypted like the content of files and it doesn't need to exist. A relative one is resolved
+    /// from `parent`, see [`EncryptedFs::resolve_path`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn symlink(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        target: &SecretString,
+    ) -> FsResult<FileAttr> {
+        #[allow(unused_mut)]
+        let mut create_attr = CreateFileAttr {
+            kind: FileType::Symlink,
+            perm: 0o777,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        };
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        unsafe {
+            create_attr.uid = libc::getuid();
+            create_attr.gid = libc::getgid();
+        }
+        self.symlink2(parent, name, target, create_attr).await
+    }
+
+    /// Like [`EncryptedFs::symlink`] with the owner from `create_attr`, its kind needs to be
+    /// [`FileType::Symlink`].
+    pub(crate) async fn symlink2(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        target: &SecretString,
+        create_attr: CreateFileAttr,
+    ) -> FsResult<FileAttr> {
+        if target.expose_secret().is_empty() {
+            return Err(FsError::InvalidInput("symlink target cannot be empty"));
+        }
+        let (_, attr) = self.create(parent, name, create_attr, false, false).await?;
+        if let Err(err) = self
+            .replace_contents2(attr.ino, target.expose_secret().as_bytes())
+            .await
+        {
+            if let Err(err) = self.remove_file(parent, name).await {
+                error!(err = %err, "removing the symlink after failing to write it");
+            }
+            return Err(err);
+        }
+        self.get_attr(attr.ino).await
+    }
+
+    /// Where the symlink `ino` points to, fails with [`FsError::InvalidInodeType`] if it's not a symlink.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_link(&self, ino: u64) -> FsResult<SecretString> {
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::Symlink {
+            return Err(FsError::InvalidInodeType);
+        }
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.read_link(ino)).await;
+        }
+        let mut reader = self
+            .create_read(File::open(self.contents_path(ino))?)
+            .await?;
+        let mut target = String::new();
+        reader.read_to_string(&mut target)?;
+        Ok(SecretString::new(Box::new(target)))
+    }
+
+    /// Create the directory `name` in `parent` with the `mode` permissions, owned by the user running us.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn mkdir(&self, parent: u64, name: &SecretString, mode: u16) -> FsResult<FileAttr> {
+        #[allow(unused_mut)]
+        let mut create_attr = CreateFileAttr {
+            kind: FileType::Directory,
+            perm: mode,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        };
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        unsafe {
+            create_attr.uid = libc::getuid();
+            create_attr.gid = libc::getgid();
+        }
+        Ok(self
+            .create(parent, name, create_attr, false, false)
+            .await?
+            .1)
+    }
+
+    /// Create the directory at `path` and the missing ones before it, like `mkdir -p`, see
+    /// [`EncryptedFs::getattr_path`]. It succeeds if it already exists.
+    ///
+    /// If it fails partway, the directories it created are removed, so the path is left like it was.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn mkdir_all(&self, path: &str, mode: u16) -> FsResult<FileAttr> {
+        let mut created = vec![];
+        let res = self.mkdir_all2(path, mode, &mut created).await;
+        if res.is_err() {
+            for (parent, name) in created.iter().rev() {
+                if let Err(err) = self.remove_dir(*parent, name).await {
+                    warn!(err = %err, "cannot remove directory created by mkdir_all");
+                }
+            }
+        }
+        res
+    }
+
+    async fn mkdir_all2(
+        &self,
+        path: &str,
+        mode: u16,
+        created: &mut Vec<(u64, SecretString)>,
+    ) -> FsResult<FileAttr> {
+        let mut attr = self.get_attr(ROOT_INODE).await?;
+        let mut links = 0;
+        for component in self.path_components(path)? {
+            attr = match self.lookup_component(attr.ino, component).await {
+                Ok(link) if link.kind == FileType::Symlink => {
+                    // a missing target is not created
+                    self.resolve_from(attr, vec![component], true, &mut links)
+                        .await?
+                }
+                Ok(attr) => attr,
+                Err(FsError::NotFound(_)) => {
+                    let name = SecretString::from_str(component).unwrap();
+                    match self.mkdir(attr.ino, &name, mode).await {
+                        Ok(dir) => {
+                            created.push((attr.ino, name));
+                            dir
+                        }
+                        // created by someone else in the meantime
+                        Err(FsError::AlreadyExists) => {
+                            self.lookup_component(attr.ino, component).await?
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                Err(err) => return Err(err),
+            };
         }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+        if attr.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        if !hash_path.is_file() {
-            return Ok(None);
+        Ok(attr)
+    }
+
+    /// The non empty components of `path`, failing with [`FsError::TooDeep`] if it's deeper than anything
+    /// [`FsOptions::max_dir_depth`] lets exist.
+    fn path_components<'a>(&self, path: &'a str) -> FsResult<Vec<&'a str>> {
+        let components: Vec<_> = path.split('/').filter(|name| !name.is_empty()).collect();
+        if let Some(max) = self.options.max_dir_depth {
+            // the last one can be a file in the deepest directory
+            if components.len() > max + 1 {
+                return Err(FsError::TooDeep { max });
+            }
         }
-        let lock = self
-            .serialize_dir_entries_hash_locks
-            .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
-                RwLock::new(false)
-            });
-        let guard = lock.read().await;
-        let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
-            File::open(hash_path)?,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?;
-        drop(guard);
-        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+        Ok(components)
     }
 
-    /// Count children of a directory. This **EXCLUDES** "." and "..".
-    #[allow(clippy::missing_errors_doc)]
-    pub fn len(&self, ino: u64) -> FsResult<usize> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+    /// The directory `path` is in and its last component, which must be a name.
+    async fn resolve_parent(&self, path: &str) -> FsResult<(u64, SecretString)> {
+        let mut components = self.path_components(path)?;
+        let name = match components.pop() {
+            Some(name) if name != "." && name != ".." => name,
+            _ => return Err(FsError::InvalidInput("path must end with a name")),
+        };
+        let root = self.get_attr(ROOT_INODE).await?;
+        let parent = self.resolve_from(root, components, true, &mut 0).await?;
+        if parent.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
         }
-        let mut count = fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count();
-        if ino == ROOT_INODE {
-            // we don't count "."
-            count -= 1;
-        } else {
-            // we don't count "." and ".."
-            count -= 2;
+        Ok((parent.ino, SecretString::from_str(name).unwrap()))
+    }
+
+    async fn lookup_component(&self, parent: u64, name: &str) -> FsResult<FileAttr> {
+        if !self.is_dir(parent) {
+            return Err(FsError::NotADirectory);
+        }
+        match name {
+            "." => self.get_attr(parent).await,
+            // the root has no ".."
+            ".." if parent == ROOT_INODE => self.get_attr(parent).await,
+            _ => self
+                .find_by_name(parent, &SecretString::from_str(name).unwrap())
+                .await?
+                .ok_or(FsError::NotFound("no such file or directory")),
         }
-        Ok(count)
     }
 
     /// Delete a directory
@@ -873,13 +4227,13 @@ impl EncryptedFs {
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::NotADirectory);
         }
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
 
-        if !self.exists_by_name(parent, name)? {
+        if !self.exists_by_name_async(parent, name).await? {
             return Err(FsError::NotFound("name not found"));
         }
 
@@ -888,8 +4242,11 @@ impl EncryptedFs {
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
         if !matches!(attr.kind, FileType::Directory) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::NotADirectory);
         }
+        check_not_protected(&attr)?;
+        self.check_not_read_only_path(parent)?;
+        self.check_not_read_only_path(attr.ino)?;
         // check if it's empty
         if self.len(attr.ino)? > 0 {
             return Err(FsError::NotEmpty);
@@ -905,17 +4262,30 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
+                // in an overlay it might be only in the lower layer, which we don't change
+                if self_clone.lower_only(attr.ino).is_none() {
+                    // remove inode file
+                    {
+                        let lock = self_clone
+                            .serialize_inode_locks
+                            .get_or_insert_with(attr.ino, || RwLock::new(false));
+                        let _guard = lock.write();
+                        self_clone.remove_inode_from_storage(attr.ino)?;
+                    }
 
-                // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
+                    // remove contents directory
+                    fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
+                    if self_clone.xattr_path(attr.ino).exists() {
+                        fs::remove_file(self_clone.xattr_path(attr.ino))?;
+                    }
+                    if let Some(db) = &self_clone.metadata_db {
+                        db.remove_entries(attr.ino)?;
+                    }
+                    self_clone.sync_dirs(&[
+                        self_clone.data_dir.join(INODES_DIR),
+                        self_clone.data_dir.join(CONTENTS_DIR),
+                    ])?;
+                }
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
@@ -929,7 +4299,7 @@ impl EncryptedFs {
                     .await
                     .demote(&attr.ino);
 
-                let now = SystemTime::now();
+                let now = self_clone.now();
                 self_clone
                     .set_attr(
                         parent,
@@ -945,14 +4315,79 @@ impl EncryptedFs {
             .await?
     }
 
+    /// Remove the directory `ino` and everything under it, like `rm -r`. For the root only what's in it is removed.
+    ///
+    /// Entries are removed depth first, each directory after what's in it, so what's left after a failure is still
+    /// a consistent tree and calling it again continues from there. It stops at the first entry which can't be
+    /// removed, like an immutable file, with [`FsError::RemoveFailed`] telling which one.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_dir_all(&self, ino: u64) -> FsResult<()> {
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let failed = |ino| {
+            move |source| FsError::RemoveFailed {
+                ino,
+                source: Box::new(source),
+            }
+        };
+        // (parent, name, ino) of the directories to empty, `None` for the root which stays
+        let mut dirs = vec![(self.parent_and_name(ino).await?, ino)];
+        while let Some((entry, dir)) = dirs.last() {
+            let (entry, dir) = (entry.clone(), *dir);
+            let mut subdirs = vec![];
+            for child in self.list_dir(dir).await? {
+                let child = child?;
+                let name = child.name.expose_secret();
+                if *name == "." || *name == ".." {
+                    continue;
+                }
+                let name = SecretString::from_str(&name).unwrap();
+                match child.kind {
+                    FileType::RegularFile | FileType::Symlink => self
+                        .remove_file(dir, &name)
+                        .await
+                        .map_err(failed(child.ino))?,
+                    FileType::Directory => subdirs.push((Some((dir, name)), child.ino)),
+                }
+            }
+            if subdirs.is_empty() {
+                dirs.pop();
+                if let Some((parent, name)) = entry {
+                    self.remove_dir(parent, &name).await.map_err(failed(dir))?;
+                }
+            } else {
+                dirs.extend(subdirs);
+            }
+        }
+        Ok(())
+    }
+
+    /// The parent of the directory `ino` and its name in it, `None` for the root.
+    async fn parent_and_name(&self, ino: u64) -> FsResult<Option<(u64, SecretString)>> {
+        if ino == ROOT_INODE {
+            return Ok(None);
+        }
+        let parent = self.lookup_component(ino, "..").await?.ino;
+        for entry in self.list_dir(parent).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret();
+            if entry.ino == ino && *name != "." && *name != ".." {
+                return Ok(Some((parent, SecretString::from_str(&name).unwrap())));
+            }
+        }
+        Err(FsError::NotFound("directory not found in its parent"))
+    }
+
     /// Delete a file
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::NotADirectory);
         }
-        if !self.exists_by_name(parent, name)? {
+        if !self.exists_by_name_async(parent, name).await? {
             return Err(FsError::NotFound("name not found"));
         }
         if self.read_only {
@@ -963,9 +4398,12 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
-        if !matches!(attr.kind, FileType::RegularFile) {
-            return Err(FsError::InvalidInodeType);
+        if attr.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
         }
+        check_not_protected(&attr)?;
+        self.check_not_read_only_path(parent)?;
+        self.check_not_read_only_path(attr.ino)?;
         let self_clone = self
             .self_weak
             .lock()
@@ -977,17 +4415,33 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
-                // remove inode file
-                {
-                    let lock = self_clone
-                        .serialize_inode_locks
-                        .get_or_insert_with(attr.ino, || RwLock::new(false));
-                    let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
-                }
+                // in an overlay it might be only in the lower layer, which we don't change
+                if self_clone.lower_only(attr.ino).is_none() {
+                    // remove inode file
+                    {
+                        let lock = self_clone
+                            .serialize_inode_locks
+                            .get_or_insert_with(attr.ino, || RwLock::new(false));
+                        let _guard = lock.write();
+                        self_clone.remove_inode_from_storage(attr.ino)?;
+                    }
 
-                // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
+                    // remove from contents directory
+                    fs::remove_file(self_clone.contents_path(attr.ino))?;
+                    for path in [
+                        self_clone.parity_path(attr.ino),
+                        self_clone.xattr_path(attr.ino),
+                    ] {
+                        if path.exists() {
+                            fs::remove_file(path)?;
+                        }
+                    }
+                    self_clone.sync_dirs(&[
+                        self_clone.data_dir.join(INODES_DIR),
+                        self_clone.data_dir.join(CONTENTS_DIR),
+                    ])?;
+                    self_clone.delete_from_storage(attr.ino).await?;
+                }
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
@@ -1001,7 +4455,7 @@ impl EncryptedFs {
                     .await
                     .demote(&attr.ino);
 
-                let now = SystemTime::now();
+                let now = self_clone.now();
                 self_clone
                     .set_attr(
                         parent,
@@ -1012,55 +4466,641 @@ impl EncryptedFs {
                     )
                     .await?;
 
-                Ok(())
-            })
-            .await?
+                Ok(())
+            })
+            .await?
+    }
+
+    /// If `parent` has an entry `name`, like [`EncryptedFs::exists_by_name_async`].
+    ///
+    /// The hash of the name needs the key, which we get asynchronously, so this blocks on it with
+    /// [`async_util::call_async`] and must be called from a multi-threaded tokio runtime. In async code use
+    /// [`EncryptedFs::exists_by_name_async`].
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        async_util::call_async(self.exists_by_name_async(parent, name))
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn exists_by_name_async(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
+        if !self.exists(parent) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(parent) {
+            return Err(FsError::NotADirectory);
+        }
+        let hash = self.name_hash(name).await?;
+        if let Some(db) = &self.metadata_db {
+            return Ok(db.get_entry(parent, &hash)?.is_some());
+        }
+        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
+        Ok(hash_path.is_file() || self.lower_with_name(parent, name).await?.is_some())
+    }
+
+    /// The entries of a directory, in the order of [`FsOptions::readdir_order`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        let entries = self.read_dir_natural(ino).await?.0;
+        let entries = match self.options.readdir_order {
+            ReaddirOrder::Natural => entries,
+            ReaddirOrder::SortedByName => sort_entries(entries, |entry| {
+                (SystemTime::UNIX_EPOCH, entry.name.expose_secret().clone())
+            }),
+            ReaddirOrder::InsertionOrder => {
+                let mut crtimes = HashMap::new();
+                for entry in entries.iter().flatten() {
+                    crtimes.insert(entry.ino, self.get_attr(entry.ino).await?.crtime);
+                }
+                sort_entries(entries, |entry| {
+                    (crtimes[&entry.ino], entry.name.expose_secret().clone())
+                })
+            }
+        };
+        let entries = self.without_hidden(entries, |entry| &entry.name);
+        Ok(DirectoryEntryIterator(entries))
+    }
+
+    /// Leaves out the entries matching [`FsOptions::hidden_patterns`].
+    fn without_hidden<T>(
+        &self,
+        entries: VecDeque<FsResult<T>>,
+        name: impl Fn(&T) -> &SecretString,
+    ) -> VecDeque<FsResult<T>> {
+        if self.options.hidden_patterns.is_empty() {
+            return entries;
+        }
+        let fold = |s: &str| {
+            if self.options.case_insensitive {
+                fold_case(s)
+            } else {
+                s.to_string()
+            }
+        };
+        let patterns: Vec<_> = self
+            .options
+            .hidden_patterns
+            .iter()
+            .map(|pattern| fold(pattern))
+            .collect();
+        entries
+            .into_iter()
+            .filter(|entry| {
+                let Ok(entry) = entry else {
+                    return true;
+                };
+                let name = name(entry).expose_secret();
+                if *name == "." || *name == ".." {
+                    return true;
+                }
+                let name = fold(&name);
+                !patterns.iter().any(|pattern| glob_match(pattern, &name))
+            })
+            .collect()
+    }
+
+    async fn read_dir_natural(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
+        if !self.is_dir(ino) {
+            return Err(FsError::NotADirectory);
+        }
+        if self.lower.is_some() {
+            // don't copy up only to update atime
+            return Ok(DirectoryEntryIterator(self.read_dir_layered(ino).await?));
+        }
+        if self.metadata_db.is_some() {
+            let entries = self.read_dir_db(ino).await?;
+            if !self.read_only && !self.is_read_only_path(ino) {
+                let set_attr = SetFileAttr::default().with_atime(self.now());
+                self.set_attr(ino, set_attr).await?;
+            }
+            return Ok(DirectoryEntryIterator(entries));
+        }
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !ls_dir.is_dir() {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        let iter = fs::read_dir(ls_dir)?;
+        if !self.read_only && !self.is_read_only_path(ino) {
+            let set_attr = SetFileAttr::default().with_atime(self.now());
+            self.set_attr(ino, set_attr).await?;
+        }
+        Ok(self.create_directory_entry_iterator(iter).await)
+    }
+
+    /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
+    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
+        let entries = self.read_dir_plus_natural(ino).await?.0;
+        let entries = match self.options.readdir_order {
+            ReaddirOrder::Natural => entries,
+            ReaddirOrder::SortedByName => sort_entries(entries, |entry| {
+                (SystemTime::UNIX_EPOCH, entry.name.expose_secret().clone())
+            }),
+            ReaddirOrder::InsertionOrder => sort_entries(entries, |entry| {
+                (entry.attr.crtime, entry.name.expose_secret().clone())
+            }),
+        };
+        let entries = self.without_hidden(entries, |entry| &entry.name);
+        Ok(DirectoryEntryPlusIterator(entries))
+    }
+
+    /// Opens the directory `ino` to read it in chunks with [`EncryptedFs::read_dir_at`] or
+    /// [`EncryptedFs::read_dir_plus_at`], close it with [`EncryptedFs::release_dir`].
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn open_dir(&self, ino: u64) -> FsResult<u64> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_dir(ino) {
+            return Err(FsError::NotADirectory);
+        }
+        let handle = self.next_handle();
+        self.dir_handles.lock().unwrap().insert(
+            handle,
+            DirHandle {
+                ino,
+                snapshot: None,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// The entries of the directory opened with `handle` from the `offset`th one on.
+    ///
+    /// They are read when `offset` is 0, the ones after come from what was read then, so the offsets stay the same
+    /// even if entries are added or removed meanwhile.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read_dir_at(&self, handle: u64, offset: u64) -> FsResult<DirectoryEntryIterator> {
+        let (ino, snapshot) = self.dir_handle(handle, |dir| (dir.ino, dir.snapshot.clone()))?;
+        let entries: VecDeque<_> = match snapshot {
+            Some(DirSnapshot::Plus(entries)) if offset > 0 => entries
+                .iter()
+                .skip(offset as usize)
+                .map(|entry| {
+                    entry.as_ref().map(|entry| DirectoryEntry {
+                        ino: entry.ino,
+                        name: entry.name.clone(),
+                        kind: entry.kind,
+                    })
+                })
+                .map(snapshot_entry)
+                .collect(),
+            Some(DirSnapshot::Names(entries)) if offset > 0 => entries
+                .iter()
+                .skip(offset as usize)
+                .cloned()
+                .map(snapshot_entry)
+                .collect(),
+            _ => {
+                let entries: Arc<Vec<_>> =
+                    Arc::new(self.read_dir(ino).await?.map(snapshot_ok).collect());
+                self.dir_handle(handle, |dir| {
+                    dir.snapshot = Some(DirSnapshot::Names(entries.clone()));
+                })?;
+                entries
+                    .iter()
+                    .skip(offset as usize)
+                    .cloned()
+                    .map(snapshot_entry)
+                    .collect()
+            }
+        };
+        Ok(DirectoryEntryIterator(entries))
+    }
+
+    /// Like [`EncryptedFs::read_dir_at`] but with [`FileAttr`].
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read_dir_plus_at(
+        &self,
+        handle: u64,
+        offset: u64,
+    ) -> FsResult<DirectoryEntryPlusIterator> {
+        let (ino, snapshot) = self.dir_handle(handle, |dir| (dir.ino, dir.snapshot.clone()))?;
+        let entries: VecDeque<_> = match snapshot {
+            Some(DirSnapshot::Plus(entries)) if offset > 0 => entries
+                .iter()
+                .skip(offset as usize)
+                .cloned()
+                .map(snapshot_entry)
+                .collect(),
+            Some(DirSnapshot::Names(entries)) if offset > 0 => {
+                let mut plus = VecDeque::new();
+                for entry in entries.iter().skip(offset as usize) {
+                    let entry = match entry {
+                        Some(entry) => {
+                            self.get_attr(entry.ino)
+                                .await
+                                .map(|attr| DirectoryEntryPlus {
+                                    ino: entry.ino,
+                                    name: entry.name.clone(),
+                                    kind: entry.kind,
+                                    attr,
+                                })
+                        }
+                        None => snapshot_entry(None),
+                    };
+                    plus.push_back(entry);
+                }
+                plus
+            }
+            _ => {
+                let entries: Arc<Vec<_>> =
+                    Arc::new(self.read_dir_plus(ino).await?.map(snapshot_ok).collect());
+                self.dir_handle(handle, |dir| {
+                    dir.snapshot = Some(DirSnapshot::Plus(entries.clone()));
+                })?;
+                entries
+                    .iter()
+                    .skip(offset as usize)
+                    .cloned()
+                    .map(snapshot_entry)
+                    .collect()
+            }
+        };
+        Ok(DirectoryEntryPlusIterator(entries))
+    }
+
+    /// Closes a directory opened with [`EncryptedFs::open_dir`].
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn release_dir(&self, handle: u64) -> FsResult<()> {
+        self.dir_handles
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .map(|_| ())
+            .ok_or(FsError::InvalidFileHandle)
+    }
+
+    fn dir_handle<T>(&self, handle: u64, f: impl FnOnce(&mut DirHandle) -> T) -> FsResult<T> {
+        self.dir_handles
+            .lock()
+            .unwrap()
+            .get_mut(&handle)
+            .map(f)
+            .ok_or(FsError::InvalidFileHandle)
+    }
+
+    async fn read_dir_plus_natural(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
+        if !self.is_dir(ino) {
+            return Err(FsError::NotADirectory);
+        }
+        if self.lower.is_some() {
+            let entries = self.read_dir_layered(ino).await?;
+            return Ok(self.with_attrs(entries).await);
+        }
+        if self.metadata_db.is_some() {
+            let entries = self.read_dir_db(ino).await?;
+            if !self.read_only && !self.is_read_only_path(ino) {
+                let set_attr = SetFileAttr::default().with_atime(self.now());
+                self.set_attr(ino, set_attr).await?;
+            }
+            return Ok(self.with_attrs(entries).await);
+        }
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        if !ls_dir.is_dir() {
+            return Err(FsError::InvalidInodeType);
+        }
+
+        let iter = fs::read_dir(ls_dir)?;
+        if !self.read_only && !self.is_read_only_path(ino) {
+            let set_attr = SetFileAttr::default().with_atime(self.now());
+            self.set_attr(ino, set_attr).await?;
+        }
+        Ok(self.create_directory_entry_plus_iterator(iter).await)
+    }
+
+    /// Load in cache the attributes of all entries of a directory, so the `getattr` calls that usually follow
+    /// listing it don't wait to decrypt them one by one, see [`FsOptions::prefetch_dir_metadata`].
+    ///
+    /// Returns how many were loaded. Unlike [`EncryptedFs::read_dir`] it doesn't change the atime of the directory.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn prefetch_dir_metadata(&self, ino: u64) -> FsResult<usize> {
+        let entries = self.list_dir(ino).await?;
+        let futures: Vec<_> = entries
+            .into_iter()
+            .flatten()
+            .map(|entry| {
+                let fs = {
+                    self.self_weak
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .unwrap()
+                        .upgrade()
+                        .unwrap()
+                };
+                DIR_ENTRIES_RT
+                    .spawn(async move { fs.get_inode_from_cache_or_storage(entry.ino).await })
+            })
+            .collect();
+        let mut count = 0;
+        for f in futures {
+            if f.await?.is_ok() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Warm the caches for files about to be read, like the sources of a project before a build, so the first reads
+    /// don't wait for the disk.
+    ///
+    /// The attributes are loaded in cache and the kernel is asked to read the encrypted content in its page cache in
+    /// background, on Linux only. We don't keep decrypted blocks, they are still decrypted when read, and the kernel
+    /// drops the pages when it needs the memory. Directories and missing inodes are skipped.
+    ///
+    /// Returns how many files were prefetched.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn prefetch(&self, inos: &[u64]) -> FsResult<usize> {
+        let mut count = 0;
+        for ino in inos {
+            if let Some(lower) = self.lower_only(*ino) {
+                count += Box::pin(lower.prefetch(&[*ino])).await?;
+                continue;
+            }
+            let Ok(attr) = self.get_inode_from_cache_or_storage(*ino).await else {
+                continue;
+            };
+            if attr.kind != FileType::RegularFile {
+                continue;
+            }
+            let Ok(file) = File::open(self.contents_path(*ino)) else {
+                // deleted in the meantime
+                continue;
+            };
+            fs_util::advise_will_need(&file)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Entries of a directory, without changing its atime like [`EncryptedFs::read_dir`] does.
+    async fn list_dir(&self, ino: u64) -> FsResult<VecDeque<FsResult<DirectoryEntry>>> {
+        if !self.is_dir(ino) {
+            return Err(FsError::NotADirectory);
+        }
+        if self.lower.is_some() {
+            self.read_dir_layered(ino).await
+        } else if self.metadata_db.is_some() {
+            self.read_dir_db(ino).await
+        } else {
+            let ls_dir = self.contents_path(ino).join(LS_DIR);
+            if !ls_dir.is_dir() {
+                return Err(FsError::InvalidInodeType);
+            }
+            Ok(self
+                .create_directory_entry_iterator(fs::read_dir(ls_dir)?)
+                .await
+                .0)
+        }
+    }
+
+    /// Inodes of [`FsOptions::read_only_paths`] and of everything under them.
+    async fn resolve_read_only_paths(&self) -> FsResult<HashSet<u64>> {
+        let mut inos = HashSet::new();
+        'paths: for path in &self.options.read_only_paths {
+            let mut ino = ROOT_INODE;
+            for component in path.components() {
+                let std::path::Component::Normal(name) = component else {
+                    continue;
+                };
+                let name = SecretString::from_str(&name.to_string_lossy()).unwrap();
+                let Some(attr) = self.find_by_name(ino, &name).await? else {
+                    warn!(path = %path.display(), "read-only path doesn't exist, ignoring it");
+                    continue 'paths;
+                };
+                ino = attr.ino;
+            }
+            inos.extend(self.walk_subtree(ino).await?.iter().map(|attr| attr.ino));
+        }
+        Ok(inos)
+    }
+
+    /// If `ino` is under one of [`FsOptions::read_only_paths`].
+    fn is_read_only_path(&self, ino: u64) -> bool {
+        self.read_only_inos
+            .get()
+            .is_some_and(|inos| inos.contains(&ino))
     }
 
-    #[allow(clippy::missing_panics_doc)]
-    #[allow(clippy::missing_errors_doc)]
-    pub fn exists_by_name(&self, parent: u64, name: &SecretString) -> FsResult<bool> {
-        if !self.exists(parent) {
-            return Err(FsError::InodeNotFound);
+    /// Fails with [`FsError::ReadOnly`] if `ino` is under one of [`FsOptions::read_only_paths`].
+    fn check_not_read_only_path(&self, ino: u64) -> FsResult<()> {
+        if self.is_read_only_path(ino) {
+            return Err(FsError::ReadOnly);
         }
-        if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+        Ok(())
+    }
+
+    /// Attributes of `ino` and of everything under it, if it's a directory. Each inode is returned once, even if
+    /// it's reachable by more than one name.
+    async fn walk_subtree(&self, ino: u64) -> FsResult<Vec<FileAttr>> {
+        let mut seen = HashSet::from([ino]);
+        let mut attrs = vec![];
+        let mut pending = vec![self.get_inode_from_cache_or_storage(ino).await?];
+        while let Some(attr) = pending.pop() {
+            if attr.kind == FileType::Directory {
+                for entry in self.list_dir(attr.ino).await? {
+                    let entry = entry?;
+                    let name = entry.name.expose_secret();
+                    if name.as_str() == "." || name.as_str() == ".." || !seen.insert(entry.ino) {
+                        continue;
+                    }
+                    pending.push(self.get_inode_from_cache_or_storage(entry.ino).await?);
+                }
+            }
+            attrs.push(attr);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        Ok(hash_path.is_file())
+        Ok(attrs)
     }
 
+    /// Disk usage of `ino` and everything under it, like for a "properties" view of a folder.
+    ///
+    /// Files reachable by more than one name are counted once.
     #[allow(clippy::missing_errors_doc)]
-    pub async fn read_dir(&self, ino: u64) -> FsResult<DirectoryEntryIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+    pub async fn du(&self, ino: u64) -> FsResult<DiskUsage> {
+        let mut usage = DiskUsage::default();
+        for attr in self.walk_subtree(ino).await? {
+            match attr.kind {
+                FileType::RegularFile | FileType::Symlink => {
+                    usage.files += 1;
+                    usage.logical_bytes += attr.size;
+                    for path in [self.contents_path(attr.ino), self.parity_path(attr.ino)] {
+                        if path.is_file() {
+                            usage.physical_bytes += fs_util::allocated_size(&path)?;
+                        }
+                    }
+                }
+                FileType::Directory => usage.dirs += 1,
+            }
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
-            return Err(FsError::InvalidInodeType);
+        Ok(usage)
+    }
+
+    /// How much space encryption takes for a file, or for all files under a directory, to see what a different
+    /// block size would change. The metadata is not included.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn overhead(&self, ino: u64) -> FsResult<OverheadInfo> {
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        let mut info = OverheadInfo {
+            per_block_overhead: ciphertext_block_len - self.block_size as u64,
+            ..OverheadInfo::default()
+        };
+        for attr in self.walk_subtree(ino).await? {
+            if attr.kind != FileType::RegularFile {
+                continue;
+            }
+            info.plaintext_bytes += attr.size;
+            let len = |path: PathBuf| fs::metadata(path).map_or(0, |metadata| metadata.len());
+            let ciphertext_bytes = len(self.contents_path(attr.ino));
+            info.ciphertext_bytes += ciphertext_bytes;
+            info.blocks += ciphertext_bytes.div_ceil(ciphertext_block_len);
+            info.parity_bytes += len(self.parity_path(attr.ino));
         }
+        Ok(info)
+    }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_iterator(iter).await)
+    /// Every file and directory under `root`, with its path relative to it, found lazily as the stream is polled, so
+    /// a tool can process the entries while the rest of the tree is read.
+    ///
+    /// Directories are read depth first, each one once even if reachable by more than one name, so a loop in the
+    /// tree can't keep it going. The ones deeper than [`FsOptions::max_dir_depth`] are not read, a
+    /// [`FsError::TooDeep`] is yielded after them. Entries which can't be read yield an error and the walk continues.
+    pub fn walk_async(&self, root: u64) -> impl Stream<Item = FsResult<(PathBuf, FileAttr)>> + '_ {
+        struct Walk {
+            // (path, ino, depth)
+            dirs: Vec<(PathBuf, u64, usize)>,
+            entries: VecDeque<FsResult<(PathBuf, FileAttr, usize)>>,
+            seen: HashSet<u64>,
+        }
+        let walk = Walk {
+            dirs: vec![(PathBuf::new(), root, 0)],
+            entries: VecDeque::new(),
+            seen: HashSet::from([root]),
+        };
+        stream::unfold(walk, move |mut walk| async move {
+            loop {
+                if let Some(entry) = walk.entries.pop_front() {
+                    let entry = entry.map(|(path, attr, depth)| {
+                        if attr.kind == FileType::Directory && walk.seen.insert(attr.ino) {
+                            match self.options.max_dir_depth {
+                                Some(max) if depth > max => {
+                                    walk.entries.push_front(Err(FsError::TooDeep { max }));
+                                }
+                                _ => walk.dirs.push((path.clone(), attr.ino, depth)),
+                            }
+                        }
+                        (path, attr)
+                    });
+                    return Some((entry, walk));
+                }
+                let (path, ino, depth) = walk.dirs.pop()?;
+                let entries = match self.list_dir(ino).await {
+                    Ok(entries) => entries,
+                    Err(err) => return Some((Err(err), walk)),
+                };
+                for entry in entries {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(err) => {
+                            walk.entries.push_back(Err(err));
+                            continue;
+                        }
+                    };
+                    let name = entry.name.expose_secret();
+                    if name.as_str() == "." || name.as_str() == ".." {
+                        continue;
+                    }
+                    let path = path.join(name.as_str());
+                    walk.entries.push_back(
+                        self.get_inode_from_cache_or_storage(entry.ino)
+                            .await
+                            .map(|attr| (path, attr, depth + 1)),
+                    );
+                }
+            }
+        })
     }
 
-    /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
-    pub async fn read_dir_plus(&self, ino: u64) -> FsResult<DirectoryEntryPlusIterator> {
-        if !self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+    async fn with_attrs(
+        &self,
+        entries: VecDeque<FsResult<DirectoryEntry>>,
+    ) -> DirectoryEntryPlusIterator {
+        let mut res = VecDeque::with_capacity(entries.len());
+        for entry in entries {
+            res.push_back(match entry {
+                Ok(entry) => self
+                    .get_inode_from_cache_or_storage(entry.ino)
+                    .await
+                    .map(|attr| DirectoryEntryPlus {
+                        ino: entry.ino,
+                        name: entry.name,
+                        kind: entry.kind,
+                        attr,
+                    }),
+                Err(err) => Err(err),
+            });
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
-            return Err(FsError::InvalidInodeType);
+        DirectoryEntryPlusIterator(res)
+    }
+
+    /// Entries of a directory from [`MetadataDb`].
+    async fn read_dir_db(&self, ino: u64) -> FsResult<VecDeque<FsResult<DirectoryEntry>>> {
+        let Some(db) = &self.metadata_db else {
+            return Ok(VecDeque::new());
+        };
+        let mut res = VecDeque::new();
+        for data in db.entries(ino)? {
+            let entry: FsResult<(u64, FileType, String)> = self.decrypt_db_value(&data).await;
+            res.push_back(match entry {
+                Ok((ino, kind, name)) => self
+                    .decode_entry_name(&name)
+                    .await
+                    .map(|name| DirectoryEntry { ino, name, kind }),
+                Err(err) => Err(err),
+            });
         }
+        Ok(res)
+    }
 
-        let iter = fs::read_dir(ls_dir)?;
-        let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
-        self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_plus_iterator(iter).await)
+    /// Entries of a directory merged from all layers of an overlay, the ones in upper hide the ones in lower with the
+    /// same name.
+    async fn read_dir_layered(&self, ino: u64) -> FsResult<VecDeque<FsResult<DirectoryEntry>>> {
+        let contents = self.contents_path(ino);
+        let mut entries = if contents.join(LS_DIR).is_dir() {
+            self.create_directory_entry_iterator(fs::read_dir(contents.join(LS_DIR))?)
+                .await
+                .0
+        } else {
+            VecDeque::new()
+        };
+        let Some(lower) = &self.lower else {
+            return Ok(entries);
+        };
+        if lower.is_dir(ino) {
+            let mut hidden = HashSet::new();
+            for dir in [HASH_DIR, WHITEOUT_DIR] {
+                if contents.join(dir).is_dir() {
+                    for entry in fs::read_dir(contents.join(dir))? {
+                        hidden.insert(entry?.file_name().to_string_lossy().to_string());
+                    }
+                }
+            }
+            for entry in Box::pin(lower.read_dir_layered(ino)).await? {
+                if let Ok(entry) = &entry {
+                    if hidden.contains(&self.name_hash(&entry.name).await?) {
+                        continue;
+                    }
+                }
+                entries.push_back(entry);
+            }
+        }
+        Ok(entries)
     }
 
     async fn create_directory_entry_plus(
@@ -1122,34 +5162,7 @@ impl EncryptedFs {
         }
         let entry = entry.unwrap();
         let name = entry.file_name().to_string_lossy().to_string();
-        let name = {
-            if name == "$." {
-                SecretString::new(Box::new(".".into()))
-            } else if name == "$.." {
-                SecretString::from_str("..").unwrap()
-            } else {
-                // try from cache
-                let lock = self.get_dir_entries_name_cache().await?;
-                let mut cache = lock.lock().await;
-                if let Some(name_cached) = cache.get(&name).cloned() {
-                    name_cached
-                } else {
-                    drop(cache);
-                    if let Ok(decrypted_name) =
-                        crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)
-                            .map_err(|err| {
-                                error!(err = %err, "decrypting file name");
-                                err
-                            })
-                    {
-                        lock.lock().await.put(name.clone(), decrypted_name.clone());
-                        decrypted_name
-                    } else {
-                        return Err(FsError::InvalidInput("invalid file name"));
-                    }
-                }
-            }
-        };
+        let name = self.decode_entry_name(&name).await?;
         let file_path = entry.path().to_str().unwrap().to_string();
         // try from cache
         let lock = self.dir_entries_meta_cache.get().await?;
@@ -1167,7 +5180,7 @@ impl EncryptedFs {
             .get_or_insert_with(file_path.clone(), || RwLock::new(false));
         let guard = lock.read().await;
         let file = File::open(entry.path())?;
-        let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
+        let res: bincode::Result<(u64, FileType)> = self.deserialize_metadata(crypto::create_read(
             file,
             self.cipher,
             &*self.key.get().await?,
@@ -1188,6 +5201,44 @@ impl EncryptedFs {
         Ok(DirectoryEntry { ino, name, kind })
     }
 
+    /// The plaintext name from the one saved in the directory, see [`FsOptions::encrypt_names`].
+    async fn decode_entry_name(&self, name: &str) -> FsResult<SecretString> {
+        if name == "$." {
+            Ok(SecretString::new(Box::new(".".into())))
+        } else if name == "$.." {
+            Ok(SecretString::from_str("..").unwrap())
+        } else if !self.encrypt_names {
+            Ok(SecretString::from_str(name).unwrap())
+        } else {
+            // try from cache
+            let lock = self.get_dir_entries_name_cache().await?;
+            let mut cache = lock.lock().await;
+            if let Some(name_cached) = cache.get(name).cloned() {
+                Ok(name_cached)
+            } else {
+                drop(cache);
+                if let Ok(decrypted_name) = crypto::decrypt_file_name_with(
+                    name,
+                    self.name_cipher,
+                    self.cipher,
+                    &*self.key.get().await?,
+                    &*self.name_keys.get().await?,
+                )
+                .map_err(|err| {
+                    error!(err = %err, "decrypting file name");
+                    err
+                }) {
+                    lock.lock()
+                        .await
+                        .put(name.to_string(), decrypted_name.clone());
+                    Ok(decrypted_name)
+                } else {
+                    Err(FsError::InvalidInput("invalid file name"))
+                }
+            }
+        }
+    }
+
     async fn get_dir_entries_name_cache(
         &self,
     ) -> FsResult<Arc<Mutex<LruCache<String, SecretString>>>> {
@@ -1222,11 +5273,18 @@ impl EncryptedFs {
 
     #[allow(clippy::missing_errors_doc)]
     async fn get_inode_from_storage(&self, ino: u64) -> FsResult<FileAttr> {
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.get_inode_from_storage(ino)).await;
+        }
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(ino, || RwLock::new(false));
         let _guard = lock.read();
 
+        if let Some(db) = &self.metadata_db {
+            let data = db.get_inode(ino)?.ok_or(FsError::InodeNotFound)?;
+            return self.decrypt_db_value(&data).await;
+        }
         let path = self.ino_file(ino);
         if !path.is_file() {
             return Err(FsError::InodeNotFound);
@@ -1235,7 +5293,7 @@ impl EncryptedFs {
             error!(err = %err, "opening file");
             FsError::InodeNotFound
         })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
+        Ok(self.deserialize_metadata(crypto::create_read(
             file,
             self.cipher,
             &*self.key.get().await?,
@@ -1247,8 +5305,10 @@ impl EncryptedFs {
         let mut guard = lock.write().await;
         let attr = guard.get(&ino);
         if let Some(attr) = attr {
+            self.attr_cache_hits.fetch_add(1, Ordering::Relaxed);
             Ok(*attr)
         } else {
+            self.attr_cache_misses.fetch_add(1, Ordering::Relaxed);
             drop(guard);
             let attr = self.get_inode_from_storage(ino).await?;
             let mut guard = lock.write().await;
@@ -1293,12 +5353,316 @@ impl EncryptedFs {
         Ok(attr)
     }
 
+    /// Get metadata of many inodes at once, with less overhead than calling [`Self::get_attr`] for each, as it
+    /// takes the locks only once.
+    ///
+    /// Results are in the same order as `inos`, missing inodes are [`FsError::InodeNotFound`] without failing the
+    /// others.
+    pub async fn getattr_batch(&self, inos: &[u64]) -> Vec<FsResult<FileAttr>> {
+        let Ok(cache) = self.attr_cache.get().await else {
+            let mut attrs = Vec::with_capacity(inos.len());
+            for ino in inos {
+                attrs.push(self.get_attr(*ino).await);
+            }
+            return attrs;
+        };
+        let mut attrs = Vec::with_capacity(inos.len());
+        {
+            let mut guard = cache.write().await;
+            for ino in inos {
+                if let Some(attr) = guard.get(ino) {
+                    attrs.push(Ok(*attr));
+                    continue;
+                }
+                let attr = self.get_inode_from_storage(*ino).await;
+                if let Ok(attr) = attr {
+                    guard.put(*ino, attr);
+                }
+                attrs.push(attr);
+            }
+        }
+
+        // merge time info with any open read handles
+        let opened_for_read = {
+            let guard = self.opened_files_for_read.read().await;
+            inos.iter()
+                .filter_map(|ino| guard.get(ino).map(|fhs| (*ino, fhs.clone())))
+                .collect::<HashMap<_, _>>()
+        };
+        if !opened_for_read.is_empty() {
+            let guard = self.read_handles.read().await;
+            for (ino, attr) in inos.iter().zip(attrs.iter_mut()) {
+                let (Ok(attr), Some(fhs)) = (attr, opened_for_read.get(ino)) else {
+                    continue;
+                };
+                for fh in fhs {
+                    if let Some(ctx) = guard.get(fh) {
+                        let set_atr: SetFileAttr = ctx.lock().await.attr.clone().into();
+                        merge_attr(attr, &set_atr, false);
+                    }
+                }
+            }
+        }
+
+        // merge time info and size with any open write handles
+        let opened_for_write = {
+            let guard = self.opened_files_for_write.read().await;
+            inos.iter()
+                .filter_map(|ino| guard.get(ino).map(|fh| (*ino, *fh)))
+                .collect::<HashMap<_, _>>()
+        };
+        if !opened_for_write.is_empty() {
+            let guard = self.write_handles.read().await;
+            for (ino, attr) in inos.iter().zip(attrs.iter_mut()) {
+                let (Ok(attr), Some(fh)) = (attr, opened_for_write.get(ino)) else {
+                    continue;
+                };
+                if let Some(ctx) = guard.get(fh) {
+                    let set_atr: SetFileAttr = ctx.lock().await.attr.clone().into();
+                    merge_attr(attr, &set_atr, false);
+                }
+            }
+        }
+
+        attrs
+    }
+
     /// Set metadata
     pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        self.set_attr2(ino, set_attr, false).await
+        self.check_not_read_only_path(ino)?;
+        self.copy_up(ino).await?;
+        self.set_attr2(ino, set_attr, false).await?;
+        if set_attr.perm.is_some() {
+            self.chmod_acl(ino).await?;
+        }
+        Ok(())
+    }
+
+    /// Set the access and modification times of `ino` itself, a symlink is not followed, like `utimensat` with
+    /// `AT_SYMLINK_NOFOLLOW`. `None` keeps the time as it is.
+    ///
+    /// Unlike [`EncryptedFs::set_attr`] they are set as given, also when older than what the file has, which is what
+    /// archivers restoring files need. The ctime is set to now. It fails with [`FsError::NotPermitted`] on files
+    /// with [`FS_IMMUTABLE_FL`] or [`FS_APPEND_FL`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_times(
+        &self,
+        ino: u64,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.check_not_read_only_path(ino)?;
+        self.copy_up(ino).await?;
+        let serialize_update_lock = self
+            .serialize_update_inode_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _serialize_update_guard = serialize_update_lock.lock().await;
+
+        let mut attr = self.get_attr(ino).await?;
+        check_not_protected(&attr)?;
+        if let Some(atime) = atime {
+            attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            attr.mtime = mtime;
+        }
+        attr.ctime = self.now();
+        self.write_inode_to_storage(&attr).await
+    }
+
+    /// The flags of the file, what `FS_IOC_GETFLAGS` returns, like [`FS_IMMUTABLE_FL`] and [`FS_APPEND_FL`].
+    pub async fn get_flags(&self, ino: u64) -> FsResult<u32> {
+        Ok(self.get_attr(ino).await?.flags)
+    }
+
+    /// Replace the flags of the file, like `FS_IOC_SETFLAGS` does.
+    ///
+    /// The FUSE lib we use doesn't support ioctl yet, so `chattr` and `lsattr` don't work on the mount, this is how
+    /// to set them for now.
+    pub async fn set_flags(&self, ino: u64, flags: u32) -> FsResult<()> {
+        self.set_attr(ino, SetFileAttr::default().with_flags(flags))
+            .await
+    }
+
+    /// Extended attribute of the file.
+    ///
+    /// The value is stored encrypted, in the same form it was set, ACLs in [`XATTR_ACL_ACCESS`] and
+    /// [`XATTR_ACL_DEFAULT`] included.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn get_xattr(&self, ino: u64, name: &str) -> FsResult<Vec<u8>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        self.read_xattrs(ino)
+            .await?
+            .remove(name)
+            .ok_or(FsError::XattrNotFound)
+    }
+
+    /// Names of the extended attributes of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn list_xattr(&self, ino: u64) -> FsResult<Vec<String>> {
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        Ok(self.read_xattrs(ino).await?.into_keys().collect())
+    }
+
+    /// Set an extended attribute of the file.
+    ///
+    /// Setting [`XATTR_ACL_ACCESS`] changes the permission bits to match the ACL, if it has nothing more than
+    /// them it's not kept, like on local filesystems. [`XATTR_ACL_DEFAULT`] can only be set on directories, it's
+    /// applied to the files and directories created in it. ACLs are validated, others are kept as they are.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn set_xattr(&self, ino: u64, name: &str, value: &[u8]) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if name.is_empty() {
+            return Err(FsError::InvalidInput("empty xattr name"));
+        }
+        self.check_not_read_only_path(ino)?;
+        self.copy_up(ino).await?;
+        let attr = self.get_attr(ino).await?;
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _guard = lock.lock().await;
+        let mut xattrs = self.read_xattrs(ino).await?;
+        match name {
+            XATTR_ACL_ACCESS => {
+                let acl = Acl::parse(value)?;
+                if acl.is_equivalent_to_mode() {
+                    xattrs.remove(name);
+                } else {
+                    xattrs.insert(name.to_string(), acl.to_bytes());
+                }
+                self.write_xattrs(ino, &xattrs).await?;
+                let perm = (attr.perm & !0o777) | acl.perm();
+                self.set_attr2(
+                    ino,
+                    SetFileAttr::default()
+                        .with_perm(perm)
+                        .with_ctime(self.now()),
+                    false,
+                )
+                .await?;
+                return Ok(());
+            }
+            XATTR_ACL_DEFAULT => {
+                if attr.kind != FileType::Directory {
+                    return Err(FsError::NotADirectory);
+                }
+                xattrs.insert(name.to_string(), Acl::parse(value)?.to_bytes());
+            }
+            _ => {
+                xattrs.insert(name.to_string(), value.to_vec());
+            }
+        }
+        self.write_xattrs(ino, &xattrs).await?;
+        self.set_attr2(ino, SetFileAttr::default().with_ctime(self.now()), false)
+            .await
+    }
+
+    /// Remove an extended attribute of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_xattr(&self, ino: u64, name: &str) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        self.check_not_read_only_path(ino)?;
+        self.copy_up(ino).await?;
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _guard = lock.lock().await;
+        let mut xattrs = self.read_xattrs(ino).await?;
+        if xattrs.remove(name).is_none() {
+            return Err(FsError::XattrNotFound);
+        }
+        self.write_xattrs(ino, &xattrs).await?;
+        self.set_attr2(ino, SetFileAttr::default().with_ctime(self.now()), false)
+            .await
+    }
+
+    async fn read_xattrs(&self, ino: u64) -> FsResult<BTreeMap<String, Vec<u8>>> {
+        if let Some(lower) = self.lower_only(ino) {
+            return Box::pin(lower.read_xattrs(ino)).await;
+        }
+        let path = self.xattr_path(ino);
+        if !path.is_file() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(self.deserialize_metadata(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?)
+    }
+
+    /// Without any the file is removed, most files don't have xattrs.
+    async fn write_xattrs(&self, ino: u64, xattrs: &BTreeMap<String, Vec<u8>>) -> FsResult<()> {
+        let path = self.xattr_path(ino);
+        if xattrs.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+                self.sync_dirs(&[self.data_dir.join(XATTR_DIR)])?;
+            }
+            return Ok(());
+        }
+        fs::create_dir_all(self.data_dir.join(XATTR_DIR))?;
+        self.atomic_serialize_metadata(&path, xattrs, &*self.key.get().await?)?;
+        Ok(())
+    }
+
+    /// Give a new file the default ACL of its parent, as its access ACL limited by the permission bits it's
+    /// created with, and for directories also as their default ACL.
+    async fn inherit_default_acl(&self, parent: u64, attr: &mut FileAttr) -> FsResult<()> {
+        if attr.kind == FileType::Symlink {
+            return Ok(());
+        }
+        let Some(default) = self.read_xattrs(parent).await?.remove(XATTR_ACL_DEFAULT) else {
+            return Ok(());
+        };
+        let mut xattrs = BTreeMap::new();
+        let mut acl = Acl::parse(&default)?;
+        acl.mask_perm(attr.perm);
+        attr.perm = (attr.perm & !0o777) | acl.perm();
+        if !acl.is_equivalent_to_mode() {
+            xattrs.insert(XATTR_ACL_ACCESS.to_string(), acl.to_bytes());
+        }
+        if attr.kind == FileType::Directory {
+            xattrs.insert(XATTR_ACL_DEFAULT.to_string(), default);
+        }
+        self.write_xattrs(attr.ino, &xattrs).await
+    }
+
+    /// Keep the access ACL in sync with the permission bits after they changed, like `chmod` does.
+    async fn chmod_acl(&self, ino: u64) -> FsResult<()> {
+        let lock = self
+            .xattr_locks
+            .get_or_insert_with(ino, || Mutex::new(false));
+        let _guard = lock.lock().await;
+        let mut xattrs = self.read_xattrs(ino).await?;
+        let Some(data) = xattrs.get_mut(XATTR_ACL_ACCESS) else {
+            return Ok(());
+        };
+        let mut acl = Acl::parse(data)?;
+        acl.set_perm(self.get_attr(ino).await?.perm);
+        *data = acl.to_bytes();
+        self.write_xattrs(ino, &xattrs).await
     }
 
     async fn set_attr2(
@@ -1312,9 +5676,15 @@ impl EncryptedFs {
             .get_or_insert_with(ino, || Mutex::new(false));
         let _serialize_update_guard = serialize_update_lock.lock().await;
 
+        if self.options.nodev && set_attr.rdev.is_some_and(|rdev| rdev != 0) {
+            return Err(FsError::NotPermitted);
+        }
         let mut attr = self.get_attr(ino).await?;
         merge_attr(&mut attr, &set_attr, overwrite_size);
-        let now = SystemTime::now();
+        if set_attr.perm.is_some() {
+            attr.perm = self.allowed_perm(attr.kind, attr.perm);
+        }
+        let now = self.now();
         attr.ctime = now;
         attr.atime = now;
 
@@ -1323,18 +5693,61 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// `perm` without the setuid and setgid bits on files, with [`FsOptions::nosuid`]. On directories setgid only
+    /// makes the entries inherit the group, so it's kept.
+    /// The time to set on files, from [`FsOptions::timestamp_source`].
+    fn now(&self) -> SystemTime {
+        let now = SystemTime::now();
+        if self.options.timestamp_source == TimestampSource::SystemClock {
+            return now;
+        }
+        let mut clock = self.clock.lock().unwrap();
+        let (last, at) = *clock;
+        let monotonic = last + at.elapsed();
+        if now >= monotonic {
+            *clock = (now, std::time::Instant::now());
+            now
+        } else {
+            // the system clock went back
+            monotonic
+        }
+    }
+
+    const fn allowed_perm(&self, kind: FileType, perm: u16) -> u16 {
+        if self.options.nosuid && matches!(kind, FileType::RegularFile) {
+            perm & !0o6000
+        } else {
+            perm
+        }
+    }
+
     async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(attr.ino, || RwLock::new(false));
         let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
-            &self.ino_file(attr.ino),
-            attr,
-            self.cipher,
-            &*self.key.get().await?,
-        )?;
+        if let Some(db) = &self.metadata_db {
+            db.put_inode(attr.ino, &self.encrypt_db_value(attr).await?)?;
+        } else {
+            self.atomic_serialize_metadata(
+                &self.ino_file(attr.ino),
+                attr,
+                &*self.key.get().await?,
+            )?;
+            self.encryptions.add(1);
+        }
         drop(guard);
+        if self.options.mirror_mtime_to_backing && attr.kind == FileType::RegularFile {
+            match OpenOptions::new()
+                .write(true)
+                .open(self.contents_path(attr.ino))
+            {
+                Ok(file) => file.set_modified(attr.mtime)?,
+                // not created yet or already removed
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
         // update cache also
         {
             let lock = self.attr_cache.get().await?;
@@ -1348,9 +5761,9 @@ impl EncryptedFs {
     ///
     /// If we try to read outside of file size, we return zero bytes.
     /// If the file is not opened for read, it will return an error of type [FsError::InvalidFileHandle].
+    /// If [`FsOptions::redundancy`] is enabled and a block fails the integrity check we try to repair it from parity.
     #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
     #[allow(clippy::missing_errors_doc)]
-    #[allow(clippy::cast_possible_truncation)]
     pub async fn read(
         &self,
         ino: u64,
@@ -1358,17 +5771,87 @@ impl EncryptedFs {
         buf: &mut [u8],
         handle: u64,
     ) -> FsResult<usize> {
+        let res = self.read_or_repair(ino, offset, buf, handle).await;
+        self.record_error(res)
+    }
+
+    /// Like [`EncryptedFs::read`] but fills `bufs` one after the other from consecutive regions of the file, like
+    /// `readv(2)`.
+    ///
+    /// A block that spans two buffers is decrypted once, the reader keeps it until the next buffer is filled from it.
+    /// It stops at the end of the file, the last buffers might be filled partially or not at all. If a read fails
+    /// after some bytes were read it returns how many were, it fails only when nothing could be read.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_vectored(
+        &self,
+        ino: u64,
+        offset: u64,
+        bufs: &mut [IoSliceMut<'_>],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let mut read = 0;
+        for buf in bufs.iter_mut().filter(|buf| !buf.is_empty()) {
+            match self.read(ino, offset + read as u64, buf, handle).await {
+                Ok(len) => {
+                    read += len;
+                    if len < buf.len() {
+                        // end of file
+                        break;
+                    }
+                }
+                Err(err) if read > 0 => {
+                    warn!(err = %err, read, "short read");
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(read)
+    }
+
+    async fn read_or_repair(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        match self.read2(ino, offset, buf, handle).await {
+            Err(err @ FsError::Io { .. }) if self.options.redundancy.is_some() => {
+                if self.repair(ino).await?.is_empty() {
+                    return Err(err);
+                }
+                if let Some(ctx) = self.read_handles.read().await.get(&handle) {
+                    let reader = self
+                        .create_read_seek(File::open(self.contents_path(ino))?)
+                        .await?;
+                    ctx.lock().await.reader = Some(Box::new(reader));
+                }
+                self.read2(ino, offset, buf, handle).await
+            }
+            res => res,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read2(&self, ino: u64, offset: u64, buf: &mut [u8], handle: u64) -> FsResult<usize> {
+        let lower_handle = self.lower_handles.read().await.get(&handle).copied();
+        if let (Some(lower), Some(lower_handle)) = (&self.lower, lower_handle) {
+            return Box::pin(lower.read(ino, offset, buf, lower_handle)).await;
+        }
         if !self.exists(ino) {
             return Err(FsError::InodeNotFound);
         }
         if !self.is_file(ino) {
-            return Err(FsError::InvalidInodeType);
+            return Err(self.wrong_file_type_of(ino));
         }
         if !self.read_handles.read().await.contains_key(&handle) {
             return Err(FsError::InvalidFileHandle);
         }
 
         let _size = self.get_attr(ino).await?.size;
+        // take it before locking so we don't wait on the writer while holding the read lock
+        let tail = self.append_buffer_snapshot(ino).await;
 
         let lock = self
             .read_write_locks
@@ -1382,7 +5865,7 @@ impl EncryptedFs {
             return Err(FsError::InvalidFileHandle);
         }
         if self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::IsADirectory);
         }
         if buf.is_empty() {
             // no-op
@@ -1390,7 +5873,7 @@ impl EncryptedFs {
         }
 
         // read data
-        let (_buf, len) = {
+        let len = {
             let reader = ctx.reader.as_mut().unwrap();
 
             reader.seek(SeekFrom::Start(offset)).map_err(|err| {
@@ -1401,7 +5884,7 @@ impl EncryptedFs {
                 error!(err = %err, "getting position");
                 err
             })?;
-            if pos != offset {
+            if pos != offset && tail.is_none() {
                 // we would need to seek after filesize
                 return Ok(0);
             }
@@ -1414,14 +5897,21 @@ impl EncryptedFs {
             } else {
                 buf
             };
-            let len = stream_util::read(reader, buf).map_err(|err| {
-                error!(err = %err, "reading");
-                err
-            })?;
-            (buf, len)
+            let (len, file_end) = if pos == offset {
+                let len = stream_util::read(reader, buf).map_err(|err| {
+                    error!(err = %err, "reading");
+                    err
+                })?;
+                (len, offset + len as u64)
+            } else {
+                // after the end of the file, only the pending appends can have data here
+                (0, pos)
+            };
+            // add appends that are not yet written
+            tail.map_or(len, |tail| tail.read_into(offset, file_end, buf, len))
         };
 
-        ctx.attr.atime = SystemTime::now();
+        ctx.attr.atime = self.now();
         drop(ctx);
 
         // self.sizes_read
@@ -1456,6 +5946,78 @@ impl EncryptedFs {
         Ok(len)
     }
 
+    /// Read up to `len` bytes from `offset` passing them to `f` as they are decrypted, in slices of at most a block.
+    ///
+    /// Unlike [`EncryptedFs::read`] there is no copy to a buffer of the caller, so it's cheaper when the data is
+    /// only hashed or written somewhere else. `f` is called while holding the handle, it shouldn't block for long.
+    ///
+    /// Returns how many bytes were passed to `f`, less than `len` at the end of the file.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_into(
+        &self,
+        ino: u64,
+        offset: u64,
+        len: usize,
+        handle: u64,
+        mut f: impl FnMut(&[u8]) -> FsResult<()> + Send,
+    ) -> FsResult<usize> {
+        let lower_handle = self.lower_handles.read().await.get(&handle).copied();
+        if let (Some(lower), Some(lower_handle)) = (&self.lower, lower_handle) {
+            return Box::pin(lower.read_into(ino, offset, len, lower_handle, f)).await;
+        }
+        if !self.exists(ino) {
+            return Err(FsError::InodeNotFound);
+        }
+        if !self.is_file(ino) {
+            return Err(self.wrong_file_type_of(ino));
+        }
+        if !self.read_handles.read().await.contains_key(&handle) {
+            return Err(FsError::InvalidFileHandle);
+        }
+        if self.append_buffer_snapshot(ino).await.is_some() {
+            // appends not yet written are not in the reader, merge them the usual way
+            let mut buf = vec![0; len];
+            let len = self.read(ino, offset, &mut buf, handle).await?;
+            f(&buf[..len])?;
+            return Ok(len);
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _read_guard = lock.read().await;
+
+        let guard = self.read_handles.read().await;
+        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        if ctx.ino != ino {
+            return Err(FsError::InvalidFileHandle);
+        }
+        let reader = ctx.reader.as_mut().unwrap();
+        if reader.seek(SeekFrom::Start(offset))? != offset {
+            // after the end of the file
+            return Ok(0);
+        }
+        let mut read = 0;
+        while read < len {
+            let page = reader.fill_buf()?;
+            if page.is_empty() {
+                break;
+            }
+            let n = page.len().min(len - read);
+            f(&page[..n])?;
+            reader.consume(n);
+            read += n;
+        }
+        ctx.attr.atime = self.now();
+        Ok(read)
+    }
+
+    /// Close a handle.
+    ///
+    /// For a write handle the content is written and the attributes are saved atomically. With
+    /// [`FsOptions::fsync_on_release`] the content is also synced to disk, with its directory, before returning, so
+    /// after a successful release the data survives a crash.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn release(&self, handle: u64) -> FsResult<()> {
@@ -1464,6 +6026,11 @@ impl EncryptedFs {
             // without being opened we don't use a handle
             return Ok(());
         }
+        self.handle_infos.lock().unwrap().remove(&handle);
+        let lower_handle = self.lower_handles.write().await.remove(&handle);
+        if let (Some(lower), Some(lower_handle)) = (&self.lower, lower_handle) {
+            return Box::pin(lower.release(lower_handle)).await;
+        }
         let mut valid_fh = false;
 
         // read
@@ -1491,7 +6058,10 @@ impl EncryptedFs {
             let set_attr: SetFileAttr = ctx.attr.clone().into();
             let ino = ctx.ino;
             drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            // on read-only we can't keep the atime
+            if !self.read_only && !self.is_read_only_path(ino) {
+                self.set_attr(ino, set_attr).await?;
+            }
 
             valid_fh = true;
         }
@@ -1504,18 +6074,32 @@ impl EncryptedFs {
             }
             let mut ctx = ctx.lock().await;
 
-            let mut writer = ctx.writer.take().unwrap();
             let lock = self
                 .read_write_locks
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
+            self.write_tail(&mut ctx).await?;
+            let mut writer = ctx.writer.take().unwrap();
             let file = writer.finish()?;
-            file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            // the journal of the overwritten blocks can go only after the new ones are on disk
+            let overwritten = ctx
+                .journaled
+                .as_ref()
+                .is_some_and(|(_, blocks)| !blocks.is_empty());
+            if self.options.fsync_on_release || overwritten {
+                file.sync_all()?;
+                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                #[cfg(test)]
+                self.release_syncs.fetch_add(1, Ordering::Relaxed);
+            }
+            self.commit_journal(&mut ctx)?;
+            self.update_parity(ctx.ino, Some(&std::mem::take(&mut ctx.parity_dirty)))?;
+            self.save_encryptions(true)?;
             // write attr only here to avoid serializing it multiple times while writing
             // it will merge time fields with existing data because it might got change while we kept the handle
             let ino = ctx.ino;
             let attr = ctx.attr.clone();
+            let dirty = std::mem::take(&mut ctx.dirty);
             drop(ctx);
             self.set_attr(ino, attr.into()).await?;
             let attr = self.get_attr(ino).await?;
@@ -1558,6 +6142,7 @@ impl EncryptedFs {
             drop(write_guard);
             self.opened_files_for_write.write().await.remove(&ino);
             self.reset_handles(ino, Some(handle), true).await?;
+            self.upload_to_storage(ino, Some(&dirty)).await?;
 
             valid_fh = true;
         }
@@ -1571,6 +6156,7 @@ impl EncryptedFs {
     /// Check if a file is opened for reading with this handle.
     pub async fn is_read_handle(&self, fh: u64) -> bool {
         self.read_handles.read().await.contains_key(&fh)
+            || self.lower_handles.read().await.contains_key(&fh)
     }
 
     /// Check if a file is opened for writing with this handle.
@@ -1578,6 +6164,179 @@ impl EncryptedFs {
         self.write_handles.read().await.contains_key(&fh)
     }
 
+    /// Snapshot of the open file handles, useful to find the ones keeping the filesystem busy.
+    ///
+    /// It doesn't wait on reads and writes in progress.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn open_handles(&self) -> Vec<HandleInfo> {
+        let mut handles: Vec<_> = self
+            .handle_infos
+            .lock()
+            .unwrap()
+            .values()
+            .copied()
+            .collect();
+        handles.sort_by_key(|info| info.fh);
+        handles
+    }
+
+    /// The content of a file encrypted on its own, to share it with someone who has the password, who decrypts it with
+    /// [`decrypt_file_envelope`] without the data dir.
+    ///
+    /// The key is derived from the password with a new salt, both it and the KDF parameters are in the envelope. The
+    /// whole file is kept in memory. Changes not yet flushed from open handles are not included.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn export_file_envelope(&self, ino: u64) -> FsResult<Vec<u8>> {
+        let attr = self.get_attr(ino).await?;
+        if attr.kind != FileType::RegularFile {
+            return Err(wrong_file_type(attr.kind));
+        }
+        let password = self
+            .key
+            .provider()
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        #[allow(clippy::cast_possible_truncation)]
+        let mut content = vec![0; attr.size as usize];
+        let fh = self.open(ino, true, false).await?;
+        let mut len = 0;
+        let res = async {
+            while len < content.len() {
+                let n = self.read(ino, len as u64, &mut content[len..], fh).await?;
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+            Ok::<(), FsError>(())
+        }
+        .await;
+        self.release(fh).await?;
+        res?;
+        content.truncate(len);
+
+        let mut salt = vec![0; 16];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let kdf = crypto::KdfParams::default();
+        let key = crypto::derive_key_with(&password, self.cipher, &salt, kdf)?;
+        let envelope = FileEnvelope {
+            magic: FILE_ENVELOPE_MAGIC,
+            cipher: self.cipher,
+            kdf,
+            salt,
+            sealed: crypto::seal(self.cipher, &key, &content)?,
+        };
+        Ok(bincode::serialize(&envelope)?)
+    }
+
+    /// Health of the filesystem, for monitoring a mount.
+    ///
+    /// Made from counters and the handles snapshot, it doesn't wait on reads and writes in progress.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::cast_precision_loss)]
+    pub async fn status(&self) -> Status {
+        let (open_handles, dirty_bytes) = {
+            let infos = self.handle_infos.lock().unwrap();
+            (
+                infos.len(),
+                infos.values().map(|info| info.dirty_bytes).sum(),
+            )
+        };
+        let hits = self.attr_cache_hits.load(Ordering::Relaxed);
+        let lookups = hits + self.attr_cache_misses.load(Ordering::Relaxed);
+        Status {
+            locked: !self.key.is_cached().await,
+            open_handles,
+            dirty_bytes,
+            cache_hit_rate: (lookups > 0).then(|| hits as f64 / lookups as f64),
+            last_error: self.last_error.lock().unwrap().clone(),
+            uptime: self.started_at.elapsed(),
+        }
+    }
+
+    /// Keep the error for [`EncryptedFs::status`] if it's from the storage or the crypto.
+    fn record_error<T>(&self, res: FsResult<T>) -> FsResult<T> {
+        if let Err(
+            err @ (FsError::Io { .. }
+            | FsError::SerializeError { .. }
+            | FsError::Crypto { .. }
+            | FsError::JoinError { .. }
+            | FsError::Timeout
+            | FsError::CorruptFile { .. }),
+        ) = &res
+        {
+            *self.last_error.lock().unwrap() = Some((SystemTime::now(), err.to_string()));
+        }
+        res
+    }
+
+    /// Closes all the handles of `ino`, like before removing or moving it outside, returns how many it closed.
+    ///
+    /// With `flush` what was written is kept, like on [`EncryptedFs::release`]. Else the writes since the last flush
+    /// are dropped and the file is as it was then, which needs [`FsOptions::write_journal`] to bring back the blocks
+    /// they changed. Reads and writes in progress with these handles finish first, the ones after get
+    /// [`FsError::InvalidFileHandle`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn close_handles_for(&self, ino: u64, flush: bool) -> FsResult<usize> {
+        let handles: Vec<_> = self
+            .open_handles()
+            .into_iter()
+            .filter(|info| info.ino == ino)
+            .collect();
+        if !flush && !self.options.write_journal && handles.iter().any(|info| info.write) {
+            return Err(FsError::InvalidInput(
+                "dropping the writes needs the write journal",
+            ));
+        }
+        for info in &handles {
+            if info.write && !flush {
+                self.discard_writes(info.fh).await?;
+                if !info.read {
+                    continue;
+                }
+            }
+            self.release(info.fh).await?;
+        }
+        Ok(handles.len())
+    }
+
+    /// Closes the write side of `handle` without writing what it has pending, the journal brings back the blocks it
+    /// changed since the last flush.
+    async fn discard_writes(&self, handle: u64) -> FsResult<()> {
+        let Some(ctx) = self.write_handles.write().await.remove(&handle) else {
+            return Ok(());
+        };
+        self.handle_infos.lock().unwrap().remove(&handle);
+        let mut ctx = ctx.lock().await;
+        let ino = ctx.ino;
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let write_guard = lock.write().await;
+        ctx.writer = None;
+        ctx.tail.buf.clear();
+        let restored = ctx.journaled.take().is_some();
+        if restored {
+            self.restore_journal(ino, &self.journal_path(ino)).await?;
+        }
+        // the size we saved might be from before the last flush
+        let len = fs::metadata(self.contents_path(ino))?.len();
+        let size = plaintext_len(len, self.cipher, self.block_size);
+        self.set_attr2(ino, SetFileAttr::default().with_size(size), true)
+            .await?;
+        drop(ctx);
+        drop(write_guard);
+        if restored {
+            self.upload_to_storage(ino, None).await?;
+        }
+        self.sizes_write.lock().await.remove(&ino);
+        self.sizes_read.lock().await.remove(&ino);
+        self.requested_read.lock().await.remove(&ino);
+        self.opened_files_for_write.write().await.remove(&ino);
+        self.reset_handles(ino, None, false).await
+    }
+
     /// Writes the contents of `buf` to the file with `ino` starting at `offset`.
     ///
     /// If we write outside file size, we fill up with zeros until the `offset`.
@@ -1585,14 +6344,133 @@ impl EncryptedFs {
     /// it will return an error of type [FsError::InvalidFileHandle].
     #[instrument(skip(self, buf), fields(len = %buf.len()), ret(level = Level::DEBUG))]
     pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        let len = self.record_error(self.write2(ino, offset, buf, handle).await)?;
+        let mut over_limit = false;
+        if len > 0 {
+            let mut infos = self.handle_infos.lock().unwrap();
+            if let Some(info) = infos.get_mut(&handle) {
+                info.dirty = true;
+                info.bytes_written += len as u64;
+                info.dirty_bytes += len as u64;
+                over_limit = self.over_dirty_limit(&infos, handle, 0);
+            }
+        }
+        if over_limit {
+            // backpressure, the writer waits until what it wrote so far is written out
+            self.write_out(handle).await?;
+        }
+        Ok(len)
+    }
+
+    /// Like [`EncryptedFs::write`] but writes all of `buf`, with as many writes as needed.
+    ///
+    /// If one fails after some bytes were written, like when the disk is full, it returns how many were, like a short
+    /// `write(2)`. The file has the data up to there and nothing after, so the caller can retry the rest. It fails
+    /// only when nothing could be written.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_all(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &[u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self
+                .write(ino, offset + written as u64, &buf[written..], handle)
+                .await
+            {
+                Ok(0) => break,
+                Ok(len) => written += len,
+                Err(err) if written > 0 => {
+                    warn!(err = %err, written, "short write");
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Like [`EncryptedFs::write_all`] but writes `bufs` one after the other to consecutive regions of the file, like
+    /// `writev(2)`.
+    ///
+    /// A block that spans two buffers is encrypted once, when it's complete or on flush, as if it was written from one
+    /// buffer. If a write fails after some bytes were written it returns how many were, like [`EncryptedFs::write_all`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_vectored(
+        &self,
+        ino: u64,
+        offset: u64,
+        bufs: &[IoSlice<'_>],
+        handle: u64,
+    ) -> FsResult<usize> {
+        let mut written = 0;
+        for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+            match self
+                .write_all(ino, offset + written as u64, buf, handle)
+                .await
+            {
+                Ok(len) => {
+                    written += len;
+                    if len < buf.len() {
+                        break;
+                    }
+                }
+                Err(err) if written > 0 => {
+                    warn!(err = %err, written, "short write");
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    /// If writing `len` more bytes with `handle` would go over [`FsOptions::max_dirty_per_handle`] or
+    /// [`FsOptions::max_memory_bytes`] and wait for what it buffered to be written out, for non-blocking writers
+    /// which should get `EAGAIN` instead. Never when the handle has nothing buffered, as waiting wouldn't make room.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn write_would_block(&self, handle: u64, len: usize) -> bool {
+        self.over_dirty_limit(&self.handle_infos.lock().unwrap(), handle, len as u64)
+    }
+
+    /// If `handle` with `len` more dirty bytes is over [`FsOptions::max_dirty_per_handle`], or the dirty handles
+    /// are over the part of [`FsOptions::max_memory_bytes`] left after the caches. Only what writing out can
+    /// release is counted, so a handle with nothing dirty is never over.
+    fn over_dirty_limit(&self, infos: &HashMap<u64, HandleInfo>, handle: u64, len: u64) -> bool {
+        let Some(info) = infos.get(&handle).filter(|info| info.dirty_bytes > 0) else {
+            return false;
+        };
+        if self
+            .options
+            .max_dirty_per_handle
+            .is_some_and(|max| info.dirty_bytes + len >= max)
+        {
+            return true;
+        }
+        self.options.max_memory_bytes.is_some_and(|max| {
+            // each dirty writer also keeps a block
+            let buffers: u64 = infos
+                .values()
+                .filter(|info| info.dirty_bytes > 0)
+                .map(|info| info.dirty_bytes + self.block_size as u64)
+                .sum();
+            buffers + len >= max - max / MEMORY_CACHES_DIVISOR
+        })
+    }
+
+    async fn write2(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        self.check_not_read_only_path(ino)?;
         if !self.exists(ino) {
             return Err(FsError::InodeNotFound);
         }
         if !self.is_file(ino) {
-            return Err(FsError::InvalidInodeType);
+            return Err(self.wrong_file_type_of(ino));
         }
         {
             if !self.write_handles.read().await.contains_key(&handle) {
@@ -1610,6 +6488,10 @@ impl EncryptedFs {
             // no-op
             return Ok(0);
         }
+        let flags = self.get_attr(ino).await?.flags;
+        if flags & FS_IMMUTABLE_FL != 0 {
+            return Err(FsError::NotPermitted);
+        }
 
         let lock = self
             .read_write_locks
@@ -1618,14 +6500,86 @@ impl EncryptedFs {
 
         let guard = self.write_handles.read().await;
         let mut ctx = guard.get(&handle).unwrap().lock().await;
+        if flags & FS_APPEND_FL != 0 && offset != ctx.attr.size {
+            // only at the end
+            return Err(FsError::NotPermitted);
+        }
+
+        if offset > self.cipher.max_plaintext_len() as u64 {
+            return Err(FsError::MaxFilesizeExceeded(
+                self.cipher.max_plaintext_len(),
+            ));
+        }
+        if let Some(reserved) = self.options.reserved_space_bytes {
+            let end = offset + buf.len() as u64;
+            if end > ctx.attr.size {
+                let needed = reserved.saturating_add(end - ctx.attr.size);
+                let available = fs_util::available_space(&self.data_dir)?;
+                if needed > available {
+                    return Err(FsError::InsufficientSpace { needed, available });
+                }
+            }
+        }
+        if offset + buf.len() as u64 <= self.cipher.max_plaintext_len() as u64
+            && ctx.tail.accepts(offset, ctx.attr.size)
+        {
+            // append at the end of the file, keep it in memory until we have a full block
+            if ctx.tail.buf.is_empty() {
+                ctx.tail.offset = offset;
+            }
+            // up to the end of the block, so if writing it fails the ones before are kept
+            let block_end = (offset / self.block_size as u64 + 1) * self.block_size as u64;
+            #[allow(clippy::cast_possible_truncation)]
+            let buf = &buf[..buf.len().min((block_end - offset) as usize)];
+            let (tail, size) = (ctx.tail.clone(), ctx.attr.size);
+            ctx.tail.buf.extend_from_slice(buf);
+            ctx.attr.size = ctx.tail.end();
+            let full = ctx.tail.buf.len() >= self.block_size;
+            if full {
+                let res = self.write_tail(&mut ctx).await;
+                let res = res.and_then(|()| Ok(ctx.writer.as_mut().unwrap().flush()?));
+                if let Err(err) = res {
+                    // take back only this write, the writer may hold the block so we start with a new one
+                    ctx.tail = tail;
+                    ctx.attr.size = size;
+                    let writer = self
+                        .create_write_seek(
+                            OpenOptions::new()
+                                .read(true)
+                                .write(true)
+                                .open(self.contents_path(ino))?,
+                        )
+                        .await?;
+                    ctx.writer = Some(Box::new(writer));
+                    return Err(err);
+                }
+            }
+            let now = self.now();
+            ctx.attr.mtime = now;
+            ctx.attr.ctime = now;
+            ctx.attr.atime = now;
+            drop(ctx);
+            drop(write_guard);
+            if full {
+                self.reset_handles(ino, Some(handle), true).await?;
+            }
+            self.sizes_write
+                .lock()
+                .await
+                .get_mut(&ino)
+                .unwrap()
+                .fetch_add(buf.len() as u64, Ordering::SeqCst);
+            return Ok(buf.len());
+        }
+        // the writer needs to see the pending appends before we write somewhere else
+        self.write_tail(&mut ctx).await?;
+        // if we write after the end, the zeros we fill with will change the last block
+        let from = offset.min(ctx.attr.size);
+        self.journal_blocks(&mut ctx, from, offset + buf.len() as u64)
+            .await?;
 
         // write new data
         let (pos, len) = {
-            if offset > self.cipher.max_plaintext_len() as u64 {
-                return Err(FsError::MaxFilesizeExceeded(
-                    self.cipher.max_plaintext_len(),
-                ));
-            }
             let writer = ctx.writer.as_mut().unwrap();
             let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
                 error!(err = %err, "seeking");
@@ -1656,7 +6610,7 @@ impl EncryptedFs {
             debug!("setting new file size {}", pos);
             ctx.attr.size = pos;
         }
-        let now = SystemTime::now();
+        let now = self.now();
         ctx.attr.mtime = now;
         ctx.attr.ctime = now;
         ctx.attr.atime = now;
@@ -1690,9 +6644,41 @@ impl EncryptedFs {
         Ok(len)
     }
 
-    /// Flush the data to the underlying storage.
+    /// Writes what `handle` buffered to the content file, for [`FsOptions::max_dirty_per_handle`]. Unlike
+    /// [`EncryptedFs::flush`] it doesn't sync, so the journal is kept, and the writer isn't reopened. At most the
+    /// last incomplete block is left in the writer.
+    pub(crate) async fn write_out(&self, handle: u64) -> FsResult<()> {
+        let lock = self.write_handles.read().await;
+        let Some(ctx) = lock.get(&handle) else {
+            return Err(FsError::InvalidFileHandle);
+        };
+        let mut ctx = ctx.lock().await;
+        let ino = ctx.ino;
+        let rw_lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let write_guard = rw_lock.write().await;
+        self.write_tail(&mut ctx).await?;
+        ctx.writer.as_mut().expect("writer is missing").flush()?;
+        drop(write_guard);
+        drop(ctx);
+        drop(lock);
+        self.reset_handles(ino, Some(handle), true).await?;
+        if let Some(info) = self.handle_infos.lock().unwrap().get_mut(&handle) {
+            info.dirty_bytes = 0;
+        }
+        Ok(())
+    }
+
+    /// Flush the data to the underlying storage and sync it to disk, so it's durable after it returns. With
+    /// [`FsOptions::write_journal`] the sync is needed anyway before dropping the journal of the overwritten blocks.
     #[allow(clippy::missing_panics_doc)]
     pub async fn flush(&self, handle: u64) -> FsResult<()> {
+        let res = self.flush2(handle).await;
+        self.record_error(res)
+    }
+
+    async fn flush2(&self, handle: u64) -> FsResult<()> {
         if handle == 0 {
             // in the case of directory or if the file was crated without being opened we don't use a handle
             return Ok(());
@@ -1700,6 +6686,10 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        if self.lower_handles.read().await.contains_key(&handle) {
+            // nothing to flush for reads from the lower layer
+            return Ok(());
+        }
         let lock = self.read_handles.read().await;
         let mut valid_fh = lock.get(&handle).is_some();
         let lock = self.write_handles.read().await;
@@ -1709,21 +6699,61 @@ impl EncryptedFs {
                 .read_write_locks
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
+            // write everything, including the last incomplete block
+            self.write_tail(&mut ctx).await?;
+            let file = ctx.writer.take().expect("writer is missing").finish()?;
+            file.sync_all()?;
             File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            self.commit_journal(&mut ctx)?;
+            self.update_parity(ctx.ino, Some(&std::mem::take(&mut ctx.parity_dirty)))?;
+            let writer = self
+                .create_write_seek(
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(self.contents_path(ctx.ino))?,
+                )
+                .await?;
+            ctx.writer = Some(Box::new(writer));
             drop(write_guard);
             let ino = ctx.ino;
             drop(ctx);
             self.reset_handles(ino, Some(handle), true).await?;
+            if let Some(info) = self.handle_infos.lock().unwrap().get_mut(&handle) {
+                info.dirty = false;
+                info.dirty_bytes = 0;
+            }
             valid_fh = true;
         }
 
         if !valid_fh {
             return Err(FsError::InvalidFileHandle);
         }
-
-        Ok(())
+
+        Ok(())
+    }
+
+    /// Flush all files opened for write.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn flush_all(&self) -> FsResult<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let handles = self
+            .write_handles
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        for handle in handles {
+            match self.flush(handle).await {
+                // released in the meantime
+                Ok(()) | Err(FsError::InvalidFileHandle) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.save_encryptions(false)
     }
 
     /// Helpful when we want to copy just some portions of the file.
@@ -1733,7 +6763,7 @@ impl EncryptedFs {
         size: usize,
     ) -> FsResult<usize> {
         if self.is_dir(file_range_req.src_ino) || self.is_dir(file_range_req.dest_ino) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::IsADirectory);
         }
         if self.read_only {
             return Err(FsError::ReadOnly);
@@ -1751,23 +6781,86 @@ impl EncryptedFs {
         if len == 0 {
             return Ok(0);
         }
-        let mut copied = 0;
-        while copied < size {
-            let len = self
-                .write(
-                    file_range_req.dest_ino,
-                    file_range_req.dest_offset,
-                    &buf[copied..len],
-                    file_range_req.dest_fh,
-                )
-                .await?;
-            if len == 0 && copied < size {
-                error!(len, "Failed to copy all read bytes");
-                return Err(FsError::Other("Failed to copy all read bytes"));
+        self.write_all(
+            file_range_req.dest_ino,
+            file_range_req.dest_offset,
+            &buf[..len],
+            file_range_req.dest_fh,
+        )
+        .await
+    }
+
+    /// Copy the file `src_ino` to `dst_name` in `dst_parent` without decrypting it, the new file shares the
+    /// encrypted blocks on disk with the source where the filesystem of the data dir supports it, like Btrfs and
+    /// XFS. A block is copied only when one of the files changes it, so it's instant and takes no space, like
+    /// `cp --reflink`. On other filesystems the encrypted content is copied, which is still faster than reading
+    /// and writing it.
+    ///
+    /// The permissions and owner are kept, the timestamps are new.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn reflink(
+        &self,
+        src_ino: u64,
+        dst_parent: u64,
+        dst_name: &SecretString,
+    ) -> FsResult<FileAttr> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let src = self.get_attr(src_ino).await?;
+        if src.kind != FileType::RegularFile {
+            return Err(wrong_file_type(src.kind));
+        }
+
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(src_ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        // the content not written yet
+        self.flush_and_reset_writers(src_ino).await?;
+        let src = self.get_attr(src_ino).await?;
+
+        let (_, attr) = self
+            .create(
+                dst_parent,
+                dst_name,
+                CreateFileAttr {
+                    kind: FileType::RegularFile,
+                    perm: src.perm,
+                    uid: src.uid,
+                    gid: src.gid,
+                    rdev: 0,
+                    flags: 0,
+                },
+                false,
+                false,
+            )
+            .await?;
+        if let Err(err) = self.reflink_contents(src_ino, attr.ino, src.size).await {
+            if let Err(err) = self.remove_file(dst_parent, dst_name).await {
+                error!(err = %err, "removing the copy after failing to write it");
             }
-            copied += len;
+            return Err(err);
         }
-        Ok(len)
+        self.get_attr(attr.ino).await
+    }
+
+    async fn reflink_contents(&self, src_ino: u64, dst_ino: u64, size: u64) -> FsResult<()> {
+        let dst = self.contents_path(dst_ino);
+        if let Some(lower) = self.lower_only(src_ino) {
+            // the lower layer can have another key
+            let mut reader = lower
+                .create_read(File::open(lower.contents_path(src_ino))?)
+                .await?;
+            let mut writer = self.create_write(File::create(&dst)?).await?;
+            io::copy(&mut reader, &mut writer)?;
+            writer.finish()?.sync_all()?;
+        } else if !fs_util::clone_file(&self.contents_path(src_ino), &dst)? {
+            debug!(src_ino, dst_ino, "blocks not shared, copied");
+        }
+        self.update_parity(dst_ino, None)?;
+        self.set_attr2(dst_ino, SetFileAttr::default().with_size(size), true)
+            .await
     }
 
     /// Open a file. We can open multiple times for read but only one to write at a time.
@@ -1776,13 +6869,33 @@ impl EncryptedFs {
         if write && self.read_only {
             return Err(FsError::ReadOnly);
         }
+        if write {
+            self.check_not_read_only_path(ino)?;
+        }
         if !read && !write {
             return Err(FsError::InvalidInput(
                 "read and write cannot be false at the same time",
             ));
         }
         if self.is_dir(ino) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::IsADirectory);
+        }
+        self.download_from_storage(ino).await?;
+        if write && self.get_attr(ino).await?.flags & FS_IMMUTABLE_FL != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        self.record_error(self.verify_on_open(ino).await)?;
+        if write {
+            self.copy_up(ino).await?;
+        } else if let Some(lower) = self.lower_only(ino) {
+            let lower_handle = Box::pin(lower.open(ino, true, false)).await?;
+            let handle = self.next_handle();
+            self.lower_handles
+                .write()
+                .await
+                .insert(handle, lower_handle);
+            self.insert_handle_info(handle, ino, true, false);
+            return Ok(handle);
         }
 
         let mut handle: Option<u64> = None;
@@ -1818,6 +6931,7 @@ impl EncryptedFs {
             res?;
         }
         let fh = handle.unwrap();
+        self.insert_handle_info(fh, ino, read, write);
         self.sizes_write
             .lock()
             .await
@@ -1843,10 +6957,13 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
-        info!("truncate {ino} to {size}");
+        self.check_not_read_only_path(ino)?;
         let attr = self.get_attr(ino).await?;
+        check_not_protected(&attr)?;
+        self.copy_up(ino).await?;
+        info!("truncate {ino} to {size}");
         if matches!(attr.kind, FileType::Directory) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::IsADirectory);
         }
 
         if size == attr.size {
@@ -1857,7 +6974,7 @@ impl EncryptedFs {
         let lock = self
             .read_write_locks
             .get_or_insert_with(ino, || RwLock::new(false));
-        let _write_guard = lock.write().await;
+        let write_guard = lock.write().await;
 
         // flush writers
         self.flush_and_reset_writers(ino).await?;
@@ -1896,8 +7013,9 @@ impl EncryptedFs {
             file.commit()?;
         }
         File::open(file_path.parent().unwrap())?.sync_all()?;
+        self.update_parity(ino, None)?;
 
-        let now = SystemTime::now();
+        let now = self.now();
         let set_attr = SetFileAttr::default()
             .with_size(size)
             .with_mtime(now)
@@ -1921,6 +7039,93 @@ impl EncryptedFs {
         if size != attr.size {
             error!("error truncating file expected {size} actual {}", attr.size);
         }
+        drop(write_guard);
+        self.upload_to_storage(ino, None).await?;
+
+        Ok(())
+    }
+
+    /// Replaces all the content of the file with `data`.
+    ///
+    /// The new content is written to a new file which then takes the place of the old one, so readers see either
+    /// all the old content or all the new one, never blocks of both. Like the atomic save of editors, without a
+    /// temporary file the caller needs to rename.
+    ///
+    /// The new size is saved in the journal of the file before the swap and in its attributes after it. If we crash
+    /// in between, opening the vault gives the file the size saved in the journal if the new content took the place
+    /// of the old one, so the size always matches the content.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn replace_contents(&self, ino: u64, data: &[u8]) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.check_not_read_only_path(ino)?;
+        let attr = self.get_attr(ino).await?;
+        check_not_protected(&attr)?;
+        if attr.kind != FileType::RegularFile {
+            return Err(wrong_file_type(attr.kind));
+        }
+        if data.len() > self.cipher.max_plaintext_len() {
+            return Err(FsError::MaxFilesizeExceeded(
+                self.cipher.max_plaintext_len(),
+            ));
+        }
+        if let Some(reserved) = self.options.reserved_space_bytes {
+            // the old content is there until the swap
+            let needed = reserved.saturating_add(data.len() as u64);
+            let available = fs_util::available_space(&self.data_dir)?;
+            if needed > available {
+                return Err(FsError::InsufficientSpace { needed, available });
+            }
+        }
+        self.copy_up(ino).await?;
+        self.replace_contents2(ino, data).await?;
+        self.upload_to_storage(ino, None).await
+    }
+
+    async fn replace_contents2(&self, ino: u64, data: &[u8]) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+
+        // flush writers, so they don't write the old content after us
+        self.flush_and_reset_writers(ino).await?;
+
+        let file_path = self.contents_path(ino);
+        let file = fs_util::open_atomic_write(&file_path)?;
+        let mut writer = self.create_write(file).await?;
+        writer.write_all(data)?;
+        let file = writer.finish()?;
+        file.as_file().sync_all()?;
+
+        if let Err(err) = self.journal_size(ino, data.len() as u64).await {
+            // dropping it would swap it in
+            file.discard()?;
+            return Err(err);
+        }
+        file.commit()?;
+        File::open(file_path.parent().unwrap())?.sync_all()?;
+        let now = self.now();
+        let set_attr = SetFileAttr::default()
+            .with_size(data.len() as u64)
+            .with_mtime(now)
+            .with_ctime(now)
+            .with_atime(now);
+        self.set_attr2(ino, set_attr, true).await?;
+        self.remove_journal(ino)?;
+        self.update_parity(ino, None)?;
+        // the writer still has the old size, which would win over the new one when merging
+        let fh = self.opened_files_for_write.read().await.get(&ino).copied();
+        if let Some(fh) = fh {
+            if let Some(ctx) = self.write_handles.read().await.get(&fh) {
+                ctx.lock().await.attr = self.get_inode_from_storage(ino).await?.into();
+            }
+        }
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
 
         Ok(())
     }
@@ -1943,10 +7148,13 @@ impl EncryptedFs {
             if let Some(lock) = ctx {
                 let mut ctx = lock.lock().await;
 
+                self.write_tail(&mut ctx).await?;
                 let mut writer = ctx.writer.take().unwrap();
                 let file = writer.finish()?;
                 file.sync_all()?;
                 File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                self.commit_journal(&mut ctx)?;
+                self.update_parity(ctx.ino, Some(&std::mem::take(&mut ctx.parity_dirty)))?;
                 let handle = *handle;
                 let set_attr: SetFileAttr = ctx.attr.clone().into();
                 drop(ctx);
@@ -1983,19 +7191,20 @@ impl EncryptedFs {
         if self.read_only {
             return Err(FsError::ReadOnly);
         }
+        check_entry_name(new_name)?;
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
         if !self.is_dir(parent) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::NotADirectory);
         }
         if !self.exists(new_parent) {
             return Err(FsError::InodeNotFound);
         }
         if !self.is_dir(new_parent) {
-            return Err(FsError::InvalidInodeType);
+            return Err(FsError::NotADirectory);
         }
-        if !self.exists_by_name(parent, name)? {
+        if !self.exists_by_name_async(parent, name).await? {
             return Err(FsError::NotFound("name not found"));
         }
 
@@ -2004,21 +7213,33 @@ impl EncryptedFs {
             return Ok(());
         }
 
+        let attr = self
+            .find_by_name(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        check_not_protected(&attr)?;
+        self.check_not_read_only_path(parent)?;
+        self.check_not_read_only_path(new_parent)?;
+        self.check_not_read_only_path(attr.ino)?;
+
         // Only overwrite an existing directory if it's empty
         if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
+            if new_attr.ino != attr.ino {
+                check_not_protected(&new_attr)?;
+                self.check_not_read_only_path(new_attr.ino)?;
+            }
+            // with case_insensitive it can be the same entry, renamed to change the case
+            if new_attr.ino != attr.ino
+                && new_attr.kind == FileType::Directory
+                && self.len(new_attr.ino)? > 0
+            {
                 return Err(FsError::NotEmpty);
             }
         }
-
-        let attr = self
-            .find_by_name(parent, name)
-            .await?
-            .ok_or(FsError::NotFound("name not found"))?;
         // remove from parent contents
         self.remove_directory_entry(parent, name).await?;
         // remove from new_parent contents, if exists
-        if self.exists_by_name(new_parent, new_name)? {
+        if self.exists_by_name_async(new_parent, new_name).await? {
             self.remove_directory_entry(new_parent, new_name).await?;
         }
         // add to new parent contents
@@ -2045,7 +7266,7 @@ impl EncryptedFs {
             .await?;
         }
 
-        let now = SystemTime::now();
+        let now = self.now();
         let set_attr = SetFileAttr::default()
             .with_mtime(now)
             .with_ctime(now)
@@ -2064,83 +7285,614 @@ impl EncryptedFs {
         Ok(())
     }
 
-    /// Create a crypto writer using internal encryption info.
-    pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
+    /// Create a crypto writer using internal encryption info.
+    pub async fn create_write<W: CryptoInnerWriter + Seek + Send + Sync + 'static>(
+        &self,
+        file: W,
+    ) -> FsResult<impl CryptoWrite<W>> {
+        Ok(crypto::create_write_with_transforms(
+            file,
+            self.cipher,
+            &*self.key.get().await?,
+            self.block_size,
+            self.write_transforms(),
+            Some(self.encryptions.clone()),
+        ))
+    }
+
+    /// Create a crypto writer with seek using internal encryption info.
+    pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
+        &self,
+        file: W,
+    ) -> FsResult<impl CryptoWriteSeek<W>> {
+        Ok(crypto::create_write_seek_with_transforms(
+            file,
+            self.cipher,
+            &*self.key.get().await?,
+            self.block_size,
+            self.write_transforms(),
+            Some(self.encryptions.clone()),
+        ))
+    }
+
+    /// Create a crypto reader using internal encryption info.
+    pub async fn create_read<R: Read + Send + Sync>(
+        &self,
+        reader: R,
+    ) -> FsResult<impl CryptoRead<R>> {
+        Ok(crypto::create_read_with_transforms(
+            reader,
+            self.cipher,
+            &*self.key.get().await?,
+            self.block_size,
+            self.options.block_transforms.clone(),
+        ))
+    }
+
+    /// Create a crypto reader with seek using internal encryption info.
+    pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
+        &self,
+        reader: R,
+    ) -> FsResult<impl CryptoReadSeek<R>> {
+        Ok(crypto::create_read_seek_with_transforms(
+            reader,
+            self.cipher,
+            &*self.key.get().await?,
+            self.block_size,
+            self.options.block_transforms.clone(),
+        ))
+    }
+
+    /// Change the password of the filesystem used to access the encryption key.
+    pub async fn passwd(
+        data_dir: &Path,
+        old_password: SecretBox<String>,
+        new_password: SecretBox<String>,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        Self::passwd_with_protection(data_dir, old_password, new_password, cipher, &PasswordOnly)
+            .await
+    }
+
+    /// Like [`EncryptedFs::passwd`], for data dirs created with a [`FsOptions::header_protection`].
+    ///
+    /// The new header replaces the old one atomically, so at every point the data dir opens with exactly one of the
+    /// passwords. After writing it we check the new password opens it, if not the old header is put back and it still
+    /// opens with the old password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn passwd_with_protection(
+        data_dir: &Path,
+        old_password: SecretBox<String>,
+        new_password: SecretBox<String>,
+        cipher: Cipher,
+        header_protection: &dyn HeaderProtection,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let key = decrypt_key(data_dir, &old_password, cipher, header_protection)?;
+        let salt: Vec<u8> = bincode::deserialize_from(File::open(
+            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        )?)?;
+        // encrypt it with a new key derived from new password
+        let new_key = derive_key(&new_password, cipher, &salt, header_protection)?;
+        let key_path = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        let old_header = fs::read(&key_path)?;
+        let res = write_key_file(
+            &key_path,
+            &key.expose_secret(),
+            cipher,
+            stored_key_wrap(data_dir),
+            &new_key,
+            header_protection,
+        )
+        .and_then(|()| {
+            let opened = decrypt_key(data_dir, &new_password, cipher, header_protection)?;
+            if opened.expose_secret() != key.expose_secret() {
+                return Err(FsError::Other("the new password opens another key"));
+            }
+            Ok(())
+        });
+        if let Err(err) = res {
+            error!(err = %err, "changing the password, putting back the old one");
+            let mut file = fs_util::open_atomic_write(&key_path)?;
+            file.write_all(&old_header)?;
+            file.commit()?;
+            File::open(data_dir.join(SECURITY_DIR))?.sync_all()?;
+            decrypt_key(data_dir, &old_password, cipher, header_protection)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// If `password` opens the data dir, without opening it, like for a tool checking the passwords of many of them.
+    ///
+    /// The first check in a process costs the slow derivation of the key from the password, like opening it. If it's
+    /// right, checking it again in the same process is fast, until the header of the data dir changes, like with
+    /// [`EncryptedFs::passwd`]. What's kept for that is a tag of the password and the cipher with a key new for each
+    /// process, only in memory. A wrong password is slow each time.
+    ///
+    /// # Errors
+    ///
+    /// If the files with the key can't be read.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn check_password(
+        data_dir: &Path,
+        password: &SecretString,
+        cipher: Cipher,
+    ) -> FsResult<bool> {
+        let dir = data_dir.join(SECURITY_DIR);
+        let header_tag = {
+            let mut header = vec![];
+            for part in [
+                fs::read(dir.join(KEY_ENC_FILENAME))?,
+                fs::read(dir.join(KEY_SALT_FILENAME))?,
+                cipher.to_string().into_bytes(),
+            ] {
+                header.extend_from_slice(&(part.len() as u64).to_le_bytes());
+                header.extend_from_slice(&part);
+            }
+            crypto::derive_subkey(&CHECKED_PASSWORDS_KEY, &header)
+        };
+        let password_tag =
+            crypto::derive_subkey(&CHECKED_PASSWORDS_KEY, password.expose_secret().as_bytes());
+        let checked = (
+            password_tag.expose_secret().clone(),
+            header_tag.expose_secret().clone(),
+        );
+        let data_dir = data_dir.canonicalize()?;
+        if CHECKED_PASSWORDS.lock().unwrap().get(&data_dir) == Some(&checked) {
+            return Ok(true);
+        }
+
+        let right =
+            Self::check_password_with_protection(&data_dir, password, cipher, &PasswordOnly)?;
+        if right {
+            CHECKED_PASSWORDS.lock().unwrap().insert(data_dir, checked);
+        }
+        Ok(right)
+    }
+
+    /// Like [`EncryptedFs::check_password`], for data dirs created with a [`FsOptions::header_protection`].
+    ///
+    /// It's slow each time, nothing is remembered so the token is always asked, like when opening the data dir.
+    ///
+    /// # Errors
+    ///
+    /// Like [`EncryptedFs::check_password`], or if the [`HeaderProtection`] fails.
+    pub fn check_password_with_protection(
+        data_dir: &Path,
+        password: &SecretString,
+        cipher: Cipher,
+        header_protection: &dyn HeaderProtection,
+    ) -> FsResult<bool> {
+        let dir = data_dir.join(SECURITY_DIR);
+        let key_enc = fs::read(dir.join(KEY_ENC_FILENAME))?;
+        let salt: Vec<u8> = bincode::deserialize(&fs::read(dir.join(KEY_SALT_FILENAME))?)?;
+        let header = header_protection.unprotect(&key_enc)?;
+        let derived_key = derive_key(password, cipher, &salt, header_protection)?;
+        Ok(crypto::unwrap_key(stored_key_wrap(data_dir), cipher, &derived_key, &header).is_ok())
+    }
+
+    /// The small files needed to open the data dir, besides the password: the encrypted key, its salt and the
+    /// params, like the block size. Without them the data is lost even with the password, keep this somewhere
+    /// else as a backup and restore it with [`EncryptedFs::import_header`].
+    ///
+    /// The key is encrypted with the password, like in the data dir, so the backup is as safe as the data dir. After
+    /// changing the password export it again, the old one still opens with the old password.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn export_header(data_dir: &Path) -> FsResult<Vec<u8>> {
+        let dir = data_dir.join(SECURITY_DIR);
+        let params = dir.join(PARAMS_FILENAME);
+        let header = HeaderBackup {
+            magic: HEADER_BACKUP_MAGIC,
+            key_enc: fs::read(dir.join(KEY_ENC_FILENAME))?,
+            key_salt: fs::read(dir.join(KEY_SALT_FILENAME))?,
+            params: if params.exists() {
+                Some(fs::read(params)?)
+            } else {
+                None
+            },
+        };
+        Ok(bincode::serialize(&header)?)
+    }
+
+    /// Restore the files from [`EncryptedFs::export_header`] in a data dir that lost them, or where they are
+    /// corrupted, so it can be opened again. Existing ones are replaced.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn import_header(data_dir: &Path, header: &[u8]) -> FsResult<()> {
+        let header = HeaderBackup::parse(header)?;
+        let dir = data_dir.join(SECURITY_DIR);
+        fs::create_dir_all(&dir)?;
+        let write = |name: &str, data: &[u8]| -> FsResult<()> {
+            let mut file = fs_util::open_atomic_write(&dir.join(name))?;
+            file.write_all(data)?;
+            file.commit()?;
+            Ok(())
+        };
+        write(KEY_SALT_FILENAME, &header.key_salt)?;
+        if let Some(params) = &header.params {
+            write(PARAMS_FILENAME, params)?;
+        } else if dir.join(PARAMS_FILENAME).exists() {
+            // created before we had params, the defaults are used
+            fs::remove_file(dir.join(PARAMS_FILENAME))?;
+        }
+        // last, so the data dir is not considered initialized until all are there
+        write(KEY_ENC_FILENAME, &header.key_enc)?;
+        File::open(&dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Re-encrypts the content of all files in blocks of `new_block_size` bytes. Bigger blocks have less overhead and
+    /// suit large files read sequentially, smaller ones suit random access.
+    ///
+    /// This is an offline operation, the filesystem must not be in use. Each file is converted atomically, so if it's
+    /// interrupted just run it again with the same block size and it will continue with the remaining files, until
+    /// then the filesystem can't be opened.
+    ///
+    /// `progress` is called after each file with the number of processed files and the total.
+    ///
+    /// Size must be between [`MIN_BLOCK_SIZE`] and [`MAX_BLOCK_SIZE`], and we need enough free space for a
+    /// converted copy of the largest file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn change_block_size(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+        new_block_size: usize,
+        progress: impl FnMut(u64, u64) + Send,
+    ) -> FsResult<()> {
+        Self::change_block_size_with_protection(
+            data_dir,
+            password,
+            cipher,
+            new_block_size,
+            progress,
+            &PasswordOnly,
+        )
+        .await
+    }
+
+    /// Like [`EncryptedFs::change_block_size`], for data dirs created with a [`FsOptions::header_protection`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn change_block_size_with_protection(
+        data_dir: &Path,
+        password: SecretString,
+        cipher: Cipher,
+        new_block_size: usize,
+        mut progress: impl FnMut(u64, u64) + Send,
+        header_protection: &dyn HeaderProtection,
+    ) -> FsResult<()> {
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&new_block_size) {
+            return Err(FsError::InvalidInput("block size out of range"));
+        }
+        check_structure(data_dir, false).await?;
+        let key = decrypt_key(data_dir, &password, cipher, header_protection)?;
+        let mut params = VaultParams::load(data_dir)?;
+        match params.pending_block_size {
+            Some(pending) if pending != new_block_size => {
+                return Err(FsError::BlockSizeChangeUnfinished(pending));
+            }
+            None if params.block_size == new_block_size => return Ok(()),
+            _ => {}
+        }
+        let journal_dir = data_dir.join(JOURNAL_DIR);
+        if journal_dir.exists() && fs::read_dir(&journal_dir)?.next().is_some() {
+            return Err(FsError::Other(
+                "some files were not closed properly, open the filesystem first to recover them",
+            ));
+        }
+
+        // files not yet converted, if we are resuming some of them might be
+        let mut files = vec![];
+        for entry in fs::read_dir(data_dir.join(CONTENTS_DIR))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file()
+                || entry.file_name().to_string_lossy().parse::<u64>().is_err()
+            {
+                continue;
+            }
+            let path = entry.path();
+            if first_block_valid(&path, cipher, &key, params.block_size)? {
+                files.push(path);
+            }
+        }
+        // we convert one file at a time, so we need space for the largest one
+        let mut needed = 0;
+        for path in &files {
+            let len = plaintext_len(fs::metadata(path)?.len(), cipher, params.block_size);
+            needed = needed.max(ciphertext_len(len, cipher, new_block_size));
+        }
+        let available = fs_util::available_space(data_dir)?;
+        if needed > available {
+            return Err(FsError::InsufficientSpace { needed, available });
+        }
+
+        params.pending_block_size = Some(new_block_size);
+        params.save(data_dir)?;
+        let total = files.len() as u64;
+        for (done, path) in files.iter().enumerate() {
+            let len = plaintext_len(fs::metadata(path)?.len(), cipher, params.block_size);
+            let mut file = fs_util::open_atomic_write(path)?;
+            {
+                let mut reader = crypto::create_read_with_block_size(
+                    File::open(path)?,
+                    cipher,
+                    &key,
+                    params.block_size,
+                );
+                let mut writer =
+                    crypto::create_write_with_block_size(file, cipher, &key, new_block_size);
+                io::copy(&mut reader, &mut writer)?;
+                file = writer.finish()?;
+            }
+            file.commit()?;
+            params.encryptions += len.div_ceil(new_block_size as u64);
+            progress(done as u64 + 1, total);
+        }
+        File::open(data_dir.join(CONTENTS_DIR))?.sync_all()?;
+        // parity is for the old blocks, it's computed again when files are written
+        if data_dir.join(PARITY_DIR).exists() {
+            fs::remove_dir_all(data_dir.join(PARITY_DIR))?;
+        }
+        params.block_size = new_block_size;
+        params.pending_block_size = None;
+        params.save(data_dir)?;
+        Ok(())
+    }
+
+    /// Writes the pending appends to the writer, saving in the journal the blocks they change.
+    async fn write_tail(&self, ctx: &mut WriteHandleContext) -> FsResult<()> {
+        if ctx.tail.buf.is_empty() {
+            return Ok(());
+        }
+        let (from, to) = (ctx.tail.offset, ctx.tail.end());
+        self.journal_blocks(ctx, from, to).await?;
+        ctx.drain_tail()?;
+        Ok(())
+    }
+
+    /// Marks the blocks a write in `[from, to)` would change as dirty, see [`DirtyBlocks`]. With
+    /// [`FsOptions::write_journal`] it also saves the original encrypted ones, if we didn't already, so
+    /// [`EncryptedFs::recover_journal`] can bring the file back to the last synced state if we crash before all
+    /// changed blocks are written.
+    async fn journal_blocks(
         &self,
-        file: W,
-    ) -> FsResult<impl CryptoWrite<W>> {
-        Ok(crypto::create_write(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+        ctx: &mut WriteHandleContext,
+        from: u64,
+        to: u64,
+    ) -> FsResult<()> {
+        if from >= to {
+            return Ok(());
+        }
+        let (start, end) = (
+            from / self.block_size as u64,
+            (to - 1) / self.block_size as u64 + 1,
+        );
+        ctx.dirty.insert(start, end);
+        ctx.parity_dirty.insert(start, end);
+        if !self.options.write_journal {
+            return Ok(());
+        }
+        let dir = self.journal_path(ctx.ino);
+        let contents = self.contents_path(ctx.ino);
+        let key = self.key.get().await?;
+        if ctx.journaled.is_none() {
+            fs::create_dir_all(&dir)?;
+            let len = fs::metadata(&contents)?.len();
+            crypto::atomic_serialize_encrypt_into(
+                &dir.join(JOURNAL_LEN_FILENAME),
+                &len,
+                self.cipher,
+                &key,
+            )?;
+            self.encryptions.add(1);
+            File::open(dir.parent().unwrap())?.sync_all()?;
+            ctx.journaled = Some((len, HashSet::new()));
+        }
+        let (len, journaled) = ctx.journaled.as_mut().unwrap();
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        // blocks after the original end are new, we just truncate them on recovery
+        let blocks = len.div_ceil(ciphertext_block_len);
+        let first = from / self.block_size as u64;
+        let last = ((to - 1) / self.block_size as u64).min(blocks.saturating_sub(1));
+        if blocks == 0 || first > last {
+            return Ok(());
+        }
+        let mut file = File::open(&contents)?;
+        for index in first..=last {
+            if !journaled.insert(index) {
+                continue;
+            }
+            let mut block = vec![];
+            file.seek(SeekFrom::Start(index * ciphertext_block_len))?;
+            (&mut file)
+                .take(ciphertext_block_len)
+                .read_to_end(&mut block)?;
+            crypto::atomic_serialize_encrypt_into(
+                &dir.join(index.to_string()),
+                &block,
+                self.cipher,
+                &key,
+            )?;
+            self.encryptions
+                .add(1 + (block.len() / self.block_size) as u64);
+        }
+        Ok(())
     }
 
-    /// Create a crypto writer with seek using internal encryption info.
-    pub async fn create_write_seek<W: Write + Seek + Read + Send + Sync + 'static>(
-        &self,
-        file: W,
-    ) -> FsResult<impl CryptoWriteSeek<W>> {
-        Ok(crypto::create_write_seek(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+    /// The changes are synced, we don't need the original blocks anymore.
+    fn commit_journal(&self, ctx: &mut WriteHandleContext) -> FsResult<()> {
+        if ctx.journaled.take().is_none() {
+            return Ok(());
+        }
+        self.remove_journal(ctx.ino)
     }
 
-    /// Create a crypto reader using internal encryption info.
-    pub async fn create_read<R: Read + Send + Sync>(
-        &self,
-        reader: R,
-    ) -> FsResult<impl CryptoRead<R>> {
-        Ok(crypto::create_read(
-            reader,
+    /// Saves `size` in the journal of `ino`, before [`EncryptedFs::replace_contents`] swaps the content, see
+    /// [`EncryptedFs::restore_journal`]. The writers are flushed before, so there is no other journal of the file.
+    async fn journal_size(&self, ino: u64, size: u64) -> FsResult<()> {
+        let dir = self.journal_path(ino);
+        fs::create_dir_all(&dir)?;
+        crypto::atomic_serialize_encrypt_into(
+            &dir.join(JOURNAL_SIZE_FILENAME),
+            &size,
             self.cipher,
             &*self.key.get().await?,
-        ))
+        )?;
+        self.encryptions.add(1);
+        File::open(dir.parent().unwrap())?.sync_all()?;
+        Ok(())
     }
 
-    /// Create a crypto reader with seek using internal encryption info.
-    pub async fn create_read_seek<R: Read + Seek + Send + Sync>(
-        &self,
-        reader: R,
-    ) -> FsResult<impl CryptoReadSeek<R>> {
-        Ok(crypto::create_read_seek(
-            reader,
-            self.cipher,
-            &*self.key.get().await?,
-        ))
+    fn remove_journal(&self, ino: u64) -> FsResult<()> {
+        let dir = self.journal_path(ino);
+        if dir.exists() {
+            // rename first, so we don't recover from a partially deleted journal
+            let done = dir.with_extension("done");
+            fs::rename(&dir, &done)?;
+            File::open(self.data_dir.join(JOURNAL_DIR))?.sync_all()?;
+            fs::remove_dir_all(done)?;
+        }
+        Ok(())
     }
 
-    /// Change the password of the filesystem used to access the encryption key.
-    pub async fn passwd(
-        data_dir: &Path,
-        old_password: SecretBox<String>,
-        new_password: SecretBox<String>,
-        cipher: Cipher,
-    ) -> FsResult<()> {
-        check_structure(data_dir, false).await?;
-        // decrypt key
-        let salt: Vec<u8> = bincode::deserialize_from(File::open(
-            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-        )?)?;
-        let initial_key = crypto::derive_key(&old_password, cipher, &salt)?;
-        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
-        let reader = crypto::create_read(File::open(enc_file)?, cipher, &initial_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        let key = SecretBox::new(Box::new(key));
-        // encrypt it with a new key derived from new password
-        let new_key = crypto::derive_key(&new_password, cipher, &salt)?;
-        crypto::atomic_serialize_encrypt_into(
-            &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            &*key.expose_secret(),
-            cipher,
-            &new_key,
-        )?;
+    /// If we crashed while writing, some blocks of a file might be from the new content and others from the old one.
+    /// Write back the original blocks from the journal so the file is like it was before the changes.
+    async fn recover_journal(&self) -> FsResult<()> {
+        let dir = self.data_dir.join(JOURNAL_DIR);
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(ino) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<u64>().ok())
+            else {
+                // leftover from a commit
+                if !self.read_only {
+                    fs::remove_dir_all(&path)?;
+                }
+                continue;
+            };
+            if self.read_only {
+                warn!(
+                    ino,
+                    "file was not closed properly, cannot recover it in read-only mode"
+                );
+                continue;
+            }
+            if self.contents_path(ino).is_file() && path.join(JOURNAL_LEN_FILENAME).is_file() {
+                warn!(ino, "file was not closed properly, recovering it");
+            }
+            self.restore_journal(ino, &path).await?;
+            if self.contents_path(ino).is_file() {
+                self.upload_to_storage(ino, None).await?;
+            }
+        }
+        if !self.read_only {
+            File::open(&dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Writes back the original blocks saved in the journal at `path`, or sets the size saved there by
+    /// [`EncryptedFs::replace_contents`], and removes it.
+    ///
+    /// Only the data dir is changed, the callers upload the content to the [`Storage::Mirror`] after, as it might
+    /// have some of the blocks we brought back, and uploading needs the lock of the file they might hold here.
+    async fn restore_journal(&self, ino: u64, path: &Path) -> FsResult<()> {
+        let ciphertext_block_len = self.ciphertext_block_len() as u64;
+        let contents = self.contents_path(ino);
+        let len_path = path.join(JOURNAL_LEN_FILENAME);
+        // if we don't have the length we crashed before changing anything
+        if contents.is_file() && len_path.is_file() {
+            let key = self.key.get().await?;
+            let len: u64 = bincode::deserialize_from(crypto::create_read(
+                File::open(&len_path)?,
+                self.cipher,
+                &key,
+            ))?;
+            let mut file = OpenOptions::new().write(true).open(&contents)?;
+            for block in fs::read_dir(path)? {
+                let block = block?.path();
+                let Some(index) = block
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.parse::<u64>().ok())
+                else {
+                    continue;
+                };
+                let data: Vec<u8> = bincode::deserialize_from(crypto::create_read(
+                    File::open(&block)?,
+                    self.cipher,
+                    &key,
+                ))?;
+                file.seek(SeekFrom::Start(index * ciphertext_block_len))?;
+                file.write_all(&data)?;
+            }
+            file.set_len(len)?;
+            file.sync_all()?;
+            // size from the content we recovered
+            let size = plaintext_len(len, self.cipher, self.block_size);
+            self.set_attr2(ino, SetFileAttr::default().with_size(size), true)
+                .await?;
+        }
+        let size_path = path.join(JOURNAL_SIZE_FILENAME);
+        if contents.is_file() && size_path.is_file() {
+            let size: u64 = bincode::deserialize_from(crypto::create_read(
+                File::open(&size_path)?,
+                self.cipher,
+                &*self.key.get().await?,
+            ))?;
+            // the length of the content grows with the size, if it matches the new content took the place of the old
+            // one, else we crashed before and the old size is right
+            if fs::metadata(&contents)?.len() == ciphertext_len(size, self.cipher, self.block_size)
+            {
+                self.set_attr2(ino, SetFileAttr::default().with_size(size), true)
+                    .await?;
+            }
+        }
+        let done = path.with_extension("done");
+        fs::rename(path, &done)?;
+        fs::remove_dir_all(done)?;
         Ok(())
     }
 
+    /// Copy of the appends not yet written by the writer of this file, if any.
+    async fn append_buffer_snapshot(&self, ino: u64) -> Option<AppendBuffer> {
+        let fh = self
+            .opened_files_for_write
+            .read()
+            .await
+            .get(&ino)
+            .copied()?;
+        let guard = self.write_handles.read().await;
+        let ctx = guard.get(&fh)?.lock().await;
+        if ctx.tail.buf.is_empty() {
+            None
+        } else {
+            Some(ctx.tail.clone())
+        }
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    fn insert_handle_info(&self, fh: u64, ino: u64, read: bool, write: bool) {
+        self.handle_infos.lock().unwrap().insert(
+            fh,
+            HandleInfo {
+                fh,
+                ino,
+                read,
+                write,
+                dirty: false,
+                bytes_written: 0,
+                dirty_bytes: 0,
+                opened_at: SystemTime::now(),
+            },
+        );
+    }
+
     fn next_handle(&self) -> u64 {
         self.current_handle
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
@@ -2193,10 +7945,13 @@ impl EncryptedFs {
             let lock = self.write_handles.read().await;
             if let Some(lock) = lock.get(fh) {
                 let mut ctx = lock.lock().await;
+                self.write_tail(&mut ctx).await?;
                 let writer = ctx.writer.as_mut().unwrap();
                 let file = writer.finish()?;
                 file.sync_all()?;
                 File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                self.commit_journal(&mut ctx)?;
+                self.update_parity(ctx.ino, Some(&std::mem::take(&mut ctx.parity_dirty)))?;
                 let set_attr: Option<SetFileAttr> = if save_attr {
                     Some(ctx.attr.clone().into())
                 } else {
@@ -2268,6 +8023,10 @@ impl EncryptedFs {
                     ino,
                     attr,
                     writer: Some(Box::new(writer)),
+                    tail: AppendBuffer::default(),
+                    journaled: None,
+                    dirty: DirtyBlocks::default(),
+                    parity_dirty: DirtyBlocks::default(),
                 };
                 self.write_handles
                     .write()
@@ -2328,9 +8087,35 @@ impl EncryptedFs {
         ino_contents_dir: u64,
         entry: &DirectoryEntry,
     ) -> FsResult<()> {
+        self.copy_up(ino_contents_dir).await?;
         let parent_path = self.contents_path(ino_contents_dir);
-        let encrypted_name =
-            crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
+        let name = self.stored_name(&entry.name);
+        let encrypted_name = if self.encrypt_names {
+            if self.name_cipher == NameCipher::Content {
+                // the others use a subkey
+                self.encryptions.add(1);
+            }
+            crypto::encrypt_file_name_with(
+                &name,
+                self.name_cipher,
+                self.cipher,
+                &*self.key.get().await?,
+                &*self.name_keys.get().await?,
+            )?
+        } else {
+            match name.expose_secret().as_str() {
+                "." | ".." => format!("${}", name.expose_secret()),
+                name => name.to_string(),
+            }
+        };
+        if let Some(db) = &self.metadata_db {
+            // we save the encrypted name also because we need it to list the entries
+            let hash = self.name_hash(&entry.name).await?;
+            let value = self
+                .encrypt_db_value(&(entry.ino, entry.kind, encrypted_name))
+                .await?;
+            return db.put_entry(ino_contents_dir, &hash, &value);
+        }
         // add to LS directory
         let self_clone = self
             .self_weak
@@ -2356,12 +8141,20 @@ impl EncryptedFs {
             let _guard = lock.write().await;
             // write inode and file type
             let entry = (entry_clone.ino, entry_clone.kind);
-            crypto::atomic_serialize_encrypt_into(
+            self_clone.atomic_serialize_metadata(
                 &file_path,
                 &entry,
-                self_clone.cipher,
                 &*self_clone.key.get().await?,
             )?;
+            self_clone.encryptions.add(1);
+            // an entry with the same name may have been cached with another inode and type
+            self_clone
+                .dir_entries_meta_cache
+                .get()
+                .await?
+                .lock()
+                .await
+                .put(file_path.to_str().unwrap().to_string(), entry);
             Ok::<(), FsError>(())
         });
         // add to HASH directory
@@ -2375,7 +8168,7 @@ impl EncryptedFs {
             .unwrap();
         let entry_hash = entry.clone();
         tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
+            let name = self_clone.name_hash(&entry_hash.name).await?;
             let file_path = parent_path.join(HASH_DIR).join(name);
             let lock = self_clone
                 .serialize_dir_entries_hash_locks
@@ -2386,12 +8179,12 @@ impl EncryptedFs {
             // write inode and file type
             // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
             let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
-            crypto::atomic_serialize_encrypt_into(
+            self_clone.atomic_serialize_metadata(
                 &file_path,
                 &entry,
-                self_clone.cipher,
                 &*self_clone.key.get().await?,
             )?;
+            self_clone.encryptions.add(1);
             Ok::<(), FsError>(())
         })
         .await??;
@@ -2399,6 +8192,116 @@ impl EncryptedFs {
         Ok(())
     }
 
+    fn remove_inode_from_storage(&self, ino: u64) -> FsResult<()> {
+        if let Some(db) = &self.metadata_db {
+            db.remove_inode(ino)
+        } else {
+            fs::remove_file(self.ino_file(ino))?;
+            Ok(())
+        }
+    }
+
+    /// Encrypt a value to keep in [`MetadataDb`], the same as we do for the files of [`MetadataStore::Files`].
+    async fn encrypt_db_value<T: Serialize + ?Sized>(&self, value: &T) -> FsResult<Vec<u8>> {
+        let key = self.key.get().await?;
+        let cursor = if self.compress_metadata {
+            crypto::serialize_encrypt_into_with(
+                io::Cursor::new(vec![]),
+                value,
+                crypto::varint_encoding(),
+                self.cipher,
+                &key,
+            )?
+        } else {
+            crypto::serialize_encrypt_into_with(
+                io::Cursor::new(vec![]),
+                value,
+                crypto::fixint_encoding(),
+                self.cipher,
+                &key,
+            )?
+        };
+        self.encryptions.add(1);
+        Ok(cursor.into_inner())
+    }
+
+    /// Write attributes or directory entries with the encoding from [`FsOptions::compress_metadata`].
+    fn atomic_serialize_metadata<T: Serialize + ?Sized>(
+        &self,
+        file: &Path,
+        value: &T,
+        key: &SecretVec<u8>,
+    ) -> crypto::Result<()> {
+        if self.compress_metadata {
+            crypto::atomic_serialize_encrypt_into_with(
+                file,
+                value,
+                crypto::varint_encoding(),
+                self.cipher,
+                key,
+            )
+        } else {
+            crypto::atomic_serialize_encrypt_into_with(
+                file,
+                value,
+                crypto::fixint_encoding(),
+                self.cipher,
+                key,
+            )
+        }
+    }
+
+    /// Read what [`Self::atomic_serialize_metadata`] wrote.
+    fn deserialize_metadata<T: serde::de::DeserializeOwned, R: Read>(
+        &self,
+        reader: R,
+    ) -> bincode::Result<T> {
+        use bincode::Options;
+        if self.compress_metadata {
+            crypto::varint_encoding().deserialize_from(reader)
+        } else {
+            crypto::fixint_encoding().deserialize_from(reader)
+        }
+    }
+
+    async fn decrypt_db_value<T: serde::de::DeserializeOwned>(&self, data: &[u8]) -> FsResult<T> {
+        Ok(self.deserialize_metadata(crypto::create_read(
+            data,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?)
+    }
+
+    /// Key of a directory entry, the same for names differing only by case with [`FsOptions::case_insensitive`] or
+    /// by the Unicode normal form with [`FsOptions::normalize_names`]. Keyed with a deterministic
+    /// [`FsOptions::name_cipher`].
+    async fn name_hash(&self, name: &SecretString) -> FsResult<String> {
+        let key = if !self.case_insensitive && !self.normalize_names {
+            name.expose_secret().clone()
+        } else if self.case_insensitive {
+            fold_case(&self.stored_name(name).expose_secret())
+        } else {
+            self.stored_name(name).expose_secret().clone()
+        };
+        let key = SecretString::new(Box::new(key));
+        if self.name_cipher.is_deterministic() {
+            return Ok(self.name_keys.get().await?.hash_file_name(&key));
+        }
+        Ok(crypto::hash_file_name(&key))
+    }
+
+    /// The name as we store it in the directory entry, see [`FsOptions::normalize_names`].
+    fn stored_name(&self, name: &SecretString) -> SecretString {
+        if self.normalize_names {
+            let nfc = ComposingNormalizerBorrowed::new_nfc()
+                .normalize(&name.expose_secret())
+                .into_owned();
+            SecretString::new(Box::new(nfc))
+        } else {
+            name.clone()
+        }
+    }
+
     fn ino_file(&self, ino: u64) -> PathBuf {
         self.data_dir.join(INODES_DIR).join(ino.to_string())
     }
@@ -2407,17 +8310,44 @@ impl EncryptedFs {
         self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
     }
 
+    fn journal_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(JOURNAL_DIR).join(ino.to_string())
+    }
+
+    fn parity_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(PARITY_DIR).join(ino.to_string())
+    }
+
+    fn xattr_path(&self, ino: u64) -> PathBuf {
+        self.data_dir.join(XATTR_DIR).join(ino.to_string())
+    }
+
     async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
         let parent_path = self.contents_path(parent);
+        let hash = self.name_hash(name).await?;
+        if let Some(db) = &self.metadata_db {
+            db.remove_entry(parent, &hash)?
+                .ok_or(FsError::NotFound("name not found"))?;
+            return Ok(());
+        }
+        if self.lower_with_name(parent, name).await?.is_some() {
+            // hide it in the lower layer
+            self.copy_up(parent).await?;
+            fs::create_dir_all(parent_path.join(WHITEOUT_DIR))?;
+            File::create(parent_path.join(WHITEOUT_DIR).join(&hash))?;
+            self.sync_dirs(&[parent_path.join(WHITEOUT_DIR), parent_path.clone()])?;
+            if !parent_path.join(HASH_DIR).join(&hash).is_file() {
+                return Ok(());
+            }
+        }
         // remove from HASH
-        let name = crypto::hash_file_name(name);
-        let path = parent_path.join(HASH_DIR).join(name);
+        let path = parent_path.join(HASH_DIR).join(hash);
         let lock = self
             .serialize_dir_entries_hash_locks
             .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
         let guard = lock.write().await;
         let (_, _, name): (u64, FileType, String) =
-            bincode::deserialize_from(crypto::create_read(
+            self.deserialize_metadata(crypto::create_read(
                 File::open(path.clone())?,
                 self.cipher,
                 &*self.key.get().await?,
@@ -2430,7 +8360,26 @@ impl EncryptedFs {
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
         let _guard = lock.write().await;
-        fs::remove_file(path)?;
+        fs::remove_file(&path)?;
+        self.dir_entries_meta_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .pop(path.to_str().unwrap());
+        self.sync_dirs(&[parent_path.join(HASH_DIR), parent_path.join(LS_DIR)])?;
+        Ok(())
+    }
+
+    /// Make the changes to the entries of these directories durable, if [`FsOptions::sync_metadata`] is enabled.
+    ///
+    /// Files written with [`crypto::atomic_serialize_encrypt_into`] are already synced with their directory.
+    fn sync_dirs(&self, dirs: &[PathBuf]) -> FsResult<()> {
+        if self.options.sync_metadata {
+            for dir in dirs {
+                File::open(dir)?.sync_all()?;
+            }
+        }
         Ok(())
     }
 
@@ -2481,10 +8430,13 @@ impl CopyFileRangeReq {
 }
 
 fn read_or_create_key(
-    key_path: &PathBuf,
+    key_path: &Path,
     salt_path: &PathBuf,
+    header: Option<Vec<u8>>,
     password: &SecretString,
     cipher: Cipher,
+    key_wrap: KeyWrapAlgorithm,
+    header_protection: &dyn HeaderProtection,
 ) -> FsResult<SecretVec<u8>> {
     let salt = if salt_path.exists() {
         bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?
@@ -2504,37 +8456,88 @@ fn read_or_create_key(
         salt
     };
     // derive key from password
-    let derived_key = crypto::derive_key(password, cipher, &salt)?;
-    if key_path.exists() {
+    let derived_key = derive_key(password, cipher, &salt, header_protection)?;
+    if let Some(header) = header {
         // read key
-        let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        Ok(SecretBox::new(Box::new(key)))
+        crypto::unwrap_key(key_wrap, cipher, &derived_key, &header)
+            .map_err(|_| FsError::InvalidPassword)
     } else {
         // first time, create a random key and encrypt it with the derived key from password
         let mut key: Vec<u8> = vec![];
         let key_len = cipher.key_len();
         key.resize(key_len, 0);
         crypto::create_rng().fill_bytes(&mut key);
-        let mut writer = crypto::create_write(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(key_path)?,
+        write_key_file(
+            key_path,
+            &key,
             cipher,
+            key_wrap,
             &derived_key,
-        );
-        bincode::serialize_into(&mut writer, &key)?;
-        let file = writer.finish()?;
-        file.sync_all()?;
-        File::open(key_path.parent().unwrap())?.sync_all()?;
+            header_protection,
+        )?;
         Ok(SecretBox::new(Box::new(key)))
     }
 }
 
+/// Encrypt `key` with the key derived from the password and save it protected by `header_protection`.
+fn write_key_file(
+    key_path: &Path,
+    key: &[u8],
+    cipher: Cipher,
+    key_wrap: KeyWrapAlgorithm,
+    derived_key: &SecretVec<u8>,
+    header_protection: &dyn HeaderProtection,
+) -> FsResult<()> {
+    let encrypted = crypto::wrap_key(key_wrap, cipher, derived_key, key)?;
+    let mut file = fs_util::open_atomic_write(key_path)?;
+    file.write_all(&header_protection.protect(&encrypted)?)?;
+    file.commit()?;
+    File::open(key_path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
+    Ok(())
+}
+
+/// If `data_dir` is on a read-only filesystem, so we open it read-only, mutating operations fail with
+/// [`FsError::ReadOnly`].
+fn backing_read_only(data_dir: &Path) -> bool {
+    if !data_dir.exists() {
+        return false;
+    }
+    match fs_util::is_read_only_fs(data_dir) {
+        Ok(true) => {
+            info!("data dir is on a read-only filesystem, opening it read-only");
+            true
+        }
+        Ok(false) => false,
+        Err(err) => {
+            warn!(err = %err, "cannot check if the data dir is on a read-only filesystem");
+            false
+        }
+    }
+}
+
+/// Fail with [`FsError::DataDirOnFuse`] if `data_dir`, or where it will be created, is on a FUSE mount.
+///
+/// Best effort, if we can't tell we only log it.
+fn check_not_on_fuse(data_dir: &Path) -> FsResult<()> {
+    let Some(existing) = std::path::absolute(data_dir)
+        .ok()
+        .and_then(|dir| dir.ancestors().find(|p| p.exists()).map(Path::to_path_buf))
+    else {
+        return Ok(());
+    };
+    match fs_util::fuse_mount_of(&existing) {
+        Ok(Some((mount_point, fs_type))) => Err(FsError::DataDirOnFuse {
+            mount_point,
+            fs_type,
+        }),
+        Ok(None) => Ok(()),
+        Err(err) => {
+            warn!(err = %err, "cannot check if the data dir is on a FUSE mount");
+            Ok(())
+        }
+    }
+}
+
 async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
     if data_dir.exists() {
         check_structure(data_dir, true).await?;
@@ -2543,7 +8546,7 @@ async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
     }
 
     // create directories
-    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
+    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR, JOURNAL_DIR];
     for dir in dirs {
         let path = data_dir.join(dir);
         if !path.exists() {
@@ -2567,6 +8570,8 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     if vec.is_empty() && ignore_empty {
         return Ok(());
     }
+    // data dirs created by older versions don't have it
+    vec.retain(|name| name != JOURNAL_DIR && name != PARITY_DIR && name != XATTR_DIR);
     if vec.len() != 3 {
         return Err(FsError::InvalidDataDirStructure);
     }
@@ -2587,6 +8592,150 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     Ok(())
 }
 
+/// Decrypts the encryption key with the password.
+fn decrypt_key(
+    data_dir: &Path,
+    password: &SecretString,
+    cipher: Cipher,
+    header_protection: &dyn HeaderProtection,
+) -> FsResult<SecretVec<u8>> {
+    let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+    let header = header_protection.unprotect(&fs::read(enc_file)?)?;
+    let salt: Vec<u8> = bincode::deserialize_from(File::open(
+        data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+    )?)?;
+    let derived_key = derive_key(password, cipher, &salt, header_protection)?;
+    crypto::unwrap_key(stored_key_wrap(data_dir), cipher, &derived_key, &header)
+        .map_err(|_| FsError::InvalidPassword)
+}
+
+/// The key derived from the password, with the [`HeaderProtection::kdf_secret`] mixed in if there is one.
+fn derive_key(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    header_protection: &dyn HeaderProtection,
+) -> FsResult<SecretVec<u8>> {
+    let derived_key = crypto::derive_key(password, cipher, salt)?;
+    Ok(match header_protection.kdf_secret()? {
+        Some(secret) => crypto::derive_subkey(&derived_key, &secret.expose_secret()),
+        None => derived_key,
+    })
+}
+
+/// The [`FsOptions::key_wrap`] saved in the params of the data dir. If they can't be read we try the default, opening
+/// fails with [`FsError::InvalidPassword`] if it's not that one.
+fn stored_key_wrap(data_dir: &Path) -> KeyWrapAlgorithm {
+    VaultParams::load(data_dir).map_or_else(
+        |err| {
+            warn!(err = %err, "cannot read the params, trying the default key_wrap");
+            KeyWrapAlgorithm::default()
+        },
+        |params| params.key_wrap,
+    )
+}
+
+/// If the first block of the file decrypts when read with `block_size`, empty files are valid with any size.
+fn first_block_valid(
+    path: &Path,
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    block_size: usize,
+) -> FsResult<bool> {
+    let mut buf = vec![];
+    File::open(path)?
+        .take(cipher.ciphertext_block_len_for(block_size) as u64)
+        .read_to_end(&mut buf)?;
+    Ok(buf.is_empty() || crypto::decrypt_block(cipher, key, 0, &mut buf).is_ok())
+}
+
+/// Size of the plaintext of a file with `ciphertext_len` encrypted in blocks of `block_size`.
+fn plaintext_len(ciphertext_len: u64, cipher: Cipher, block_size: usize) -> u64 {
+    let ciphertext_block_len = cipher.ciphertext_block_len_for(block_size) as u64;
+    let overhead = ciphertext_block_len - block_size as u64;
+    (ciphertext_len / ciphertext_block_len) * block_size as u64
+        + (ciphertext_len % ciphertext_block_len).saturating_sub(overhead)
+}
+
+/// Size of the encrypted content of a file with `plaintext_len` when encrypted in blocks of `block_size`.
+fn ciphertext_len(plaintext_len: u64, cipher: Cipher, block_size: usize) -> u64 {
+    let overhead = (cipher.ciphertext_block_len_for(block_size) - block_size) as u64;
+    plaintext_len + plaintext_len.div_ceil(block_size as u64) * overhead
+}
+
+/// The error when we need a regular file and have a `kind` one.
+fn wrong_file_type(kind: FileType) -> FsError {
+    if kind == FileType::Directory {
+        FsError::IsADirectory
+    } else {
+        FsError::InvalidInodeType
+    }
+}
+
+/// Fold the case of `name` for comparing ignoring it. Upper then lower case gets the full Unicode folding for most
+/// scripts, like `ß` and `SS` both becoming `ss`, which lowercasing alone doesn't.
+fn fold_case(name: &str) -> String {
+    name.to_uppercase().to_lowercase()
+}
+
+/// If `name` matches the glob `pattern`, where `*` matches any characters and `?` a single one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was and the position in name it matched up to
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` match one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Sort directory entries by `key`, "." and ".." first and the ones we couldn't read at the end.
+fn sort_entries<T>(
+    entries: VecDeque<FsResult<T>>,
+    key: impl Fn(&T) -> (SystemTime, String),
+) -> VecDeque<FsResult<T>> {
+    let (mut ok, failed): (Vec<_>, Vec<_>) = entries.into_iter().partition(Result::is_ok);
+    ok.sort_by_cached_key(|entry| {
+        let (time, name) = key(entry.as_ref().unwrap());
+        (name != ".", name != "..", time, name)
+    });
+    ok.into_iter().chain(failed).collect()
+}
+
+/// `.` and `..` are added by us to each directory, saved as `$.` and `$..`, even when the names are not encrypted, so
+/// none of them can be used for another entry.
+fn check_entry_name(name: &SecretString) -> FsResult<()> {
+    match name.expose_secret().as_str() {
+        "." | ".." | "$." | "$.." => Err(FsError::InvalidInput(
+            "name cannot be '.', '..', '$.' or '$..'",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Files with [`FS_IMMUTABLE_FL`] or [`FS_APPEND_FL`] can't be truncated, renamed or removed.
+const fn check_not_protected(attr: &FileAttr) -> FsResult<()> {
+    if attr.flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+        return Err(FsError::NotPermitted);
+    }
+    Ok(())
+}
+
 fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
     if let Some(size) = set_attr.size {
         if overwrite_size {
@@ -2639,15 +8788,8 @@ pub async fn write_all_bytes_to_fs(
     buf: &[u8],
     fh: u64,
 ) -> FsResult<()> {
-    let mut pos = 0_usize;
-    loop {
-        let len = fs.write(ino, offset, &buf[pos..], fh).await?;
-        pos += len;
-        if pos == buf.len() {
-            break;
-        } else if len == 0 {
-            return Err(FsError::Other("Failed to write all bytes"));
-        }
+    if fs.write_all(ino, offset, buf, fh).await? != buf.len() {
+        return Err(FsError::Other("Failed to write all bytes"));
     }
     fs.flush(fh).await?;
     Ok(())
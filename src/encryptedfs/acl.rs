@@ -0,0 +1,135 @@
+use crate::encryptedfs::{FsError, FsResult};
+
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+/// A POSIX ACL in the binary form Linux keeps in the [`XATTR_ACL_ACCESS`] and [`XATTR_ACL_DEFAULT`] xattrs, what
+/// `getfacl` and `setfacl` read and write.
+///
+/// The blobs are stored as they are, this is to keep the permission bits in sync with them and to apply the default
+/// ACL of a directory to what is created in it, like the kernel does for local filesystems.
+///
+/// [`XATTR_ACL_ACCESS`]: crate::encryptedfs::XATTR_ACL_ACCESS
+/// [`XATTR_ACL_DEFAULT`]: crate::encryptedfs::XATTR_ACL_DEFAULT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Acl {
+    entries: Vec<Entry>,
+}
+
+impl Acl {
+    /// Parse and validate, one owner, owning group and other entry each and a mask if there are named users or
+    /// groups.
+    pub(super) fn parse(data: &[u8]) -> FsResult<Self> {
+        if data.len() < HEADER_LEN || !(data.len() - HEADER_LEN).is_multiple_of(ENTRY_LEN) {
+            return Err(FsError::InvalidInput("invalid ACL length"));
+        }
+        if u32::from_le_bytes(data[..HEADER_LEN].try_into().unwrap()) != VERSION {
+            return Err(FsError::InvalidInput("unsupported ACL version"));
+        }
+        let entries: Vec<Entry> = data[HEADER_LEN..]
+            .chunks_exact(ENTRY_LEN)
+            .map(|e| Entry {
+                tag: u16::from_le_bytes([e[0], e[1]]),
+                perm: u16::from_le_bytes([e[2], e[3]]),
+                id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+            })
+            .collect();
+        let count = |tag| entries.iter().filter(|e| e.tag == tag).count();
+        if entries.iter().any(|e| {
+            e.perm & !0o7 != 0 || ![USER_OBJ, USER, GROUP_OBJ, GROUP, MASK, OTHER].contains(&e.tag)
+        }) {
+            return Err(FsError::InvalidInput("invalid ACL entry"));
+        }
+        if count(USER_OBJ) != 1 || count(GROUP_OBJ) != 1 || count(OTHER) != 1 || count(MASK) > 1 {
+            return Err(FsError::InvalidInput("invalid ACL entries"));
+        }
+        if count(MASK) == 0 && (count(USER) > 0 || count(GROUP) > 0) {
+            return Err(FsError::InvalidInput("ACL with named entries needs a mask"));
+        }
+        Ok(Self { entries })
+    }
+
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.entries.len() * ENTRY_LEN);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        for e in &self.entries {
+            data.extend_from_slice(&e.tag.to_le_bytes());
+            data.extend_from_slice(&e.perm.to_le_bytes());
+            data.extend_from_slice(&e.id.to_le_bytes());
+        }
+        data
+    }
+
+    /// If it has only what the mode bits can say, so there's no need to keep it.
+    pub(super) fn is_equivalent_to_mode(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// The group class is the mask if there is one, the owning group otherwise.
+    fn group_class_tag(&self) -> u16 {
+        if self.entries.iter().any(|e| e.tag == MASK) {
+            MASK
+        } else {
+            GROUP_OBJ
+        }
+    }
+
+    fn perm_of(&self, tag: u16) -> u16 {
+        self.entries
+            .iter()
+            .find(|e| e.tag == tag)
+            .map_or(0, |e| e.perm)
+    }
+
+    /// The permission bits of the mode, what `chmod` would show for it.
+    pub(super) fn perm(&self) -> u16 {
+        (self.perm_of(USER_OBJ) << 6)
+            | (self.perm_of(self.group_class_tag()) << 3)
+            | self.perm_of(OTHER)
+    }
+
+    /// Set the owner, group class and other entries from the permission bits, like `chmod` does on a file with an
+    /// ACL.
+    pub(super) fn set_perm(&mut self, perm: u16) {
+        let group_class = self.group_class_tag();
+        for e in &mut self.entries {
+            if e.tag == USER_OBJ {
+                e.perm = (perm >> 6) & 0o7;
+            } else if e.tag == group_class {
+                e.perm = (perm >> 3) & 0o7;
+            } else if e.tag == OTHER {
+                e.perm = perm & 0o7;
+            }
+        }
+    }
+
+    /// Limit the owner, group class and other entries to what the permission bits of a newly created file allow,
+    /// for the access ACL it gets from the default ACL of its parent.
+    pub(super) fn mask_perm(&mut self, perm: u16) {
+        let group_class = self.group_class_tag();
+        for e in &mut self.entries {
+            if e.tag == USER_OBJ {
+                e.perm &= (perm >> 6) & 0o7;
+            } else if e.tag == group_class {
+                e.perm &= (perm >> 3) & 0o7;
+            } else if e.tag == OTHER {
+                e.perm &= perm & 0o7;
+            }
+        }
+    }
+}
@@ -53,11 +53,12 @@ fn bench_exists_by_name(b: &mut Bencher) {
         b.iter(|| {
             async_util::call_async(async {
                 let _ = fs
-                    .exists_by_name(
+                    .exists_by_name_async(
                         ROOT_INODE,
                         &SecretString::from_str(&format!("test-file-{}", rnd.gen_range(1..100)))
                             .unwrap(),
                     )
+                    .await
                     .unwrap();
             });
             black_box(());
@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use redb::{Database, StorageBackend, TableDefinition};
+
+use crate::encryptedfs::{FsError, FsResult};
+
+/// Encrypted attributes, by ino.
+const INODES: TableDefinition<u64, &[u8]> = TableDefinition::new("inodes");
+/// Encrypted directory entries, by parent ino and hash of the name.
+const DIR_ENTRIES: TableDefinition<(u64, &str), &[u8]> = TableDefinition::new("dir_entries");
+
+/// Inodes and directory entries kept in one [`redb`] database, for [`MetadataStore::EmbeddedDb`].
+///
+/// Values are encrypted before they get here, the same as the files of [`MetadataStore::Files`].
+///
+/// [`MetadataStore::EmbeddedDb`]: crate::encryptedfs::MetadataStore::EmbeddedDb
+/// [`MetadataStore::Files`]: crate::encryptedfs::MetadataStore::Files
+pub(super) struct MetadataDb {
+    db: Database,
+}
+
+fn db_err(err: impl Into<redb::Error>) -> FsError {
+    io::Error::other(err.into()).into()
+}
+
+/// A [`StorageBackend`] over a file we can't write, like on read-only media. redb writes its header even to only
+/// read, so what it writes is kept in memory over the file and dropped when closed.
+#[derive(Debug)]
+struct ReadOnlyBackend {
+    file: Mutex<File>,
+    len: Mutex<u64>,
+    /// (offset, data), in the order they were written.
+    written: Mutex<Vec<(u64, Vec<u8>)>>,
+}
+
+impl ReadOnlyBackend {
+    fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file: Mutex::new(file),
+            len: Mutex::new(len),
+            written: Mutex::new(vec![]),
+        })
+    }
+}
+
+impl StorageBackend for ReadOnlyBackend {
+    fn len(&self) -> io::Result<u64> {
+        Ok(*self.len.lock().unwrap())
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        {
+            let mut file = self.file.lock().unwrap();
+            // past the end of the file it's zeros, like after extending it
+            let file_len = file.metadata()?.len();
+            if offset < file_len {
+                let n = (file_len - offset).min(len as u64) as usize;
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut buf[..n])?;
+            }
+        }
+        let end = offset + len as u64;
+        for (at, data) in self.written.lock().unwrap().iter() {
+            let data_end = at + data.len() as u64;
+            if *at >= end || data_end <= offset {
+                continue;
+            }
+            let from = at.max(&offset);
+            let to = data_end.min(end);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&data[(from - at) as usize..(to - at) as usize]);
+        }
+        Ok(buf)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        *self.len.lock().unwrap() = len;
+        Ok(())
+    }
+
+    fn sync_data(&self, _eventual: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.written.lock().unwrap().push((offset, data.to_vec()));
+        Ok(())
+    }
+}
+
+impl MetadataDb {
+    /// With `read_only` the file is not written, the tables must already be there.
+    pub(super) fn open(path: &Path, read_only: bool) -> FsResult<Self> {
+        if read_only {
+            let backend = ReadOnlyBackend::new(File::open(path)?)?;
+            let db = Database::builder()
+                .create_with_backend(backend)
+                .map_err(db_err)?;
+            return Ok(Self { db });
+        }
+        let db = Database::create(path).map_err(db_err)?;
+        // create the tables so readers don't need to handle them missing
+        let tx = db.begin_write().map_err(db_err)?;
+        tx.open_table(INODES).map_err(db_err)?;
+        tx.open_table(DIR_ENTRIES).map_err(db_err)?;
+        tx.commit().map_err(db_err)?;
+        Ok(Self { db })
+    }
+
+    pub(super) fn get_inode(&self, ino: u64) -> FsResult<Option<Vec<u8>>> {
+        let tx = self.db.begin_read().map_err(db_err)?;
+        let table = tx.open_table(INODES).map_err(db_err)?;
+        let value = table.get(ino).map_err(db_err)?;
+        Ok(value.map(|v| v.value().to_vec()))
+    }
+
+    pub(super) fn contains_inode(&self, ino: u64) -> FsResult<bool> {
+        let tx = self.db.begin_read().map_err(db_err)?;
+        let table = tx.open_table(INODES).map_err(db_err)?;
+        let value = table.get(ino).map_err(db_err)?;
+        Ok(value.is_some())
+    }
+
+    pub(super) fn put_inode(&self, ino: u64, value: &[u8]) -> FsResult<()> {
+        let tx = self.db.begin_write().map_err(db_err)?;
+        tx.open_table(INODES)
+            .map_err(db_err)?
+            .insert(ino, value)
+            .map_err(db_err)?;
+        tx.commit().map_err(db_err)
+    }
+
+    pub(super) fn remove_inode(&self, ino: u64) -> FsResult<()> {
+        let tx = self.db.begin_write().map_err(db_err)?;
+        tx.open_table(INODES)
+            .map_err(db_err)?
+            .remove(ino)
+            .map_err(db_err)?;
+        tx.commit().map_err(db_err)
+    }
+
+    pub(super) fn get_entry(&self, parent: u64, hash: &str) -> FsResult<Option<Vec<u8>>> {
+        let tx = self.db.begin_read().map_err(db_err)?;
+        let table = tx.open_table(DIR_ENTRIES).map_err(db_err)?;
+        let value = table.get((parent, hash)).map_err(db_err)?;
+        Ok(value.map(|v| v.value().to_vec()))
+    }
+
+    pub(super) fn put_entry(&self, parent: u64, hash: &str, value: &[u8]) -> FsResult<()> {
+        let tx = self.db.begin_write().map_err(db_err)?;
+        tx.open_table(DIR_ENTRIES)
+            .map_err(db_err)?
+            .insert((parent, hash), value)
+            .map_err(db_err)?;
+        tx.commit().map_err(db_err)
+    }
+
+    /// Returns the removed value, if there was one.
+    pub(super) fn remove_entry(&self, parent: u64, hash: &str) -> FsResult<Option<Vec<u8>>> {
+        let tx = self.db.begin_write().map_err(db_err)?;
+        let value = tx
+            .open_table(DIR_ENTRIES)
+            .map_err(db_err)?
+            .remove((parent, hash))
+            .map_err(db_err)?
+            .map(|v| v.value().to_vec());
+        tx.commit().map_err(db_err)?;
+        Ok(value)
+    }
+
+    /// Remove all entries of `parent`, like "." and ".." of an empty directory being deleted.
+    pub(super) fn remove_entries(&self, parent: u64) -> FsResult<()> {
+        let tx = self.db.begin_write().map_err(db_err)?;
+        {
+            let mut table = tx.open_table(DIR_ENTRIES).map_err(db_err)?;
+            table
+                .retain_in((parent, "")..=(parent, "\u{10ffff}"), |_, _| false)
+                .map_err(db_err)?;
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    /// Values of all entries in `parent`, ordered by the hash of the name.
+    pub(super) fn entries(&self, parent: u64) -> FsResult<Vec<Vec<u8>>> {
+        let tx = self.db.begin_read().map_err(db_err)?;
+        let table = tx.open_table(DIR_ENTRIES).map_err(db_err)?;
+        let mut values = vec![];
+        for entry in table.range((parent, "")..).map_err(db_err)? {
+            let (key, value) = entry.map_err(db_err)?;
+            if key.value().0 != parent {
+                break;
+            }
+            values.push(value.value().to_vec());
+        }
+        Ok(values)
+    }
+
+    pub(super) fn count_entries(&self, parent: u64) -> FsResult<usize> {
+        let tx = self.db.begin_read().map_err(db_err)?;
+        let table = tx.open_table(DIR_ENTRIES).map_err(db_err)?;
+        let mut count = 0;
+        for entry in table.range((parent, "")..).map_err(db_err)? {
+            if entry.map_err(db_err)?.0.value().0 != parent {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
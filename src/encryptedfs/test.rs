@@ -1,24 +1,37 @@
+use std::io::{IoSlice, IoSliceMut};
 use std::str::FromStr;
 use std::string::ToString;
-use std::time::SystemTime;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use shush_rs::{ExposeSecret, SecretString};
 use tracing_test::traced_test;
 
-use crate::crypto::Cipher;
-use crate::encryptedfs::write_all_bytes_to_fs;
+use crate::crypto::{BlockTransform, Cipher, KeyWrapAlgorithm, NameCipher};
 use crate::encryptedfs::INODES_DIR;
+use crate::encryptedfs::JOURNAL_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
+use crate::encryptedfs::PARITY_DIR;
 use crate::encryptedfs::SECURITY_DIR;
-use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR};
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsResult, SetFileAttr,
-    CONTENTS_DIR, ROOT_INODE,
+    cache_capacity, decrypt_file_envelope, plaintext_len, write_all_bytes_to_fs,
 };
+use crate::encryptedfs::{CopyFileRangeReq, HASH_DIR, LS_DIR};
+use crate::encryptedfs::{
+    CreateFileAttr, DirectoryEntry, DirectoryEntryPlus, EncryptedFs, FileType, FsError, FsEvent,
+    FsOptions, FsResult, HeaderProtection, MetadataStore, NonceReuseDetector, PasswordSource,
+    ReaddirOrder, SetFileAttr, Status, Storage, TimestampSource, VerifyLevel, CONTENTS_DIR,
+    FS_APPEND_FL, FS_IMMUTABLE_FL, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE, NONCE_REUSE_WINDOW, ROOT_INODE,
+    XATTR_ACL_ACCESS, XATTR_ACL_DEFAULT,
+};
+use crate::fido2::{Fido2Protection, HmacSecretDevice};
+use crate::storage::{LocalBackend, StorageBackend};
 use crate::test_common::run_test;
 use crate::test_common::TestSetup;
 use crate::test_common::{create_attr, get_fs, PasswordProviderImpl};
+use crate::test_util::TestVault;
 use crate::{crypto, test_common};
 
 static ROOT_INODE_STR: &str = "1";
@@ -145,7 +158,7 @@ async fn test_write() {
             let fh = fs.open(attr.ino, false, true).await.unwrap();
             assert!(matches!(
                 fs.write(ROOT_INODE, 0, &buf, fh).await,
-                Err(FsError::InvalidInodeType)
+                Err(FsError::IsADirectory)
             ));
             assert!(matches!(
                 fs.write(0, 0, &buf, fh).await,
@@ -164,7 +177,7 @@ async fn test_write() {
                 .unwrap();
             assert!(matches!(
                 fs.write(dir_attr.ino, 0, &buf, fh).await,
-                Err(FsError::InvalidInodeType)
+                Err(FsError::IsADirectory)
             ));
         },
     )
@@ -326,7 +339,7 @@ async fn test_read() {
             let mut buf = [0; 0];
             assert!(matches!(
                 fs.read(ROOT_INODE, 0, &mut buf, fh).await,
-                Err(FsError::InvalidInodeType)
+                Err(FsError::IsADirectory)
             ));
             assert!(matches!(
                 fs.read(0, 0, &mut buf, fh).await,
@@ -345,7 +358,7 @@ async fn test_read() {
                 .unwrap();
             assert!(matches!(
                 fs.read(dir_attr.ino, 0, &mut buf, fh).await,
-                Err(FsError::InvalidInodeType)
+                Err(FsError::IsADirectory)
             ));
         },
     )
@@ -994,9 +1007,13 @@ async fn test_exists_by_name() {
                     .await
                     .unwrap();
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(fs
+                    .exists_by_name_async(ROOT_INODE, &test_file)
+                    .await
+                    .unwrap());
                 assert!(
-                    !(fs.exists_by_name(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                    !(fs.exists_by_name_async(ROOT_INODE, &SecretString::from_str("42").unwrap())
+                        .await
                         .unwrap())
                 );
             }
@@ -1029,9 +1046,15 @@ async fn test_remove_dir() {
                     .await
                     .unwrap();
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert!(fs
+                    .exists_by_name_async(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap());
                 fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+                assert!(!fs
+                    .exists_by_name_async(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap());
                 assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_dir).await.unwrap());
                 assert_eq!(
                     0,
@@ -1074,9 +1097,15 @@ async fn test_remove_file() {
                     .await
                     .unwrap();
 
-                assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(fs
+                    .exists_by_name_async(ROOT_INODE, &test_file)
+                    .await
+                    .unwrap());
                 fs.remove_file(ROOT_INODE, &test_file).await.unwrap();
-                assert!(!fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+                assert!(!fs
+                    .exists_by_name_async(ROOT_INODE, &test_file)
+                    .await
+                    .unwrap());
                 assert_eq!(None, fs.find_by_name(ROOT_INODE, &test_file).await.unwrap());
                 assert_eq!(
                     0,
@@ -1095,6 +1124,20 @@ async fn test_remove_file() {
     .await;
 }
 
+#[tokio::test(flavor = "multi_thread")]
+#[traced_test]
+async fn test_exists_by_name_sync() {
+    let vault = TestVault::builder().build().await.unwrap();
+    vault.create_file("test-file").await.unwrap();
+    let fs = vault.fs();
+    assert!(fs
+        .exists_by_name(ROOT_INODE, &SecretString::from_str("test-file").unwrap())
+        .unwrap());
+    assert!(!fs
+        .exists_by_name(ROOT_INODE, &SecretString::from_str("missing").unwrap())
+        .unwrap());
+}
+
 #[tokio::test]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -1134,14 +1177,20 @@ async fn test_find_by_name_exists_by_name100files() {
                 .unwrap();
 
             let test_file = SecretString::from_str("test-file-42").unwrap();
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs
+                .exists_by_name_async(ROOT_INODE, &test_file)
+                .await
+                .unwrap());
             assert!(fs
                 .find_by_name(ROOT_INODE, &test_file)
                 .await
                 .unwrap()
                 .is_some());
 
-            assert!(fs.exists_by_name(ROOT_INODE, &special_test_file).unwrap());
+            assert!(fs
+                .exists_by_name_async(ROOT_INODE, &special_test_file)
+                .await
+                .unwrap());
             assert!(fs
                 .find_by_name(ROOT_INODE, &special_test_file)
                 .await
@@ -1241,7 +1290,10 @@ async fn test_create() {
                 .collect();
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             assert_eq!(attr, entries[1].attr);
-            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert!(fs
+                .exists_by_name_async(ROOT_INODE, &test_file)
+                .await
+                .unwrap());
             assert_eq!(
                 attr,
                 fs.find_by_name(ROOT_INODE, &test_file)
@@ -1292,7 +1344,10 @@ async fn test_create() {
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             assert_eq!(ROOT_INODE, entries[0].attr.ino);
             assert_eq!(attr, entries[1].attr);
-            assert!(fs.exists_by_name(ROOT_INODE, &test_dir).unwrap());
+            assert!(fs
+                .exists_by_name_async(ROOT_INODE, &test_dir)
+                .await
+                .unwrap());
             assert_eq!(
                 attr,
                 fs.find_by_name(ROOT_INODE, &test_dir)
@@ -1343,7 +1398,7 @@ async fn test_create() {
             entries.sort_by(|a, b| a.name.expose_secret().cmp(&*b.name.expose_secret()));
             assert_eq!(attr, entries[2].attr);
             assert_eq!(parent, entries[0].attr.ino);
-            assert!(fs.exists_by_name(parent, &test_dir_2).unwrap());
+            assert!(fs.exists_by_name_async(parent, &test_dir_2).await.unwrap());
             assert_eq!(
                 attr,
                 fs.find_by_name(parent, &test_dir_2).await.unwrap().unwrap()
@@ -1408,8 +1463,11 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_1_new)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1_new).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs
+                .exists_by_name_async(new_parent, &file_1_new)
+                .await
+                .unwrap());
             let new_attr = fs
                 .find_by_name(new_parent, &file_1_new)
                 .await
@@ -1454,8 +1512,11 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1_new)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1_new).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs
+                .exists_by_name_async(new_parent, &dir_1_new)
+                .await
+                .unwrap());
             let new_attr = fs
                 .find_by_name(new_parent, &dir_1_new)
                 .await
@@ -1543,8 +1604,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &file_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
             assert!(fs.is_file(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -1595,8 +1656,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &dir_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
             assert!(fs.is_dir(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -1688,8 +1749,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_2).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &file_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_2).await.unwrap().unwrap();
             assert!(fs.is_file(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -1738,8 +1799,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_2)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_2).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &dir_2).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_2).await.unwrap().unwrap();
             assert!(fs.is_dir(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -1820,8 +1881,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &file_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &file_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
             assert!(fs.is_file(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -1870,8 +1931,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_1, new_parent, &dir_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &dir_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &dir_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
             assert!(fs.is_dir(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -1952,8 +2013,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_1, new_parent, &dir_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &file_1).unwrap());
-            assert!(fs.exists_by_name(new_parent, &dir_1).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &file_1).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &dir_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_1).await.unwrap().unwrap();
             assert!(fs.is_file(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -2003,8 +2064,8 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_3, new_parent, &file_1)
                 .await
                 .unwrap();
-            assert!(!fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-            assert!(fs.exists_by_name(new_parent, &file_1).unwrap());
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &dir_3).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &file_1).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_1).await.unwrap().unwrap();
             assert!(fs.is_dir(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -2078,8 +2139,8 @@ async fn test_rename() {
                 fs.rename(ROOT_INODE, &dir_3, new_parent, &name_2).await,
                 Err(FsError::NotEmpty)
             ));
-            assert!(fs.exists_by_name(ROOT_INODE, &dir_3).unwrap());
-            assert!(fs.exists_by_name(new_parent, &name_2).unwrap());
+            assert!(fs.exists_by_name_async(ROOT_INODE, &dir_3).await.unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &name_2).await.unwrap());
             let attr_3 = fs.find_by_name(ROOT_INODE, &dir_3).await.unwrap().unwrap();
             assert!(fs.is_dir(attr_3.ino));
             let attr_2 = fs.find_by_name(new_parent, &name_2).await.unwrap().unwrap();
@@ -2157,7 +2218,7 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &file_3, new_parent, &file_3)
                 .await
                 .unwrap();
-            assert!(fs.exists_by_name(new_parent, &file_3).unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &file_3).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &file_3).await.unwrap().unwrap();
             assert!(fs.is_file(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -2188,7 +2249,7 @@ async fn test_rename() {
             fs.rename(ROOT_INODE, &dir_5, new_parent, &dir_5)
                 .await
                 .unwrap();
-            assert!(fs.exists_by_name(new_parent, &dir_5).unwrap());
+            assert!(fs.exists_by_name_async(new_parent, &dir_5).await.unwrap());
             let new_attr = fs.find_by_name(new_parent, &dir_5).await.unwrap().unwrap();
             assert!(fs.is_dir(new_attr.ino));
             assert_eq!(new_attr.ino, attr.ino);
@@ -2254,7 +2315,7 @@ async fn test_rename() {
                 .unwrap();
             assert!(matches!(
                 fs.rename(attr_file.ino, &invalid, 0, &invalid).await,
-                Err(FsError::InvalidInodeType)
+                Err(FsError::NotADirectory)
             ));
             assert!(matches!(
                 fs.rename(ROOT_INODE, &invalid, ROOT_INODE, &invalid).await,
@@ -2267,7 +2328,7 @@ async fn test_rename() {
             assert!(matches!(
                 fs.rename(ROOT_INODE, &existing_file, attr_file.ino, &invalid)
                     .await,
-                Err(FsError::InvalidInodeType)
+                Err(FsError::NotADirectory)
             ));
         },
     )
@@ -2482,3 +2543,5279 @@ async fn test_read_only_write() {
     )
     .await
 }
+
+#[tokio::test]
+#[traced_test]
+async fn test_append_buffer() {
+    run_test(
+        TestSetup {
+            key: "test_append_buffer",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+
+            // small appends, less than a block, are visible before flush
+            let mut expected = String::new();
+            for i in 0..5 {
+                let data = format!("append-{i};");
+                let len = fs
+                    .write(attr.ino, expected.len() as u64, data.as_bytes(), fh)
+                    .await
+                    .unwrap();
+                assert_eq!(data.len(), len);
+                expected.push_str(&data);
+            }
+            assert_eq!(
+                expected.len() as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+            let mut buf = vec![0; expected.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(expected, String::from_utf8(buf).unwrap());
+            // read from the middle of the pending data
+            let mut buf = vec![0; 5];
+            test_common::read_exact(&fs, attr.ino, 9, &mut buf, fh_read).await;
+            assert_eq!(&expected[9..14], String::from_utf8(buf).unwrap());
+
+            // fill more than a block
+            let data = "0123456789".repeat(12);
+            write_all_bytes_to_fs(&fs, attr.ino, expected.len() as u64, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            expected.push_str(&data);
+
+            // write in the middle, the pending appends need to be kept
+            fs.write(attr.ino, 0, b"APPEND", fh).await.unwrap();
+            expected.replace_range(0..6, "APPEND");
+            let data = "tail";
+            fs.write(attr.ino, expected.len() as u64, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            expected.push_str(data);
+            fs.flush(fh).await.unwrap();
+            let mut buf = vec![0; expected.len()];
+            test_common::read_exact(&fs, attr.ino, 0, &mut buf, fh_read).await;
+            assert_eq!(expected, String::from_utf8(buf).unwrap());
+
+            fs.release(fh).await.unwrap();
+            fs.release(fh_read).await.unwrap();
+            assert_eq!(expected, test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(
+                expected.len() as u64,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_data_files() {
+    run_test(
+        TestSetup {
+            key: "test_data_files",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(crypto::write::BLOCK_SIZE * 2 + 1);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let files = fs.data_files_for(attr.ino).unwrap();
+            assert_eq!(
+                vec![
+                    fs.data_dir.join(INODES_DIR).join(attr.ino.to_string()),
+                    fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string())
+                ],
+                files
+            );
+            assert_eq!(3, fs.data_file_blocks(&files[1]).unwrap());
+
+            // root has "." and the file, in ls and hash
+            let files = fs.data_files_for(ROOT_INODE).unwrap();
+            assert_eq!(5, files.len());
+
+            let all = fs.all_data_files().unwrap();
+            assert!(all.contains(&fs.data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME)));
+            for file in fs.data_files_for(attr.ino).unwrap() {
+                assert!(all.contains(&file));
+            }
+
+            assert!(matches!(fs.data_files_for(42), Err(FsError::InodeNotFound)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_recover_journal() {
+    assert!(!FsOptions::default().write_journal);
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_write_journal(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let ino = vault.create_file("test-file").await.unwrap();
+    let data = "a".repeat(crypto::write::BLOCK_SIZE * 2 + 50);
+    vault.write_all(ino, 0, data.as_bytes()).await.unwrap();
+    assert!(!fs.journal_path(ino).exists());
+
+    // change the first two blocks, the first one is written when we move to the second one
+    let fh = fs.open(ino, false, true).await.unwrap();
+    let new_data = "b".repeat(crypto::write::BLOCK_SIZE + 20);
+    fs.write_all(ino, 50, new_data.as_bytes(), fh)
+        .await
+        .unwrap();
+    assert!(fs.journal_path(ino).is_dir());
+
+    // crash, don't release and open again
+    vault
+        .reopen(false, FsOptions::default().with_write_journal(true))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(!fs.journal_path(ino).exists());
+    assert_eq!(data.as_bytes(), &vault.read_all(ino).await.unwrap()[..]);
+    assert_eq!(data.len() as u64, fs.get_attr(ino).await.unwrap().size);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_journal_off() {
+    let vault = TestVault::builder().build().await.unwrap();
+    let fs = vault.fs();
+    let ino = vault.create_file("test-file").await.unwrap();
+    let data = vec![1; fs.block_size() * 2 + 50];
+    vault.write_all(ino, 0, &data).await.unwrap();
+
+    // nothing saved before overwriting, and no sync when released
+    let syncs = fs.release_syncs.load(Ordering::SeqCst);
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write_all(ino, 50, &[2; 150], fh).await.unwrap();
+    assert!(!fs.journal_path(ino).exists());
+    assert!(matches!(
+        fs.close_handles_for(ino, false).await,
+        Err(FsError::InvalidInput(_))
+    ));
+    fs.release(fh).await.unwrap();
+    assert_eq!(syncs, fs.release_syncs.load(Ordering::SeqCst));
+    let mut expected = data.clone();
+    expected[50..200].fill(2);
+    assert_eq!(expected, vault.read_all(ino).await.unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_recover_journal_mirror() {
+    let root = tempfile::tempdir().unwrap();
+    let backend = Arc::new(LocalBackend::new(root.path()));
+    let options = || {
+        FsOptions::default()
+            .with_write_journal(true)
+            .with_storage(Storage::Mirror(backend.clone()))
+    };
+    let mut vault = TestVault::builder()
+        .options(options())
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let ino = vault.create_file("test-file").await.unwrap();
+    let data = vec![1; fs.block_size() * 3];
+    vault.write_all(ino, 0, &data).await.unwrap();
+
+    // say the mirror got some of the new blocks before we crashed
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write_all(ino, 0, &[2; 150], fh).await.unwrap();
+    fs.upload_to_storage(ino, None).await.unwrap();
+
+    vault.reopen(false, options()).await.unwrap();
+    // what we get back from the mirror is the recovered content
+    std::fs::remove_file(vault.fs().contents_path(ino)).unwrap();
+    assert_eq!(data, vault.read_all(ino).await.unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_scrub() {
+    run_test(
+        TestSetup {
+            key: "test_scrub",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(crypto::write::BLOCK_SIZE * 2 + 50);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs.scrub_now().await.unwrap().is_empty());
+
+            // flip a bit in the second block
+            let path = fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let mut content = std::fs::read(&path).unwrap();
+            content[Cipher::ChaCha20Poly1305.ciphertext_block_len() + 20] ^= 1;
+            std::fs::write(&path, content).unwrap();
+
+            let mut events = fs.events();
+            assert_eq!(vec![(attr.ino, 1)], fs.scrub_now().await.unwrap());
+            assert_eq!(
+                FsEvent::CorruptBlock {
+                    ino: attr.ino,
+                    block: 1
+                },
+                events.recv().await.unwrap()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_change_block_size() {
+    run_test(
+        TestSetup {
+            key: "test_change_block_size",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(MIN_BLOCK_SIZE * 3 + 42);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let data_dir = fs.data_dir.clone();
+            let password = || SecretString::from_str("password").unwrap();
+
+            assert!(matches!(
+                EncryptedFs::change_block_size(
+                    &data_dir,
+                    password(),
+                    Cipher::ChaCha20Poly1305,
+                    MAX_BLOCK_SIZE + 1,
+                    |_, _| {}
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            let mut calls = vec![];
+            EncryptedFs::change_block_size(
+                &data_dir,
+                password(),
+                Cipher::ChaCha20Poly1305,
+                MIN_BLOCK_SIZE,
+                |done, total| calls.push((done, total)),
+            )
+            .await
+            .unwrap();
+            assert_eq!(vec![(1, 1)], calls);
+
+            let fs = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(MIN_BLOCK_SIZE, fs.block_size());
+            assert_eq!(
+                4,
+                fs.data_file_blocks(&data_dir.join(CONTENTS_DIR).join(attr.ino.to_string()))
+                    .unwrap()
+            );
+            assert_eq!(data, test_common::read_to_string(attr.ino, &fs).await);
+
+            // writes use the new size also
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 10, b"bb", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let mut data = data.into_bytes();
+            data[10..12].copy_from_slice(b"bb");
+            assert_eq!(
+                String::from_utf8(data).unwrap(),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+        },
+    )
+    .await;
+}
+
+#[test]
+#[traced_test]
+fn test_password_source() {
+    use std::io::Write;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"password\n").unwrap();
+    let source = PasswordSource::File(file.path().to_path_buf());
+    assert_eq!("password", *source.read().unwrap().expose_secret());
+
+    let fd = std::fs::File::open(file.path()).unwrap().into();
+    let provider = PasswordSource::Fd(fd).into_provider().unwrap();
+    assert_eq!(
+        "password",
+        *provider.get_password().unwrap().expose_secret()
+    );
+    assert_eq!(
+        "password",
+        *provider.get_password().unwrap().expose_secret()
+    );
+
+    assert!(matches!(
+        PasswordSource::Env("RENCFS_TEST_MISSING_PASSWORD".to_string()).read(),
+        Err(FsError::NotFound(_))
+    ));
+
+    // no trailing new lines, not even `\r\n`
+    std::fs::write(file.path(), b"pass\nword\r\n\n").unwrap();
+    let source = PasswordSource::File(file.path().to_path_buf());
+    assert_eq!("pass\nword", *source.read().unwrap().expose_secret());
+    std::fs::write(file.path(), [0xff, 0xfe]).unwrap();
+    let source = PasswordSource::File(file.path().to_path_buf());
+    assert!(matches!(source.read(), Err(FsError::Io { .. })));
+    // longer than what it reads at first
+    let long = "a".repeat(1000);
+    std::fs::write(file.path(), &long).unwrap();
+    let source = PasswordSource::File(file.path().to_path_buf());
+    assert_eq!(long, *source.read().unwrap().expose_secret());
+}
+
+#[test]
+#[traced_test]
+#[cfg(target_os = "linux")]
+fn test_password_fd_arg() {
+    use std::io::{Seek, Write};
+    use std::os::fd::AsRawFd;
+
+    use crate::cli::{self, Command, PasswordArg};
+
+    let args = |fd: &str| {
+        cli::command().try_get_matches_from([
+            "rencfs",
+            "mount",
+            "--mount-point",
+            "mnt",
+            "--data-dir",
+            "data",
+            "--password-fd",
+            fd,
+        ])
+    };
+    assert!(args("-1").is_err());
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"password\n").unwrap();
+    let opened = std::fs::File::open(file.path()).unwrap();
+    let matches = args(&opened.as_raw_fd().to_string()).unwrap();
+    // parsing only keeps the number, it can be done again
+    for _ in 0..2 {
+        let Command::Mount(mount) = cli::parse_command(&matches).unwrap() else {
+            panic!("not a mount");
+        };
+        assert!(
+            matches!(mount.password_source, Some(PasswordArg::Fd(fd)) if fd == opened.as_raw_fd())
+        );
+        let source = mount.password_source.unwrap().into_source().unwrap();
+        assert_eq!("password", *source.read().unwrap().expose_secret());
+        // we read a copy, ours is still opened, it shares the offset so rewind it
+        (&opened).rewind().unwrap();
+    }
+
+    assert!(PasswordArg::Fd(i32::MAX).into_source().is_err());
+    // only `--password-env` is taken from the environment
+    assert!(cli::take_env_password(&matches).is_none());
+}
+
+#[test]
+fn test_keyring_unavailable() {
+    let err = crate::keyring::map_err(keyring::Error::NoStorageAccess(Box::new(
+        std::io::Error::other("no secret service"),
+    )));
+    assert!(matches!(err, FsError::KeyringUnavailable { .. }));
+    let err = crate::keyring::map_err(keyring::Error::PlatformFailure(Box::new(
+        std::io::Error::other("dbus"),
+    )));
+    assert!(matches!(err, FsError::KeyringUnavailable { .. }));
+    assert!(matches!(
+        crate::keyring::map_err(keyring::Error::NoEntry),
+        FsError::Keyring { .. }
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_getattr_batch() {
+    run_test(
+        TestSetup {
+            key: "test_getattr_batch",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr_1) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file-1").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let (_, attr_2) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // size from the opened handle
+            fs.write(attr_1.ino, 0, b"test", fh).await.unwrap();
+
+            let attrs = fs.getattr_batch(&[attr_1.ino, 42_000, attr_2.ino]).await;
+            assert_eq!(3, attrs.len());
+            let attr = attrs[0].as_ref().unwrap();
+            assert_eq!(fs.get_attr(attr_1.ino).await.unwrap(), *attr);
+            assert_eq!(4, attr.size);
+            assert!(matches!(attrs[1], Err(FsError::InodeNotFound)));
+            let attr = attrs[2].as_ref().unwrap();
+            assert_eq!(FileType::Directory, attr.kind);
+            assert_eq!(fs.get_attr(attr_2.ino).await.unwrap(), *attr);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_overlay() {
+    run_test(
+        TestSetup {
+            key: "test_overlay",
+            read_only: false,
+        },
+        async {
+            let lower = get_fs().await;
+
+            let kept = SecretString::from_str("kept").unwrap();
+            let (fh, kept_attr) = lower
+                .create(
+                    ROOT_INODE,
+                    &kept,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&lower, kept_attr.ino, 0, b"lower", fh)
+                .await
+                .unwrap();
+            lower.release(fh).await.unwrap();
+            let changed = SecretString::from_str("changed").unwrap();
+            let (fh, changed_attr) = lower
+                .create(
+                    ROOT_INODE,
+                    &changed,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&lower, changed_attr.ino, 0, b"lower", fh)
+                .await
+                .unwrap();
+            lower.release(fh).await.unwrap();
+            let removed = SecretString::from_str("removed").unwrap();
+            lower
+                .create(
+                    ROOT_INODE,
+                    &removed,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            let upper_dir = tempfile::tempdir().unwrap();
+            let fs = EncryptedFs::new_overlay(
+                upper_dir.path().to_path_buf(),
+                lower.clone(),
+                Box::new(PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            // reads fall through
+            let attr = fs.find_by_name(ROOT_INODE, &kept).await.unwrap().unwrap();
+            assert_eq!(kept_attr.ino, attr.ino);
+            assert_eq!("lower", test_common::read_to_string(attr.ino, &fs).await);
+
+            // writes copy up
+            let fh = fs.open(changed_attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, changed_attr.ino, 0, b"upper", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                "upper",
+                test_common::read_to_string(changed_attr.ino, &fs).await
+            );
+            assert_eq!(
+                "lower",
+                test_common::read_to_string(changed_attr.ino, &lower).await
+            );
+
+            // removes leave a whiteout
+            fs.remove_file(ROOT_INODE, &removed).await.unwrap();
+            assert!(!fs.exists_by_name_async(ROOT_INODE, &removed).await.unwrap());
+            assert!(lower
+                .exists_by_name_async(ROOT_INODE, &removed)
+                .await
+                .unwrap());
+
+            // new files go to upper only
+            let added = SecretString::from_str("added").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &added,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(!lower
+                .exists_by_name_async(ROOT_INODE, &added)
+                .await
+                .unwrap());
+
+            let mut names = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(vec![".", "added", "changed", "kept"], names);
+            assert_eq!(3, fs.len(ROOT_INODE).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_handles() {
+    run_test(
+        TestSetup {
+            key: "test_open_handles",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let fh_read = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(fs.open_handles().iter().all(|info| !info.dirty));
+
+            fs.write(attr.ino, 0, b"test", fh).await.unwrap();
+            let handles = fs.open_handles();
+            assert_eq!(2, handles.len());
+            let info = handles.iter().find(|info| info.fh == fh).unwrap();
+            assert_eq!(attr.ino, info.ino);
+            assert!(!info.read && info.write);
+            assert!(info.dirty);
+            assert_eq!(4, info.bytes_written);
+            let info = handles.iter().find(|info| info.fh == fh_read).unwrap();
+            assert!(info.read && !info.write);
+            assert_eq!(0, info.bytes_written);
+
+            fs.flush(fh).await.unwrap();
+            let handles = fs.open_handles();
+            let info = handles.iter().find(|info| info.fh == fh).unwrap();
+            assert!(!info.dirty);
+            assert_eq!(4, info.bytes_written);
+
+            fs.release(fh).await.unwrap();
+            fs.release(fh_read).await.unwrap();
+            assert!(fs.open_handles().is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_independent_read_handles() {
+    run_test(
+        TestSetup {
+            key: "test_independent_read_handles",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..1050).map(|i| (i % 251) as u8).collect();
+            let mut written = 0;
+            while written < data.len() {
+                written += fs
+                    .write(attr.ino, written as u64, &data[written..], fh)
+                    .await
+                    .unwrap();
+            }
+            fs.release(fh).await.unwrap();
+
+            let fh1 = fs.open(attr.ino, true, false).await.unwrap();
+            let fh2 = fs.open(attr.ino, true, false).await.unwrap();
+            assert_ne!(fh1, fh2);
+            // each reads its half sequentially in small chunks, interleaved with the other, so a position shared
+            // between them would make both jump around
+            let read_region = |fh: u64, start: usize| {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 525];
+                    for (i, chunk) in buf.chunks_mut(30).enumerate() {
+                        let offset = (start + i * 30) as u64;
+                        let len = fs.read(attr.ino, offset, chunk, fh).await.unwrap();
+                        assert_eq!(chunk.len(), len);
+                        tokio::task::yield_now().await;
+                    }
+                    buf
+                })
+            };
+            let (first, second) = tokio::join!(read_region(fh1, 0), read_region(fh2, 525));
+            assert_eq!(&data[..525], &first.unwrap()[..]);
+            assert_eq!(&data[525..], &second.unwrap()[..]);
+
+            // and one going back doesn't move the other
+            let mut buf = [0; 10];
+            fs.read(attr.ino, 1040, &mut buf, fh1).await.unwrap();
+            assert_eq!(&data[1040..], &buf);
+            fs.read(attr.ino, 0, &mut buf, fh2).await.unwrap();
+            assert_eq!(&data[..10], &buf);
+            fs.read(attr.ino, 10, &mut buf, fh2).await.unwrap();
+            assert_eq!(&data[10..20], &buf);
+            fs.release(fh1).await.unwrap();
+            fs.release(fh2).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_encrypt_names() {
+    assert!(FsOptions::default().encrypt_names);
+
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_encrypt_names(false))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let data_dir = vault.data_dir();
+    assert!(!fs.encrypt_names());
+
+    let name = SecretString::from_str("plain-name").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(fs, attr.ino, 0, b"secret content", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let ls_dir = data_dir
+        .join(CONTENTS_DIR)
+        .join(ROOT_INODE.to_string())
+        .join("ls");
+    assert!(ls_dir.join("plain-name").is_file());
+    let content = std::fs::read(data_dir.join(CONTENTS_DIR).join(attr.ino.to_string())).unwrap();
+    assert!(!content
+        .windows(b"secret content".len())
+        .any(|w| w == b"secret content"));
+
+    // the value saved in the data dir is used
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    assert!(!fs.encrypt_names());
+    let names = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().to_string())
+        .collect::<Vec<_>>();
+    assert!(names.contains(&"plain-name".to_string()));
+    let attr = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+    assert_eq!(
+        "secret content",
+        test_common::read_to_string(attr.ino, fs).await
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_max_read_size_zero() {
+    let res = TestVault::builder()
+        .options(FsOptions::default().with_max_read_size(0))
+        .build()
+        .await;
+    assert!(matches!(res, Err(FsError::InvalidInput(_))));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_reserved_names() {
+    for encrypt_names in [false, true] {
+        let vault = TestVault::builder()
+            .options(FsOptions::default().with_encrypt_names(encrypt_names))
+            .build()
+            .await
+            .unwrap();
+        let fs = vault.fs();
+        let dir = fs
+            .mkdir(ROOT_INODE, &SecretString::from_str("dir").unwrap(), 0o755)
+            .await
+            .unwrap();
+        let file = vault.create_file("file").await.unwrap();
+
+        // they would take the place of the links to the directory and its parent
+        for name in ["$.", "$..", ".", ".."] {
+            let name = SecretString::from_str(name).unwrap();
+            assert!(matches!(
+                fs.create(
+                    dir.ino,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.rename(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    dir.ino,
+                    &name
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+        }
+        let parent = fs
+            .find_by_name(dir.ino, &SecretString::from_str("..").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ROOT_INODE, parent.ino);
+        let this = fs
+            .find_by_name(dir.ino, &SecretString::from_str(".").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dir.ino, this.ino);
+
+        // other names starting with `$` are fine
+        let name = SecretString::from_str("$file").unwrap();
+        fs.rename(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            dir.ino,
+            &name,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            file,
+            fs.find_by_name(dir.ino, &name).await.unwrap().unwrap().ino
+        );
+        let mut names = fs
+            .read_dir(dir.ino)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec!["$file", ".", ".."], names);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_redundancy() {
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_redundancy(2))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let data_dir = vault.data_dir();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = (0..fs.block_size() * 5 + 42)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect::<String>();
+    write_all_bytes_to_fs(fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+
+    // only the changed groups are computed again, it has to be the same as for the whole file
+    let mut data = data.into_bytes();
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    let offset = fs.block_size() * 3 + 7;
+    data[offset..offset + 10].copy_from_slice(b"0123456789");
+    write_all_bytes_to_fs(fs, attr.ino, offset as u64, b"0123456789", fh)
+        .await
+        .unwrap();
+    // leave a hole, the zeros before the write change the parity too
+    data.extend_from_slice(&vec![0; fs.block_size() * 2]);
+    data.extend_from_slice(&vec![b'z'; fs.block_size()]);
+    let end = data.len() - fs.block_size();
+    write_all_bytes_to_fs(fs, attr.ino, end as u64, &data[end..], fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let data = String::from_utf8(data).unwrap();
+    let parity_path = data_dir.join(PARITY_DIR).join(attr.ino.to_string());
+    let parity = std::fs::read(&parity_path).unwrap();
+    fs.compute_parity(attr.ino, 2).unwrap();
+    assert_eq!(parity, std::fs::read(&parity_path).unwrap());
+
+    let block_len = Cipher::ChaCha20Poly1305.ciphertext_block_len_for(fs.block_size());
+    let path = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+    let corrupt = |block: usize| {
+        let mut content = std::fs::read(&path).unwrap();
+        content[block * block_len + 20] ^= 0xff;
+        std::fs::write(&path, content).unwrap();
+    };
+
+    // one in a group is repaired on read
+    corrupt(3);
+    corrupt(5);
+    assert_eq!(data, test_common::read_to_string(attr.ino, fs).await);
+    assert!(fs.repair(attr.ino).await.unwrap().is_empty());
+
+    // two in the same group can't be
+    corrupt(0);
+    corrupt(1);
+    assert!(fs.repair(attr.ino).await.unwrap().is_empty());
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = vec![0; 10];
+    assert!(fs.read(attr.ino, 0, &mut buf, fh).await.is_err());
+    fs.release(fh).await.unwrap();
+
+    // the parity groups are saved in the data dir
+    for options in [
+        FsOptions::default(),
+        FsOptions::default().with_redundancy(3),
+    ] {
+        assert!(matches!(
+            vault.reopen(false, options).await,
+            Err(FsError::InvalidInput(_))
+        ));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_push_pull_blocks() {
+    run_test(
+        TestSetup {
+            key: "test_push_pull_blocks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(fs.block_size() * 3 + 42);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let root = tempfile::tempdir().unwrap();
+            let backend = LocalBackend::new(root.path());
+            // inode and 4 blocks of content
+            assert_eq!(5, fs.push_blocks(attr.ino, &backend).await.unwrap());
+            assert_eq!(
+                4,
+                backend
+                    .list(&format!("{CONTENTS_DIR}/{}/", attr.ino))
+                    .await
+                    .unwrap()
+                    .len()
+            );
+
+            // shorter content removes the extra blocks
+            fs.set_len(attr.ino, 10).await.unwrap();
+            assert_eq!(2, fs.push_blocks(attr.ino, &backend).await.unwrap());
+            assert_eq!(
+                1,
+                backend
+                    .list(&format!("{CONTENTS_DIR}/{}/", attr.ino))
+                    .await
+                    .unwrap()
+                    .len()
+            );
+
+            fs.set_len(attr.ino, 0).await.unwrap();
+            fs.pull_blocks(attr.ino, &backend).await.unwrap();
+            assert_eq!(10, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(
+                "a".repeat(10),
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            assert!(matches!(
+                fs.pull_blocks(42_000, &backend).await,
+                Err(FsError::InodeNotFound)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fsync_on_release() {
+    assert!(!FsOptions::default().fsync_on_release);
+    for fsync_on_release in [false, true] {
+        let vault = TestVault::builder()
+            .options(
+                FsOptions::default()
+                    .with_fsync_on_release(fsync_on_release)
+                    .with_write_journal(true),
+            )
+            .build()
+            .await
+            .unwrap();
+        let fs = vault.fs();
+        let ino = vault.create_file("test-file").await.unwrap();
+        let syncs = fs.release_syncs.load(Ordering::SeqCst);
+        let data = vec![42; fs.block_size() * 2 + 42];
+        vault.write_all(ino, 0, &data).await.unwrap();
+        assert_eq!(
+            syncs + u64::from(fsync_on_release),
+            fs.release_syncs.load(Ordering::SeqCst)
+        );
+        // on disk when released, not only in the writer
+        assert!(std::fs::metadata(fs.contents_path(ino)).unwrap().len() > data.len() as u64);
+        assert_eq!(data, vault.read_all(ino).await.unwrap());
+
+        // with the journal, overwriting blocks is synced before dropping it even when turned off
+        let syncs = fs.release_syncs.load(Ordering::SeqCst);
+        vault.write_all(ino, 10, &[7; 100]).await.unwrap();
+        assert_eq!(syncs + 1, fs.release_syncs.load(Ordering::SeqCst));
+        let mut expected = data.clone();
+        expected[10..110].fill(7);
+        assert_eq!(expected, vault.read_all(ino).await.unwrap());
+        assert!(!vault
+            .data_dir()
+            .join(JOURNAL_DIR)
+            .join(ino.to_string())
+            .exists());
+
+        // read handles have nothing to sync
+        let syncs = fs.release_syncs.load(Ordering::SeqCst);
+        vault.read_all(ino).await.unwrap();
+        assert_eq!(syncs, fs.release_syncs.load(Ordering::SeqCst));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_storage_mirror() {
+    let root = tempfile::tempdir().unwrap();
+    let backend = Arc::new(LocalBackend::new(root.path()));
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_storage(Storage::Mirror(backend.clone())))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let blocks = |ino: u64| {
+        let backend = backend.clone();
+        async move {
+            backend
+                .list(&format!("{CONTENTS_DIR}/{ino}/"))
+                .await
+                .unwrap()
+                .len()
+        }
+    };
+
+    // uploaded when the writer is released
+    let ino = vault.create_file("test-file").await.unwrap();
+    let data = vec![42; fs.block_size() * 3 + 42];
+    vault.write_all(ino, 0, &data).await.unwrap();
+    assert_eq!(4, blocks(ino).await);
+    // only the blocks written are uploaded again
+    let first = format!("{CONTENTS_DIR}/{ino}/0");
+    let third = format!("{CONTENTS_DIR}/{ino}/2");
+    let old_third = backend.get_block(&third).await.unwrap().unwrap();
+    backend.delete_block(&first).await.unwrap();
+    vault
+        .write_all(ino, fs.block_size() as u64 * 2 + 1, &[7; 10])
+        .await
+        .unwrap();
+    assert!(backend.get_block(&first).await.unwrap().is_none());
+    assert_ne!(old_third, backend.get_block(&third).await.unwrap().unwrap());
+    fs.set_len(ino, 10).await.unwrap();
+    assert_eq!(1, blocks(ino).await);
+
+    // missing locally, downloaded when opened
+    std::fs::remove_file(vault.data_dir().join(CONTENTS_DIR).join(ino.to_string())).unwrap();
+    assert_eq!(vec![42; 10], vault.read_all(ino).await.unwrap());
+
+    fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file").unwrap())
+        .await
+        .unwrap();
+    assert_eq!(0, blocks(ino).await);
+    assert!(backend.list("").await.unwrap().is_empty());
+
+    // the metadata has to be in files
+    let res = TestVault::builder()
+        .options(
+            FsOptions::default()
+                .with_metadata_store(MetadataStore::EmbeddedDb)
+                .with_storage(Storage::Mirror(backend.clone())),
+        )
+        .build()
+        .await;
+    assert!(matches!(res, Err(FsError::InvalidInput(_))));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_sync_metadata() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_sync_metadata(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let dir = SecretString::from_str("test-dir").unwrap();
+    let file = SecretString::from_str("test-file").unwrap();
+    let renamed = SecretString::from_str("test-file-renamed").unwrap();
+    let (_, dir_attr) = fs
+        .create(
+            ROOT_INODE,
+            &dir,
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.create(
+        dir_attr.ino,
+        &file,
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+    fs.rename(dir_attr.ino, &file, ROOT_INODE, &renamed)
+        .await
+        .unwrap();
+    assert!(!fs.exists_by_name_async(dir_attr.ino, &file).await.unwrap());
+    assert!(fs.exists_by_name_async(ROOT_INODE, &renamed).await.unwrap());
+    fs.remove_file(ROOT_INODE, &renamed).await.unwrap();
+    fs.remove_dir(ROOT_INODE, &dir).await.unwrap();
+    assert_eq!(0, fs.len(ROOT_INODE).unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_metadata_store_embedded_db() {
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_metadata_store(MetadataStore::EmbeddedDb))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let data_dir = vault.data_dir();
+    assert_eq!(MetadataStore::EmbeddedDb, fs.metadata_store());
+
+    let dir = SecretString::from_str("test-dir").unwrap();
+    let file = SecretString::from_str("test-file").unwrap();
+    let (_, dir_attr) = fs
+        .create(
+            ROOT_INODE,
+            &dir,
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (fh, attr) = fs
+        .create(
+            dir_attr.ino,
+            &file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(fs, attr.ino, 0, b"test-content", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!(1, fs.len(dir_attr.ino).unwrap());
+    // only the db, no file for each inode
+    assert_eq!(
+        1,
+        std::fs::read_dir(data_dir.join(INODES_DIR))
+            .unwrap()
+            .count()
+    );
+
+    // the value saved in the data dir is used
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    assert_eq!(MetadataStore::EmbeddedDb, fs.metadata_store());
+    let mut names = fs
+        .read_dir_plus(dir_attr.ino)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(vec![".", "..", "test-file"], names);
+    let found = fs.find_by_name(dir_attr.ino, &file).await.unwrap().unwrap();
+    assert_eq!(attr.ino, found.ino);
+    assert_eq!(12, found.size);
+    assert_eq!(
+        "test-content",
+        test_common::read_to_string(attr.ino, fs).await
+    );
+
+    let renamed = SecretString::from_str("test-file-renamed").unwrap();
+    fs.rename(dir_attr.ino, &file, ROOT_INODE, &renamed)
+        .await
+        .unwrap();
+    assert!(!fs.exists_by_name_async(dir_attr.ino, &file).await.unwrap());
+    assert!(fs.exists_by_name_async(ROOT_INODE, &renamed).await.unwrap());
+    fs.remove_file(ROOT_INODE, &renamed).await.unwrap();
+    assert!(!fs.exists(attr.ino));
+    fs.remove_dir(ROOT_INODE, &dir).await.unwrap();
+    assert!(!fs.exists(dir_attr.ino));
+    assert_eq!(0, fs.len(ROOT_INODE).unwrap());
+    assert!(matches!(
+        fs.data_files_for(ROOT_INODE),
+        Err(FsError::InvalidInput(_))
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_prefetch_dir_metadata() {
+    run_test(
+        TestSetup {
+            key: "test_prefetch_dir_metadata",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut inos = vec![];
+            for i in 0..5 {
+                let name = SecretString::from_str(&format!("test-file-{i}")).unwrap();
+                let (_, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name,
+                        create_attr(FileType::RegularFile),
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                inos.push(attr.ino);
+            }
+            let root_atime = fs.get_attr(ROOT_INODE).await.unwrap().atime;
+            {
+                let lock = fs.attr_cache.get().await.unwrap();
+                let mut cache = lock.write().await;
+                for ino in &inos {
+                    cache.pop(ino);
+                }
+            }
+
+            // the 5 files and "."
+            assert_eq!(6, fs.prefetch_dir_metadata(ROOT_INODE).await.unwrap());
+            let lock = fs.attr_cache.get().await.unwrap();
+            let cache = lock.read().await;
+            for ino in &inos {
+                assert!(cache.contains(ino));
+            }
+            drop(cache);
+            assert_eq!(root_atime, fs.get_attr(ROOT_INODE).await.unwrap().atime);
+            assert!(matches!(
+                fs.prefetch_dir_metadata(inos[0]).await,
+                Err(FsError::NotADirectory)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_quick_verify() {
+    run_test(
+        TestSetup {
+            key: "test_quick_verify",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(crypto::write::BLOCK_SIZE * 2 + 50);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert!(fs.quick_verify(attr.ino).await.unwrap().is_empty());
+            assert!(matches!(
+                fs.quick_verify(ROOT_INODE).await,
+                Err(FsError::IsADirectory)
+            ));
+
+            // flip a bit in the first block
+            let path = fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let mut content = std::fs::read(&path).unwrap();
+            content[20] ^= 1;
+            std::fs::write(&path, &content).unwrap();
+            assert_eq!(vec![0], fs.quick_verify(attr.ino).await.unwrap());
+
+            // lose the last block
+            content.truncate(Cipher::ChaCha20Poly1305.ciphertext_block_len() * 2);
+            std::fs::write(&path, &content).unwrap();
+            assert_eq!(vec![0, 2], fs.quick_verify(attr.ino).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_block_digests() {
+    run_test(
+        TestSetup {
+            key: "test_block_digests",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(crypto::write::BLOCK_SIZE * 2 + 50);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let digests = fs.block_digests(attr.ino).await.unwrap();
+            assert_eq!(
+                vec![0, 1, 2],
+                digests.iter().map(|d| d.index).collect::<Vec<_>>()
+            );
+            assert_eq!(digests, fs.block_digests(attr.ino).await.unwrap());
+            assert!(matches!(
+                fs.block_digests(ROOT_INODE).await,
+                Err(FsError::IsADirectory)
+            ));
+
+            // rewrite only the second block
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(
+                &fs,
+                attr.ino,
+                crypto::write::BLOCK_SIZE as u64 + 10,
+                b"b",
+                fh,
+            )
+            .await
+            .unwrap();
+            fs.release(fh).await.unwrap();
+            let changed = fs.block_digests(attr.ino).await.unwrap();
+            assert_eq!(digests[0], changed[0]);
+            assert_ne!(digests[1], changed[1]);
+            assert_eq!(digests[2], changed[2]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_verify_block() {
+    run_test(
+        TestSetup {
+            key: "test_verify_block",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(crypto::write::BLOCK_SIZE * 2 + 50);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            for block in 0..3 {
+                assert!(fs.verify_block(attr.ino, block).await.unwrap());
+            }
+            assert!(matches!(
+                fs.verify_block(attr.ino, 3).await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            // flip a bit in the second block
+            let path = fs.data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+            let mut content = std::fs::read(&path).unwrap();
+            content[Cipher::ChaCha20Poly1305.ciphertext_block_len() + 20] ^= 1;
+            std::fs::write(&path, &content).unwrap();
+            assert!(fs.verify_block(attr.ino, 0).await.unwrap());
+            assert!(!fs.verify_block(attr.ino, 1).await.unwrap());
+            assert!(fs.verify_block(attr.ino, 2).await.unwrap());
+
+            // recovered
+            content[Cipher::ChaCha20Poly1305.ciphertext_block_len() + 20] ^= 1;
+            std::fs::write(&path, &content).unwrap();
+            assert!(fs.verify_block(attr.ino, 1).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_random_writes() {
+    run_test(
+        TestSetup {
+            key: "test_random_writes",
+            read_only: false,
+        },
+        async {
+            use rand::Rng;
+
+            let fs = get_fs().await;
+
+            // like a disk image used by a loop device
+            let test_file = SecretString::from_str("disk.img").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let len = 1024 * 1024;
+            fs.set_len(attr.ino, len as u64).await.unwrap();
+            let mut expected = vec![0_u8; len];
+
+            let mut rng = rand::thread_rng();
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            let mut data = [0_u8; 4096];
+            for _ in 0..200 {
+                let offset = rng.gen_range(0..len / data.len()) * data.len();
+                rng.fill(&mut data[..]);
+                // a write in the middle of the file can stop at the end of a block
+                let mut pos = 0;
+                while pos < data.len() {
+                    pos += fs
+                        .write(attr.ino, (offset + pos) as u64, &data[pos..], fh)
+                        .await
+                        .unwrap();
+                }
+                expected[offset..offset + data.len()].copy_from_slice(&data);
+            }
+            fs.release(fh).await.unwrap();
+            assert_eq!(len as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut actual = vec![0_u8; len];
+            let mut pos = 0;
+            while pos < len {
+                let read = fs
+                    .read(attr.ino, pos as u64, &mut actual[pos..], fh)
+                    .await
+                    .unwrap();
+                assert_ne!(0, read);
+                pos += read;
+            }
+            fs.release(fh).await.unwrap();
+            assert!(expected == actual);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_write_past_4gib() {
+    let vault = TestVault::builder().build().await.unwrap();
+    let fs = vault.fs();
+    let ino = vault.create_file("disk.img").await.unwrap();
+
+    // a hole up to the first block after 4 GiB, so we don't encrypt all the zeros before it
+    let block_size = fs.block_size() as u64;
+    let blocks = (1_u64 << 32).div_ceil(block_size);
+    let block_len = Cipher::ChaCha20Poly1305.ciphertext_block_len_for(fs.block_size()) as u64;
+    std::fs::File::options()
+        .write(true)
+        .open(fs.contents_path(ino))
+        .unwrap()
+        .set_len(blocks * block_len)
+        .unwrap();
+    let offset = blocks * block_size;
+    fs.set_attr(ino, SetFileAttr::default().with_size(offset))
+        .await
+        .unwrap();
+
+    let data: Vec<u8> = (0..block_size * 2 + 42).map(|i| (i % 251) as u8).collect();
+    vault.write_all(ino, offset, &data).await.unwrap();
+    assert_eq!(
+        offset + data.len() as u64,
+        fs.get_attr(ino).await.unwrap().size
+    );
+    assert_eq!(data, vault.read(ino, offset, data.len()).await.unwrap());
+    // the blocks are where the size says, after the hole
+    assert_eq!(
+        (blocks + 3) * block_len - block_size + 42,
+        std::fs::metadata(fs.contents_path(ino)).unwrap().len()
+    );
+
+    // and change it in place, across two blocks
+    let mut expected = data.clone();
+    expected[90..110].fill(7);
+    vault.write_all(ino, offset + 90, &[7; 20]).await.unwrap();
+    assert_eq!(expected, vault.read(ino, offset, data.len()).await.unwrap());
+    assert_eq!(
+        offset + data.len() as u64,
+        fs.get_attr(ino).await.unwrap().size
+    );
+}
+
+/// Takes a second for each operation, like a stuck network request.
+struct SlowBackend;
+
+#[async_trait::async_trait]
+impl StorageBackend for SlowBackend {
+    async fn get_block(&self, _key: &str) -> FsResult<Option<Vec<u8>>> {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        Ok(None)
+    }
+
+    async fn put_block(&self, _key: &str, _data: &[u8]) -> FsResult<()> {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        Ok(())
+    }
+
+    async fn delete_block(&self, _key: &str) -> FsResult<()> {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        Ok(())
+    }
+
+    async fn list(&self, _prefix: &str) -> FsResult<Vec<String>> {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        Ok(vec![])
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_op_timeout() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_op_timeout(std::time::Duration::from_millis(50)))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let start = std::time::Instant::now();
+    assert!(matches!(
+        fs.push_blocks(ROOT_INODE, &SlowBackend).await,
+        Err(FsError::Timeout)
+    ));
+    assert!(matches!(
+        fs.pull_blocks(ROOT_INODE, &SlowBackend).await,
+        Err(FsError::Timeout)
+    ));
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+    // blocking the thread, like a read from a hung disk
+    let start = std::time::Instant::now();
+    assert!(matches!(
+        fs.with_timeout_blocking(|| {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+            Ok(())
+        })
+        .await,
+        Err(FsError::Timeout)
+    ));
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            true,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write(attr.ino, 0, b"test", fh).await.unwrap();
+    fs.flush(fh).await.unwrap();
+    assert_eq!(
+        b"test".to_vec(),
+        fs.read_with_timeout(attr.ino, 0, 10, fh).await.unwrap()
+    );
+    fs.release(fh).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_case_insensitive() {
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_case_insensitive(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(fs.case_insensitive());
+
+    let name = SecretString::from_str("Straße.TXT").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+
+    for other in ["straße.txt", "STRASSE.txt", "strasse.Txt"] {
+        let other = SecretString::from_str(other).unwrap();
+        let found = fs.find_by_name(ROOT_INODE, &other).await.unwrap().unwrap();
+        assert_eq!(attr.ino, found.ino);
+        assert!(matches!(
+            fs.create(
+                ROOT_INODE,
+                &other,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await,
+            Err(FsError::AlreadyExists)
+        ));
+    }
+    // the case it was created with is kept
+    let names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .collect();
+    assert!(names.contains(&"Straße.TXT".to_string()));
+
+    // rename to change only the case
+    let new_name = SecretString::from_str("strasse.txt").unwrap();
+    fs.rename(ROOT_INODE, &name, ROOT_INODE, &new_name)
+        .await
+        .unwrap();
+    let names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .collect();
+    assert!(names.contains(&"strasse.txt".to_string()));
+    assert!(!names.contains(&"Straße.TXT".to_string()));
+    assert_eq!(1, fs.len(ROOT_INODE).unwrap());
+
+    // the value saved in the data dir is used
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    assert!(fs.case_insensitive());
+    let other = SecretString::from_str("STRASSE.TXT").unwrap();
+    assert!(fs.exists_by_name_async(ROOT_INODE, &other).await.unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_normalize_names() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_normalize_names(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(fs.normalize_names());
+
+    let nfd = SecretString::from_str("cafe\u{301}").unwrap();
+    let nfc = SecretString::from_str("caf\u{e9}").unwrap();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &nfd,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+
+    let found = fs.find_by_name(ROOT_INODE, &nfc).await.unwrap().unwrap();
+    assert_eq!(attr.ino, found.ino);
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &nfc,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::AlreadyExists)
+    ));
+    // stored in NFC
+    let names: Vec<_> = fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .collect();
+    assert!(names.contains(&"caf\u{e9}".to_string()));
+    assert!(!names.contains(&"cafe\u{301}".to_string()));
+
+    let new_name = SecretString::from_str("re\u{301}sume\u{301}").unwrap();
+    fs.rename(ROOT_INODE, &nfc, ROOT_INODE, &new_name)
+        .await
+        .unwrap();
+    let lookup = SecretString::from_str("r\u{e9}sum\u{e9}").unwrap();
+    assert!(fs.exists_by_name_async(ROOT_INODE, &lookup).await.unwrap());
+    fs.remove_file(ROOT_INODE, &lookup).await.unwrap();
+    assert!(!fs
+        .exists_by_name_async(ROOT_INODE, &new_name)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_du() {
+    run_test(
+        TestSetup {
+            key: "test_du",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, sub_dir) = fs
+                .create(
+                    dir.ino,
+                    &SecretString::from_str("sub-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            for (parent, name, len) in [(dir.ino, "a", 250), (sub_dir.ino, "b", 42)] {
+                let (fh, attr) = fs
+                    .create(
+                        parent,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &vec![7; len], fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+            }
+
+            let usage = fs.du(dir.ino).await.unwrap();
+            assert_eq!(292, usage.logical_bytes);
+            assert!(usage.physical_bytes >= usage.logical_bytes);
+            assert_eq!(2, usage.files);
+            assert_eq!(2, usage.dirs);
+
+            let usage = fs.du(sub_dir.ino).await.unwrap();
+            assert_eq!(42, usage.logical_bytes);
+            assert_eq!(1, usage.files);
+            assert_eq!(1, usage.dirs);
+
+            let usage = fs.du(ROOT_INODE).await.unwrap();
+            assert_eq!(292, usage.logical_bytes);
+            assert_eq!(3, usage.dirs);
+        },
+    )
+    .await;
+}
+
+/// Stands for a hardware token, the byte it holds is its key.
+#[derive(Debug)]
+struct XorToken(u8);
+
+impl HeaderProtection for XorToken {
+    fn protect(&self, header: &[u8]) -> FsResult<Vec<u8>> {
+        Ok(header.iter().map(|b| b ^ self.0).collect())
+    }
+
+    fn unprotect(&self, data: &[u8]) -> FsResult<Vec<u8>> {
+        self.protect(data)
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_header_protection() {
+    let options = |token: Option<u8>| {
+        let options = FsOptions::default();
+        match token {
+            Some(token) => options.with_header_protection(Arc::new(XorToken(token))),
+            None => options,
+        }
+    };
+    let mut vault = TestVault::builder()
+        .options(options(Some(0x5a)))
+        .build()
+        .await
+        .unwrap();
+    let ino = vault.create_file("secret").await.unwrap();
+    vault.write_all(ino, 0, b"test-content").await.unwrap();
+
+    // without the token, or with another one, the password is not enough
+    assert!(matches!(
+        vault.reopen(false, options(None)).await,
+        Err(FsError::InvalidPassword)
+    ));
+    assert!(matches!(
+        vault.reopen(false, options(Some(0x33))).await,
+        Err(FsError::InvalidPassword)
+    ));
+
+    vault.reopen(false, options(Some(0x5a))).await.unwrap();
+    assert_eq!(b"test-content", &vault.read_all(ino).await.unwrap()[..]);
+
+    EncryptedFs::passwd_with_protection(
+        vault.data_dir(),
+        SecretString::from_str("password").unwrap(),
+        SecretString::from_str("new-password").unwrap(),
+        Cipher::ChaCha20Poly1305,
+        &XorToken(0x5a),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        EncryptedFs::passwd(
+            vault.data_dir(),
+            SecretString::from_str("new-password").unwrap(),
+            SecretString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+}
+
+/// Stands for a FIDO2 authenticator, `None` when it's not plugged in.
+#[derive(Debug)]
+struct FakeAuthenticator(Option<[u8; 32]>);
+
+impl HmacSecretDevice for FakeAuthenticator {
+    fn make_credential(&self) -> FsResult<Vec<u8>> {
+        self.0.ok_or(FsError::SecondFactorRequired)?;
+        Ok(b"credential".to_vec())
+    }
+
+    fn hmac_secret(&self, credential_id: &[u8], salt: &[u8; 32]) -> FsResult<[u8; 32]> {
+        let secret = self.0.ok_or(FsError::SecondFactorRequired)?;
+        let mut data = secret.to_vec();
+        data.extend_from_slice(credential_id);
+        data.extend_from_slice(salt);
+        Ok(crypto::hash(&data))
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_fido2_protection() {
+    let options = |device: FakeAuthenticator| {
+        FsOptions::default()
+            .with_header_protection(Arc::new(Fido2Protection::new(Box::new(device))))
+    };
+    let mut vault = TestVault::builder()
+        .options(options(FakeAuthenticator(Some([1; 32]))))
+        .build()
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        vault.reopen(false, options(FakeAuthenticator(None))).await,
+        Err(FsError::SecondFactorRequired)
+    ));
+    assert!(matches!(
+        vault
+            .reopen(false, options(FakeAuthenticator(Some([2; 32]))))
+            .await,
+        Err(FsError::SecondFactorRequired)
+    ));
+    // the password alone is not enough
+    assert!(matches!(
+        vault.reopen(false, FsOptions::default()).await,
+        Err(FsError::InvalidPassword)
+    ));
+    vault
+        .reopen(false, options(FakeAuthenticator(Some([1; 32]))))
+        .await
+        .unwrap();
+
+    // checking the password always asks the authenticator, even after it was right
+    let data_dir = vault.data_dir();
+    let password = SecretString::from_str("password").unwrap();
+    let check = |device: FakeAuthenticator| {
+        EncryptedFs::check_password_with_protection(
+            data_dir,
+            &password,
+            Cipher::ChaCha20Poly1305,
+            &Fido2Protection::new(Box::new(device)),
+        )
+    };
+    assert!(check(FakeAuthenticator(Some([1; 32]))).unwrap());
+    assert!(check(FakeAuthenticator(Some([1; 32]))).unwrap());
+    assert!(matches!(
+        check(FakeAuthenticator(None)),
+        Err(FsError::SecondFactorRequired)
+    ));
+    assert!(!EncryptedFs::check_password(data_dir, &password, Cipher::ChaCha20Poly1305).unwrap());
+
+    // even with the key unwrapped from the authenticator, its HMAC is mixed in the password's key
+    let key_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+    let protection = Fido2Protection::new(Box::new(FakeAuthenticator(Some([1; 32]))));
+    let header = protection
+        .unprotect(&std::fs::read(&key_file).unwrap())
+        .unwrap();
+    std::fs::write(&key_file, header).unwrap();
+    assert!(matches!(
+        vault.reopen(false, FsOptions::default()).await,
+        Err(FsError::InvalidPassword)
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_into() {
+    run_test(
+        TestSetup {
+            key: "test_read_into",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &data, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut pages = vec![];
+            let len = fs
+                .read_into(attr.ino, 150, 600, fh, |page| {
+                    pages.push(page.to_vec());
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            assert_eq!(600, len);
+            // at most a block each
+            assert!(pages.iter().all(|page| page.len() <= 100));
+            assert_eq!(&data[150..750], &pages.concat()[..]);
+
+            // stops at the end of the file
+            let mut read = vec![];
+            let len = fs
+                .read_into(attr.ino, 900, 500, fh, |page| {
+                    read.extend_from_slice(page);
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            assert_eq!(100, len);
+            assert_eq!(&data[900..], &read[..]);
+            let len = fs
+                .read_into(attr.ino, 2000, 10, fh, |_| panic!("after the end"))
+                .await
+                .unwrap();
+            assert_eq!(0, len);
+
+            // errors from the callback stop the read
+            let mut calls = 0;
+            assert!(matches!(
+                fs.read_into(attr.ino, 0, 1000, fh, |_| {
+                    calls += 1;
+                    Err(FsError::Other("stop"))
+                })
+                .await,
+                Err(FsError::Other("stop"))
+            ));
+            assert_eq!(1, calls);
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_max_dirty_per_handle() {
+    let vault = TestVault::builder()
+        .options(
+            FsOptions::default()
+                .with_max_dirty_per_handle(250)
+                .with_write_journal(true),
+        )
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let info = |fs: &EncryptedFs| {
+        fs.open_handles()
+            .into_iter()
+            .find(|info| info.fh == fh)
+            .unwrap()
+    };
+    let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    for (i, chunk) in data.chunks(100).enumerate() {
+        fs.write(attr.ino, i as u64 * 100, chunk, fh).await.unwrap();
+        assert!(info(fs).dirty_bytes < 250);
+    }
+    assert_eq!(1000, info(fs).bytes_written);
+    // written out after every 3rd write, only the last one is not
+    assert_eq!(100, info(fs).dirty_bytes);
+    let len = std::fs::metadata(fs.contents_path(attr.ino)).unwrap().len();
+    assert!(plaintext_len(len, fs.cipher, fs.block_size) >= 900);
+    // but not synced, the journal is kept until the flush
+    assert!(info(fs).dirty);
+    assert!(fs.journal_path(attr.ino).exists());
+    assert!(!fs.write_would_block(fh, 100));
+    assert!(fs.write_would_block(fh, 150));
+    fs.flush(fh).await.unwrap();
+    assert_eq!(0, info(fs).dirty_bytes);
+    assert!(!info(fs).dirty);
+    assert!(!fs.journal_path(attr.ino).exists());
+    // nothing to flush, a write larger than the limit goes through instead of waiting forever
+    assert!(!fs.write_would_block(fh, 1000));
+    fs.release(fh).await.unwrap();
+
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = vec![0; 1000];
+    let mut read = 0;
+    while read < buf.len() {
+        let len = fs
+            .read(attr.ino, read as u64, &mut buf[read..], fh)
+            .await
+            .unwrap();
+        assert!(len > 0);
+        read += len;
+    }
+    fs.release(fh).await.unwrap();
+    assert_eq!(data, buf);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_file_flags() {
+    run_test(
+        TestSetup {
+            key: "test_file_flags",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let name = SecretString::from_str("immutable").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.write(attr.ino, 0, b"data", fh).await.unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.set_flags(attr.ino, FS_IMMUTABLE_FL).await.unwrap();
+            assert_eq!(FS_IMMUTABLE_FL, fs.get_flags(attr.ino).await.unwrap());
+            assert!(matches!(
+                fs.write(attr.ino, 4, b"more", fh).await,
+                Err(FsError::NotPermitted)
+            ));
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.open(attr.ino, false, true).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.set_len(attr.ino, 0).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.rename(
+                    ROOT_INODE,
+                    &name,
+                    ROOT_INODE,
+                    &SecretString::from_str("renamed").unwrap()
+                )
+                .await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &name).await,
+                Err(FsError::NotPermitted)
+            ));
+            // can still be read
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = [0; 4];
+            fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(b"data", &buf);
+            fs.release(fh).await.unwrap();
+            // and removed after clearing the flag
+            fs.set_flags(attr.ino, 0).await.unwrap();
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+
+            let name = SecretString::from_str("append-only").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.set_flags(attr.ino, FS_APPEND_FL).await.unwrap();
+            fs.write(attr.ino, 0, b"data", fh).await.unwrap();
+            fs.write(attr.ino, 4, b"more", fh).await.unwrap();
+            assert!(matches!(
+                fs.write(attr.ino, 0, b"over", fh).await,
+                Err(FsError::NotPermitted)
+            ));
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.set_len(attr.ino, 4).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &name).await,
+                Err(FsError::NotPermitted)
+            ));
+            assert_eq!(8, fs.get_attr(attr.ino).await.unwrap().size);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_lock_memory() {
+    // not strict, so it works even if RLIMIT_MEMLOCK is 0 where the tests run
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_lock_memory(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write(attr.ino, 0, b"data", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [0; 4];
+    fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!(b"data", &buf);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+#[traced_test]
+async fn test_protect_from_coredump() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_protect_from_coredump(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert_eq!(0, unsafe { libc::prctl(libc::PR_GET_DUMPABLE) });
+    // the key is still usable
+    fs.create(
+        ROOT_INODE,
+        &SecretString::from_str("test-file").unwrap(),
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_readdir_order() {
+    for (order, expected) in [
+        (ReaddirOrder::SortedByName, [".", "..", "B", "a", "b", "c"]),
+        (
+            ReaddirOrder::InsertionOrder,
+            [".", "..", "c", "a", "B", "b"],
+        ),
+    ] {
+        let vault = TestVault::builder()
+            .options(FsOptions::default().with_readdir_order(order))
+            .build()
+            .await
+            .unwrap();
+        let fs = vault.fs();
+
+        let (_, dir) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("dir").unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        for name in ["c", "a", "B", "b"] {
+            fs.create(
+                dir.ino,
+                &SecretString::from_str(name).unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            // so the creation times differ
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let names: Vec<_> = fs
+            .read_dir(dir.ino)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().clone())
+            .collect();
+        assert_eq!(expected.to_vec(), names);
+        let names: Vec<_> = fs
+            .read_dir_plus(dir.ino)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().clone())
+            .collect();
+        assert_eq!(expected.to_vec(), names);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_past_eof() {
+    run_test(
+        TestSetup {
+            key: "test_read_past_eof",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            // a partial last block, and only full blocks
+            for (name, size) in [
+                ("partial", crypto::write::BLOCK_SIZE + 3),
+                ("full", crypto::write::BLOCK_SIZE * 2),
+            ] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                let mut written = 0;
+                while written < data.len() {
+                    written += fs
+                        .write(attr.ino, written as u64, &data[written..], fh)
+                        .await
+                        .unwrap();
+                }
+                fs.release(fh).await.unwrap();
+                assert_eq!(size as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+                let fh = fs.open(attr.ino, true, false).await.unwrap();
+                // spans past the end, gets only what's in the file
+                let mut buf = vec![0; 50];
+                let offset = size - 20;
+                let len = fs
+                    .read(attr.ino, offset as u64, &mut buf, fh)
+                    .await
+                    .unwrap();
+                assert_eq!(20, len);
+                assert_eq!(&data[offset..], &buf[..len]);
+                // the whole file in one read
+                let mut buf = vec![0; size + 100];
+                let mut read = 0;
+                loop {
+                    let len = fs
+                        .read(attr.ino, read as u64, &mut buf[read..], fh)
+                        .await
+                        .unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    read += len;
+                }
+                assert_eq!(size, read);
+                assert_eq!(data, buf[..read]);
+                // after the end
+                assert_eq!(
+                    0,
+                    fs.read(attr.ino, size as u64 + 10, &mut buf, fh)
+                        .await
+                        .unwrap()
+                );
+                fs.release(fh).await.unwrap();
+            }
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_seal() {
+    run_test(
+        TestSetup {
+            key: "test_seal",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let sealed = fs.seal(b"config").await.unwrap();
+            assert!(!sealed.windows(6).any(|w| w == b"config"));
+            assert_eq!(b"config".to_vec(), fs.unseal(&sealed).await.unwrap());
+            // a new nonce each time
+            assert_ne!(sealed, fs.seal(b"config").await.unwrap());
+            assert!(fs
+                .unseal(&fs.seal(b"").await.unwrap())
+                .await
+                .unwrap()
+                .is_empty());
+
+            let mut changed = sealed.clone();
+            *changed.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                fs.unseal(&changed).await,
+                Err(FsError::Crypto { .. })
+            ));
+            assert!(matches!(
+                fs.unseal(&sealed[..10]).await,
+                Err(FsError::Crypto { .. })
+            ));
+
+            // another vault has another key
+            let other = TestVault::builder().build().await.unwrap();
+            assert!(matches!(
+                other.fs().unseal(&sealed).await,
+                Err(FsError::Crypto { .. })
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_mirror_mtime_to_backing() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_mirror_mtime_to_backing(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let data_dir = vault.data_dir();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write(attr.ino, 0, b"data", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let path = data_dir.join(CONTENTS_DIR).join(attr.ino.to_string());
+    let backing_mtime = || std::fs::metadata(&path).unwrap().modified().unwrap();
+    assert_eq!(fs.get_attr(attr.ino).await.unwrap().mtime, backing_mtime());
+
+    // like `touch -d`
+    let mtime = SystemTime::now() + std::time::Duration::from_secs(3600);
+    fs.set_attr(attr.ino, SetFileAttr::default().with_mtime(mtime))
+        .await
+        .unwrap();
+    assert_eq!(mtime, fs.get_attr(attr.ino).await.unwrap().mtime);
+    assert_eq!(mtime, backing_mtime());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_export_import_header() {
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_case_insensitive(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let data_dir = vault.data_dir();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write(attr.ino, 0, b"data", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    let header = EncryptedFs::export_header(data_dir).unwrap();
+    assert!(matches!(
+        EncryptedFs::import_header(data_dir, b"garbage"),
+        Err(FsError::InvalidInput(_))
+    ));
+    // lose it
+    std::fs::remove_dir_all(data_dir.join(SECURITY_DIR)).unwrap();
+    EncryptedFs::import_header(data_dir, &header).unwrap();
+
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    // from the params
+    assert!(fs.case_insensitive());
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = [0; 4];
+    fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!(b"data", &buf);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_only_paths() {
+    let mut vault = TestVault::builder().build().await.unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+
+    let fs = vault.fs();
+    let (_, templates) = fs
+        .create(
+            ROOT_INODE,
+            &name("templates"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (fh, file) = fs
+        .create(
+            templates.ino,
+            &name("file"),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write(file.ino, 0, b"template", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    vault
+        .reopen(
+            false,
+            FsOptions::default().with_read_only_paths(vec!["/templates".into(), "/missing".into()]),
+        )
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(matches!(
+        fs.open(file.ino, false, true).await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.set_len(file.ino, 0).await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.set_attr(file.ino, SetFileAttr::default().with_perm(0o600))
+            .await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.create(
+            templates.ino,
+            &name("new"),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.remove_file(templates.ino, &name("file")).await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.remove_dir(ROOT_INODE, &name("templates")).await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.rename(templates.ino, &name("file"), ROOT_INODE, &name("file"))
+            .await,
+        Err(FsError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.rename(ROOT_INODE, &name("templates"), ROOT_INODE, &name("t"))
+            .await,
+        Err(FsError::ReadOnly)
+    ));
+    // reading still works
+    let fh = fs.open(file.ino, true, false).await.unwrap();
+    let mut buf = [0; 8];
+    fs.read(file.ino, 0, &mut buf, fh).await.unwrap();
+    assert_eq!(b"template", &buf);
+    fs.release(fh).await.unwrap();
+
+    // the rest of the vault is writable, but nothing can be moved in
+    let (fh, other) = fs
+        .create(
+            ROOT_INODE,
+            &name("other"),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    fs.write(other.ino, 0, b"data", fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    assert!(matches!(
+        fs.rename(ROOT_INODE, &name("other"), templates.ino, &name("other"))
+            .await,
+        Err(FsError::ReadOnly)
+    ));
+    fs.remove_file(ROOT_INODE, &name("other")).await.unwrap();
+}
+
+#[derive(Debug)]
+struct RejectSecrets;
+
+impl crypto::BlockTransform for RejectSecrets {
+    fn on_write(&self, block: &mut Vec<u8>) -> std::io::Result<()> {
+        if block.windows(6).any(|w| w == b"SECRET") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "secrets are not allowed",
+            ));
+        }
+        Ok(())
+    }
+
+    fn on_read(&self, _block: &mut Vec<u8>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Reverse;
+
+impl crypto::BlockTransform for Reverse {
+    fn on_write(&self, block: &mut Vec<u8>) -> std::io::Result<()> {
+        block.reverse();
+        Ok(())
+    }
+
+    fn on_read(&self, block: &mut Vec<u8>) -> std::io::Result<()> {
+        block.reverse();
+        Ok(())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_block_transforms() {
+    let mut vault = TestVault::builder()
+        .options(
+            FsOptions::default()
+                .with_block_transforms(vec![Arc::new(RejectSecrets), Arc::new(Reverse)]),
+        )
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let ino = vault.create_file("test-file").await.unwrap();
+    let data: Vec<u8> = (0..250).map(|i| (i % 251) as u8).collect();
+    vault.write_all(ino, 0, &data).await.unwrap();
+    assert_eq!(data, vault.read_all(ino).await.unwrap());
+
+    // a transform can reject the content
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write(ino, 0, b"my SECRET", fh).await.unwrap();
+    assert!(fs.flush(fh).await.is_err());
+
+    // what's stored is what the transforms gave
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let stored = vault.read_all(ino).await.unwrap();
+    assert_eq!(data.len(), stored.len());
+    for (block, stored) in data.chunks(100).zip(stored.chunks(100)) {
+        assert_eq!(
+            block.iter().rev().copied().collect::<Vec<_>>(),
+            stored.to_vec()
+        );
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_max_encryptions_per_key() {
+    let options = || FsOptions::default().with_max_encryptions_per_key(100);
+    let mut vault = TestVault::builder()
+        .options(options())
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let mut events = fs.events();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let before = fs.encryptions();
+    assert!(before > 0);
+    // 100 blocks
+    let data = vec![42_u8; 10_000];
+    let mut written = 0;
+    while written < data.len() {
+        written += fs
+            .write(attr.ino, written as u64, &data[written..], fh)
+            .await
+            .unwrap();
+    }
+    fs.release(fh).await.unwrap();
+    assert!(fs.encryptions() >= before + 100);
+    let mut recommended = false;
+    while let Ok(event) = events.try_recv() {
+        if let FsEvent::RekeyRecommended { encryptions, max } = event {
+            assert_eq!(100, max);
+            assert!(encryptions >= 90);
+            recommended = true;
+        }
+    }
+    assert!(recommended);
+
+    // it's saved in the data dir
+    fs.flush_all().await.unwrap();
+    let encryptions = fs.encryptions();
+    vault.reopen(false, options()).await.unwrap();
+    assert!(vault.fs().encryptions() >= encryptions);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_new_forgiving() {
+    let mut vault = TestVault::builder().build().await.unwrap();
+    let fs = vault.fs();
+    let mut inos = vec![];
+    for name in ["good", "bad"] {
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(name).unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.write(attr.ino, 0, name.as_bytes(), fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        inos.push(attr.ino);
+    }
+    let (good, bad) = (inos[0], inos[1]);
+
+    // damage the attributes of one file and the params
+    let data_dir = vault.data_dir().to_path_buf();
+    let ino_file = data_dir.join(INODES_DIR).join(bad.to_string());
+    let mut data = std::fs::read(&ino_file).unwrap();
+    let len = data.len();
+    data[len - 1] ^= 0xff;
+    std::fs::write(&ino_file, data).unwrap();
+    std::fs::write(
+        data_dir
+            .join(SECURITY_DIR)
+            .join(crate::encryptedfs::PARAMS_FILENAME),
+        b"garbage",
+    )
+    .unwrap();
+    fn snapshot(dir: &std::path::Path, files: &mut Vec<(std::path::PathBuf, u64, SystemTime)>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let meta = entry.metadata().unwrap();
+            files.push((entry.path(), meta.len(), meta.modified().unwrap()));
+            if meta.is_dir() {
+                snapshot(&entry.path(), files);
+            }
+        }
+        files.sort();
+    }
+    let mut before = vec![];
+    snapshot(&data_dir, &mut before);
+
+    assert!(vault.reopen(true, FsOptions::default()).await.is_err());
+    let fs = EncryptedFs::new_forgiving(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    assert!(fs.get_attr(bad).await.is_err());
+    assert_eq!(4, fs.get_attr(good).await.unwrap().size);
+    let fh = fs.open(good, true, false).await.unwrap();
+    let mut buf = [0; 4];
+    assert_eq!(4, fs.read(good, 0, &mut buf, fh).await.unwrap());
+    fs.release(fh).await.unwrap();
+    assert_eq!(b"good", &buf);
+    assert!(fs
+        .read_dir(ROOT_INODE)
+        .await
+        .unwrap()
+        .any(|entry| entry.is_ok_and(|entry| entry.name.expose_secret().as_str() == "good")));
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("new").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::ReadOnly)
+    ));
+    let mut after = vec![];
+    snapshot(&data_dir, &mut after);
+    assert_eq!(before, after);
+}
+
+/// Fails like a full disk after `blocks` blocks were written.
+#[derive(Debug)]
+struct DiskFull {
+    blocks: std::sync::atomic::AtomicI64,
+}
+
+impl crypto::BlockTransform for DiskFull {
+    fn on_write(&self, _block: &mut Vec<u8>) -> std::io::Result<()> {
+        if self
+            .blocks
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst)
+            <= 0
+        {
+            return Err(std::io::ErrorKind::StorageFull.into());
+        }
+        Ok(())
+    }
+
+    fn on_read(&self, _block: &mut Vec<u8>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_short_write() {
+    let disk = Arc::new(DiskFull {
+        blocks: std::sync::atomic::AtomicI64::new(i64::MAX),
+    });
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_block_transforms(vec![disk.clone()]))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+
+    // room for 2 blocks
+    disk.blocks.store(2, std::sync::atomic::Ordering::SeqCst);
+    let data: Vec<u8> = (0..450).map(|i| b'a' + (i % 26) as u8).collect();
+    let written = fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+    assert!(written > 0 && written < data.len(), "{written}");
+    assert_eq!(written as u64, fs.get_attr(attr.ino).await.unwrap().size);
+    // nothing more fits
+    let err = fs
+        .write_all(attr.ino, written as u64, &data[written..], fh)
+        .await
+        .unwrap_err();
+    assert!(err.is_no_space(), "{err}");
+
+    // after freeing some space the rest can be written
+    disk.blocks
+        .store(i64::MAX, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(
+        data.len() - written,
+        fs.write_all(attr.ino, written as u64, &data[written..], fh)
+            .await
+            .unwrap()
+    );
+    fs.release(fh).await.unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&data),
+        test_common::read_to_string(attr.ino, fs).await
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_reserved_space_bytes() {
+    // more than any disk has
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_reserved_space_bytes(u64::MAX / 2))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    // creating is metadata, it can use the reserve
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("test-file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let err = fs.write(attr.ino, 0, b"test", fh).await.unwrap_err();
+    assert!(err.is_no_space(), "{err}");
+    assert_eq!(0, fs.get_attr(attr.ino).await.unwrap().size);
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    // without growing the file it's fine
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    write_all_bytes_to_fs(fs, attr.ino, 0, b"test", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    vault
+        .reopen(
+            false,
+            FsOptions::default().with_reserved_space_bytes(u64::MAX / 2),
+        )
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    write_all_bytes_to_fs(fs, attr.ino, 0, b"TEST", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!("TEST", test_common::read_to_string(attr.ino, fs).await);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_close_handles_for() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_write_journal(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let ino = vault.create_file("test-file").await.unwrap();
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write_all(ino, 0, b"hello", fh).await.unwrap();
+    fs.flush(fh).await.unwrap();
+    let read_fh = fs.open(ino, true, false).await.unwrap();
+    // over a few blocks and into the one we flushed
+    let data = "a".repeat(250);
+    fs.write_all(ino, 2, data.as_bytes(), fh).await.unwrap();
+    assert_eq!(2, fs.close_handles_for(ino, false).await.unwrap());
+    assert!(fs.open_handles().is_empty());
+    assert!(matches!(
+        fs.write(ino, 0, b"test", fh).await,
+        Err(FsError::InvalidFileHandle)
+    ));
+    assert!(!fs.is_read_handle(read_fh).await);
+    assert_eq!(5, fs.get_attr(ino).await.unwrap().size);
+    assert_eq!("hello", test_common::read_to_string(ino, fs).await);
+
+    let fh = fs.open(ino, false, true).await.unwrap();
+    fs.write_all(ino, 5, data.as_bytes(), fh).await.unwrap();
+    assert_eq!(1, fs.close_handles_for(ino, true).await.unwrap());
+    assert_eq!(
+        format!("hello{data}"),
+        test_common::read_to_string(ino, fs).await
+    );
+    assert_eq!(0, fs.close_handles_for(ino, true).await.unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_handles() {
+    run_test(
+        TestSetup {
+            key: "test_dir_handles",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let names = |entries: Vec<FsResult<DirectoryEntry>>| {
+                entries
+                    .into_iter()
+                    .map(|entry| entry.unwrap().name.expose_secret().clone())
+                    .collect::<Vec<_>>()
+            };
+            for name in ["a", "b", "c"] {
+                fs.create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            }
+            let fh = fs.open_dir(ROOT_INODE).unwrap();
+            let all = names(fs.read_dir_at(fh, 0).await.unwrap().collect());
+            // with `.` and maybe `..`
+            assert!(all.len() >= 4, "{all:?}");
+            let len = all.len();
+
+            // what's read after the first chunk is as it was then
+            fs.create(
+                ROOT_INODE,
+                &SecretString::from_str("d").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                all[2..],
+                names(fs.read_dir_at(fh, 2).await.unwrap().collect())
+            );
+            let plus: Vec<_> = fs
+                .read_dir_plus_at(fh, 0)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .collect();
+            assert_eq!(len + 1, plus.len());
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("d").unwrap())
+                .await
+                .unwrap();
+            // the first chunk with attributes and the rest without
+            assert_eq!(
+                plus[2..]
+                    .iter()
+                    .map(|entry| entry.name.expose_secret().clone())
+                    .collect::<Vec<_>>(),
+                names(fs.read_dir_at(fh, 2).await.unwrap().collect())
+            );
+            assert!(fs
+                .read_dir_at(fh, len as u64 + 1)
+                .await
+                .unwrap()
+                .next()
+                .is_none());
+            // from the start again
+            assert_eq!(len, fs.read_dir_at(fh, 0).await.unwrap().count());
+
+            fs.release_dir(fh).unwrap();
+            assert!(matches!(
+                fs.read_dir_at(fh, 0).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+            assert!(matches!(
+                fs.release_dir(fh),
+                Err(FsError::InvalidFileHandle)
+            ));
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("e").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(fs.open_dir(attr.ino), Err(FsError::NotADirectory)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_hidden_patterns() {
+    let vault = TestVault::builder()
+        .options(
+            FsOptions::default()
+                .with_hidden_patterns(vec![".trash".to_string(), "*.lock".to_string()]),
+        )
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    for (file, kind) in [
+        (".trash", FileType::Directory),
+        ("db.lock", FileType::RegularFile),
+        ("db.lock.txt", FileType::RegularFile),
+        ("db", FileType::RegularFile),
+    ] {
+        let (fh, _) = fs
+            .create(ROOT_INODE, &name(file), create_attr(kind), false, false)
+            .await
+            .unwrap();
+        if fh != 0 {
+            fs.release(fh).await.unwrap();
+        }
+    }
+
+    let names = |entries: Vec<String>| {
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        entries.sort();
+        entries
+    };
+    let listed = names(
+        fs.read_dir(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().clone())
+            .collect(),
+    );
+    assert_eq!(vec!["db", "db.lock.txt"], listed);
+    let listed = names(
+        fs.read_dir_plus(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().clone())
+            .collect(),
+    );
+    assert_eq!(vec!["db", "db.lock.txt"], listed);
+    // still there by name
+    assert!(fs
+        .find_by_name(ROOT_INODE, &name(".trash"))
+        .await
+        .unwrap()
+        .is_some());
+    assert!(fs
+        .find_by_name(ROOT_INODE, &name("db.lock"))
+        .await
+        .unwrap()
+        .is_some());
+
+    assert!(super::glob_match("*", ""));
+    assert!(super::glob_match("a*b?d", "axxbcd"));
+    assert!(super::glob_match("*.tar.*", "x.tar.gz"));
+    assert!(!super::glob_match("a?", "a"));
+    assert!(!super::glob_match("*.lock", "db.lock.txt"));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_dir_limits() {
+    let vault = TestVault::builder()
+        .options(
+            FsOptions::default()
+                .with_max_dir_depth(2)
+                .with_max_dir_entries(2),
+        )
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+
+    let (_, a) = fs
+        .create(
+            ROOT_INODE,
+            &name("a"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (_, b) = fs
+        .create(
+            a.ino,
+            &name("b"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        fs.create(
+            b.ino,
+            &name("c"),
+            create_attr(FileType::Directory),
+            false,
+            false
+        )
+        .await,
+        Err(FsError::TooDeep { max: 2 })
+    ));
+    // files can still be created in the deepest directories
+    fs.create(
+        b.ino,
+        &name("file"),
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+
+    fs.create(
+        a.ino,
+        &name("file"),
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        fs.create(
+            a.ino,
+            &name("more"),
+            create_attr(FileType::RegularFile),
+            false,
+            false
+        )
+        .await,
+        Err(FsError::TooManyEntries { max: 2 })
+    ));
+    assert_eq!(2, fs.len(a.ino).unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_walk_async() {
+    use futures_util::StreamExt;
+
+    let mut vault = TestVault::builder().build().await.unwrap();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+    let fs = vault.fs();
+    let (_, a) = fs
+        .create(
+            ROOT_INODE,
+            &name("a"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (_, b) = fs
+        .create(
+            a.ino,
+            &name("b"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.create(
+        b.ino,
+        &name("f"),
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+    fs.create(
+        ROOT_INODE,
+        &name("g"),
+        create_attr(FileType::RegularFile),
+        false,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let walk = |fs: std::sync::Arc<EncryptedFs>, root: u64| async move {
+        let walk = fs.walk_async(root);
+        tokio::pin!(walk);
+        let mut paths = vec![];
+        let mut errors = vec![];
+        while let Some(entry) = walk.next().await {
+            match entry {
+                Ok((path, _)) => paths.push(path.to_str().unwrap().to_string()),
+                Err(err) => errors.push(err),
+            }
+        }
+        paths.sort();
+        (paths, errors)
+    };
+    let (paths, errors) = walk(fs.clone(), ROOT_INODE).await;
+    assert_eq!(vec!["a", "a/b", "a/b/f", "g"], paths);
+    assert!(errors.is_empty());
+    let (paths, _) = walk(fs.clone(), a.ino).await;
+    assert_eq!(vec!["b", "b/f"], paths);
+
+    // deeper directories are not read
+    vault
+        .reopen(false, FsOptions::default().with_max_dir_depth(1))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let (paths, errors) = walk(fs.clone(), ROOT_INODE).await;
+    assert_eq!(vec!["a", "a/b", "g"], paths);
+    assert!(matches!(errors[..], [FsError::TooDeep { max: 1 }]));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_prefetch() {
+    run_test(
+        TestSetup {
+            key: "test_prefetch",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"data", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // the directory and the missing inode are skipped
+            assert_eq!(
+                1,
+                fs.prefetch(&[attr.ino, ROOT_INODE, attr.ino + 100])
+                    .await
+                    .unwrap()
+            );
+            assert_eq!("data", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_nosuid_nodev() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_nosuid(true).with_nodev(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let name = |name: &str| SecretString::from_str(name).unwrap();
+
+    let mut attr = create_attr(FileType::RegularFile);
+    attr.perm = 0o6755;
+    let (_, file) = fs
+        .create(ROOT_INODE, &name("file"), attr, false, false)
+        .await
+        .unwrap();
+    assert_eq!(0o755, file.perm);
+    fs.set_attr(file.ino, SetFileAttr::default().with_perm(0o4711))
+        .await
+        .unwrap();
+    assert_eq!(0o711, fs.get_attr(file.ino).await.unwrap().perm);
+    // directories keep setgid, it's for the group of the entries
+    let mut attr = create_attr(FileType::Directory);
+    attr.perm = 0o2755;
+    let (_, dir) = fs
+        .create(ROOT_INODE, &name("dir"), attr, false, false)
+        .await
+        .unwrap();
+    assert_eq!(0o2755, dir.perm);
+
+    let mut attr = create_attr(FileType::RegularFile);
+    attr.rdev = 0x0801;
+    assert!(matches!(
+        fs.create(ROOT_INODE, &name("sda1"), attr, false, false)
+            .await,
+        Err(FsError::NotPermitted)
+    ));
+    assert!(matches!(
+        fs.set_attr(file.ino, SetFileAttr::default().with_rdev(0x0801))
+            .await,
+        Err(FsError::NotPermitted)
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_overhead() {
+    run_test(
+        TestSetup {
+            key: "test_overhead",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut files = vec![];
+            for (name, len) in [("a", crypto::write::BLOCK_SIZE * 2 + 50), ("b", 10)] {
+                let (fh, attr) = fs
+                    .create(
+                        dir.ino,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_bytes_to_fs(&fs, attr.ino, 0, &vec![1; len], fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                files.push(attr.ino);
+            }
+
+            let per_block = (Cipher::ChaCha20Poly1305.ciphertext_block_len()
+                - crypto::write::BLOCK_SIZE) as u64;
+            let plaintext = crypto::write::BLOCK_SIZE as u64 * 2 + 50;
+            let info = fs.overhead(files[0]).await.unwrap();
+            assert_eq!(plaintext, info.plaintext_bytes);
+            assert_eq!(3, info.blocks);
+            assert_eq!(per_block, info.per_block_overhead);
+            assert_eq!(plaintext + 3 * per_block, info.ciphertext_bytes);
+            assert_eq!(0, info.parity_bytes);
+            assert!(info.expansion_ratio() > 1.0);
+
+            let info = fs.overhead(dir.ino).await.unwrap();
+            assert_eq!(plaintext + 10, info.plaintext_bytes);
+            assert_eq!(4, info.blocks);
+            assert_eq!(plaintext + 10 + 4 * per_block, info.ciphertext_bytes);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_path_apis() {
+    run_test(
+        TestSetup {
+            key: "test_path_apis",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            fs.create_path("docs", create_attr(FileType::Directory), false, false)
+                .await
+                .unwrap();
+            let (fh, attr) = fs
+                .create_path(
+                    "/docs/notes.txt",
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"notes", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            assert_eq!(
+                attr.ino,
+                fs.getattr_path("docs/notes.txt").await.unwrap().ino
+            );
+            assert_eq!(
+                attr.ino,
+                fs.getattr_path("/docs/./../docs//notes.txt")
+                    .await
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(ROOT_INODE, fs.getattr_path("/..").await.unwrap().ino);
+            assert!(matches!(
+                fs.getattr_path("docs/missing").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.getattr_path("docs/notes.txt/x").await,
+                Err(FsError::NotADirectory)
+            ));
+
+            let fh = fs.open_path("docs/notes.txt", true, false).await.unwrap();
+            let mut buf = [0; 5];
+            fs.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(b"notes", &buf);
+
+            // the directory is not empty
+            assert!(fs.remove_path("docs").await.is_err());
+            fs.remove_path("docs/notes.txt").await.unwrap();
+            fs.remove_path("docs").await.unwrap();
+            assert!(matches!(
+                fs.getattr_path("docs").await,
+                Err(FsError::NotFound(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_mkdir_all() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_max_dir_depth(3))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let a = fs
+        .mkdir(ROOT_INODE, &SecretString::from_str("a").unwrap(), 0o700)
+        .await
+        .unwrap();
+    assert_eq!(FileType::Directory, a.kind);
+    assert_eq!(0o700, a.perm);
+
+    let c = fs.mkdir_all("a/b/c", 0o755).await.unwrap();
+    assert_eq!(c.ino, fs.getattr_path("a/b/c").await.unwrap().ino);
+    assert_eq!(0o755, c.perm);
+    // already there
+    assert_eq!(c.ino, fs.mkdir_all("/a/b/c/", 0o755).await.unwrap().ino);
+
+    fs.create_path("a/file", create_attr(FileType::RegularFile), false, false)
+        .await
+        .unwrap();
+    assert!(matches!(
+        fs.mkdir_all("a/file/d", 0o755).await,
+        Err(FsError::NotADirectory)
+    ));
+
+    // too deep for the last one, the ones created before are removed
+    assert!(matches!(
+        fs.mkdir_all("x/y/z/w", 0o755).await,
+        Err(FsError::TooDeep { max: 3 })
+    ));
+    assert!(matches!(
+        fs.getattr_path("x").await,
+        Err(FsError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_remove_dir_all() {
+    run_test(
+        TestSetup {
+            key: "test_remove_dir_all",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            fs.mkdir_all("a/b/c", 0o755).await.unwrap();
+            for path in ["a/f1", "a/b/f2", "a/b/c/f3"] {
+                fs.create_path(path, create_attr(FileType::RegularFile), false, false)
+                    .await
+                    .unwrap();
+            }
+            fs.mkdir_all("a/d", 0o755).await.unwrap();
+            let protected = fs.getattr_path("a/b/f2").await.unwrap();
+            fs.set_flags(protected.ino, FS_IMMUTABLE_FL).await.unwrap();
+            let a = fs.getattr_path("a").await.unwrap();
+            assert_ne!(a.ino, ROOT_INODE);
+
+            // stops at the immutable file
+            match fs.remove_dir_all(a.ino).await {
+                Err(FsError::RemoveFailed { ino, source }) => {
+                    assert_eq!(protected.ino, ino);
+                    assert!(matches!(*source, FsError::NotPermitted));
+                }
+                res => panic!("unexpected {res:?}"),
+            }
+            assert!(fs.getattr_path("a/b/f2").await.is_ok());
+
+            // continues from there
+            fs.set_flags(protected.ino, 0).await.unwrap();
+            fs.remove_dir_all(a.ino).await.unwrap();
+            assert!(matches!(
+                fs.getattr_path("a").await,
+                Err(FsError::NotFound(_))
+            ));
+
+            // the root stays
+            fs.mkdir_all("x/y", 0o755).await.unwrap();
+            fs.remove_dir_all(ROOT_INODE).await.unwrap();
+            assert_eq!(0, fs.len(ROOT_INODE).unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_compress_metadata() {
+    let plain = TestVault::builder().build().await.unwrap();
+    let plain = plain.fs();
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_compress_metadata(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let name = SecretString::from_str("dir").unwrap();
+    let (_, dir) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let file_name = SecretString::from_str("file").unwrap();
+    let (fh, file) = fs
+        .create(
+            dir.ino,
+            &file_name,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_bytes_to_fs(fs, file.ino, 0, b"test-42", fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    let (fh, plain_file) = plain
+        .create(
+            ROOT_INODE,
+            &file_name,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    plain.release(fh).await.unwrap();
+    let compressed_len = std::fs::metadata(fs.ino_file(file.ino)).unwrap().len();
+    let plain_len = std::fs::metadata(plain.ino_file(plain_file.ino))
+        .unwrap()
+        .len();
+    assert!(compressed_len < plain_len);
+
+    // the value saved in the data dir wins
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    let found = fs.find_by_name(ROOT_INODE, &name).await.unwrap().unwrap();
+    assert_eq!(dir.ino, found.ino);
+    let found = fs.find_by_name(dir.ino, &file_name).await.unwrap().unwrap();
+    assert_eq!(file.ino, found.ino);
+    assert_eq!(7, fs.get_attr(file.ino).await.unwrap().size);
+    assert_eq!("test-42", test_common::read_to_string(file.ino, fs).await);
+    let names: Vec<_> = fs
+        .read_dir(dir.ino)
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().to_string())
+        .collect();
+    assert!(names.contains(&"file".to_string()));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_wrong_file_type() {
+    run_test(
+        TestSetup {
+            key: "test_wrong_file_type",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let dir_name = SecretString::from_str("dir").unwrap();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &dir_name,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let file_name = SecretString::from_str("file").unwrap();
+            let (fh, file) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // file operations on a directory
+            assert!(matches!(
+                fs.open(dir.ino, true, false).await,
+                Err(FsError::IsADirectory)
+            ));
+            assert!(matches!(
+                fs.set_len(dir.ino, 0).await,
+                Err(FsError::IsADirectory)
+            ));
+            assert!(matches!(
+                fs.remove_file(ROOT_INODE, &dir_name).await,
+                Err(FsError::IsADirectory)
+            ));
+
+            // directory operations on a file
+            assert!(matches!(
+                fs.read_dir(file.ino).await,
+                Err(FsError::NotADirectory)
+            ));
+            assert!(matches!(fs.open_dir(file.ino), Err(FsError::NotADirectory)));
+            assert!(matches!(
+                fs.find_by_name(file.ino, &dir_name).await,
+                Err(FsError::NotADirectory)
+            ));
+            assert!(matches!(
+                fs.create(
+                    file.ino,
+                    &dir_name,
+                    create_attr(FileType::Directory),
+                    false,
+                    false
+                )
+                .await,
+                Err(FsError::NotADirectory)
+            ));
+            assert!(matches!(
+                fs.remove_dir(ROOT_INODE, &file_name).await,
+                Err(FsError::NotADirectory)
+            ));
+            assert!(matches!(
+                fs.remove_dir_all(file.ino).await,
+                Err(FsError::NotADirectory)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_replace_contents() {
+    run_test(
+        TestSetup {
+            key: "test_replace_contents",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("config").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            let old = "a".repeat(crypto::write::BLOCK_SIZE * 3 + 7);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, old.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            let reader = fs.open(attr.ino, true, false).await.unwrap();
+
+            fs.replace_contents(attr.ino, b"new-42").await.unwrap();
+            assert_eq!(6, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!("new-42", test_common::read_to_string(attr.ino, &fs).await);
+            // open handles see the new content
+            let mut buf = [0; 10];
+            let len = fs.read(attr.ino, 0, &mut buf, reader).await.unwrap();
+            assert_eq!(b"new-42", &buf[..len]);
+            // and the writer continues on it
+            write_all_bytes_to_fs(&fs, attr.ino, 6, b"-37", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            fs.release(reader).await.unwrap();
+            assert_eq!(
+                "new-42-37",
+                test_common::read_to_string(attr.ino, &fs).await
+            );
+
+            let new = "b".repeat(crypto::write::BLOCK_SIZE * 2);
+            fs.replace_contents(attr.ino, new.as_bytes()).await.unwrap();
+            assert_eq!(new, test_common::read_to_string(attr.ino, &fs).await);
+            fs.replace_contents(attr.ino, b"").await.unwrap();
+            assert_eq!(0, fs.get_attr(attr.ino).await.unwrap().size);
+            assert!(matches!(
+                fs.replace_contents(ROOT_INODE, b"x").await,
+                Err(FsError::IsADirectory)
+            ));
+
+            // a crash after the size is saved in the journal, before the swap, keeps the old size
+            let reopen = || {
+                EncryptedFs::new(
+                    fs.data_dir.clone(),
+                    Box::new(PasswordProviderImpl {}),
+                    Cipher::ChaCha20Poly1305,
+                    false,
+                )
+            };
+            fs.replace_contents(attr.ino, new.as_bytes()).await.unwrap();
+            fs.journal_size(attr.ino, 42).await.unwrap();
+            let fs2 = reopen().await.unwrap();
+            assert_eq!(new.len() as u64, fs2.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(new, test_common::read_to_string(attr.ino, &fs2).await);
+            drop(fs2);
+
+            // one after the swap, before the attributes are saved, gets the new size from the journal
+            fs.journal_size(attr.ino, new.len() as u64).await.unwrap();
+            fs.set_attr2(attr.ino, SetFileAttr::default().with_size(7), true)
+                .await
+                .unwrap();
+            let fs2 = reopen().await.unwrap();
+            assert_eq!(new.len() as u64, fs2.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(new, test_common::read_to_string(attr.ino, &fs2).await);
+            assert!(!fs
+                .data_dir
+                .join(JOURNAL_DIR)
+                .join(attr.ino.to_string())
+                .exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_reflink() {
+    run_test(
+        TestSetup {
+            key: "test_reflink",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+
+            let (fh, src) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("src").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "a".repeat(crypto::write::BLOCK_SIZE * 3 + 50);
+            // not flushed yet, it's written before copying
+            write_all_bytes_to_fs(&fs, src.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+
+            let copy_name = SecretString::from_str("copy").unwrap();
+            let copy = fs.reflink(src.ino, ROOT_INODE, &copy_name).await.unwrap();
+            assert_ne!(src.ino, copy.ino);
+            assert_eq!(data.len() as u64, copy.size);
+            assert_eq!(src.perm, copy.perm);
+            assert_eq!(data, test_common::read_to_string(copy.ino, &fs).await);
+
+            // they change independently
+            write_all_bytes_to_fs(&fs, src.ino, 0, b"src", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            let fh = fs.open(copy.ino, false, true).await.unwrap();
+            write_all_bytes_to_fs(&fs, copy.ino, 0, b"copy", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(
+                format!("src{}", &data[3..]),
+                test_common::read_to_string(src.ino, &fs).await
+            );
+            assert_eq!(
+                format!("copy{}", &data[4..]),
+                test_common::read_to_string(copy.ino, &fs).await
+            );
+
+            assert!(matches!(
+                fs.reflink(src.ino, ROOT_INODE, &copy_name).await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.reflink(
+                    ROOT_INODE,
+                    ROOT_INODE,
+                    &SecretString::from_str("root").unwrap()
+                )
+                .await,
+                Err(FsError::IsADirectory)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_symlinks() {
+    run_test(
+        TestSetup {
+            key: "test_symlinks",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let secret = |s: &str| SecretString::from_str(s).unwrap();
+
+            let docs = fs.mkdir_all("docs", 0o755).await.unwrap();
+            let (fh, notes) = fs
+                .create_path(
+                    "docs/notes.txt",
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, notes.ino, 0, b"notes", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let link = fs
+                .symlink(ROOT_INODE, &secret("link"), &secret("docs/notes.txt"))
+                .await
+                .unwrap();
+            assert_eq!(FileType::Symlink, link.kind);
+            assert_eq!(14, link.size);
+            assert_eq!(
+                "docs/notes.txt",
+                *fs.read_link(link.ino).await.unwrap().expose_secret()
+            );
+            // stat and lstat
+            assert_eq!(notes.ino, fs.getattr_path("link").await.unwrap().ino);
+            assert_eq!(notes.ino, fs.resolve_path("link", true).await.unwrap().ino);
+            assert_eq!(link.ino, fs.resolve_path("link", false).await.unwrap().ino);
+            let fh = fs.open_path("link", true, false).await.unwrap();
+            let mut buf = [0; 10];
+            let len = fs.read(notes.ino, 0, &mut buf, fh).await.unwrap();
+            assert_eq!(b"notes", &buf[..len]);
+            fs.release(fh).await.unwrap();
+
+            // the ones before the last are always followed, absolute and relative
+            fs.symlink(ROOT_INODE, &secret("abs"), &secret("/docs"))
+                .await
+                .unwrap();
+            fs.symlink(docs.ino, &secret("up"), &secret(".."))
+                .await
+                .unwrap();
+            for path in ["abs/notes.txt", "docs/up/abs/notes.txt"] {
+                assert_eq!(notes.ino, fs.resolve_path(path, false).await.unwrap().ino);
+            }
+            let created = fs.mkdir_all("abs/new", 0o755).await.unwrap();
+            assert_eq!(created.ino, fs.getattr_path("docs/new").await.unwrap().ino);
+
+            // dangling and loops
+            fs.symlink(ROOT_INODE, &secret("dangling"), &secret("missing"))
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.getattr_path("dangling").await,
+                Err(FsError::NotFound(_))
+            ));
+            fs.resolve_path("dangling", false).await.unwrap();
+            fs.symlink(ROOT_INODE, &secret("a"), &secret("b"))
+                .await
+                .unwrap();
+            fs.symlink(ROOT_INODE, &secret("b"), &secret("a"))
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs.getattr_path("a").await,
+                Err(FsError::TooManySymlinks)
+            ));
+            assert!(matches!(
+                fs.resolve_path("a/x", false).await,
+                Err(FsError::TooManySymlinks)
+            ));
+            assert_eq!(
+                FileType::Symlink,
+                fs.resolve_path("a", false).await.unwrap().kind
+            );
+
+            // removing the symlink leaves the target
+            fs.remove_path("link").await.unwrap();
+            assert!(matches!(
+                fs.resolve_path("link", false).await,
+                Err(FsError::NotFound(_))
+            ));
+            assert_eq!(
+                notes.ino,
+                fs.getattr_path("abs/notes.txt").await.unwrap().ino
+            );
+            assert!(matches!(
+                fs.read_link(notes.ino).await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_nonce_reuse_detector() {
+    let detector = NonceReuseDetector::default();
+    detector.on_sealed(&[1; 12]);
+    detector.on_sealed(&[2; 12]);
+    assert_eq!(0, detector.reuses.load(Ordering::SeqCst));
+    detector.on_sealed(&[1; 12]);
+    assert_eq!(1, detector.reuses.load(Ordering::SeqCst));
+    // only the last ones are remembered
+    for i in 0..NONCE_REUSE_WINDOW as u64 {
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&(i + 3).to_le_bytes());
+        detector.on_sealed(&nonce);
+    }
+    detector.on_sealed(&[2; 12]);
+    assert_eq!(1, detector.reuses.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_detect_nonce_reuse() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_detect_nonce_reuse(true))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = "a".repeat(crypto::write::BLOCK_SIZE * 10);
+    write_all_bytes_to_fs(fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    // rewrite the same blocks, they get new nonces
+    write_all_bytes_to_fs(fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    // and again with another handle, over the middle and past the end
+    let fh = fs.open(attr.ino, false, true).await.unwrap();
+    let offset = crypto::write::BLOCK_SIZE as u64 * 9 + 10;
+    write_all_bytes_to_fs(fs, attr.ino, offset, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    fs.set_len(attr.ino, offset / 2).await.unwrap();
+    let detector = fs.nonce_reuse.as_ref().unwrap();
+    assert!(detector.seen.lock().unwrap().order.len() >= 30);
+    assert_eq!(0, fs.nonce_reuses());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_open_verify() {
+    let options = |level| FsOptions::default().with_open_verify(level);
+    let mut vault = TestVault::builder()
+        .options(options(VerifyLevel::Full))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let data = "a".repeat(crypto::write::BLOCK_SIZE * 5 + 10);
+    write_all_bytes_to_fs(fs, attr.ino, 0, data.as_bytes(), fh)
+        .await
+        .unwrap();
+    fs.flush(fh).await.unwrap();
+    fs.release(fh).await.unwrap();
+    let path = fs.contents_path(attr.ino);
+    let block_len = fs.ciphertext_block_len() as u64;
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    fs.release(fh).await.unwrap();
+
+    let flip = |offset: u64| {
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset as usize] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+    };
+    // a block in the middle, only a full verify sees it
+    flip(block_len * 2 + 20);
+    vault
+        .reopen(false, options(VerifyLevel::Full))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(matches!(
+        fs.open(attr.ino, true, false).await,
+        Err(FsError::CorruptFile { blocks, .. }) if blocks == vec![2]
+    ));
+    vault
+        .reopen(false, options(VerifyLevel::Header))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    fs.release(fh).await.unwrap();
+    flip(block_len * 2 + 20);
+
+    // the first block
+    flip(20);
+    vault
+        .reopen(false, options(VerifyLevel::Header))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(matches!(
+        fs.open(attr.ino, true, false).await,
+        Err(FsError::CorruptFile { blocks, .. }) if blocks == vec![0]
+    ));
+    vault
+        .reopen(false, options(VerifyLevel::None))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    fs.release(fh).await.unwrap();
+    flip(20);
+
+    // truncated
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - block_len)
+        .unwrap();
+    vault
+        .reopen(false, options(VerifyLevel::Header))
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    assert!(matches!(
+        fs.open(attr.ino, true, false).await,
+        Err(FsError::CorruptFile { blocks, .. }) if blocks == vec![5]
+    ));
+}
+
+/// The binary form of an ACL, from (tag, perm, id) entries.
+fn acl_bytes(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+    let mut data = 2_u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in entries {
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&perm.to_le_bytes());
+        data.extend_from_slice(&id.to_le_bytes());
+    }
+    data
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_xattr() {
+    run_test(
+        TestSetup {
+            key: "test_xattr",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let file_name = SecretString::from_str("file").unwrap();
+            let (_, file) = fs
+                .create(
+                    ROOT_INODE,
+                    &file_name,
+                    CreateFileAttr {
+                        perm: 0o644,
+                        ..create_attr(FileType::RegularFile)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            fs.set_xattr(file.ino, "user.a", b"1").await.unwrap();
+            fs.set_xattr(file.ino, "user.b", &[0, 1, 2]).await.unwrap();
+            assert_eq!(
+                b"1".to_vec(),
+                fs.get_xattr(file.ino, "user.a").await.unwrap()
+            );
+            assert_eq!(
+                vec!["user.a".to_string(), "user.b".to_string()],
+                fs.list_xattr(file.ino).await.unwrap()
+            );
+            fs.remove_xattr(file.ino, "user.a").await.unwrap();
+            assert!(matches!(
+                fs.get_xattr(file.ino, "user.a").await,
+                Err(FsError::XattrNotFound)
+            ));
+            assert!(matches!(
+                fs.remove_xattr(file.ino, "user.a").await,
+                Err(FsError::XattrNotFound)
+            ));
+
+            // a named user with a mask, the group bits of the mode are the mask
+            let acl = acl_bytes(&[
+                (0x01, 7, 0),
+                (0x02, 6, 1000),
+                (0x04, 5, 0),
+                (0x10, 5, 0),
+                (0x20, 4, 0),
+            ]);
+            fs.set_xattr(file.ino, XATTR_ACL_ACCESS, &acl)
+                .await
+                .unwrap();
+            assert_eq!(acl, fs.get_xattr(file.ino, XATTR_ACL_ACCESS).await.unwrap());
+            assert_eq!(0o754, fs.get_attr(file.ino).await.unwrap().perm);
+            // chmod changes the mask, not the owning group
+            fs.set_attr(file.ino, SetFileAttr::default().with_perm(0o700))
+                .await
+                .unwrap();
+            assert_eq!(
+                acl_bytes(&[
+                    (0x01, 7, 0),
+                    (0x02, 6, 1000),
+                    (0x04, 5, 0),
+                    (0x10, 0, 0),
+                    (0x20, 0, 0)
+                ]),
+                fs.get_xattr(file.ino, XATTR_ACL_ACCESS).await.unwrap()
+            );
+            // nothing more than the mode, it's not kept
+            fs.set_xattr(
+                file.ino,
+                XATTR_ACL_ACCESS,
+                &acl_bytes(&[(0x01, 6, 0), (0x04, 4, 0), (0x20, 0, 0)]),
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                fs.get_xattr(file.ino, XATTR_ACL_ACCESS).await,
+                Err(FsError::XattrNotFound)
+            ));
+            assert_eq!(0o640, fs.get_attr(file.ino).await.unwrap().perm);
+            // named entries without a mask
+            assert!(matches!(
+                fs.set_xattr(
+                    file.ino,
+                    XATTR_ACL_ACCESS,
+                    &acl_bytes(&[(0x01, 6, 0), (0x02, 6, 1000), (0x04, 4, 0), (0x20, 0, 0)])
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            let default = acl_bytes(&[
+                (0x01, 7, 0),
+                (0x02, 7, 1000),
+                (0x04, 5, 0),
+                (0x10, 7, 0),
+                (0x20, 5, 0),
+            ]);
+            assert!(matches!(
+                fs.set_xattr(file.ino, XATTR_ACL_DEFAULT, &default).await,
+                Err(FsError::NotADirectory)
+            ));
+
+            // the default ACL of a directory is inherited
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("dir").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o755,
+                        ..create_attr(FileType::Directory)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.set_xattr(dir.ino, XATTR_ACL_DEFAULT, &default)
+                .await
+                .unwrap();
+            let child_name = SecretString::from_str("child").unwrap();
+            let (_, child) = fs
+                .create(
+                    dir.ino,
+                    &child_name,
+                    CreateFileAttr {
+                        perm: 0o644,
+                        ..create_attr(FileType::RegularFile)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                acl_bytes(&[
+                    (0x01, 6, 0),
+                    (0x02, 7, 1000),
+                    (0x04, 5, 0),
+                    (0x10, 4, 0),
+                    (0x20, 4, 0)
+                ]),
+                fs.get_xattr(child.ino, XATTR_ACL_ACCESS).await.unwrap()
+            );
+            assert_eq!(0o644, child.perm);
+            assert!(matches!(
+                fs.get_xattr(child.ino, XATTR_ACL_DEFAULT).await,
+                Err(FsError::XattrNotFound)
+            ));
+            let (_, subdir) = fs
+                .create(
+                    dir.ino,
+                    &SecretString::from_str("subdir").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o750,
+                        ..create_attr(FileType::Directory)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                default,
+                fs.get_xattr(subdir.ino, XATTR_ACL_DEFAULT).await.unwrap()
+            );
+            assert_eq!(0o750, subdir.perm);
+
+            // removed with the file
+            fs.remove_file(dir.ino, &child_name).await.unwrap();
+            assert!(!fs.xattr_path(child.ino).exists());
+            fs.remove_file(ROOT_INODE, &file_name).await.unwrap();
+            assert!(!fs.xattr_path(file.ino).exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_status() {
+    run_test(
+        TestSetup {
+            key: "test_status",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let status = fs.status().await;
+            assert!(!status.locked);
+            assert_eq!(0, status.open_handles);
+            assert_eq!(None, status.last_error);
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            assert_eq!(4, fs.write(attr.ino, 0, b"test", fh).await.unwrap());
+            fs.get_attr(attr.ino).await.unwrap();
+            fs.get_attr(attr.ino).await.unwrap();
+            let Status {
+                open_handles,
+                dirty_bytes,
+                cache_hit_rate,
+                ..
+            } = fs.status().await;
+            assert_eq!(1, open_handles);
+            assert_eq!(4, dirty_bytes);
+            assert!(cache_hit_rate.is_some_and(|rate| rate > 0.0));
+            fs.flush(fh).await.unwrap();
+            assert_eq!(0, fs.status().await.dirty_bytes);
+            fs.release(fh).await.unwrap();
+            assert_eq!(0, fs.status().await.open_handles);
+
+            // a bad request is not an error of the filesystem
+            let mut buf = [0; 4];
+            assert!(fs.read(attr.ino, 0, &mut buf, 42).await.is_err());
+            assert_eq!(None, fs.status().await.last_error);
+            let path = fs.contents_path(attr.ino);
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[20] ^= 1;
+            std::fs::write(&path, bytes).unwrap();
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            assert!(fs.read(attr.ino, 0, &mut buf, fh).await.is_err());
+            fs.release(fh).await.unwrap();
+            assert!(fs.status().await.last_error.is_some());
+
+            fs.key.clear().await;
+            assert!(fs.status().await.locked);
+            assert!(fs.status().await.uptime > Duration::ZERO);
+        },
+    )
+    .await;
+}
+
+/// Writes headers that don't open, like a disk returning garbage after the write.
+#[derive(Debug)]
+struct CorruptingProtection;
+
+impl HeaderProtection for CorruptingProtection {
+    fn protect(&self, header: &[u8]) -> FsResult<Vec<u8>> {
+        let mut header = header.to_vec();
+        *header.last_mut().unwrap() ^= 1;
+        Ok(header)
+    }
+
+    fn unprotect(&self, data: &[u8]) -> FsResult<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_passwd_rollback() {
+    let mut vault = TestVault::builder().build().await.unwrap();
+    let data_dir = vault.data_dir();
+    let header = std::fs::read(data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME)).unwrap();
+
+    assert!(EncryptedFs::passwd_with_protection(
+        data_dir,
+        SecretString::from_str("password").unwrap(),
+        SecretString::from_str("new-password").unwrap(),
+        Cipher::ChaCha20Poly1305,
+        &CorruptingProtection,
+    )
+    .await
+    .is_err());
+    // the old header is back, it opens only with the old password
+    assert_eq!(
+        header,
+        std::fs::read(data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME)).unwrap()
+    );
+    assert!(matches!(
+        EncryptedFs::passwd(
+            data_dir,
+            SecretString::from_str("new-password").unwrap(),
+            SecretString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_name_cipher() {
+    assert!(matches!(
+        TestVault::builder()
+            .options(
+                FsOptions::default()
+                    .with_encrypt_names(false)
+                    .with_name_cipher(NameCipher::Aes256GcmSynthetic),
+            )
+            .build()
+            .await,
+        Err(FsError::InvalidInput(_))
+    ));
+    let mut vault = TestVault::builder()
+        .options(FsOptions::default().with_name_cipher(NameCipher::Aes256GcmSynthetic))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let name = SecretString::from_str("file").unwrap();
+    let mut dirs = vec![];
+    for dir_name in ["a", "b"] {
+        let (_, dir) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(dir_name).unwrap(),
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        fs.create(
+            dir.ino,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        dirs.push(dir.ino);
+    }
+    // the same name is encrypted the same in both
+    let ls = |ino: u64| {
+        let mut names: Vec<_> = std::fs::read_dir(fs.contents_path(ino).join(LS_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| !name.to_string_lossy().starts_with('$'))
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(1, ls(dirs[0]).len());
+    assert_eq!(ls(dirs[0]), ls(dirs[1]));
+    // looked up by a keyed hash, not the plain one of the name
+    let hash_dir = fs.contents_path(dirs[0]).join(HASH_DIR);
+    assert!(!hash_dir.join(crypto::hash_file_name(&name)).exists());
+    assert_eq!(3, std::fs::read_dir(hash_dir).unwrap().count());
+
+    // the one saved in the data dir is used
+    vault.reopen(false, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    assert!(fs.find_by_name(dirs[0], &name).await.unwrap().is_some());
+    let names: Vec<_> = fs
+        .read_dir(dirs[1])
+        .await
+        .unwrap()
+        .map(|entry| entry.unwrap().name.expose_secret().clone())
+        .collect();
+    assert!(names.contains(&"file".to_string()));
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_file_envelope() {
+    run_test(
+        TestSetup {
+            key: "test_file_envelope",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let data = "envelope".repeat(crypto::write::BLOCK_SIZE);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, data.as_bytes(), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let envelope = fs.export_file_envelope(attr.ino).await.unwrap();
+            // a new salt each time
+            assert_ne!(envelope, fs.export_file_envelope(attr.ino).await.unwrap());
+            let password = SecretString::from_str("password").unwrap();
+            assert_eq!(
+                data.as_bytes(),
+                &decrypt_file_envelope(&envelope, &password).unwrap()[..]
+            );
+            assert!(matches!(
+                decrypt_file_envelope(&envelope, &SecretString::from_str("other").unwrap()),
+                Err(FsError::InvalidPassword)
+            ));
+            let mut changed = envelope.clone();
+            *changed.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                decrypt_file_envelope(&changed, &password),
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(matches!(
+                decrypt_file_envelope(b"not an envelope", &password),
+                Err(FsError::InvalidInput(_))
+            ));
+            // m_cost, after the magic and the cipher, asking for TiBs of memory
+            let mut costly = envelope.clone();
+            costly[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(matches!(
+                decrypt_file_envelope(&costly, &password),
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                fs.export_file_envelope(ROOT_INODE).await,
+                Err(FsError::IsADirectory)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_write_vectored() {
+    run_test(
+        TestSetup {
+            key: "test_read_write_vectored",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let block_size = crypto::write::BLOCK_SIZE;
+            let data: Vec<u8> = (0..block_size * 2 + block_size / 2)
+                .map(|i| (i % 251) as u8)
+                .collect();
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("single").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let encryptions = fs.encryptions();
+            fs.write_all(attr.ino, 0, &data, fh).await.unwrap();
+            fs.flush(fh).await.unwrap();
+            let single = fs.encryptions() - encryptions;
+            fs.release(fh).await.unwrap();
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("vectored").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            // the second block is split between three buffers
+            let (a, rest) = data.split_at(block_size - 30);
+            let (b, c) = rest.split_at(block_size + 10);
+            let (b1, b2) = b.split_at(50);
+            let bufs = [
+                IoSlice::new(a),
+                IoSlice::new(&[]),
+                IoSlice::new(b1),
+                IoSlice::new(b2),
+                IoSlice::new(c),
+            ];
+            let encryptions = fs.encryptions();
+            assert_eq!(
+                data.len(),
+                fs.write_vectored(attr.ino, 0, &bufs, fh).await.unwrap()
+            );
+            fs.flush(fh).await.unwrap();
+            // each block is encrypted once, like with one buffer
+            assert_eq!(single, fs.encryptions() - encryptions);
+            fs.release(fh).await.unwrap();
+            assert_eq!(data.len() as u64, fs.get_attr(attr.ino).await.unwrap().size);
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut a = vec![0; block_size / 2];
+            let mut b = vec![0; block_size - 5];
+            let mut c = vec![0; block_size * 2];
+            let mut d = vec![0; 10];
+            let mut bufs = [
+                IoSliceMut::new(&mut a),
+                IoSliceMut::new(&mut []),
+                IoSliceMut::new(&mut b),
+                IoSliceMut::new(&mut c),
+                IoSliceMut::new(&mut d),
+            ];
+            let offset = 10;
+            let len = fs
+                .read_vectored(attr.ino, offset, &mut bufs, fh)
+                .await
+                .unwrap();
+            assert_eq!(data.len() - offset as usize, len);
+            let rest = data.len() - offset as usize - a.len() - b.len();
+            let read = [&a[..], &b[..], &c[..rest]].concat();
+            assert_eq!(&data[offset as usize..], &read[..]);
+            // the end of the file was reached before these
+            assert!(c[rest..].iter().all(|&b| b == 0));
+            assert_eq!(vec![0; 10], d);
+
+            let mut bufs = [IoSliceMut::new(&mut a)];
+            assert_eq!(
+                0,
+                fs.read_vectored(attr.ino, data.len() as u64, &mut bufs, fh)
+                    .await
+                    .unwrap()
+            );
+            fs.release(fh).await.unwrap();
+            assert!(matches!(
+                fs.read_vectored(attr.ino, 0, &mut bufs, fh).await,
+                Err(FsError::InvalidFileHandle)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_max_memory_bytes() {
+    assert_eq!(2000, cache_capacity(&FsOptions::default()).get());
+    let options = |max| FsOptions::default().with_max_memory_bytes(max);
+    assert_eq!(2000, cache_capacity(&options(64 * 1024 * 1024)).get());
+    assert_eq!(512, cache_capacity(&options(3 * 1024 * 1024)).get());
+    assert_eq!(16, cache_capacity(&options(1000)).get());
+
+    // 750 for the buffers, each handle keeps a block of 100
+    let vault = TestVault::builder()
+        .options(options(1000))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("a").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let (fh2, attr2) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("b").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    let dirty_bytes = |fs: &EncryptedFs, fh| {
+        fs.open_handles()
+            .into_iter()
+            .find(|info| info.fh == fh)
+            .unwrap()
+            .dirty_bytes
+    };
+    let data: Vec<u8> = (0..400).map(|i| (i % 251) as u8).collect();
+    for (i, chunk) in data.chunks(100).enumerate() {
+        fs.write(attr.ino, i as u64 * 100, chunk, fh).await.unwrap();
+    }
+    assert_eq!(400, dirty_bytes(fs, fh));
+    fs.write(attr2.ino, 0, &data[..100], fh2).await.unwrap();
+    assert_eq!(100, dirty_bytes(fs, fh2));
+    // readers don't count, a flush can't release their blocks
+    let reader = fs.open(attr.ino, true, false).await.unwrap();
+    assert!(!fs.write_would_block(fh2, 40));
+    assert!(fs.write_would_block(fh2, 50));
+    fs.release(reader).await.unwrap();
+    // over the limit, the handle writing is flushed, the others are left as they are
+    fs.write(attr2.ino, 100, &data[100..200], fh2)
+        .await
+        .unwrap();
+    assert_eq!(0, dirty_bytes(fs, fh2));
+    assert_eq!(400, dirty_bytes(fs, fh));
+    assert!(!fs.write_would_block(fh2, 500));
+    fs.release(fh).await.unwrap();
+    fs.release(fh2).await.unwrap();
+
+    let fh = fs.open(attr.ino, true, false).await.unwrap();
+    let mut buf = vec![0; 400];
+    let mut bufs = [IoSliceMut::new(&mut buf)];
+    assert_eq!(
+        400,
+        fs.read_vectored(attr.ino, 0, &mut bufs, fh).await.unwrap()
+    );
+    fs.release(fh).await.unwrap();
+    assert_eq!(data, buf);
+    assert_eq!(200, fs.get_attr(attr2.ino).await.unwrap().size);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_set_times_symlink() {
+    run_test(
+        TestSetup {
+            key: "test_set_times_symlink",
+            read_only: false,
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, target) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("target").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let target = fs.get_attr(target.ino).await.unwrap();
+            let link = fs
+                .symlink(
+                    ROOT_INODE,
+                    &SecretString::from_str("link").unwrap(),
+                    &SecretString::from_str("target").unwrap(),
+                )
+                .await
+                .unwrap();
+
+            // older than what it has, like when restoring from an archive
+            let atime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_100);
+            fs.set_times(link.ino, Some(atime), Some(mtime))
+                .await
+                .unwrap();
+            let attr = fs.get_attr(link.ino).await.unwrap();
+            assert_eq!(FileType::Symlink, attr.kind);
+            assert_eq!(atime, attr.atime);
+            assert_eq!(mtime, attr.mtime);
+            assert!(attr.ctime > mtime);
+            // the target is not followed
+            let attr = fs.get_attr(target.ino).await.unwrap();
+            assert_eq!(target.atime, attr.atime);
+            assert_eq!(target.mtime, attr.mtime);
+            assert_eq!(
+                "target",
+                *fs.read_link(link.ino).await.unwrap().expose_secret()
+            );
+
+            // None keeps it
+            let mtime2 = mtime + Duration::from_secs(1);
+            fs.set_times(link.ino, None, Some(mtime2)).await.unwrap();
+            let attr = fs.get_attr(link.ino).await.unwrap();
+            assert_eq!(atime, attr.atime);
+            assert_eq!(mtime2, attr.mtime);
+
+            fs.set_flags(target.ino, FS_IMMUTABLE_FL).await.unwrap();
+            assert!(matches!(
+                fs.set_times(target.ino, Some(atime), None).await,
+                Err(FsError::NotPermitted)
+            ));
+            fs.set_flags(target.ino, 0).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_key_wrap() {
+    for key_wrap in [
+        KeyWrapAlgorithm::Aes256Kw,
+        KeyWrapAlgorithm::ChaCha20Poly1305,
+        KeyWrapAlgorithm::Aes256Gcm,
+    ] {
+        let mut vault = TestVault::builder()
+            .options(FsOptions::default().with_key_wrap(key_wrap))
+            .build()
+            .await
+            .unwrap();
+        let ino = vault.create_file("file").await.unwrap();
+        vault.write_all(ino, 0, b"wrapped").await.unwrap();
+        let data_dir = vault.data_dir();
+        assert_eq!(
+            key_wrap,
+            crate::encryptedfs::VaultParams::load(data_dir)
+                .unwrap()
+                .key_wrap
+        );
+        let header = std::fs::read(data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME)).unwrap();
+        if key_wrap == KeyWrapAlgorithm::Aes256Kw {
+            // the IV and the key
+            assert_eq!(8 + 32, header.len());
+        }
+
+        EncryptedFs::passwd(
+            data_dir,
+            SecretString::from_str("password").unwrap(),
+            SecretString::from_str("new-password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            EncryptedFs::passwd(
+                data_dir,
+                SecretString::from_str("password").unwrap(),
+                SecretString::from_str("other").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await,
+            Err(FsError::InvalidPassword)
+        ));
+        EncryptedFs::passwd(
+            data_dir,
+            SecretString::from_str("new-password").unwrap(),
+            SecretString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await
+        .unwrap();
+
+        // the saved one is used whatever the options say
+        vault.reopen(false, FsOptions::default()).await.unwrap();
+        assert_eq!(b"wrapped", &vault.read_all(ino).await.unwrap()[..]);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_dir_kind() {
+    // plain names so the entry of the new one is saved at the same path
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_encrypt_names(false))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let name = SecretString::from_str("x").unwrap();
+    let kind_of = |fs: std::sync::Arc<EncryptedFs>| async move {
+        fs.read_dir(ROOT_INODE)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| *entry.name.expose_secret() == "x")
+            .map(|entry| (entry.ino, entry.kind))
+    };
+
+    let (fh, file_attr) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    assert_eq!(
+        Some((file_attr.ino, FileType::RegularFile)),
+        kind_of(fs.clone()).await
+    );
+
+    fs.remove_file(ROOT_INODE, &name).await.unwrap();
+    assert_eq!(None, kind_of(fs.clone()).await);
+    let (_, dir_attr) = fs
+        .create(
+            ROOT_INODE,
+            &name,
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        Some((dir_attr.ino, FileType::Directory)),
+        kind_of(fs.clone()).await
+    );
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_timestamp_source() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_timestamp_source(TimestampSource::MonotonicAdjusted))
+        .build()
+        .await
+        .unwrap();
+    let fs = vault.fs();
+    let (fh, attr) = fs
+        .create(
+            ROOT_INODE,
+            &SecretString::from_str("file").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+
+    // as if the system clock went back an hour after the last time we got from it
+    let ahead = SystemTime::now() + Duration::from_secs(3600);
+    *fs.clock.lock().unwrap() = (ahead, std::time::Instant::now());
+    write_all_bytes_to_fs(fs, attr.ino, 0, b"data", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    let attr = fs.get_attr(attr.ino).await.unwrap();
+    assert!(attr.mtime >= ahead);
+    assert!(attr.ctime >= ahead);
+    assert!(attr.mtime < ahead + Duration::from_secs(60));
+
+    // they still go on
+    fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+        .await
+        .unwrap();
+    assert!(fs.get_attr(attr.ino).await.unwrap().ctime >= attr.ctime);
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_check_password() {
+    let cipher = Cipher::ChaCha20Poly1305;
+    let vault = TestVault::builder().cipher(cipher).build().await.unwrap();
+    let data_dir = vault.data_dir();
+    let password = SecretString::from_str("password").unwrap();
+    let wrong = SecretString::from_str("wrong").unwrap();
+    assert!(EncryptedFs::check_password(data_dir, &password, cipher).unwrap());
+    assert!(!EncryptedFs::check_password(data_dir, &wrong, cipher).unwrap());
+
+    // once it was right it's fast
+    let start = std::time::Instant::now();
+    for _ in 0..100 {
+        assert!(EncryptedFs::check_password(data_dir, &password, cipher).unwrap());
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(!EncryptedFs::check_password(data_dir, &wrong, cipher).unwrap());
+    // not with another cipher
+    assert!(!EncryptedFs::check_password(data_dir, &password, Cipher::Aes256Gcm).unwrap());
+
+    // changing the header forgets it
+    EncryptedFs::passwd(
+        data_dir,
+        password.clone(),
+        SecretString::from_str("new-password").unwrap(),
+        cipher,
+    )
+    .await
+    .unwrap();
+    assert!(!EncryptedFs::check_password(data_dir, &password, cipher).unwrap());
+    let new_password = SecretString::from_str("new-password").unwrap();
+    assert!(EncryptedFs::check_password(data_dir, &new_password, cipher).unwrap());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_only_doesnt_write_on_open() {
+    let mut vault = TestVault::builder().build().await.unwrap();
+    let ino = vault.create_file("file").await.unwrap();
+    vault.write_all(ino, 0, b"archived").await.unwrap();
+    assert!(!vault.fs().read_only());
+    // like a data dir from a version without the journal
+    std::fs::remove_dir_all(vault.data_dir().join(JOURNAL_DIR)).unwrap();
+
+    vault.reopen(true, FsOptions::default()).await.unwrap();
+    let fs = vault.fs();
+    assert!(fs.read_only());
+    assert!(!vault.data_dir().join(JOURNAL_DIR).exists());
+    assert_eq!(b"archived", &vault.read_all(ino).await.unwrap()[..]);
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("new").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::ReadOnly)
+    ));
+}
+
+/// A read-only bind mount, unmounted when dropped.
+struct ReadOnlyBindMount(std::path::PathBuf);
+
+impl ReadOnlyBindMount {
+    /// `None` if we can't mount, like when not root.
+    fn new(source: &std::path::Path, target: &std::path::Path) -> Option<Self> {
+        let mount = |args: &[&std::ffi::OsStr]| {
+            std::process::Command::new("mount")
+                .args(args)
+                .stderr(std::process::Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if !mount(&["--bind".as_ref(), source.as_os_str(), target.as_os_str()]) {
+            return None;
+        }
+        let bind = Self(target.to_path_buf());
+        mount(&[
+            "-o".as_ref(),
+            "remount,bind,ro".as_ref(),
+            target.as_os_str(),
+        ])
+        .then_some(bind)
+    }
+}
+
+impl Drop for ReadOnlyBindMount {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("umount").arg(&self.0).status();
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_read_only_media() {
+    let vault = TestVault::builder()
+        .options(FsOptions::default().with_metadata_store(MetadataStore::EmbeddedDb))
+        .build()
+        .await
+        .unwrap();
+    let ino = vault.create_file("file").await.unwrap();
+    vault.write_all(ino, 0, b"archived").await.unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let Some(_mount) = ReadOnlyBindMount::new(vault.data_dir(), target.path()) else {
+        // not root
+        return;
+    };
+
+    // detected without asking for read-only, and nothing is written, the db included
+    let fs = EncryptedFs::new_with_options(
+        target.path().to_path_buf(),
+        Box::new(PasswordProviderImpl {}),
+        Cipher::ChaCha20Poly1305,
+        false,
+        FsOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert!(fs.read_only());
+    assert_eq!(MetadataStore::EmbeddedDb, fs.metadata_store());
+    assert_eq!("archived", test_common::read_to_string(ino, &fs).await);
+    assert!(fs
+        .find_by_name(ROOT_INODE, &SecretString::from_str("file").unwrap())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("new").unwrap(),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::ReadOnly)
+    ));
+}
@@ -85,6 +85,15 @@ impl<
         None
     }
 
+    pub const fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    /// If the value is in memory, so [`Self::get`] doesn't need the provider.
+    pub async fn is_cached(&self) -> bool {
+        self.get_from_ref_or_cache().await.is_some()
+    }
+
     pub async fn clear(&self) {
         self.cache.clear().await;
     }
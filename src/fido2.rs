@@ -0,0 +1,142 @@
+//! FIDO2 security keys, like a YubiKey, as a second factor besides the password, using the `hmac-secret` extension.
+//!
+//! [`Fido2Protection`] is a [`HeaderProtection`] which mixes the HMAC the authenticator computes over a random salt
+//! into the key derived from the password, and also encrypts the saved key with it, so the key must be present, and
+//! touched, to unlock. The credential id and the salt are kept next to it in the data dir, they are not secret. The
+//! authenticator is behind [`HmacSecretDevice`], on Linux [`CtapHidDevice`] talks to a USB one.
+
+use std::fmt::Debug;
+use std::io;
+use std::sync::Mutex;
+
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use shush_rs::{ExposeSecret, SecretBox, SecretVec};
+
+use crate::crypto;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{FsError, FsResult, HeaderProtection};
+
+#[cfg(target_os = "linux")]
+mod hid;
+
+#[cfg(target_os = "linux")]
+pub use hid::CtapHidDevice;
+
+/// An authenticator supporting the `hmac-secret` extension.
+pub trait HmacSecretDevice: Debug + Send + Sync + 'static {
+    /// Create a new credential with `hmac-secret` enabled and return its id.
+    ///
+    /// Fails with [`FsError::SecondFactorRequired`] if there is no authenticator.
+    #[allow(clippy::missing_errors_doc)]
+    fn make_credential(&self) -> FsResult<Vec<u8>>;
+    /// The HMAC of `salt` with the secret of the credential, waiting for the user to touch the authenticator.
+    ///
+    /// Fails with [`FsError::SecondFactorRequired`] if there is no authenticator or it doesn't have the credential.
+    #[allow(clippy::missing_errors_doc)]
+    fn hmac_secret(&self, credential_id: &[u8], salt: &[u8; 32]) -> FsResult<[u8; 32]>;
+}
+
+/// What we save in the data dir instead of the key encrypted with the password.
+#[derive(Serialize, Deserialize)]
+struct Enrolled {
+    credential_id: Vec<u8>,
+    salt: [u8; 32],
+    /// Encrypted with the HMAC.
+    header: Vec<u8>,
+}
+
+/// The credential and salt of the data dir, with the HMAC the authenticator gave for them.
+#[derive(Debug)]
+struct Enrollment {
+    credential_id: Vec<u8>,
+    salt: [u8; 32],
+    hmac: SecretVec<u8>,
+}
+
+/// Require a FIDO2 authenticator to unlock, see the [module docs](self).
+///
+/// The credential and the salt are created the first time the key is saved, after that the ones from the data dir
+/// are reused, like when changing the password. The HMAC is kept in memory once we have it, so the authenticator is
+/// touched once each time the key is read.
+#[derive(Debug)]
+pub struct Fido2Protection {
+    device: Box<dyn HmacSecretDevice>,
+    enrollment: Mutex<Option<Enrollment>>,
+}
+
+impl Fido2Protection {
+    pub fn new(device: Box<dyn HmacSecretDevice>) -> Self {
+        Self {
+            device,
+            enrollment: Mutex::new(None),
+        }
+    }
+
+    fn key(&self, credential_id: &[u8], salt: &[u8; 32]) -> FsResult<SecretVec<u8>> {
+        let hmac = self.device.hmac_secret(credential_id, salt)?;
+        Ok(SecretBox::new(Box::new(hmac.to_vec())))
+    }
+
+    /// The credential id, the salt and the HMAC, enrolling a new credential if we don't have one yet.
+    fn enrollment(&self) -> FsResult<(Vec<u8>, [u8; 32], SecretVec<u8>)> {
+        let mut enrollment = self.enrollment.lock().unwrap();
+        if enrollment.is_none() {
+            let credential_id = self.device.make_credential()?;
+            let mut salt = [0; 32];
+            crypto::create_rng().fill_bytes(&mut salt);
+            let hmac = self.key(&credential_id, &salt)?;
+            *enrollment = Some(Enrollment {
+                credential_id,
+                salt,
+                hmac,
+            });
+        }
+        let enrollment = enrollment.as_ref().unwrap();
+        Ok((
+            enrollment.credential_id.clone(),
+            enrollment.salt,
+            SecretBox::new(Box::new(enrollment.hmac.expose_secret().clone())),
+        ))
+    }
+}
+
+impl HeaderProtection for Fido2Protection {
+    fn protect(&self, header: &[u8]) -> FsResult<Vec<u8>> {
+        let (credential_id, salt, key) = self.enrollment()?;
+        let header = crypto::serialize_encrypt_into(
+            io::Cursor::new(vec![]),
+            header,
+            Cipher::ChaCha20Poly1305,
+            &key,
+        )?
+        .into_inner();
+        Ok(bincode::serialize(&Enrolled {
+            credential_id,
+            salt,
+            header,
+        })?)
+    }
+
+    fn unprotect(&self, data: &[u8]) -> FsResult<Vec<u8>> {
+        // not enrolled, or it's protected only by the password
+        let enrolled: Enrolled =
+            bincode::deserialize(data).map_err(|_| FsError::SecondFactorRequired)?;
+        let key = self.key(&enrolled.credential_id, &enrolled.salt)?;
+        let reader = crypto::create_read(&enrolled.header[..], Cipher::ChaCha20Poly1305, &key);
+        // a different authenticator, its HMAC is another key
+        let header: Vec<u8> =
+            bincode::deserialize_from(reader).map_err(|_| FsError::SecondFactorRequired)?;
+        self.enrollment.lock().unwrap().replace(Enrollment {
+            credential_id: enrolled.credential_id,
+            salt: enrolled.salt,
+            hmac: key,
+        });
+        Ok(header)
+    }
+
+    fn kdf_secret(&self) -> FsResult<Option<SecretVec<u8>>> {
+        let (_, _, hmac) = self.enrollment()?;
+        Ok(Some(hmac))
+    }
+}
@@ -0,0 +1,130 @@
+//! A USB authenticator through Linux `hidraw`, with the `ctap-hid-fido2` crate, doing only what
+//! [`HmacSecretDevice`] needs: creating a credential with `hmac-secret` and getting an assertion with it.
+//!
+//! Signatures and attestations aren't checked, we only want the HMAC, and a fake authenticator can't compute it
+//! without the secret of the credential anyway.
+
+use std::path::PathBuf;
+
+use ctap_hid_fido2::fidokey::{
+    AssertionExtension, CredentialExtension, GetAssertionArgsBuilder, MakeCredentialArgsBuilder,
+};
+use ctap_hid_fido2::{FidoKeyHid, HidParam, LibCfg};
+use rand_chacha::rand_core::RngCore;
+use shush_rs::{ExposeSecret, SecretString};
+use tracing::debug;
+
+use crate::crypto;
+use crate::encryptedfs::{FsError, FsResult};
+use crate::fido2::HmacSecretDevice;
+
+/// Relying party the credentials are created for.
+const RP_ID: &str = "rencfs";
+
+/// A USB FIDO2 authenticator, like a YubiKey, through its `/dev/hidrawN`, see the [module docs](self).
+///
+/// The PIN, if the authenticator has one, is only needed to create the credential. It's not used when getting the
+/// HMAC, the authenticator would give another one for the same salt.
+#[derive(Debug)]
+pub struct CtapHidDevice {
+    path: PathBuf,
+    pin: Option<SecretString>,
+}
+
+impl CtapHidDevice {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            pin: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_pin(mut self, pin: SecretString) -> Self {
+        self.pin = Some(pin);
+        self
+    }
+
+    /// The first FIDO authenticator plugged in.
+    ///
+    /// # Errors
+    ///
+    /// [`FsError::SecondFactorRequired`] if there is none.
+    pub fn find() -> FsResult<Self> {
+        ctap_hid_fido2::get_fidokey_devices()
+            .into_iter()
+            .find_map(|info| match info.param {
+                HidParam::Path(path) => Some(Self::new(path)),
+                HidParam::VidPid { .. } => None,
+            })
+            .ok_or(FsError::SecondFactorRequired)
+    }
+
+    fn open(&self) -> FsResult<FidoKeyHid> {
+        let param = HidParam::Path(self.path.to_string_lossy().into_owned());
+        // the message asking to touch it would go to stdout, which is the output of the commands
+        let cfg = LibCfg::init().with_keep_alive_msg_to_stderr(true);
+        FidoKeyHid::new(&[param], &cfg).map_err(|err| {
+            debug!(%err, path = ?self.path, "cannot open the authenticator");
+            FsError::SecondFactorRequired
+        })
+    }
+}
+
+impl HmacSecretDevice for CtapHidDevice {
+    fn make_credential(&self) -> FsResult<Vec<u8>> {
+        let challenge = challenge();
+        let extensions = [CredentialExtension::HmacSecret(Some(true))];
+        let args = MakeCredentialArgsBuilder::new(RP_ID, &challenge).extensions(&extensions);
+        let pin = self.pin.as_ref().map(ExposeSecret::expose_secret);
+        let args = match &pin {
+            Some(pin) => args.pin(pin.as_str()),
+            None => args.without_pin_and_uv(),
+        };
+        let attestation = self
+            .open()?
+            .make_credential_with_args(&args.build())
+            .map_err(map_err)?;
+        Ok(attestation.credential_descriptor.id)
+    }
+
+    fn hmac_secret(&self, credential_id: &[u8], salt: &[u8; 32]) -> FsResult<[u8; 32]> {
+        let challenge = challenge();
+        let extensions = [AssertionExtension::HmacSecret(Some(*salt))];
+        let args = GetAssertionArgsBuilder::new(RP_ID, &challenge)
+            .credential_id(credential_id)
+            .extensions(&extensions)
+            .without_pin_and_uv()
+            .build();
+        let assertions = self
+            .open()?
+            .get_assertion_with_args(&args)
+            .map_err(map_err)?;
+        assertions
+            .iter()
+            .flat_map(|assertion| &assertion.extensions)
+            .find_map(|extension| match extension {
+                AssertionExtension::HmacSecret(Some(hmac)) => Some(*hmac),
+                _ => None,
+            })
+            .ok_or(FsError::Other("invalid response from the authenticator"))
+    }
+}
+
+fn challenge() -> [u8; 32] {
+    let mut challenge = [0; 32];
+    crypto::create_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+/// `ctap-hid-fido2` gives only a message, with the name of the CTAP2 status in it.
+fn map_err(err: anyhow::Error) -> FsError {
+    let err = err.to_string();
+    if err.contains("CTAP2_ERR_PIN_INVALID") {
+        FsError::InvalidPassword
+    } else {
+        // like CTAP2_ERR_NO_CREDENTIALS, not made by this authenticator
+        debug!(err, "authenticator refused");
+        FsError::SecondFactorRequired
+    }
+}
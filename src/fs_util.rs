@@ -2,7 +2,13 @@
 use atomic_write_file::unix::OpenOptionsExt;
 use atomic_write_file::AtomicWriteFile;
 use futures_util::TryStreamExt;
-use std::path::Path;
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 use tokio_stream::wrappers::ReadDirStream;
 
@@ -43,3 +49,292 @@ pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     opt.preserve_mode(true).preserve_owner(true);
     opt.open(file)
 }
+
+/// Space (in bytes) allocated on disk for the file at `path`, less than its length if it has holes.
+#[cfg(unix)]
+pub fn allocated_size(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path)?.blocks() * 512)
+}
+
+/// Space (in bytes) allocated on disk for the file at `path`, less than its length if it has holes.
+#[cfg(not(unix))]
+pub fn allocated_size(path: &Path) -> io::Result<u64> {
+    Ok(fs::metadata(path)?.len())
+}
+
+/// Free space (in bytes) available to unprivileged users on the filesystem containing `path`.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space (in bytes) available to unprivileged users on the filesystem containing `path`.
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// If the filesystem containing `path` is mounted read-only, like a CD or a read-only bind mount.
+#[cfg(unix)]
+pub fn is_read_only_fs(path: &Path) -> io::Result<bool> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_flag & libc::ST_RDONLY != 0)
+}
+
+/// If the filesystem containing `path` is mounted read-only, like a CD or a read-only bind mount.
+#[cfg(not(unix))]
+pub fn is_read_only_fs(path: &Path) -> io::Result<bool> {
+    Ok(fs::metadata(path)?.permissions().readonly())
+}
+
+/// Keep the pages holding `buf` in RAM so they are never written to swap. They stay locked until the process exits.
+///
+/// Fails if it would exceed `RLIMIT_MEMLOCK`, see `ulimit -l`.
+#[cfg(unix)]
+pub fn lock_memory(buf: &[u8]) -> io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    #[allow(clippy::cast_sign_loss)]
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = buf.as_ptr() as usize & !(page - 1);
+    let len = buf.as_ptr() as usize + buf.len() - start;
+    if unsafe { libc::mlock(start as *const libc::c_void, len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Keep the pages holding `buf` in RAM so they are never written to swap. They stay locked until the process exits.
+#[cfg(not(unix))]
+pub fn lock_memory(_buf: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "locking memory is not supported on this platform",
+    ))
+}
+
+/// Leave the pages holding `buf` out of core dumps, with `MADV_DONTDUMP`.
+#[cfg(target_os = "linux")]
+pub fn exclude_from_core_dump(buf: &[u8]) -> io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    #[allow(clippy::cast_sign_loss)]
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = buf.as_ptr() as usize & !(page - 1);
+    let len = buf.as_ptr() as usize + buf.len() - start;
+    if unsafe { libc::madvise(start as *mut libc::c_void, len, libc::MADV_DONTDUMP) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Leave the pages holding `buf` out of core dumps. Only supported on Linux, a no-op elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn exclude_from_core_dump(_buf: &[u8]) -> io::Result<()> {
+    Ok(())
+}
+
+/// A new descriptor for the opened file `fd`, closed on exec, which we own. `fd` stays open, it fails with `EBADF` if
+/// it's not an opened descriptor.
+#[cfg(unix)]
+pub fn dup_fd(fd: i32) -> io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+    let new_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if new_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: it's a new descriptor, nothing else has it
+    Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(new_fd) })
+}
+
+/// Don't write a core dump if the process crashes, and don't let other processes of the user attach to it, with
+/// `PR_SET_DUMPABLE`.
+#[cfg(target_os = "linux")]
+pub fn disable_core_dumps() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Only supported on Linux, a no-op elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn disable_core_dumps() -> io::Result<()> {
+    Ok(())
+}
+
+/// Ask the kernel to read all of `file` in its page cache in background, with `POSIX_FADV_WILLNEED`. It's only a hint,
+/// the pages are dropped as usual when memory is needed.
+#[cfg(target_os = "linux")]
+pub fn advise_will_need(file: &fs::File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// Only supported on Linux, a no-op elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn advise_will_need(_file: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Make `dst` a copy of `src` sharing its blocks on disk, with `FICLONE`. Returns if the blocks are shared.
+///
+/// Filesystems like Btrfs and XFS support it, copying a block only when one of the files changes it. Where that's
+/// not supported the content is copied.
+#[cfg(target_os = "linux")]
+pub fn clone_file(src: &Path, dst: &Path) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+    let src_file = fs::File::open(src)?;
+    let dst_file = fs::File::create(dst)?;
+    if unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) } == 0 {
+        dst_file.sync_all()?;
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // not supported by the filesystem, or across filesystems
+        Some(libc::EOPNOTSUPP | libc::EINVAL | libc::EXDEV | libc::ENOTTY) => {
+            drop(dst_file);
+            fs::copy(src, dst)?;
+            fs::File::open(dst)?.sync_all()?;
+            Ok(false)
+        }
+        _ => Err(err),
+    }
+}
+
+/// Only Linux can share the blocks, elsewhere the content is copied.
+#[cfg(not(target_os = "linux"))]
+pub fn clone_file(src: &Path, dst: &Path) -> io::Result<bool> {
+    fs::copy(src, dst)?;
+    fs::File::open(dst)?.sync_all()?;
+    Ok(false)
+}
+
+/// The FUSE mount `path` is on, its mount point and filesystem type like `fuse.sshfs`, from `/proc/self/mountinfo`.
+/// `None` if it's not on one.
+#[cfg(target_os = "linux")]
+pub fn fuse_mount_of(path: &Path) -> io::Result<Option<(PathBuf, String)>> {
+    let path = path.canonicalize()?;
+    Ok(fuse_mount_in(
+        &fs::read_to_string("/proc/self/mountinfo")?,
+        &path,
+    ))
+}
+
+/// We don't check elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn fuse_mount_of(_path: &Path) -> io::Result<Option<(PathBuf, String)>> {
+    Ok(None)
+}
+
+/// Like [`fuse_mount_of`] with the content of `mountinfo`, `path` must be canonical.
+#[cfg(target_os = "linux")]
+fn fuse_mount_in(mountinfo: &str, path: &Path) -> Option<(PathBuf, String)> {
+    // the longest mount point it's under, of those the last one mounted as it hides the others
+    let mut found: Option<(PathBuf, String)> = None;
+    for line in mountinfo.lines() {
+        let mut fields = line.split(' ');
+        let Some(mount_point) = fields.nth(4) else {
+            continue;
+        };
+        // the optional fields end with `-`, the filesystem type comes after
+        let Some(fs_type) = fields.skip_while(|field| *field != "-").nth(1) else {
+            continue;
+        };
+        let mount_point = PathBuf::from(OsStr::from_bytes(&unescape_mountinfo(mount_point)));
+        if !path.starts_with(&mount_point)
+            || found
+                .as_ref()
+                .is_some_and(|(found, _)| found.as_os_str().len() > mount_point.as_os_str().len())
+        {
+            continue;
+        }
+        found = Some((mount_point, fs_type.to_string()));
+    }
+    // `fuseblk` is for filesystems on block devices, like NTFS, not other mounts
+    found.filter(|(_, fs_type)| fs_type == "fuse" || fs_type.starts_with("fuse."))
+}
+
+/// The kernel escapes space, tab, newline and backslash in mountinfo as `\ooo` in octal.
+#[cfg(target_os = "linux")]
+fn unescape_mountinfo(field: &str) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        if let Some(byte) = octal {
+            unescaped.push(byte);
+            i += 4;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn test_is_read_only_fs() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!super::is_read_only_fs(dir.path()).unwrap());
+        assert!(super::is_read_only_fs(&dir.path().join("missing")).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_fuse_mount_in() {
+        use super::fuse_mount_in;
+        use std::path::{Path, PathBuf};
+
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:40 / /home/me/vault rw,nosuid,nodev,relatime shared:20 - fuse fuse rw,user_id=1000
+41 22 0:41 / /mnt/my\\040drive rw,relatime shared:21 - fuse.sshfs me@host:/ rw
+42 22 8:17 / /mnt/usb rw,relatime - fuseblk /dev/sdb1 rw
+43 41 0:42 / /mnt/my\\040drive/local rw,relatime - ext4 /dev/sda2 rw";
+        assert_eq!(
+            Some((PathBuf::from("/home/me/vault"), "fuse".to_string())),
+            fuse_mount_in(mountinfo, Path::new("/home/me/vault"))
+        );
+        assert_eq!(
+            Some((PathBuf::from("/home/me/vault"), "fuse".to_string())),
+            fuse_mount_in(mountinfo, Path::new("/home/me/vault/data"))
+        );
+        assert_eq!(
+            Some((PathBuf::from("/mnt/my drive"), "fuse.sshfs".to_string())),
+            fuse_mount_in(mountinfo, Path::new("/mnt/my drive/data"))
+        );
+        assert_eq!(None, fuse_mount_in(mountinfo, Path::new("/home/me/vaults")));
+        assert_eq!(None, fuse_mount_in(mountinfo, Path::new("/mnt/usb/data")));
+        // mounted over a directory of the FUSE mount
+        assert_eq!(
+            None,
+            fuse_mount_in(mountinfo, Path::new("/mnt/my drive/local/data"))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Entry points for fuzzing the code which reads what's in the data dir, with `cargo fuzz`.
+//!
+//! `cargo fuzz` builds with `--cfg fuzzing`, which enables this module. The data dir could be changed by someone
+//! else, so no input should make them panic, allocate without bounds or hang, only return an [`FsError`].
+//!
+//! A target looks like this:
+//!
+//! ```ignore
+//! #![no_main]
+//! use libfuzzer_sys::fuzz_target;
+//! use rencfs::crypto::Cipher;
+//!
+//! fuzz_target!(|data: &[u8]| {
+//!     let key = [42; 32];
+//!     let _ = rencfs::fuzz::fuzz_decrypt_block(Cipher::ChaCha20Poly1305, &key, data);
+//! });
+//! ```
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use shush_rs::SecretVec;
+
+use crate::crypto;
+use crate::crypto::Cipher;
+use crate::encryptedfs::{FsError, FsResult, HeaderBackup, VaultParams};
+
+/// Decrypts `bytes` as one block and as the content of a file, like when reading a file from the data dir.
+/// Returns the plaintext of the file, if it's valid.
+#[allow(clippy::missing_errors_doc)]
+pub fn fuzz_decrypt_block(cipher: Cipher, key: &[u8], bytes: &[u8]) -> FsResult<Vec<u8>> {
+    if key.len() != cipher.key_len() {
+        return Err(FsError::InvalidInput("invalid key length"));
+    }
+    let key = SecretVec::new(Box::new(key.to_vec()));
+    let _ = crypto::decrypt_block(cipher, &key, 0, &mut bytes.to_vec());
+
+    let mut reader = crypto::create_read_seek(Cursor::new(bytes), cipher, &key);
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(len / 2))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// Parses `bytes` as a backup of the header, what [`EncryptedFs::import_header`] accepts, and as the params of the
+/// data dir.
+///
+/// [`EncryptedFs::import_header`]: crate::encryptedfs::EncryptedFs::import_header
+#[allow(clippy::missing_errors_doc)]
+pub fn fuzz_parse_header(bytes: &[u8]) -> FsResult<()> {
+    let params = VaultParams::parse(bytes);
+    let header = HeaderBackup::parse(bytes)?;
+    if let Some(params) = header.params {
+        VaultParams::parse(&params)?;
+    }
+    params.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, RngCore};
+
+    use super::*;
+    use crate::crypto::write::BLOCK_SIZE;
+
+    #[test]
+    fn test_fuzz_decrypt_block() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let key = vec![42; cipher.key_len()];
+        assert!(fuzz_decrypt_block(cipher, &key[1..], &[]).is_err());
+
+        let mut rng = rand::thread_rng();
+        for len in (0..BLOCK_SIZE * 3).chain([BLOCK_SIZE * 100 + 27]) {
+            let mut bytes = vec![0; len];
+            rng.fill_bytes(&mut bytes);
+            let res = fuzz_decrypt_block(cipher, &key, &bytes);
+            assert_eq!(len == 0, res.is_ok());
+        }
+
+        // a valid file, with a tail shorter than the nonce and the tag appended
+        let mut writer = crypto::create_write(
+            Cursor::new(vec![]),
+            cipher,
+            &SecretVec::new(Box::new(key.clone())),
+        );
+        std::io::Write::write_all(&mut writer, b"test-42").unwrap();
+        let mut bytes = crypto::write::CryptoWrite::finish(&mut writer)
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            b"test-42",
+            &fuzz_decrypt_block(cipher, &key, &bytes).unwrap()[..]
+        );
+        bytes.extend_from_slice(&[0; BLOCK_SIZE + 5]);
+        assert!(fuzz_decrypt_block(cipher, &key, &bytes).is_err());
+    }
+
+    #[test]
+    fn test_fuzz_parse_header() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let mut bytes = vec![0; rng.gen_range(0..100)];
+            rng.fill_bytes(&mut bytes);
+            assert!(fuzz_parse_header(&bytes).is_err());
+        }
+        // huge lengths
+        let mut bytes = b"rencfshd".to_vec();
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(fuzz_parse_header(&bytes).is_err());
+    }
+}
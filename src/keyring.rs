@@ -3,25 +3,41 @@ use std::str::FromStr;
 use keyring::Entry;
 use shush_rs::{ExposeSecret, SecretString};
 
+use crate::encryptedfs::{FsError, FsResult};
+
 #[allow(dead_code)]
 const KEYRING_SERVICE: &str = "rencfs";
 #[allow(dead_code)]
 const KEYRING_USER: &str = "encrypted_fs";
 
 #[allow(dead_code)]
-pub(crate) fn save(password: &SecretString, suffix: &str) -> Result<(), keyring::Error> {
-    let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    entry.set_password(&password.expose_secret())
+pub(crate) fn save(password: &SecretString, suffix: &str) -> FsResult<()> {
+    entry(suffix)?
+        .set_password(&password.expose_secret())
+        .map_err(map_err)
 }
 
 #[allow(dead_code)]
-pub(crate) fn remove(suffix: &str) -> Result<(), keyring::Error> {
-    let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    entry.delete_password()
+pub(crate) fn remove(suffix: &str) -> FsResult<()> {
+    entry(suffix)?.delete_password().map_err(map_err)
 }
 
 #[allow(dead_code)]
-pub(crate) fn get(suffix: &str) -> Result<SecretString, keyring::Error> {
-    let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    Ok(SecretString::from_str(&entry.get_password()?).unwrap())
+pub(crate) fn get(suffix: &str) -> FsResult<SecretString> {
+    Ok(SecretString::from_str(&entry(suffix)?.get_password().map_err(map_err)?).unwrap())
+}
+
+fn entry(suffix: &str) -> FsResult<Entry> {
+    Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}")).map_err(map_err)
+}
+
+/// When there is no secret service, like in containers and on CI, we return [`FsError::KeyringUnavailable`] so the
+/// caller can fall back to something else.
+pub(crate) fn map_err(err: keyring::Error) -> FsError {
+    match err {
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_) => {
+            FsError::KeyringUnavailable { source: err }
+        }
+        _ => err.into(),
+    }
 }
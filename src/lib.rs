@@ -71,6 +71,8 @@
 //! }
 //! ```
 //!
+//! Programs which don't use tokio can call [`mount::run_fuse_blocking`] instead, which blocks until it's unmounted.
+//!
 //! ## Or directly work with [`encryptedfs::EncryptedFs`]
 //!
 //! You need to specify several parameters to create an encrypted file system:
@@ -292,14 +294,23 @@ use std::sync::LazyLock;
 
 pub mod arc_hashmap;
 pub mod async_util;
+#[cfg(target_os = "linux")]
+pub mod cli;
 pub mod crypto;
 pub mod encryptedfs;
 pub mod expire_value;
+pub mod fido2;
 pub mod fs_util;
+#[cfg(any(fuzzing, test))]
+pub mod fuzz;
+mod keyring;
 pub mod log;
 pub mod mount;
+pub mod storage;
 pub mod stream_util;
 pub(crate) mod test_common;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 #[allow(unreachable_code)]
 pub static UID: LazyLock<u32> = LazyLock::new(|| {
@@ -1,12 +1,6 @@
 use anyhow::Result;
 
-mod keyring;
-
-#[cfg(target_os = "linux")]
-mod run;
-
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     #[cfg(any(target_os = "macos", target_os = "windows"))]
     {
         eprintln!("he he, not yet ready for this platform, but soon my friend, soon :)");
@@ -21,5 +15,5 @@ async fn main() -> Result<()> {
     }
 
     #[cfg(target_os = "linux")]
-    run::run().await
+    rencfs::cli::run()
 }
@@ -1,11 +1,13 @@
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{FsError, FsOptions, FsResult, PasswordProvider};
 use async_trait::async_trait;
 use futures_util::FutureExt;
+use std::ffi::OsStr;
 use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{io, process};
 
 #[cfg(target_os = "linux")]
@@ -22,11 +24,14 @@ use dummy::MountHandleInnerImpl;
 #[cfg(not(target_os = "linux"))]
 use dummy::MountPointImpl;
 
+pub use crate::encryptedfs::ReconnectPolicy;
+
 #[async_trait]
 #[allow(clippy::module_name_repetitions)]
 #[allow(clippy::struct_excessive_bools)]
 pub trait MountPoint {
     #[allow(clippy::fn_params_excessive_bools)]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         mountpoint: PathBuf,
         data_dir: PathBuf,
@@ -35,6 +40,7 @@ pub trait MountPoint {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        options: FsOptions,
     ) -> Self
     where
         Self: Sized;
@@ -49,6 +55,72 @@ impl MountHandle {
     pub async fn umount(self) -> io::Result<()> {
         self.inner.unmount().await
     }
+
+    /// Unmount without getting stuck on a slow operation, like a read from a backend that doesn't respond.
+    ///
+    /// Waits up to `timeout` for the operations in progress to finish, the ones still running after that are
+    /// cancelled and fail with `EINTR`, writes among them might be lost. Then the opened files are flushed and it
+    /// unmounts.
+    pub async fn shutdown(self, timeout: Duration) -> io::Result<()> {
+        self.inner.shutdown(timeout).await
+    }
+
+    /// Unmount a mount that might be held busy, like by a process with its working directory in it, so we don't end
+    /// up with a mount no one can get rid of.
+    ///
+    /// Like [`MountHandle::shutdown`] it waits up to `timeout` for the operations in progress, then flushes the
+    /// opened files and unmounts, each also up to `timeout`. If any of that doesn't finish it detaches the mount, like
+    /// `fusermount -uz`, so it's gone for new accesses, and keeps serving the files still opened until the kernel ends
+    /// the session when they are closed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn lazy_unmount(self, timeout: Duration) -> io::Result<UnmountOutcome> {
+        self.inner.lazy_unmount(timeout).await
+    }
+
+    /// Operations being served, the longest running first.
+    #[must_use]
+    pub fn in_flight(&self) -> Vec<InFlightOp> {
+        self.inner.in_flight()
+    }
+
+    /// Tell the kernel to drop the attributes and content it cached for `ino`, after changing it with the
+    /// [`EncryptedFs`](crate::encryptedfs::EncryptedFs) directly while mounted. Without it the changes are seen
+    /// after [`FsOptions::attr_ttl`] expires, and the content when the file is opened again.
+    ///
+    /// The kernel gives us the channel for notifications only when a file on the mount is first polled, like with
+    /// `poll(2)` or `select(2)`, until then this fails.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn invalidate_inode(&self, ino: u64) -> FsResult<()> {
+        self.inner.invalidate_inode(ino).await
+    }
+
+    /// Tell the kernel to forget what `name` in `parent` resolved to, or that it didn't exist, after creating,
+    /// renaming or removing it with the [`EncryptedFs`](crate::encryptedfs::EncryptedFs) directly while mounted.
+    /// Without it the change is seen after [`FsOptions::entry_ttl`], or [`FsOptions::negative_lookup_ttl`], expires.
+    ///
+    /// Fails like [`MountHandle::invalidate_inode`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn invalidate_entry(&self, parent: u64, name: &OsStr) -> FsResult<()> {
+        self.inner.invalidate_entry(parent, name).await
+    }
+}
+
+/// How [`MountHandle::lazy_unmount`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmountOutcome {
+    /// Everything was flushed and it unmounted normally.
+    Clean,
+    /// Something didn't finish in time or failed, so it was detached, writes still in progress might be lost.
+    Detached,
+}
+
+/// An operation being served by a mount, see [`MountHandle::in_flight`].
+#[derive(Debug, Clone)]
+pub struct InFlightOp {
+    /// Name of the FUSE operation, like `read`.
+    pub op: &'static str,
+    pub ino: u64,
+    pub elapsed: Duration,
 }
 
 impl Future for MountHandle {
@@ -62,6 +134,11 @@ impl Future for MountHandle {
 #[async_trait]
 pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
     async fn unmount(mut self) -> io::Result<()>;
+    async fn shutdown(mut self, timeout: Duration) -> io::Result<()>;
+    async fn lazy_unmount(mut self, timeout: Duration) -> io::Result<UnmountOutcome>;
+    fn in_flight(&self) -> Vec<InFlightOp>;
+    async fn invalidate_inode(&self, ino: u64) -> FsResult<()>;
+    async fn invalidate_entry(&self, parent: u64, name: &OsStr) -> FsResult<()>;
 }
 /// Available arguments
 ///
@@ -86,6 +163,32 @@ pub fn create_mount_point(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+) -> impl MountPoint {
+    create_mount_point_with_options(
+        mountpoint,
+        data_dir,
+        password_provider,
+        cipher,
+        allow_root,
+        allow_other,
+        read_only,
+        FsOptions::default(),
+    )
+}
+
+/// Like [`create_mount_point`] but with [`FsOptions`] for the filesystem and the mount.
+#[must_use]
+#[allow(clippy::fn_params_excessive_bools)]
+#[allow(clippy::too_many_arguments)]
+pub fn create_mount_point_with_options(
+    mountpoint: &Path,
+    data_dir: &Path,
+    password_provider: Box<dyn PasswordProvider>,
+    cipher: Cipher,
+    allow_root: bool,
+    allow_other: bool,
+    read_only: bool,
+    options: FsOptions,
 ) -> impl MountPoint {
     MountPointImpl::new(
         mountpoint.to_path_buf(),
@@ -95,9 +198,61 @@ pub fn create_mount_point(
         allow_root,
         allow_other,
         read_only,
+        options,
     )
 }
 
+/// Mount and block the calling thread until it's unmounted, like with `umount` or `fusermount -u`, for programs
+/// which don't use tokio themselves.
+///
+/// It's [`MountPoint::mount`] and waiting on the [`MountHandle`], on a runtime it creates for it and drops after, so
+/// it must not be called from a tokio runtime. With [`FsOptions::fuse_worker_threads`] the requests are served on a
+/// runtime of its own as usual.
+#[allow(clippy::missing_errors_doc)]
+pub fn run_fuse_blocking(mount_point: impl MountPoint + Send) -> FsResult<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let handle = mount_point.mount().await?;
+        handle.await?;
+        Ok(())
+    })
+}
+
+/// Make sure we can mount on `mountpoint`, failing with [`FsError::InvalidMountPoint`] if it's not a directory we
+/// can write to or, unless `allow_nonempty`, if it has files, which would be hidden by the mount.
+pub(crate) fn check_mountpoint(mountpoint: &Path, allow_nonempty: bool) -> FsResult<()> {
+    let metadata = match mountpoint.metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotConnected => {
+            return Err(FsError::InvalidMountPoint(
+                "still mounted by a previous run that crashed, umount it first",
+            ));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if !metadata.is_dir() {
+        return Err(FsError::InvalidMountPoint("not a directory"));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path =
+            std::ffi::CString::new(mountpoint.as_os_str().as_bytes()).map_err(io::Error::from)?;
+        if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+            return Err(FsError::InvalidMountPoint("no write access"));
+        }
+    }
+    if !allow_nonempty && mountpoint.read_dir()?.next().is_some() {
+        return Err(FsError::InvalidMountPoint(
+            "not empty, the files in it would be hidden",
+        ));
+    }
+    Ok(())
+}
+
 pub fn umount(mountpoint: &str) -> io::Result<()> {
     // try normal umount
     if process::Command::new("umount")
@@ -134,3 +289,49 @@ pub fn umount(mountpoint: &str) -> io::Result<()> {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_mountpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        check_mountpoint(dir.path(), false).unwrap();
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"data").unwrap();
+        assert!(matches!(
+            check_mountpoint(dir.path(), false),
+            Err(FsError::InvalidMountPoint(_))
+        ));
+        check_mountpoint(dir.path(), true).unwrap();
+        assert!(matches!(
+            check_mountpoint(&file, true),
+            Err(FsError::InvalidMountPoint(_))
+        ));
+        assert!(check_mountpoint(&dir.path().join("missing"), true).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_run_fuse_blocking() {
+        // not from a runtime, it has its own
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"data").unwrap();
+        let mount_point = create_mount_point(
+            &file,
+            &dir.path().join("data"),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            false,
+            false,
+        );
+        assert!(matches!(
+            run_fuse_blocking(mount_point),
+            Err(FsError::InvalidMountPoint(_))
+        ));
+    }
+}
@@ -1,15 +1,17 @@
 use async_trait::async_trait;
+use std::ffi::OsStr;
 use std::future::Future;
 use std::io;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tracing::error;
 
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsError, FsResult, PasswordProvider};
+use crate::encryptedfs::{FsError, FsOptions, FsResult, PasswordProvider};
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{MountHandleInner, MountPoint, UnmountOutcome};
 
 #[allow(clippy::struct_excessive_bools)]
 #[allow(dead_code)]
@@ -21,6 +23,7 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: FsOptions,
 }
 
 #[async_trait]
@@ -33,6 +36,7 @@ impl MountPoint for MountPointImpl {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        options: FsOptions,
     ) -> Self {
         Self {
             mountpoint,
@@ -42,6 +46,7 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            options,
         }
     }
 
@@ -66,4 +71,24 @@ impl MountHandleInner for MountHandleInnerImpl {
     async fn unmount(mut self) -> io::Result<()> {
         Ok(())
     }
+
+    async fn shutdown(mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn lazy_unmount(mut self, _timeout: Duration) -> io::Result<UnmountOutcome> {
+        Ok(UnmountOutcome::Clean)
+    }
+
+    fn in_flight(&self) -> Vec<mount::InFlightOp> {
+        vec![]
+    }
+
+    async fn invalidate_inode(&self, _ino: u64) -> FsResult<()> {
+        Err(FsError::Other("Dummy implementation"))
+    }
+
+    async fn invalidate_entry(&self, _parent: u64, _name: &OsStr) -> FsResult<()> {
+        Err(FsError::Other("Dummy implementation"))
+    }
 }
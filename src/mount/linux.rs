@@ -1,43 +1,52 @@
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::future::Future;
 use std::io;
 use std::io::{BufRead, BufReader};
-use std::iter::Skip;
 use std::num::NonZeroU32;
 use std::os::raw::c_int;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::process;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs;
+use tokio::runtime::{self, Handle, Runtime};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use fuse3::raw::prelude::{
-    DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
-    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+    DirectoryEntry, DirectoryEntryPlus, Notify, ReplyAttr, ReplyCopyFileRange, ReplyCreated,
+    ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyPoll,
+    ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use fuse3::raw::{Filesystem, MountHandle, Request, Session};
 use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
 use futures_util::stream::Iter;
 use futures_util::{stream, FutureExt};
-use libc::{EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use libc::{
+    EACCES, EAGAIN, EBADF, EEXIST, EFBIG, EINTR, EINVAL, EIO, EISDIR, EMLINK, ENAMETOOLONG,
+    ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, ERANGE, EROFS, ETIMEDOUT,
+};
 use shush_rs::{ExposeSecret, SecretString};
 use tracing::{debug, error, instrument, trace, warn};
 use tracing::{info, Level};
 
 use crate::crypto::Cipher;
 use crate::encryptedfs::{
-    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult,
-    PasswordProvider, SetFileAttr,
+    CopyFileRangeReq, CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsOptions,
+    FsResult, PasswordProvider, SetFileAttr, FS_APPEND_FL, FS_IMMUTABLE_FL, XATTR_ACL_ACCESS,
+    XATTR_ACL_DEFAULT,
 };
 use crate::mount;
-use crate::mount::{MountHandleInner, MountPoint};
+use crate::mount::{MountHandleInner, MountPoint, ReconnectPolicy, UnmountOutcome};
 
-const TTL: Duration = Duration::from_secs(1);
 const STATFS: ReplyStatFs = ReplyStatFs {
     blocks: 1,
     bfree: 0,
@@ -50,9 +59,34 @@ const STATFS: ReplyStatFs = ReplyStatFs {
 };
 
 const FMODE_EXEC: i32 = 0x20;
+/// Bypass the page cache for a handle, from `fuse_kernel.h`.
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+/// A write of cached pages, the handle is any the kernel has open for writing, from `fuse_kernel.h`.
+const FUSE_WRITE_CACHE: u32 = 1 << 0;
 
 // const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
 
+/// Keep the pages of the file cached when it's opened again, from `fuse_kernel.h`.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+/// The kernel sends `release` after `close` returned, so opening for writing right after closing the previous handle
+/// might come before it. We wait this long for it.
+const RELEASE_WAIT: Duration = Duration::from_secs(1);
+
+/// Honor `O_DIRECT`, like from loop devices with `--direct-io=on`, so the blocks are not cached by the kernel on top
+/// of the page cache of the loop device and the aligned reads and writes come to us as they are.
+///
+/// Else the pages are kept between opens, so the shared libraries and the files mapped in memory over and over are
+/// not decrypted each time. Changes through the mount update them, and the ones done with the [`EncryptedFs`]
+/// directly drop them when the kernel sees the new mtime.
+#[allow(clippy::cast_sign_loss)]
+const fn open_flags(flags: u32) -> u32 {
+    if flags & libc::O_DIRECT as u32 != 0 {
+        FOPEN_DIRECT_IO
+    } else {
+        FOPEN_KEEP_CACHE
+    }
+}
+
 pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
 
 impl Iterator for DirectoryEntryIterator {
@@ -62,11 +96,7 @@ impl Iterator for DirectoryEntryIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = fuse_kind(entry.kind);
                 self.1 += 1;
                 Some(Ok(DirectoryEntry {
                     inode: entry.ino,
@@ -89,7 +119,13 @@ impl Iterator for DirectoryEntryIterator {
     }
 }
 
-pub struct DirectoryEntryPlusIterator(crate::encryptedfs::DirectoryEntryPlusIterator, u64);
+/// The entries, the offset of the last one and the [`FsOptions::entry_ttl`] and [`FsOptions::attr_ttl`].
+pub struct DirectoryEntryPlusIterator(
+    crate::encryptedfs::DirectoryEntryPlusIterator,
+    u64,
+    Duration,
+    Duration,
+);
 
 impl Iterator for DirectoryEntryPlusIterator {
     type Item = Result<DirectoryEntryPlus>;
@@ -97,11 +133,7 @@ impl Iterator for DirectoryEntryPlusIterator {
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next() {
             Some(Ok(entry)) => {
-                let kind = if entry.kind == FileType::Directory {
-                    fuse3::raw::prelude::FileType::Directory
-                } else {
-                    fuse3::raw::prelude::FileType::RegularFile
-                };
+                let kind = fuse_kind(entry.kind);
                 self.1 += 1;
                 Some(Ok(DirectoryEntryPlus {
                     inode: entry.ino,
@@ -111,8 +143,8 @@ impl Iterator for DirectoryEntryPlusIterator {
                     #[allow(clippy::cast_possible_wrap)]
                     offset: self.1 as i64,
                     attr: entry.attr.into(),
-                    entry_ttl: TTL,
-                    attr_ttl: TTL,
+                    entry_ttl: self.2,
+                    attr_ttl: self.3,
                 }))
             }
             Some(Err(FsError::Io { source, .. })) => {
@@ -128,8 +160,141 @@ impl Iterator for DirectoryEntryPlusIterator {
     }
 }
 
+/// Where we send notifications to the kernel, like for [`mount::MountHandle::invalidate_inode`].
+///
+/// fuse3 gives it to us only in `poll`, so we keep the one from the first call, until then we can't send any. A new
+/// one is needed after mounting again.
+#[derive(Default)]
+pub(in crate::mount) struct KernelNotify(std::sync::Mutex<Option<Notify>>);
+
+impl KernelNotify {
+    fn get(&self) -> FsResult<Notify> {
+        self.0.lock().unwrap().clone().ok_or(FsError::Other(
+            "the kernel didn't give us a channel for notifications yet, it does on the first poll of a file",
+        ))
+    }
+
+    fn set(&self, notify: Option<Notify>) {
+        *self.0.lock().unwrap() = notify;
+    }
+
+    /// See [`mount::MountHandle::invalidate_inode`].
+    async fn invalidate_inode(&self, ino: u64) -> FsResult<()> {
+        // from offset 0 to the end, also drops the attributes
+        self.get()?.invalid_inode(ino, 0, 0).await;
+        Ok(())
+    }
+
+    /// See [`mount::MountHandle::invalidate_entry`].
+    async fn invalidate_entry(&self, parent: u64, name: &OsStr) -> FsResult<()> {
+        self.get()?.invalid_entry(parent, name.to_owned()).await;
+        Ok(())
+    }
+}
+
+/// Operations being served, so [`mount::MountHandle::shutdown`] can wait for them and cancel the ones stuck.
+pub(in crate::mount) struct InFlight {
+    next_id: AtomicU64,
+    // (op, ino, started)
+    // use std::sync::Mutex as it's never held across an await
+    ops: std::sync::Mutex<HashMap<u64, (&'static str, u64, Instant)>>,
+    // notified when the last operation is done
+    idle: tokio::sync::Notify,
+    cancel: watch::Sender<bool>,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            ops: std::sync::Mutex::new(HashMap::new()),
+            idle: tokio::sync::Notify::new(),
+            cancel: watch::channel(false).0,
+        }
+    }
+
+    /// Run `f` registered as in-flight, it fails with `EINTR` if it's cancelled.
+    async fn run<T>(
+        &self,
+        op: &'static str,
+        ino: u64,
+        f: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.ops
+            .lock()
+            .unwrap()
+            .insert(id, (op, ino, Instant::now()));
+        let _guard = InFlightGuard {
+            in_flight: self,
+            id,
+        };
+        let mut cancel = self.cancel.subscribe();
+        tokio::select! {
+            res = f => res,
+            _ = cancel.wait_for(|cancel| *cancel) => {
+                warn!(op, ino, "cancelled");
+                Err(EINTR.into())
+            }
+        }
+    }
+
+    fn list(&self) -> Vec<mount::InFlightOp> {
+        let mut ops: Vec<_> = self
+            .ops
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(op, ino, started)| mount::InFlightOp {
+                op,
+                ino: *ino,
+                elapsed: started.elapsed(),
+            })
+            .collect();
+        ops.sort_by_key(|op| std::cmp::Reverse(op.elapsed));
+        ops
+    }
+
+    /// Returns `false` if there are still operations running after `timeout`.
+    async fn wait_idle(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let notified = self.idle.notified();
+                if self.ops.lock().unwrap().is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Make the running operations and any new ones fail.
+    fn cancel_all(&self) {
+        self.cancel.send_replace(true);
+    }
+}
+
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    id: u64,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut ops = self.in_flight.ops.lock().unwrap();
+        ops.remove(&self.id);
+        if ops.is_empty() {
+            self.in_flight.idle.notify_waiters();
+        }
+    }
+}
+
 struct EncryptedFsFuse3 {
     fs: Arc<EncryptedFs>,
+    in_flight: Arc<InFlight>,
+    notify: Arc<KernelNotify>,
 }
 
 impl EncryptedFsFuse3 {
@@ -138,9 +303,19 @@ impl EncryptedFsFuse3 {
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
         read_only: bool,
+        options: FsOptions,
     ) -> FsResult<Self> {
         Ok(Self {
-            fs: EncryptedFs::new(data_dir, password_provider, cipher, read_only).await?,
+            fs: EncryptedFs::new_with_options(
+                data_dir,
+                password_provider,
+                cipher,
+                read_only,
+                options,
+            )
+            .await?,
+            in_flight: Arc::new(InFlight::new()),
+            notify: Arc::new(KernelNotify::default()),
         })
     }
 
@@ -148,9 +323,26 @@ impl EncryptedFsFuse3 {
         self.fs.clone()
     }
 
-    #[allow(clippy::cast_possible_truncation)]
-    const fn creation_mode(&self, mode: u32) -> u16 {
-        (mode & !(libc::S_ISUID | libc::S_ISGID)) as u16
+    /// If the sticky bit of `dir` keeps `uid` from removing or renaming `entry`, see [`FsOptions::sticky_bit`].
+    fn sticky_denied(&self, dir: &FileAttr, uid: u32, entry: &FileAttr) -> bool {
+        self.fs.options().sticky_bit && sticky_bit_denies(dir, uid, entry)
+    }
+
+    /// Opens `ino`, waiting up to [`RELEASE_WAIT`] for the `release` of the previous write handle.
+    async fn open_waiting_release(&self, ino: u64, read: bool, write: bool) -> FsResult<u64> {
+        let deadline = Instant::now() + RELEASE_WAIT;
+        loop {
+            match self.fs.open(ino, read, write).await {
+                Err(FsError::AlreadyOpenForWrite) if Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn creation_mode(&self, mode: u32, kind: FileType, parent: &FileAttr) -> u16 {
+        creation_mode(mode, kind, parent.perm, self.fs.options().inherit_perm)
     }
 
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -192,7 +384,7 @@ impl EncryptedFsFuse3 {
         } else {
             file_attr()
         };
-        attr.perm = self.creation_mode(mode);
+        attr.perm = self.creation_mode(mode, kind, &parent_attr);
         attr.uid = req.uid;
         attr.gid = creation_gid(&parent_attr, req.gid);
 
@@ -210,6 +402,9 @@ impl EncryptedFsFuse3 {
                 error!(err = %err);
                 match err {
                     FsError::AlreadyExists => EEXIST,
+                    FsError::ReadOnly => EROFS,
+                    FsError::TooDeep { .. } => ENAMETOOLONG,
+                    FsError::TooManyEntries { .. } => EMLINK,
                     FsError::Io { source, .. } => {
                         if source.to_string().to_lowercase().contains("too long") {
                             ENAMETOOLONG
@@ -233,30 +428,63 @@ const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
     gid
 }
 
+/// `st_blocks` is counted in units of this, by POSIX.
+const STAT_BLOCK_SIZE: u64 = 512;
+
+/// We don't keep `blocks` and `blksize`, they are computed from the size so `du`, `stat` and tools using the file as
+/// a disk image, like `losetup`, see sensible values. Holes are counted too.
 impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
     fn from(from: FileAttr) -> Self {
         Self {
             ino: from.ino,
             size: from.size,
-            blocks: from.blocks,
+            blocks: from.size.div_ceil(STAT_BLOCK_SIZE),
             atime: from.atime.into(),
             mtime: from.mtime.into(),
             ctime: from.ctime.into(),
-            kind: if from.kind == FileType::Directory {
-                fuse3::raw::prelude::FileType::Directory
-            } else {
-                fuse3::raw::prelude::FileType::RegularFile
-            },
+            kind: fuse_kind(from.kind),
             perm: from.perm,
             nlink: from.nlink,
             uid: from.uid,
             gid: from.gid,
             rdev: from.rdev,
-            blksize: from.blksize,
+            blksize: STATFS.bsize,
         }
     }
 }
 
+const fn fuse_kind(kind: FileType) -> fuse3::raw::prelude::FileType {
+    match kind {
+        FileType::Directory => fuse3::raw::prelude::FileType::Directory,
+        FileType::RegularFile => fuse3::raw::prelude::FileType::RegularFile,
+        FileType::Symlink => fuse3::raw::prelude::FileType::Symlink,
+    }
+}
+
+/// Tells the kernel the name doesn't exist and to remember it for `ttl`, it's an entry with inode 0.
+fn negative_entry(ttl: Duration) -> ReplyEntry {
+    let time = UNIX_EPOCH.into();
+    ReplyEntry {
+        ttl,
+        attr: fuse3::raw::prelude::FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            kind: fuse3::raw::prelude::FileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+        },
+        generation: 0,
+    }
+}
+
 impl Filesystem for EncryptedFsFuse3 {
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::INFO))]
     async fn init(&self, req: Request) -> Result<ReplyInit> {
@@ -276,53 +504,61 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
         trace!("");
 
-        // if name.len() > MAX_NAME_LENGTH as usize {
-        //     warn!(name = %name.to_str().unwrap(), "name too long");
-        //     return Err(ENAMETOOLONG.into());
-        // }
-
-        match self.get_fs().get_attr(parent).await {
-            Err(err) => {
-                error!(parent, err = %err, "not found");
-                return Err(ENOENT.into());
-            }
-            Ok(parent_attr) => {
-                if !check_access(
-                    parent_attr.uid,
-                    parent_attr.gid,
-                    parent_attr.perm,
-                    req.uid,
-                    req.gid,
-                    libc::X_OK,
-                ) {
-                    return Err(EACCES.into());
+        self.in_flight
+            .run("lookup", parent, async {
+                // if name.len() > MAX_NAME_LENGTH as usize {
+                //     warn!(name = %name.to_str().unwrap(), "name too long");
+                //     return Err(ENAMETOOLONG.into());
+                // }
+
+                match self.get_fs().get_attr(parent).await {
+                    Err(err) => {
+                        error!(parent, err = %err, "not found");
+                        return Err(ENOENT.into());
+                    }
+                    Ok(parent_attr) => {
+                        if !check_access(
+                            parent_attr.uid,
+                            parent_attr.gid,
+                            parent_attr.perm,
+                            req.uid,
+                            req.gid,
+                            libc::X_OK,
+                        ) {
+                            return Err(EACCES.into());
+                        }
+                    }
                 }
-            }
-        }
 
-        let attr = match self
-            .get_fs()
-            .find_by_name(
-                parent,
-                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
-            )
-            .await
-        {
-            Ok(Some(attr)) => attr,
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT.into());
-            }
-            _ => {
-                return Err(ENOENT.into());
-            }
-        };
+                let attr = match self
+                    .get_fs()
+                    .find_by_name(
+                        parent,
+                        &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                    )
+                    .await
+                {
+                    Ok(Some(attr)) => attr,
+                    Err(FsError::NotADirectory) => return Err(ENOTDIR.into()),
+                    Err(err) => {
+                        error!(err = %err);
+                        return Err(ENOENT.into());
+                    }
+                    Ok(None) => {
+                        return match self.get_fs().options().negative_lookup_ttl {
+                            Some(ttl) => Ok(negative_entry(ttl)),
+                            None => Err(ENOENT.into()),
+                        };
+                    }
+                };
 
-        Ok(ReplyEntry {
-            ttl: TTL,
-            attr: attr.into(),
-            generation: 0,
-        })
+                Ok(ReplyEntry {
+                    ttl: self.fs.options().entry_ttl,
+                    attr: attr.into(),
+                    generation: 0,
+                })
+            })
+            .await
     }
 
     #[instrument(skip(self))]
@@ -340,16 +576,20 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyAttr> {
         trace!("");
 
-        match self.get_fs().get_attr(inode).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(ENOENT.into());
-            }
-            Ok(attr) => Ok(ReplyAttr {
-                ttl: TTL,
-                attr: attr.into(),
-            }),
-        }
+        self.in_flight
+            .run("getattr", inode, async {
+                match self.get_fs().get_attr(inode).await {
+                    Err(err) => {
+                        error!(err = %err);
+                        Err(ENOENT.into())
+                    }
+                    Ok(attr) => Ok(ReplyAttr {
+                        ttl: self.fs.options().attr_ttl,
+                        attr: attr.into(),
+                    }),
+                }
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -362,215 +602,242 @@ impl Filesystem for EncryptedFsFuse3 {
         set_attr: SetAttr,
     ) -> Result<ReplyAttr> {
         trace!("");
-        debug!("{set_attr:#?}");
-
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
-            error!(err = %err);
-            Errno::from(ENOENT)
-        })?;
 
-        let mut set_attr2 = SetFileAttr::default();
+        self.in_flight
+            .run("setattr", inode, async {
+                debug!("{set_attr:#?}");
 
-        if let Some(mode) = set_attr.mode {
-            debug!("chmod mode={mode:o}");
-            let mut set_attr2 = SetFileAttr::default();
-            if req.uid != 0 && req.uid != attr.uid {
-                return Err(EPERM.into());
-            }
-            if req.uid != 0 && req.gid != attr.gid && !get_groups(req.pid).contains(&attr.gid) {
-                // If SGID is set and the file belongs to a group that the caller is not part of
-                // then the SGID bit is supposed to be cleared during chmod
-                set_attr2 = set_attr2.with_perm((mode & !libc::S_ISGID) as u16);
-            } else {
-                set_attr2 = set_attr2.with_perm(mode as u16);
-            }
-            set_attr2 = set_attr2.with_atime(SystemTime::now());
-            self.get_fs()
-                .set_attr(inode, set_attr2)
-                .await
-                .map_err(|err| {
+                let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
                     error!(err = %err);
-                    Errno::from(EIO)
+                    Errno::from(ENOENT)
                 })?;
-            return Ok(ReplyAttr {
-                ttl: TTL,
-                attr: self
-                    .get_fs()
-                    .get_attr(inode)
-                    .await
-                    .map_err(|_err| Errno::from(ENOENT))?
-                    .into(),
-            });
-        }
-
-        if set_attr.uid.is_some() || set_attr.gid.is_some() {
-            debug!(?set_attr.uid, ?set_attr.gid, "chown");
-            let mut set_attr2 = SetFileAttr::default();
-            if let Some(gid) = set_attr2.gid {
-                // Non-root users can only change gid to a group they're in
-                if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
-                    return Err(EPERM.into());
-                }
-            }
-            if let Some(uid) = set_attr2.uid {
-                if req.uid != 0
-                    // but no-op changes by the owner are not an error
-                    && !(uid == attr.uid && req.uid == attr.uid)
-                {
-                    return Err(EPERM.into());
-                }
-            }
-            // Only owner may change the group
-            if set_attr2.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
-                return Err(EPERM.into());
-            }
 
-            set_attr2 = set_attr2.with_perm(attr.perm);
-            if attr.perm & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
-                // SUID & SGID are suppose to be cleared when chown'ing an executable file
-                set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
-            }
+                let mut set_attr2 = SetFileAttr::default();
 
-            if let Some(uid) = set_attr2.uid {
-                set_attr2 = set_attr2.with_uid(uid);
-                // Clear SETUID on owner change
-                let perm = *set_attr2.perm.as_ref().unwrap();
-                set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISUID as u16));
-            }
-            if let Some(gid) = set_attr2.gid {
-                set_attr2 = set_attr2.with_gid(gid);
-                // Clear SETGID unless user is root
-                if req.uid != 0 {
-                    let perm = *set_attr2.perm.as_ref().unwrap();
-                    set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISGID as u16));
+                if let Some(mode) = set_attr.mode {
+                    debug!("chmod mode={mode:o}");
+                    // keep only the permission bits, the kernel also checks access with these
+                    let mode = mode & 0o7777;
+                    let mut set_attr2 = SetFileAttr::default();
+                    if req.uid != 0 && req.uid != attr.uid {
+                        return Err(EPERM.into());
+                    }
+                    if req.uid != 0
+                        && req.gid != attr.gid
+                        && !get_groups(req.pid).contains(&attr.gid)
+                    {
+                        // If SGID is set and the file belongs to a group that the caller is not part of
+                        // then the SGID bit is supposed to be cleared during chmod
+                        set_attr2 = set_attr2.with_perm((mode & !libc::S_ISGID) as u16);
+                    } else {
+                        set_attr2 = set_attr2.with_perm(mode as u16);
+                    }
+                    set_attr2 = set_attr2.with_atime(SystemTime::now());
+                    self.get_fs()
+                        .set_attr(inode, set_attr2)
+                        .await
+                        .map_err(|err| {
+                            error!(err = %err);
+                            match err {
+                                FsError::ReadOnly => Errno::from(EROFS),
+                                _ => Errno::from(EIO),
+                            }
+                        })?;
+                    return Ok(ReplyAttr {
+                        ttl: self.fs.options().attr_ttl,
+                        attr: self
+                            .get_fs()
+                            .get_attr(inode)
+                            .await
+                            .map_err(|_err| Errno::from(ENOENT))?
+                            .into(),
+                    });
                 }
-            }
-            set_attr2 = set_attr2.with_atime(SystemTime::now());
-            self.get_fs()
-                .set_attr(inode, set_attr2)
-                .await
-                .map_err(|err| {
-                    error!(err = %err);
-                    Errno::from(EIO)
-                })?;
-            return Ok(ReplyAttr {
-                ttl: TTL,
-                attr: self
-                    .get_fs()
-                    .get_attr(inode)
-                    .await
-                    .map_err(|_err| Errno::from(ENOENT))?
-                    .into(),
-            });
-        }
 
-        if let Some(size) = set_attr.size {
-            debug!(size, "truncate");
+                if set_attr.uid.is_some() || set_attr.gid.is_some() {
+                    debug!(?set_attr.uid, ?set_attr.gid, "chown");
+                    let mut set_attr2 = SetFileAttr::default();
+                    if let Some(gid) = set_attr2.gid {
+                        // Non-root users can only change gid to a group they're in
+                        if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
+                            return Err(EPERM.into());
+                        }
+                    }
+                    if let Some(uid) = set_attr2.uid {
+                        if req.uid != 0
+                        // but no-op changes by the owner are not an error
+                        && !(uid == attr.uid && req.uid == attr.uid)
+                        {
+                            return Err(EPERM.into());
+                        }
+                    }
+                    // Only owner may change the group
+                    if set_attr2.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
+                        return Err(EPERM.into());
+                    }
 
-            self.get_fs().set_len(inode, size).await.map_err(|err| {
-                error!(err = %err);
-                Errno::from(EIO)
-            })?;
-            set_attr2 = set_attr2.with_size(size);
+                    set_attr2 = set_attr2.with_perm(attr.perm);
+                    if attr.perm & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
+                        // SUID & SGID are suppose to be cleared when chown'ing an executable file
+                        set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
+                    }
 
-            // Clear SETUID & SETGID on truncate
-            set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
-        }
+                    if let Some(uid) = set_attr2.uid {
+                        set_attr2 = set_attr2.with_uid(uid);
+                        // Clear SETUID on owner change
+                        let perm = *set_attr2.perm.as_ref().unwrap();
+                        set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISUID as u16));
+                    }
+                    if let Some(gid) = set_attr2.gid {
+                        set_attr2 = set_attr2.with_gid(gid);
+                        // Clear SETGID unless user is root
+                        if req.uid != 0 {
+                            let perm = *set_attr2.perm.as_ref().unwrap();
+                            set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISGID as u16));
+                        }
+                    }
+                    set_attr2 = set_attr2.with_atime(SystemTime::now());
+                    self.get_fs()
+                        .set_attr(inode, set_attr2)
+                        .await
+                        .map_err(|err| {
+                            error!(err = %err);
+                            match err {
+                                FsError::ReadOnly => Errno::from(EROFS),
+                                _ => Errno::from(EIO),
+                            }
+                        })?;
+                    return Ok(ReplyAttr {
+                        ttl: self.fs.options().attr_ttl,
+                        attr: self
+                            .get_fs()
+                            .get_attr(inode)
+                            .await
+                            .map_err(|_err| Errno::from(ENOENT))?
+                            .into(),
+                    });
+                }
 
-        if let Some(atime) = set_attr.atime {
-            debug!(?atime, "utimens");
+                if let Some(size) = set_attr.size {
+                    debug!(size, "truncate");
 
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
-                return Err(EACCES.into());
-            }
+                    self.get_fs().set_len(inode, size).await.map_err(|err| {
+                        error!(err = %err);
+                        match err {
+                            FsError::IsADirectory => Errno::from(EISDIR),
+                            FsError::NotPermitted => Errno::from(EPERM),
+                            FsError::ReadOnly => Errno::from(EROFS),
+                            _ => Errno::from(EIO),
+                        }
+                    })?;
+                    set_attr2 = set_attr2.with_size(size);
 
-            set_attr2 = set_attr2.with_atime(system_time_from_timestamp(atime));
-            set_attr2 = set_attr2.with_ctime(SystemTime::now());
-        }
+                    // Clear SETUID & SETGID on truncate
+                    set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
+                }
 
-        if let Some(mtime) = set_attr.mtime {
-            debug!(?mtime, "utimens");
+                if let Some(atime) = set_attr.atime {
+                    debug!(?atime, "utimens");
+
+                    if attr.uid != req.uid
+                        && !check_access(
+                            attr.uid,
+                            attr.gid,
+                            attr.perm,
+                            req.uid,
+                            req.gid,
+                            libc::W_OK,
+                        )
+                    {
+                        return Err(EACCES.into());
+                    }
+                }
 
-            if attr.uid != req.uid
-                && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
-            {
-                return Err(EACCES.into());
-            }
+                if let Some(mtime) = set_attr.mtime {
+                    debug!(?mtime, "utimens");
+
+                    if attr.uid != req.uid
+                        && !check_access(
+                            attr.uid,
+                            attr.gid,
+                            attr.perm,
+                            req.uid,
+                            req.gid,
+                            libc::W_OK,
+                        )
+                    {
+                        return Err(EACCES.into());
+                    }
+                }
 
-            set_attr2 = set_attr2.with_mtime(system_time_from_timestamp(mtime));
-            set_attr2 = set_attr2.with_ctime(SystemTime::now());
-        }
+                self.get_fs()
+                    .set_attr(inode, set_attr2)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        match err {
+                            FsError::ReadOnly => Errno::from(EROFS),
+                            _ => Errno::from(EIO),
+                        }
+                    })?;
+                if set_attr.atime.is_some() || set_attr.mtime.is_some() {
+                    // on the inode itself, for a symlink that's the link, the kernel already resolved it if it had
+                    // to be followed
+                    self.get_fs()
+                        .set_times(
+                            inode,
+                            set_attr.atime.map(system_time_from_timestamp),
+                            set_attr.mtime.map(system_time_from_timestamp),
+                        )
+                        .await
+                        .map_err(|err| {
+                            error!(err = %err);
+                            match err {
+                                FsError::ReadOnly => Errno::from(EROFS),
+                                FsError::NotPermitted => Errno::from(EPERM),
+                                _ => Errno::from(EIO),
+                            }
+                        })?;
+                }
 
-        self.get_fs()
-            .set_attr(inode, set_attr2)
+                Ok(ReplyAttr {
+                    ttl: self.fs.options().attr_ttl,
+                    attr: self
+                        .get_fs()
+                        .get_attr(inode)
+                        .await
+                        .map_err(|_err| Errno::from(ENOENT))?
+                        .into(),
+                })
+            })
             .await
-            .map_err(|err| {
-                error!(err = %err);
-                Errno::from(EIO)
-            })?;
-
-        Ok(ReplyAttr {
-            ttl: TTL,
-            attr: self
-                .get_fs()
-                .get_attr(inode)
-                .await
-                .map_err(|_err| Errno::from(ENOENT))?
-                .into(),
-        })
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
-    async fn mknod(
-        &self,
-        req: Request,
-        parent: Inode,
-        name: &OsStr,
-        mode: u32,
-        rdev: u32,
-    ) -> Result<ReplyEntry> {
+    #[instrument(skip(self), err(level = Level::WARN))]
+    async fn readlink(&self, _req: Request, inode: Inode) -> Result<ReplyData> {
         trace!("");
-        debug!("mode={mode:o}");
-
-        let file_type = mode & libc::S_IFMT;
-
-        if file_type != libc::S_IFREG
-            // && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR
-        {
-            // TODO
-            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
-            return Err(libc::ENOSYS.into());
-        }
 
-        self.create_nod(parent, mode, &req, name, false, false)
-            .await
-            .map_err(|err| {
+        match self.get_fs().read_link(inode).await {
+            Ok(target) => Ok(ReplyData {
+                data: Bytes::copy_from_slice(target.expose_secret().as_bytes()),
+            }),
+            Err(FsError::InvalidInodeType) => Err(EINVAL.into()),
+            Err(err) => {
                 error!(err = %err);
-                Errno::from(err)
-            })
-            .map(|(_, attr)| {
-                Ok(ReplyEntry {
-                    ttl: TTL,
-                    attr: attr.into(),
-                    generation: 0,
-                })
-            })?
+                Err(EIO.into())
+            }
+        }
     }
 
-    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
-    async fn mkdir(
+    #[instrument(skip(self, name, link), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn symlink(
         &self,
         req: Request,
         parent: Inode,
         name: &OsStr,
-        mode: u32,
-        umask: u32,
+        link: &OsStr,
     ) -> Result<ReplyEntry> {
         trace!("");
-        debug!("mode={mode:o}");
 
         let parent_attr = match self.get_fs().get_attr(parent).await {
             Err(err) => {
@@ -591,37 +858,258 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(EACCES.into());
         }
 
-        let mut attr = dir_attr();
-
-        let mut mode = mode;
-        if req.uid != 0 {
-            mode &= !(libc::S_ISUID | libc::S_ISGID);
-        }
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISGID as u16 != 0 {
-            mode |= libc::S_ISGID;
-        }
-        attr.perm = self.creation_mode(mode);
-
-        attr.uid = req.uid;
-        attr.gid = creation_gid(&parent_attr, req.gid);
-
-        let (_, attr) = self
+        let Some(link) = link.to_str() else {
+            return Err(EINVAL.into());
+        };
+        let attr = CreateFileAttr {
+            kind: FileType::Symlink,
+            perm: 0o777,
+            uid: req.uid,
+            gid: creation_gid(&parent_attr, req.gid),
+            rdev: 0,
+            flags: 0,
+        };
+        let attr = self
             .get_fs()
-            .create(
+            .symlink2(
                 parent,
                 &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                &SecretString::from_str(link).unwrap(),
                 attr,
-                false,
-                false,
             )
             .await
             .map_err(|err| {
                 error!(err = %err);
-                Errno::from(ENOENT)
+                match err {
+                    FsError::AlreadyExists => Errno::from(EEXIST),
+                    FsError::ReadOnly => Errno::from(EROFS),
+                    FsError::TooDeep { .. } => Errno::from(ENAMETOOLONG),
+                    FsError::TooManyEntries { .. } => Errno::from(EMLINK),
+                    _ => Errno::from(EIO),
+                }
+            })?;
+        Ok(ReplyEntry {
+            ttl: self.fs.options().entry_ttl,
+            attr: attr.into(),
+            generation: 0,
+        })
+    }
+
+    #[instrument(skip(self, name, value), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG))]
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+    ) -> Result<()> {
+        trace!("");
+
+        let name = name.to_str().ok_or(Errno::from(EINVAL))?;
+        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(ENOENT)
+        })?;
+        check_xattr_write(&req, &attr, name)?;
+        let exists = match self.get_fs().get_xattr(inode, name).await {
+            Ok(_) => true,
+            Err(FsError::XattrNotFound) => false,
+            Err(err) => {
+                error!(err = %err);
+                return Err(EIO.into());
+            }
+        };
+        #[allow(clippy::cast_sign_loss)]
+        if exists && flags & libc::XATTR_CREATE as u32 != 0 {
+            return Err(EEXIST.into());
+        }
+        #[allow(clippy::cast_sign_loss)]
+        if !exists && flags & libc::XATTR_REPLACE as u32 != 0 {
+            return Err(ENODATA.into());
+        }
+        match self.get_fs().set_xattr(inode, name, value).await {
+            Ok(()) => Ok(()),
+            Err(FsError::ReadOnly) => Err(EROFS.into()),
+            Err(FsError::InvalidInput(_)) => Err(EINVAL.into()),
+            // like local filesystems for a default ACL on a file
+            Err(FsError::NotADirectory) => Err(EACCES.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG))]
+    async fn getxattr(
+        &self,
+        _req: Request,
+        inode: Inode,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        trace!("");
+
+        let name = name.to_str().ok_or(Errno::from(ENODATA))?;
+        match self.get_fs().get_xattr(inode, name).await {
+            Ok(value) => reply_xattr(value, size),
+            Err(FsError::XattrNotFound) => Err(ENODATA.into()),
+            Err(FsError::InodeNotFound) => Err(ENOENT.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn listxattr(&self, _req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        trace!("");
+
+        match self.get_fs().list_xattr(inode).await {
+            Ok(names) => {
+                let mut value = vec![];
+                for name in names {
+                    value.extend_from_slice(name.as_bytes());
+                    value.push(0);
+                }
+                reply_xattr(value, size)
+            }
+            Err(FsError::InodeNotFound) => Err(ENOENT.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG))]
+    async fn removexattr(&self, req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        trace!("");
+
+        let name = name.to_str().ok_or(Errno::from(ENODATA))?;
+        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
+            error!(err = %err);
+            Errno::from(ENOENT)
+        })?;
+        check_xattr_write(&req, &attr, name)?;
+        match self.get_fs().remove_xattr(inode, name).await {
+            Ok(()) => Ok(()),
+            Err(FsError::XattrNotFound) => Err(ENODATA.into()),
+            Err(FsError::ReadOnly) => Err(EROFS.into()),
+            Err(err) => {
+                error!(err = %err);
+                Err(EIO.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn mknod(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+        debug!("mode={mode:o}");
+
+        let file_type = mode & libc::S_IFMT;
+
+        if self.fs.options().nodev && (file_type == libc::S_IFCHR || file_type == libc::S_IFBLK) {
+            return Err(EPERM.into());
+        }
+        if file_type != libc::S_IFREG
+            // && file_type != libc::S_IFLNK as u32
+            && file_type != libc::S_IFDIR
+        {
+            // TODO
+            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
+            return Err(libc::ENOSYS.into());
+        }
+
+        self.create_nod(parent, mode, &req, name, false, false)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                Errno::from(err)
+            })
+            .map(|(_, attr)| {
+                Ok(ReplyEntry {
+                    ttl: self.fs.options().entry_ttl,
+                    attr: attr.into(),
+                    generation: 0,
+                })
+            })?
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        trace!("");
+        debug!("mode={mode:o}");
+
+        let parent_attr = match self.get_fs().get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES.into());
+        }
+
+        let mut attr = dir_attr();
+
+        let mut mode = mode;
+        if req.uid != 0 {
+            mode &= !(libc::S_ISUID | libc::S_ISGID);
+        }
+        attr.perm = self.creation_mode(mode, FileType::Directory, &parent_attr);
+
+        attr.uid = req.uid;
+        attr.gid = creation_gid(&parent_attr, req.gid);
+
+        let (_, attr) = self
+            .get_fs()
+            .create(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                attr,
+                false,
+                false,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::ReadOnly => Errno::from(EROFS),
+                    FsError::TooDeep { .. } => Errno::from(ENAMETOOLONG),
+                    FsError::TooManyEntries { .. } => Errno::from(EMLINK),
+                    _ => Errno::from(ENOENT),
+                }
             })?;
         Ok(ReplyEntry {
-            ttl: TTL,
+            ttl: self.fs.options().entry_ttl,
             attr: attr.into(),
             generation: 0,
         })
@@ -666,15 +1154,8 @@ impl Filesystem for EncryptedFsFuse3 {
             _ => return Err(ENOENT.into()),
         };
 
-        let uid = req.uid;
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && uid != 0
-            && uid != parent_attr.uid
-            && uid != attr.uid
-        {
-            return Err(EACCES.into());
+        if self.sticky_denied(&parent_attr, req.uid, &attr) {
+            return Err(EPERM.into());
         }
 
         if let Err(err) = self
@@ -686,7 +1167,12 @@ impl Filesystem for EncryptedFsFuse3 {
             .await
         {
             error!(err = %err);
-            return Err(ENOENT.into());
+            return match err {
+                FsError::IsADirectory => Err(EISDIR.into()),
+                FsError::NotPermitted => Err(EPERM.into()),
+                FsError::ReadOnly => Err(EROFS.into()),
+                _ => Err(ENOENT.into()),
+            };
         }
 
         Ok(())
@@ -728,15 +1214,8 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(ENOTDIR.into());
         }
 
-        let uid = req.uid;
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && uid != 0
-            && uid != parent_attr.uid
-            && uid != attr.uid
-        {
-            return Err(EACCES.into());
+        if self.sticky_denied(&parent_attr, req.uid, &attr) {
+            return Err(EPERM.into());
         }
 
         if let Err(err) = self
@@ -750,6 +1229,9 @@ impl Filesystem for EncryptedFsFuse3 {
             error!(err = %err);
             return match err {
                 FsError::NotEmpty => Err(EISDIR.into()),
+                FsError::NotADirectory => Err(ENOTDIR.into()),
+                FsError::NotPermitted => Err(EPERM.into()),
+                FsError::ReadOnly => Err(EROFS.into()),
                 _ => Err(EIO.into()),
             };
         }
@@ -800,14 +1282,8 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(EACCES.into());
         }
 
-        // "Sticky bit" handling
-        #[allow(clippy::cast_possible_truncation)]
-        if parent_attr.perm & libc::S_ISVTX as u16 != 0
-            && req.uid != 0
-            && req.uid != parent_attr.uid
-            && req.uid != attr.uid
-        {
-            return Err(EACCES.into());
+        if self.sticky_denied(&parent_attr, req.uid, &attr) {
+            return Err(EPERM.into());
         }
 
         let Ok(new_parent_attr) = self.get_fs().get_attr(new_parent).await else {
@@ -826,20 +1302,17 @@ impl Filesystem for EncryptedFsFuse3 {
             return Err(EACCES.into());
         }
 
-        // "Sticky bit" handling in new_parent
-        #[allow(clippy::cast_possible_truncation)]
-        if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
-            if let Ok(Some(new_attrs)) = self
-                .get_fs()
-                .find_by_name(
-                    new_parent,
-                    &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
-                )
-                .await
-            {
-                if req.uid != 0 && req.uid != new_parent_attr.uid && req.uid != new_attrs.uid {
-                    return Err(EACCES.into());
-                }
+        // the entry it replaces in new_parent
+        if let Ok(Some(new_attrs)) = self
+            .get_fs()
+            .find_by_name(
+                new_parent,
+                &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+            )
+            .await
+        {
+            if self.sticky_denied(&new_parent_attr, req.uid, &new_attrs) {
+                return Err(EPERM.into());
             }
         }
 
@@ -864,6 +1337,10 @@ impl Filesystem for EncryptedFsFuse3 {
         {
             Ok(()) => Ok(()),
             Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
+            Err(FsError::NotADirectory) => Err(ENOTDIR.into()),
+            Err(FsError::IsADirectory) => Err(EISDIR.into()),
+            Err(FsError::NotPermitted) => Err(EPERM.into()),
+            Err(FsError::ReadOnly) => Err(EROFS.into()),
             _ => Err(ENOENT.into()),
         }
     }
@@ -872,56 +1349,81 @@ impl Filesystem for EncryptedFsFuse3 {
     async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
         trace!("");
 
-        #[allow(clippy::cast_possible_wrap)]
-        let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
-            libc::O_RDONLY => {
-                // Behavior is undefined, but most filesystems return EACCES
-                if flags & libc::O_TRUNC as u32 != 0 {
-                    return Err(EACCES.into());
-                }
-                if flags & FMODE_EXEC as u32 != 0 {
-                    // Open is from internal exec syscall
-                    (libc::X_OK, true, false)
-                } else {
-                    (libc::R_OK, true, false)
-                }
-            }
-            libc::O_WRONLY => (libc::W_OK, false, true),
-            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                return Err(libc::EINVAL.into());
-            }
-        };
+        self.in_flight
+            .run("open", inode, async {
+                #[allow(clippy::cast_possible_wrap)]
+                let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
+                    libc::O_RDONLY => {
+                        // Behavior is undefined, but most filesystems return EACCES
+                        if flags & libc::O_TRUNC as u32 != 0 {
+                            return Err(EACCES.into());
+                        }
+                        if flags & FMODE_EXEC as u32 != 0 {
+                            if self.fs.options().noexec {
+                                return Err(EACCES.into());
+                            }
+                            // Open is from internal exec syscall
+                            (libc::X_OK, true, false)
+                        } else {
+                            (libc::R_OK, true, false)
+                        }
+                    }
+                    libc::O_WRONLY => (libc::W_OK, false, true),
+                    libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+                    // Exactly one access mode flag must be specified
+                    _ => {
+                        return Err(libc::EINVAL.into());
+                    }
+                };
 
-        // let _create = flags & libc::O_CREAT as u32 != 0;
-        let truncate = flags & libc::O_TRUNC as u32 != 0;
-        // let _append = flags & libc::O_APPEND as u32 != 0;
+                // with the writeback cache the kernel reads the pages it only partially writes
+                let read = read || write && self.fs.options().writeback_cache;
+                // let _create = flags & libc::O_CREAT as u32 != 0;
+                let truncate = flags & libc::O_TRUNC as u32 != 0;
+                let append = flags & libc::O_APPEND as u32 != 0;
 
-        let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
-            error!(err = %err);
-            EIO
-        })?;
-        //
-        if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            if truncate {
-                self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
-                    error!(err = %err);
-                    EIO
-                })?;
-            }
-            let fh = self
-                .get_fs()
-                .open(inode, read, write)
-                .await
-                .map_err(|err| {
+                let attr = self.get_fs().get_attr(inode).await.map_err(|err| {
                     error!(err = %err);
                     EIO
                 })?;
-            Ok(ReplyOpen { fh, flags: 0 })
-        } else {
-            return Err(EACCES.into());
-        }
+                // like chattr +i and +a
+                if write
+                    && (attr.flags & FS_IMMUTABLE_FL != 0
+                        || attr.flags & FS_APPEND_FL != 0 && (!append || truncate))
+                {
+                    return Err(EPERM.into());
+                }
+                if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+                    if truncate {
+                        self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
+                            error!(err = %err);
+                            match err {
+                                FsError::IsADirectory => EISDIR,
+                                FsError::ReadOnly => EROFS,
+                                _ => EIO,
+                            }
+                        })?;
+                    }
+                    let fh = self
+                        .open_waiting_release(inode, read, write)
+                        .await
+                        .map_err(|err| {
+                            error!(err = %err);
+                            match err {
+                                FsError::IsADirectory => EISDIR,
+                                FsError::ReadOnly => EROFS,
+                                _ => EIO,
+                            }
+                        })?;
+                    Ok(ReplyOpen {
+                        fh,
+                        flags: open_flags(flags),
+                    })
+                } else {
+                    Err(EACCES.into())
+                }
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN))]
@@ -935,16 +1437,32 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyData> {
         trace!("");
 
-        let mut buf = vec![0; size as usize];
-        match self.get_fs().read(inode, offset, &mut buf, fh).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(EIO.into());
-            }
-            Ok(len) => Ok(ReplyData {
-                data: Bytes::copy_from_slice(buf[..len].as_ref()),
-            }),
-        }
+        self.in_flight
+            .run("read", inode, async {
+                if size == 0 {
+                    return Ok(ReplyData { data: Bytes::new() });
+                }
+                let size = (size as usize).min(self.get_fs().options().max_read_size);
+                match self
+                    .get_fs()
+                    .read_with_timeout(inode, offset, size, fh)
+                    .await
+                {
+                    Err(FsError::Timeout) => {
+                        error!(err = %FsError::Timeout);
+                        Err(ETIMEDOUT.into())
+                    }
+                    Err(FsError::IsADirectory) => Err(EISDIR.into()),
+                    Err(err) => {
+                        error!(err = %err);
+                        Err(EIO.into())
+                    }
+                    Ok(buf) => Ok(ReplyData {
+                        data: Bytes::from(buf),
+                    }),
+                }
+            })
+            .await
     }
 
     #[instrument(skip(self, data), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -959,24 +1477,52 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u32,
     ) -> Result<ReplyWrite> {
         trace!("");
-        debug!(size = data.len());
 
-        let len = self
-            .get_fs()
-            .write(inode, offset, data, fh)
-            .await
-            .map_err(|err| {
-                error!(err = %err);
-                match err {
-                    FsError::MaxFilesizeExceeded(_) => EFBIG,
-                    _ => EIO,
+        self.in_flight
+            .run("write", inode, async {
+                debug!(size = data.len());
+
+                let fs = self.get_fs();
+                let mut fh = fh;
+                if write_flags & FUSE_WRITE_CACHE != 0 && !fs.is_write_handle(fh).await {
+                    // written back from the cache after the handle it guessed was closed, use the one still open
+                    if let Some(info) = fs
+                        .open_handles()
+                        .into_iter()
+                        .find(|info| info.ino == inode && info.write)
+                    {
+                        fh = info.fh;
+                    }
                 }
-            })?;
+                #[allow(clippy::cast_possible_wrap)]
+                if flags as i32 & libc::O_NONBLOCK != 0 && fs.write_would_block(fh, data.len()) {
+                    // drain in background, the writer will try again
+                    tokio::spawn(async move {
+                        if let Err(err) = fs.write_out(fh).await {
+                            error!(err = %err, "writing out");
+                        }
+                    });
+                    return Err(EAGAIN.into());
+                }
+                // a short write if it fails after writing some, the error is for the next one
+                let len = fs.write_all(inode, offset, data, fh).await.map_err(|err| {
+                    error!(err = %err);
+                    match err {
+                        FsError::MaxFilesizeExceeded(_) => EFBIG,
+                        FsError::IsADirectory => EISDIR,
+                        FsError::NotPermitted => EPERM,
+                        FsError::ReadOnly => EROFS,
+                        err if err.is_no_space() => ENOSPC,
+                        _ => EIO,
+                    }
+                })?;
 
-        Ok(ReplyWrite {
-            #[allow(clippy::cast_possible_truncation)]
-            written: len as u32,
-        })
+                Ok(ReplyWrite {
+                    #[allow(clippy::cast_possible_truncation)]
+                    written: len as u32,
+                })
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -998,51 +1544,59 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<()> {
         trace!("");
 
-        let fs = self.get_fs();
+        self.in_flight
+            .run("release", inode, async {
+                let fs = self.get_fs();
 
-        if flush {
-            if let Err(err) = fs.flush(fh).await {
-                error!(err = %err);
-                return Err(EIO.into());
-            }
-        }
+                if flush {
+                    if let Err(err) = fs.flush(fh).await {
+                        error!(err = %err);
+                        return Err(if err.is_no_space() { ENOSPC } else { EIO }.into());
+                    }
+                }
 
-        let is_write_handle = fs.is_write_handle(fh);
+                let is_write_handle = fs.is_write_handle(fh);
 
-        if let Err(err) = fs.release(fh).await {
-            error!(err = %err);
-            return Err(EIO.into());
-        }
+                if let Err(err) = fs.release(fh).await {
+                    error!(err = %err);
+                    return Err(if err.is_no_space() { ENOSPC } else { EIO }.into());
+                }
 
-        if is_write_handle.await {
-            let attr = fs.get_attr(inode).await.map_err(|err| {
-                error!(err = %err);
-                Errno::from(ENOENT)
-            })?;
-            let mut set_attr = SetFileAttr::default();
-
-            // XXX: In theory we should only need to do this when WRITE_KILL_PRIV is set for 7.31+
-            // However, xfstests fail in that case
-            set_attr = set_attr.with_perm(clear_suid_sgid(attr.perm));
-            fs.set_attr(inode, set_attr).await.map_err(|err| {
-                error!(err = %err, "replace attr");
-                Errno::from(EIO)
-            })?;
-        }
+                if is_write_handle.await {
+                    let attr = fs.get_attr(inode).await.map_err(|err| {
+                        error!(err = %err);
+                        Errno::from(ENOENT)
+                    })?;
+                    let mut set_attr = SetFileAttr::default();
+
+                    // XXX: In theory we should only need to do this when WRITE_KILL_PRIV is set for 7.31+
+                    // However, xfstests fail in that case
+                    set_attr = set_attr.with_perm(clear_suid_sgid(attr.perm));
+                    fs.set_attr(inode, set_attr).await.map_err(|err| {
+                        error!(err = %err, "replace attr");
+                        Errno::from(EIO)
+                    })?;
+                }
 
-        Ok(())
+                Ok(())
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
         trace!("");
 
-        if let Err(err) = self.get_fs().flush(fh).await {
-            error!(err = %err, fh);
-            return Err(EIO.into());
-        }
+        self.in_flight
+            .run("flush", inode, async {
+                if let Err(err) = self.get_fs().flush(fh).await {
+                    error!(err = %err, fh);
+                    return Err(if err.is_no_space() { ENOSPC } else { EIO }.into());
+                }
 
-        Ok(())
+                Ok(())
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1075,17 +1629,29 @@ impl Filesystem for EncryptedFsFuse3 {
         };
 
         if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
-            Ok(ReplyOpen {
-                fh: 0, // we don't use handles for directories
-                flags: 0,
-            })
+            if self.get_fs().options().prefetch_dir_metadata {
+                let fs = self.get_fs();
+                tokio::spawn(async move {
+                    if let Err(err) = fs.prefetch_dir_metadata(inode).await {
+                        warn!(err = %err, "prefetching directory metadata");
+                    }
+                });
+            }
+            let fh = self.get_fs().open_dir(inode).map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::NotADirectory => ENOTDIR,
+                    _ => EIO,
+                }
+            })?;
+            Ok(ReplyOpen { fh, flags: 0 })
         } else {
             return Err(EACCES.into());
         }
     }
 
     type DirEntryStream<'a>
-        = Iter<Skip<DirectoryEntryIterator>>
+        = Iter<DirectoryEntryIterator>
     where
         Self: 'a;
 
@@ -1099,28 +1665,35 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
         trace!("");
 
-        #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir(inode).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(EIO.into());
-            }
-            Ok(iter) => iter,
-        };
-        let iter = DirectoryEntryIterator(iter, 0);
+        self.in_flight
+            .run("readdir", inode, async {
+                #[allow(clippy::cast_sign_loss)]
+                let offset = offset as u64;
+                let iter = match self.get_fs().read_dir_at(fh, offset).await {
+                    Err(err) => {
+                        error!(err = %err);
+                        return Err(EIO.into());
+                    }
+                    Ok(iter) => iter,
+                };
+                // it starts from `offset` already
+                let iter = DirectoryEntryIterator(iter, offset);
 
-        Ok(ReplyDirectory {
-            #[allow(clippy::cast_possible_truncation)]
-            #[allow(clippy::cast_sign_loss)]
-            entries: stream::iter(iter.skip(offset as usize)),
-        })
+                Ok(ReplyDirectory {
+                    entries: stream::iter(iter),
+                })
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
         trace!("");
 
-        Ok(())
+        self.get_fs().release_dir(fh).map_err(|err| {
+            error!(err = %err);
+            EBADF.into()
+        })
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1130,6 +1703,13 @@ impl Filesystem for EncryptedFsFuse3 {
         self.get_fs().get_attr(inode).await.map_or_else(
             |_| Err(ENOENT.into()),
             |attr| {
+                #[allow(clippy::cast_possible_wrap)]
+                if self.fs.options().noexec
+                    && attr.kind == FileType::RegularFile
+                    && mask as i32 & libc::X_OK != 0
+                {
+                    return Err(EACCES.into());
+                }
                 #[allow(clippy::cast_possible_wrap)]
                 if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
                     Ok(())
@@ -1140,6 +1720,37 @@ impl Filesystem for EncryptedFsFuse3 {
         )
     }
 
+    /// We only have regular files and directories, reading or writing them never blocks, so they are always ready
+    /// for the requested events and we never need to `notify` for them. We keep `notify` for
+    /// [`mount::MountHandle::invalidate_inode`] and [`mount::MountHandle::invalidate_entry`].
+    #[instrument(skip(self, notify), err(level = Level::WARN), ret(level = Level::DEBUG))]
+    #[allow(clippy::too_many_arguments)]
+    async fn poll(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        kh: Option<u64>,
+        flags: u32,
+        events: u32,
+        notify: &Notify,
+    ) -> Result<ReplyPoll> {
+        trace!("");
+
+        if self.notify.get().is_err() {
+            self.notify.set(Some(notify.clone()));
+        }
+
+        if !self.get_fs().exists(inode) {
+            return Err(ENOENT.into());
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let ready = (libc::POLLIN | libc::POLLOUT | libc::POLLRDNORM | libc::POLLWRNORM) as u32;
+        Ok(ReplyPoll {
+            revents: events & ready,
+        })
+    }
+
     #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::WARN), ret(level = Level::DEBUG))]
     async fn create(
         &self,
@@ -1151,35 +1762,40 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyCreated> {
         trace!("");
 
-        #[allow(clippy::cast_possible_wrap)]
-        let (read, write) = match flags as i32 & libc::O_ACCMODE {
-            libc::O_RDONLY => (true, false),
-            libc::O_WRONLY => (false, true),
-            libc::O_RDWR => (true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                return Err(libc::EINVAL.into());
-            }
-        };
+        self.in_flight
+            .run("create", parent, async {
+                #[allow(clippy::cast_possible_wrap)]
+                let (read, write) = match flags as i32 & libc::O_ACCMODE {
+                    libc::O_RDONLY => (true, false),
+                    libc::O_WRONLY => (false, true),
+                    libc::O_RDWR => (true, true),
+                    // Exactly one access mode flag must be specified
+                    _ => {
+                        return Err(libc::EINVAL.into());
+                    }
+                };
+                let read = read || write && self.fs.options().writeback_cache;
 
-        let (handle, attr) = self
-            .create_nod(parent, mode, &req, name, read, write)
+                let (handle, attr) = self
+                    .create_nod(parent, mode, &req, name, read, write)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        Errno::from(ENOENT)
+                    })?;
+                Ok(ReplyCreated {
+                    ttl: self.fs.options().entry_ttl,
+                    attr: attr.into(),
+                    generation: 0,
+                    fh: handle,
+                    flags: open_flags(flags),
+                })
+            })
             .await
-            .map_err(|err| {
-                error!(err = %err);
-                Errno::from(ENOENT)
-            })?;
-        Ok(ReplyCreated {
-            ttl: TTL,
-            attr: attr.into(),
-            generation: 0,
-            fh: handle,
-            flags: 0,
-        })
     }
 
     type DirEntryPlusStream<'a>
-        = Iter<Skip<DirectoryEntryPlusIterator>>
+        = Iter<DirectoryEntryPlusIterator>
     where
         Self: 'a;
 
@@ -1194,20 +1810,25 @@ impl Filesystem for EncryptedFsFuse3 {
     ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
         trace!("");
 
-        #[allow(clippy::cast_sign_loss)]
-        let iter = match self.get_fs().read_dir_plus(parent).await {
-            Err(err) => {
-                error!(err = %err);
-                return Err(EIO.into());
-            }
-            Ok(iter) => iter,
-        };
-        let iter = DirectoryEntryPlusIterator(iter, 0);
+        self.in_flight
+            .run("readdirplus", parent, async {
+                let iter = match self.get_fs().read_dir_plus_at(fh, offset).await {
+                    Err(err) => {
+                        error!(err = %err);
+                        return Err(EIO.into());
+                    }
+                    Ok(iter) => iter,
+                };
+                let options = self.fs.options();
+                // it starts from `offset` already
+                let iter =
+                    DirectoryEntryPlusIterator(iter, offset, options.entry_ttl, options.attr_ttl);
 
-        Ok(ReplyDirectoryPlus {
-            #[allow(clippy::cast_possible_truncation)]
-            entries: stream::iter(iter.skip(offset as usize)),
-        })
+                Ok(ReplyDirectoryPlus {
+                    entries: stream::iter(iter),
+                })
+            })
+            .await
     }
 
     #[instrument(skip(self), err(level = Level::WARN), ret(level = Level::DEBUG))]
@@ -1224,26 +1845,32 @@ impl Filesystem for EncryptedFsFuse3 {
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
         trace!("");
-        let file_range_req = CopyFileRangeReq::builder()
-            .src_ino(inode)
-            .src_offset(off_in)
-            .dest_ino(inode_out)
-            .dest_offset(off_out)
-            .src_fh(fh_in)
-            .dest_fh(fh_out)
-            .build();
-        #[allow(clippy::cast_possible_truncation)]
-        match self
-            .get_fs()
-            .copy_file_range(&file_range_req, length as usize)
+
+        self.in_flight
+            .run("copy_file_range", inode, async {
+                let file_range_req = CopyFileRangeReq::builder()
+                    .src_ino(inode)
+                    .src_offset(off_in)
+                    .dest_ino(inode_out)
+                    .dest_offset(off_out)
+                    .src_fh(fh_in)
+                    .dest_fh(fh_out)
+                    .build();
+                #[allow(clippy::cast_possible_truncation)]
+                match self
+                    .get_fs()
+                    .copy_file_range(&file_range_req, length as usize)
+                    .await
+                {
+                    Err(FsError::IsADirectory) => Err(EISDIR.into()),
+                    Err(err) => {
+                        error!(err = %err);
+                        Err(EIO.into())
+                    }
+                    Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
+                }
+            })
             .await
-        {
-            Err(err) => {
-                error!(err = %err);
-                return Err(EIO.into());
-            }
-            Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
-        }
     }
 }
 
@@ -1313,6 +1940,59 @@ const fn file_attr() -> CreateFileAttr {
     }
 }
 
+/// Permissions for a new entry in a directory with `parent_perm`. Directories in a setgid directory are setgid
+/// too, so the group is inherited all the way down. With `inherit_perm` the group and others bits can only be
+/// narrowed by the parent, never widened past what was requested.
+#[allow(clippy::cast_possible_truncation)]
+fn creation_mode(mode: u32, kind: FileType, parent_perm: u16, inherit_perm: bool) -> u16 {
+    let mut mode = mode & !libc::S_ISUID;
+    if kind == FileType::Directory {
+        if parent_perm & libc::S_ISGID as u16 != 0 {
+            mode |= libc::S_ISGID;
+        }
+    } else {
+        mode &= !libc::S_ISGID;
+    }
+    if inherit_perm {
+        mode &= u32::from(parent_perm) | !0o077;
+    }
+    mode as u16
+}
+
+fn sticky_bit_denies(dir: &FileAttr, uid: u32, entry: &FileAttr) -> bool {
+    #[allow(clippy::cast_possible_truncation)]
+    let sticky = dir.perm & libc::S_ISVTX as u16 != 0;
+    sticky && uid != 0 && uid != dir.uid && uid != entry.uid
+}
+
+/// ACLs can be changed only by the owner, `trusted.*` only by root and the others by who can write to the file.
+fn check_xattr_write(req: &Request, attr: &FileAttr, name: &str) -> Result<()> {
+    if req.uid == 0 {
+        return Ok(());
+    }
+    if name.starts_with("trusted.")
+        || ((name == XATTR_ACL_ACCESS || name == XATTR_ACL_DEFAULT) && req.uid != attr.uid)
+    {
+        return Err(EPERM.into());
+    }
+    if !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK) {
+        return Err(EACCES.into());
+    }
+    Ok(())
+}
+
+/// With `size` 0 only the size is asked, if the value doesn't fit in `size` it's `ERANGE`.
+fn reply_xattr(value: Vec<u8>, size: u32) -> Result<ReplyXAttr> {
+    if size == 0 {
+        #[allow(clippy::cast_possible_truncation)]
+        return Ok(ReplyXAttr::Size(value.len() as u32));
+    }
+    if value.len() > size as usize {
+        return Err(ERANGE.into());
+    }
+    Ok(ReplyXAttr::Data(Bytes::from(value)))
+}
+
 fn check_access(
     #[allow(clippy::similar_names)] file_uid: u32,
     #[allow(clippy::similar_names)] file_gid: u32,
@@ -1362,6 +2042,7 @@ pub struct MountPointImpl {
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
+    options: FsOptions,
 }
 
 #[async_trait]
@@ -1374,6 +2055,7 @@ impl MountPoint for MountPointImpl {
         allow_root: bool,
         allow_other: bool,
         read_only: bool,
+        options: FsOptions,
     ) -> Self {
         Self {
             mountpoint,
@@ -1383,46 +2065,285 @@ impl MountPoint for MountPointImpl {
             allow_root,
             allow_other,
             read_only,
+            options,
         }
     }
 
     async fn mount(mut self) -> FsResult<mount::MountHandle> {
-        let handle = mount_fuse(
+        let reconnect = self.options.reconnect;
+        // the session spawns a task for each request on the runtime it's mounted from
+        let runtime = self
+            .options
+            .fuse_worker_threads
+            .map(FuseRuntime::new)
+            .transpose()?;
+        let spawner = runtime
+            .as_ref()
+            .map_or_else(Handle::current, FuseRuntime::handle);
+        let (handle, fs, in_flight, notify, mount_options) = spawner
+            .spawn(mount_fuse(
+                self.mountpoint.clone(),
+                self.data_dir.clone(),
+                self.password_provider.take().unwrap(),
+                self.cipher,
+                self.allow_root,
+                self.allow_other,
+                self.read_only,
+                self.options.clone(),
+            ))
+            .await??;
+        let (umount_tx, umount_rx) = oneshot::channel();
+        let task = spawner.spawn(supervise(
+            handle,
+            fs.clone(),
+            in_flight.clone(),
+            notify.clone(),
+            mount_options,
             self.mountpoint.clone(),
-            self.data_dir.clone(),
-            self.password_provider.take().unwrap(),
-            self.cipher,
-            self.allow_root,
-            self.allow_other,
-            self.read_only,
-        )
-        .await?;
+            reconnect,
+            umount_rx,
+        ));
         Ok(mount::MountHandle {
-            inner: MountHandleInnerImpl { inner: handle },
+            inner: MountHandleInnerImpl {
+                task,
+                umount: Some(umount_tx),
+                mountpoint: self.mountpoint.clone(),
+                fs,
+                in_flight,
+                notify,
+                _runtime: runtime,
+            },
         })
     }
 }
 
+/// Runtime of the session when [`FsOptions::fuse_worker_threads`] is set.
+struct FuseRuntime(Option<Runtime>);
+
+impl FuseRuntime {
+    fn new(worker_threads: usize) -> io::Result<Self> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads.max(1))
+            .thread_name("rencfs-fuse")
+            .enable_all()
+            .build()?;
+        Ok(Self(Some(runtime)))
+    }
+
+    fn handle(&self) -> Handle {
+        self.0.as_ref().unwrap().handle().clone()
+    }
+}
+
+impl Drop for FuseRuntime {
+    fn drop(&mut self) {
+        // we might be in an async context, where it can't wait for the threads
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
 pub(in crate::mount) struct MountHandleInnerImpl {
-    inner: MountHandle,
+    task: JoinHandle<io::Result<()>>,
+    // true to unmount, false when detached to only stop mounting again
+    umount: Option<oneshot::Sender<bool>>,
+    mountpoint: PathBuf,
+    fs: Arc<EncryptedFs>,
+    in_flight: Arc<InFlight>,
+    notify: Arc<KernelNotify>,
+    // dropped last, after the session ended
+    _runtime: Option<FuseRuntime>,
 }
 
 impl Future for MountHandleInnerImpl {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.inner.poll_unpin(cx)
+        self.task.poll_unpin(cx).map(|res| res?)
     }
 }
 
 #[async_trait]
 impl MountHandleInner for MountHandleInnerImpl {
     async fn unmount(mut self) -> io::Result<()> {
-        self.inner.unmount().await
+        if let Some(umount) = self.umount.take() {
+            let _ = umount.send(true);
+        }
+        self.task.await?
+    }
+
+    async fn shutdown(mut self, timeout: Duration) -> io::Result<()> {
+        if !self.in_flight.wait_idle(timeout).await {
+            warn!(ops = ?self.in_flight.list(), "cancelling operations still running");
+            self.in_flight.cancel_all();
+        }
+        if let Err(err) = self.fs.flush_all().await {
+            error!(err = %err, "cannot flush before unmounting");
+        }
+        self.unmount().await
     }
+
+    async fn lazy_unmount(mut self, timeout: Duration) -> io::Result<UnmountOutcome> {
+        let mut graceful = self.in_flight.wait_idle(timeout).await;
+        if !graceful {
+            warn!(ops = ?self.in_flight.list(), "cancelling operations still running");
+            self.in_flight.cancel_all();
+        }
+        match tokio::time::timeout(timeout, self.fs.flush_all()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                error!(err = %err, "cannot flush before unmounting");
+                graceful = false;
+            }
+            Err(_) => {
+                warn!("flushing timed out");
+                graceful = false;
+            }
+        }
+        if graceful {
+            if let Some(umount) = self.umount.take() {
+                let _ = umount.send(true);
+            }
+            match tokio::time::timeout(timeout, &mut self.task).await {
+                Ok(Ok(Ok(()))) => return Ok(UnmountOutcome::Clean),
+                Ok(res) => {
+                    // the session has ended, what's left is to remove the mount
+                    warn!(res = ?res, "cannot unmount, detaching");
+                    lazy_umount(&self.mountpoint)?;
+                    return Ok(UnmountOutcome::Detached);
+                }
+                Err(_) => warn!("unmounting timed out, detaching"),
+            }
+        }
+        lazy_umount(&self.mountpoint)?;
+        if let Some(umount) = self.umount.take() {
+            let _ = umount.send(false);
+        }
+        // keep serving the files still opened, with the runtime if it's ours, until the kernel ends the session
+        tokio::spawn(async move {
+            let res = (&mut self.task).await;
+            debug!(res = ?res, "detached session ended");
+            drop(self);
+        });
+        Ok(UnmountOutcome::Detached)
+    }
+
+    fn in_flight(&self) -> Vec<mount::InFlightOp> {
+        self.in_flight.list()
+    }
+
+    async fn invalidate_inode(&self, ino: u64) -> FsResult<()> {
+        self.notify.invalidate_inode(ino).await
+    }
+
+    async fn invalidate_entry(&self, parent: u64, name: &OsStr) -> FsResult<()> {
+        self.notify.invalidate_entry(parent, name).await
+    }
+}
+
+/// Waits for the session to end, and if it wasn't us who unmounted it tries to mount again according to `reconnect`.
+#[allow(clippy::too_many_arguments)]
+async fn supervise(
+    mut handle: MountHandle,
+    fs: Arc<EncryptedFs>,
+    in_flight: Arc<InFlight>,
+    notify: Arc<KernelNotify>,
+    mount_options: MountOptions,
+    mountpoint: PathBuf,
+    reconnect: ReconnectPolicy,
+    mut umount: oneshot::Receiver<bool>,
+) -> io::Result<()> {
+    loop {
+        let res = tokio::select! {
+            res = &mut umount => {
+                match res {
+                    // detached, it ends when the kernel is done with it
+                    Ok(false) => return (&mut handle).await,
+                    // asked to, or the handle was dropped
+                    Ok(true) | Err(_) => return handle.unmount().await,
+                }
+            }
+            res = &mut handle => res,
+        };
+        // it ended cleanly, like with `fusermount -u`, so someone wanted it unmounted
+        if res.is_ok() || reconnect.max_retries == 0 {
+            return res;
+        }
+        warn!(res = ?res, "FUSE session ended, trying to mount again");
+        if let Err(err) = fs.flush_all().await {
+            error!(err = %err, "cannot flush before mounting again");
+        }
+        // it's for the old session
+        notify.set(None);
+        let mut attempt = 0;
+        handle = loop {
+            tokio::time::sleep(reconnect.backoff(attempt)).await;
+            // the old mount might be still there, but disconnected
+            let _ = mount::umount(&mountpoint.to_string_lossy());
+            match Session::new(mount_options.clone())
+                .mount_with_unprivileged(
+                    EncryptedFsFuse3 {
+                        fs: fs.clone(),
+                        in_flight: in_flight.clone(),
+                        notify: notify.clone(),
+                    },
+                    OsStr::new(&mountpoint),
+                )
+                .await
+            {
+                Ok(handle) => {
+                    info!("mounted again");
+                    break handle;
+                }
+                Err(err) => {
+                    attempt += 1;
+                    error!(err = %err, attempt, "cannot mount again");
+                    if attempt >= reconnect.max_retries {
+                        return Err(err);
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// Detach the mount now, it's cleaned up when it's not used anymore.
+fn lazy_umount(mountpoint: &Path) -> io::Result<()> {
+    for (program, args) in [
+        ("fusermount3", ["-u", "-z"].as_slice()),
+        ("fusermount", ["-u", "-z"].as_slice()),
+        ("umount", ["-l"].as_slice()),
+    ] {
+        match process::Command::new(program)
+            .args(args)
+            .arg(mountpoint)
+            .output()
+        {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(output) => {
+                debug!(program, stderr = %String::from_utf8_lossy(&output.stderr), "cannot detach");
+            }
+            Err(err) => debug!(program, err = %err, "cannot detach"),
+        }
+    }
+    Err(io::Error::other(format!(
+        "cannot detach {}",
+        mountpoint.display()
+    )))
 }
 
-#[instrument(skip(password_provider))]
+/// What [`mount_fuse`] gives, with the state shared by the sessions if we mount again.
+type Mounted = (
+    MountHandle,
+    Arc<EncryptedFs>,
+    Arc<InFlight>,
+    Arc<KernelNotify>,
+    MountOptions,
+);
+
+#[instrument(skip(password_provider, options))]
+#[allow(clippy::too_many_arguments)]
 async fn mount_fuse(
     mountpoint: PathBuf,
     data_dir: PathBuf,
@@ -1431,29 +2352,787 @@ async fn mount_fuse(
     allow_root: bool,
     allow_other: bool,
     read_only: bool,
-) -> FsResult<MountHandle> {
+    options: FsOptions,
+) -> FsResult<Mounted> {
     // create mount point if it doesn't exist
     if !mountpoint.exists() {
         fs::create_dir_all(&mountpoint).await?;
     }
+    mount::check_mountpoint(&mountpoint, options.allow_nonempty_mountpoint)?;
     let mut mount_options = &mut MountOptions::default();
     {
         unsafe {
             mount_options = mount_options.uid(libc::getuid()).gid(libc::getgid());
         }
     }
-    let mount_options = mount_options
+    let mut mount_options = mount_options
         .read_only(read_only)
         .allow_root(allow_root)
         .allow_other(allow_other)
+        .default_permissions(options.default_permissions)
+        .write_back(options.writeback_cache)
         .clone();
+    let security: Vec<_> = [
+        (options.nosuid, "nosuid"),
+        (options.nodev, "nodev"),
+        (options.noexec, "noexec"),
+    ]
+    .into_iter()
+    .filter_map(|(set, option)| set.then_some(option))
+    .collect();
+    if !security.is_empty() {
+        mount_options.custom_options(security.join(","));
+    }
     let mount_path = OsStr::new(mountpoint.to_str().unwrap());
 
     info!("Checking password and mounting FUSE filesystem");
-    Ok(Session::new(mount_options)
-        .mount_with_unprivileged(
-            EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only).await?,
-            mount_path,
+    let fs = EncryptedFsFuse3::new(data_dir, password_provider, cipher, read_only, options).await?;
+    let fs_clone = fs.get_fs();
+    // it might be because the data dir is on a read-only filesystem
+    mount_options.read_only(fs_clone.read_only());
+    let in_flight = fs.in_flight.clone();
+    let notify = fs.notify.clone();
+    let handle = Session::new(mount_options.clone())
+        .mount_with_unprivileged(fs, mount_path)
+        .await?;
+    Ok((handle, fs_clone, in_flight, notify, mount_options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let in_flight = Arc::new(InFlight::new());
+        assert!(in_flight.wait_idle(Duration::from_millis(10)).await);
+
+        let (tx, rx) = oneshot::channel::<()>();
+        let in_flight_clone = in_flight.clone();
+        let op = tokio::spawn(async move {
+            in_flight_clone
+                .run("read", 42, async {
+                    let _ = rx.await;
+                    Ok(())
+                })
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let ops = in_flight.list();
+        assert_eq!(1, ops.len());
+        assert_eq!("read", ops[0].op);
+        assert_eq!(42, ops[0].ino);
+        assert!(!in_flight.wait_idle(Duration::from_millis(10)).await);
+
+        // stuck, cancel it
+        in_flight.cancel_all();
+        assert_eq!(Errno::from(EINTR), op.await.unwrap().unwrap_err());
+        assert!(in_flight.list().is_empty());
+        assert!(in_flight.wait_idle(Duration::from_millis(10)).await);
+        drop(tx);
+    }
+
+    #[test]
+    fn test_attr_blocks() {
+        let attr = FileAttr {
+            ino: 2,
+            size: 1000,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        };
+        let fuse_attr: fuse3::raw::prelude::FileAttr = attr.into();
+        assert_eq!(2, fuse_attr.blocks);
+        assert_eq!(4096, fuse_attr.blksize);
+        let fuse_attr: fuse3::raw::prelude::FileAttr = FileAttr { size: 0, ..attr }.into();
+        assert_eq!(0, fuse_attr.blocks);
+    }
+
+    #[tokio::test]
+    async fn test_fuse_runtime() {
+        let runtime = FuseRuntime::new(2).unwrap();
+        let name = runtime
+            .handle()
+            .spawn(async { std::thread::current().name().map(ToString::to_string) })
+            .await
+            .unwrap();
+        assert_eq!(Some("rencfs-fuse"), name.as_deref());
+        // from an async context too
+        drop(runtime);
+    }
+
+    #[tokio::test]
+    async fn test_negative_lookup_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let new_fs = |options| {
+            EncryptedFsFuse3::new(
+                dir.path().to_path_buf(),
+                Box::new(crate::test_common::PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                options,
+            )
+        };
+
+        let fs = new_fs(FsOptions::default()).await.unwrap();
+        let err = fs
+            .lookup(req, crate::encryptedfs::ROOT_INODE, OsStr::new("missing"))
+            .await
+            .unwrap_err();
+        assert_eq!(Errno::from(ENOENT), err);
+        drop(fs);
+
+        let fs = new_fs(FsOptions::default().with_negative_lookup_ttl(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        let entry = fs
+            .lookup(req, crate::encryptedfs::ROOT_INODE, OsStr::new("missing"))
+            .await
+            .unwrap();
+        assert_eq!(0, entry.attr.ino);
+        assert_eq!(Duration::from_secs(5), entry.ttl);
+    }
+
+    #[tokio::test]
+    async fn test_attr_and_entry_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default()
+                .with_attr_ttl(Duration::from_secs(30))
+                .with_entry_ttl(Duration::from_secs(60)),
+        )
+        .await
+        .unwrap();
+        let (fh, attr) = fs
+            .get_fs()
+            .create(
+                crate::encryptedfs::ROOT_INODE,
+                &SecretString::from_str("file").unwrap(),
+                crate::test_common::create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        fs.get_fs().release(fh).await.unwrap();
+
+        let entry = fs
+            .lookup(req, crate::encryptedfs::ROOT_INODE, OsStr::new("file"))
+            .await
+            .unwrap();
+        assert_eq!(attr.ino, entry.attr.ino);
+        assert_eq!(Duration::from_secs(60), entry.ttl);
+        let reply = fs.getattr(req, attr.ino, None, 0).await.unwrap();
+        assert_eq!(Duration::from_secs(30), reply.ttl);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_without_notify() {
+        let notify = KernelNotify::default();
+        assert!(matches!(
+            notify.invalidate_inode(42).await,
+            Err(FsError::Other(_))
+        ));
+        assert!(matches!(
+            notify.invalidate_entry(1, OsStr::new("file")).await,
+            Err(FsError::Other(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_writeback_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default().with_writeback_cache(true),
         )
-        .await?)
+        .await
+        .unwrap();
+        let (fh, attr) = fs
+            .get_fs()
+            .create(
+                crate::encryptedfs::ROOT_INODE,
+                &SecretString::from_str("file").unwrap(),
+                crate::test_common::create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        fs.get_fs().release(fh).await.unwrap();
+
+        #[allow(clippy::cast_sign_loss)]
+        let reply = fs.open(req, attr.ino, libc::O_WRONLY as u32).await.unwrap();
+        // the kernel reads the pages it writes partially
+        assert!(fs.get_fs().is_read_handle(reply.fh).await);
+        // pages written back in any order
+        let data: Vec<u8> = (0..4096 * 3).map(|i| (i % 251) as u8).collect();
+        for page in [2, 0, 1] {
+            let written = fs
+                .write(
+                    req,
+                    attr.ino,
+                    reply.fh,
+                    page * 4096,
+                    &data[page as usize * 4096..(page as usize + 1) * 4096],
+                    FUSE_WRITE_CACHE,
+                    0,
+                )
+                .await
+                .unwrap();
+            assert_eq!(4096, written.written);
+        }
+        fs.release(req, attr.ino, reply.fh, 0, 0, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            data.len() as u64,
+            fs.get_fs().get_attr(attr.ino).await.unwrap().size
+        );
+        let fh = fs.get_fs().open(attr.ino, true, false).await.unwrap();
+        let mut buf = vec![0; data.len()];
+        let mut read = 0;
+        while read < buf.len() {
+            read += fs
+                .get_fs()
+                .read(attr.ino, read as u64, &mut buf[read..], fh)
+                .await
+                .unwrap();
+        }
+        fs.get_fs().release(fh).await.unwrap();
+        assert_eq!(data, buf);
+    }
+
+    #[tokio::test]
+    async fn test_open_waits_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let (fh, attr) = fs
+            .get_fs()
+            .create(
+                crate::encryptedfs::ROOT_INODE,
+                &SecretString::from_str("file").unwrap(),
+                crate::test_common::create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        // the release of the closed handle comes a bit later
+        let fs_clone = fs.get_fs();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            fs_clone.release(fh).await.unwrap();
+        });
+        #[allow(clippy::cast_sign_loss)]
+        let reply = fs.open(req, attr.ino, libc::O_RDWR as u32).await.unwrap();
+        assert!(fs.get_fs().is_write_handle(reply.fh).await);
+        // mapped files and libraries stay cached between opens
+        assert_eq!(FOPEN_KEEP_CACHE, reply.flags);
+        fs.release(req, attr.ino, reply.fh, 0, 0, true)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sticky_bit() {
+        for sticky_bit in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let fs = EncryptedFsFuse3::new(
+                dir.path().to_path_buf(),
+                Box::new(crate::test_common::PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+                FsOptions::default().with_sticky_bit(sticky_bit),
+            )
+            .await
+            .unwrap();
+            let (_, tmp) = fs
+                .get_fs()
+                .create(
+                    crate::encryptedfs::ROOT_INODE,
+                    &SecretString::from_str("tmp").unwrap(),
+                    CreateFileAttr {
+                        perm: 0o1777,
+                        uid: 1000,
+                        ..crate::test_common::create_attr(FileType::Directory)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            for name in ["a", "b"] {
+                let (fh, _) = fs
+                    .get_fs()
+                    .create(
+                        tmp.ino,
+                        &SecretString::from_str(name).unwrap(),
+                        CreateFileAttr {
+                            perm: 0o666,
+                            uid: 1001,
+                            ..crate::test_common::create_attr(FileType::RegularFile)
+                        },
+                        false,
+                        false,
+                    )
+                    .await
+                    .unwrap();
+                fs.get_fs().release(fh).await.unwrap();
+            }
+            let other = Request {
+                unique: 0,
+                uid: 1002,
+                gid: 1002,
+                pid: 0,
+            };
+            let owner = Request { uid: 1001, ..other };
+
+            let unlink = fs.unlink(other, tmp.ino, OsStr::new("a")).await;
+            let rename = fs
+                .rename(other, tmp.ino, OsStr::new("b"), tmp.ino, OsStr::new("c"))
+                .await;
+            if sticky_bit {
+                assert_eq!(Err(Errno::from(EPERM)), unlink);
+                assert_eq!(Err(Errno::from(EPERM)), rename);
+                // the owner of the file can
+                fs.unlink(owner, tmp.ino, OsStr::new("a")).await.unwrap();
+                fs.rename(owner, tmp.ino, OsStr::new("b"), tmp.ino, OsStr::new("c"))
+                    .await
+                    .unwrap();
+            } else {
+                unlink.unwrap();
+                rename.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_noexec_nodev() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 1000,
+            gid: 1000,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default().with_noexec(true).with_nodev(true),
+        )
+        .await
+        .unwrap();
+        let (_, attr) = fs
+            .get_fs()
+            .create(
+                crate::encryptedfs::ROOT_INODE,
+                &SecretString::from_str("script").unwrap(),
+                CreateFileAttr {
+                    perm: 0o755,
+                    uid: 1000,
+                    gid: 1000,
+                    ..crate::test_common::create_attr(FileType::RegularFile)
+                },
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+        #[allow(clippy::cast_sign_loss)]
+        let exec = fs
+            .open(req, attr.ino, (libc::O_RDONLY | FMODE_EXEC) as u32)
+            .await;
+        assert_eq!(Errno::from(EACCES), exec.unwrap_err());
+        #[allow(clippy::cast_sign_loss)]
+        let access = fs.access(req, attr.ino, libc::X_OK as u32).await;
+        assert_eq!(Err(Errno::from(EACCES)), access);
+        // it can still be read
+        #[allow(clippy::cast_sign_loss)]
+        fs.access(req, attr.ino, libc::R_OK as u32).await.unwrap();
+        // directories can still be searched
+        #[allow(clippy::cast_sign_loss)]
+        fs.access(req, crate::encryptedfs::ROOT_INODE, libc::X_OK as u32)
+            .await
+            .unwrap();
+
+        let mknod = fs
+            .mknod(
+                req,
+                crate::encryptedfs::ROOT_INODE,
+                OsStr::new("sda1"),
+                libc::S_IFBLK | 0o600,
+                0x0801,
+            )
+            .await;
+        assert_eq!(Errno::from(EPERM), mknod.unwrap_err());
+    }
+    #[tokio::test]
+    async fn test_wrong_file_type_errno() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 1000,
+            gid: 1000,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let mut attrs = vec![];
+        for (name, kind) in [("d", FileType::Directory), ("f", FileType::RegularFile)] {
+            let (_, attr) = fs
+                .get_fs()
+                .create(
+                    crate::encryptedfs::ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    CreateFileAttr {
+                        perm: 0o755,
+                        uid: 1000,
+                        gid: 1000,
+                        ..crate::test_common::create_attr(kind)
+                    },
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            attrs.push(attr);
+        }
+        let (dir_attr, file_attr) = (attrs[0], attrs[1]);
+
+        let lookup = fs.lookup(req, file_attr.ino, OsStr::new("x")).await;
+        assert_eq!(Errno::from(ENOTDIR), lookup.unwrap_err());
+        let opendir = fs.opendir(req, file_attr.ino, 0).await;
+        assert_eq!(Errno::from(ENOTDIR), opendir.unwrap_err());
+        #[allow(clippy::cast_sign_loss)]
+        let open = fs.open(req, dir_attr.ino, libc::O_RDONLY as u32).await;
+        assert_eq!(Errno::from(EISDIR), open.unwrap_err());
+    }
+    #[tokio::test]
+    async fn test_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let entry = fs
+            .symlink(
+                req,
+                crate::encryptedfs::ROOT_INODE,
+                OsStr::new("link"),
+                OsStr::new("../target"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(fuse3::raw::prelude::FileType::Symlink, entry.attr.kind);
+        let lookup = fs
+            .lookup(req, crate::encryptedfs::ROOT_INODE, OsStr::new("link"))
+            .await
+            .unwrap();
+        assert_eq!(entry.attr.ino, lookup.attr.ino);
+        assert_eq!(fuse3::raw::prelude::FileType::Symlink, lookup.attr.kind);
+        let target = fs.readlink(req, entry.attr.ino).await.unwrap();
+        assert_eq!(b"../target", target.data.as_ref());
+        let not_link = fs.readlink(req, crate::encryptedfs::ROOT_INODE).await;
+        assert_eq!(Errno::from(EINVAL), not_link.unwrap_err());
+        fs.unlink(req, crate::encryptedfs::ROOT_INODE, OsStr::new("link"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_xattr() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+        let ino = crate::encryptedfs::ROOT_INODE;
+        let name = OsStr::new("user.test");
+
+        fs.setxattr(req, ino, name, b"value", 0, 0).await.unwrap();
+        assert!(matches!(
+            fs.getxattr(req, ino, name, 0).await.unwrap(),
+            ReplyXAttr::Size(5)
+        ));
+        assert_eq!(
+            Errno::from(ERANGE),
+            fs.getxattr(req, ino, name, 1).await.unwrap_err()
+        );
+        assert!(matches!(
+            fs.getxattr(req, ino, name, 5).await.unwrap(),
+            ReplyXAttr::Data(data) if data.as_ref() == b"value"
+        ));
+        assert!(matches!(
+            fs.listxattr(req, ino, 100).await.unwrap(),
+            ReplyXAttr::Data(data) if data.as_ref() == b"user.test\0"
+        ));
+        #[allow(clippy::cast_sign_loss)]
+        let create = libc::XATTR_CREATE as u32;
+        assert_eq!(
+            Errno::from(EEXIST),
+            fs.setxattr(req, ino, name, b"other", create, 0)
+                .await
+                .unwrap_err()
+        );
+
+        // only the owner changes ACLs, and they are validated
+        let other = Request { uid: 1000, ..req };
+        let acl = OsStr::new(XATTR_ACL_DEFAULT);
+        assert_eq!(
+            Errno::from(EPERM),
+            fs.setxattr(other, ino, acl, &[2, 0, 0, 0], 0, 0)
+                .await
+                .unwrap_err()
+        );
+        assert_eq!(
+            Errno::from(EINVAL),
+            fs.setxattr(req, ino, acl, &[2, 0, 0, 0], 0, 0)
+                .await
+                .unwrap_err()
+        );
+
+        fs.removexattr(req, ino, name).await.unwrap();
+        assert_eq!(
+            Errno::from(ENODATA),
+            fs.getxattr(req, ino, name, 0).await.unwrap_err()
+        );
+        assert_eq!(
+            Errno::from(ENODATA),
+            fs.removexattr(req, ino, name).await.unwrap_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setattr_symlink_times() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = Request {
+            unique: 0,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let fs = EncryptedFsFuse3::new(
+            dir.path().to_path_buf(),
+            Box::new(crate::test_common::PasswordProviderImpl {}),
+            Cipher::ChaCha20Poly1305,
+            false,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let dir_entry = fs
+            .mkdir(
+                req,
+                crate::encryptedfs::ROOT_INODE,
+                OsStr::new("dir"),
+                0o755,
+                0,
+            )
+            .await
+            .unwrap();
+        let link = fs
+            .symlink(
+                req,
+                crate::encryptedfs::ROOT_INODE,
+                OsStr::new("link"),
+                OsStr::new("dir"),
+            )
+            .await
+            .unwrap();
+        // what utimensat with AT_SYMLINK_NOFOLLOW sends
+        let mtime = Timestamp::new(1_000_000_000, 500);
+        let reply = fs
+            .setattr(
+                req,
+                link.attr.ino,
+                None,
+                SetAttr {
+                    atime: Some(Timestamp::new(999_999_999, 0)),
+                    mtime: Some(mtime),
+                    ..SetAttr::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(fuse3::raw::prelude::FileType::Symlink, reply.attr.kind);
+        assert_eq!(mtime, reply.attr.mtime);
+        assert_eq!(Timestamp::new(999_999_999, 0), reply.attr.atime);
+        let attr = fs.getattr(req, dir_entry.attr.ino, None, 0).await.unwrap();
+        assert_eq!(dir_entry.attr.mtime, attr.attr.mtime);
+    }
+
+    #[test]
+    fn test_creation_mode() {
+        let file = FileType::RegularFile;
+        let dir = FileType::Directory;
+        // the parent narrows what was requested
+        assert_eq!(0o640, creation_mode(0o644, file, 0o750, true));
+        assert_eq!(0o750, creation_mode(0o755, dir, 0o750, true));
+        // but never widens it, a stricter request stays strict
+        assert_eq!(0o600, creation_mode(0o600, file, 0o777, true));
+        assert_eq!(0o700, creation_mode(0o700, dir, 0o775, true));
+        assert_eq!(0o644, creation_mode(0o644, file, 0o777, true));
+        assert_eq!(0o644, creation_mode(0o644, file, 0o700, false));
+        // setgid is inherited by directories only
+        assert_eq!(0o2750, creation_mode(0o755, dir, 0o2750, true));
+        assert_eq!(
+            0o640,
+            creation_mode(0o2644 | libc::S_ISUID, file, 0o2750, true)
+        );
+    }
+
+    #[test]
+    fn test_lazy_umount_not_mounted() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(lazy_umount(dir.path()).is_err());
+    }
+
+    /// Doesn't serve anything, its session ends when `end` is sent, like after `fusermount -u`.
+    struct EndOnSignal {
+        data_dir: PathBuf,
+        end: oneshot::Receiver<()>,
+    }
+
+    #[async_trait]
+    impl MountPoint for EndOnSignal {
+        fn new(
+            _mountpoint: PathBuf,
+            _data_dir: PathBuf,
+            _password_provider: Box<dyn PasswordProvider>,
+            _cipher: Cipher,
+            _allow_root: bool,
+            _allow_other: bool,
+            _read_only: bool,
+            _options: FsOptions,
+        ) -> Self {
+            unimplemented!("the tests build it directly")
+        }
+
+        async fn mount(mut self) -> FsResult<mount::MountHandle> {
+            let fs = EncryptedFs::new(
+                self.data_dir.clone(),
+                Box::new(crate::test_common::PasswordProviderImpl {}),
+                Cipher::ChaCha20Poly1305,
+                false,
+            )
+            .await?;
+            let task = tokio::spawn(async move {
+                let _ = self.end.await;
+                Ok(())
+            });
+            Ok(mount::MountHandle {
+                inner: MountHandleInnerImpl {
+                    task,
+                    umount: None,
+                    mountpoint: self.data_dir,
+                    fs,
+                    in_flight: Arc::new(InFlight::new()),
+                    notify: Arc::default(),
+                    _runtime: None,
+                },
+            })
+        }
+    }
+
+    #[test]
+    fn test_run_fuse_blocking_until_unmounted() {
+        // not from a runtime, it has its own
+        let dir = tempfile::tempdir().unwrap();
+        let (end_tx, end) = oneshot::channel();
+        let mount_point = EndOnSignal {
+            data_dir: dir.path().join("data"),
+            end,
+        };
+        let thread = std::thread::spawn(move || mount::run_fuse_blocking(mount_point));
+        std::thread::sleep(Duration::from_millis(500));
+        // blocked while mounted
+        assert!(!thread.is_finished());
+
+        end_tx.send(()).unwrap();
+        thread.join().unwrap().unwrap();
+    }
 }
@@ -0,0 +1,194 @@
+//! Storage for the encrypted blocks somewhere else than the local data dir, like object storage.
+//!
+//! [`EncryptedFs::push_blocks`] and [`EncryptedFs::pull_blocks`] copy the data files of an inode to and from a
+//! [`StorageBackend`], split in the same blocks as on disk. With [`Storage::Mirror`] in [`FsOptions::storage`]
+//! [`EncryptedFs`] keeps a mirror of the content on its own, uploading files when they change and downloading them
+//! when they are opened and missing locally. Blocks are encrypted before they get here, so the backend doesn't need to
+//! be trusted.
+//!
+//! It's not remote storage, reads and writes only go to the data dir and the metadata is only kept there. Remote
+//! backends have a much higher latency than the disk, fetching blocks one by one on reads would be very slow without
+//! read-ahead and caching in front of them, so files are downloaded whole when opened.
+//!
+//! [`EncryptedFs`]: crate::encryptedfs::EncryptedFs
+//! [`EncryptedFs::push_blocks`]: crate::encryptedfs::EncryptedFs::push_blocks
+//! [`EncryptedFs::pull_blocks`]: crate::encryptedfs::EncryptedFs::pull_blocks
+//! [`Storage::Mirror`]: crate::encryptedfs::Storage::Mirror
+//! [`FsOptions::storage`]: crate::encryptedfs::FsOptions::storage
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::fs;
+
+use crate::encryptedfs::FsResult;
+
+/// Where encrypted blocks are kept, by key. Keys are `/` separated paths, like `contents/<ino>/<block>`.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// `None` if there is no block with this key.
+    async fn get_block(&self, key: &str) -> FsResult<Option<Vec<u8>>>;
+    async fn put_block(&self, key: &str, data: &[u8]) -> FsResult<()>;
+    /// Deleting a missing block is not an error.
+    async fn delete_block(&self, key: &str) -> FsResult<()>;
+    /// Keys starting with `prefix`, in no particular order.
+    async fn list(&self, prefix: &str) -> FsResult<Vec<String>>;
+}
+
+/// Keeps each block in a file under a directory, useful for tests and for network filesystems.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn get_block(&self, key: &str) -> FsResult<Option<Vec<u8>>> {
+        match fs::read(self.root.join(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn put_block(&self, key: &str, data: &[u8]) -> FsResult<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn delete_block(&self, key: &str) -> FsResult<()> {
+        match fs::remove_file(self.root.join(key)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> FsResult<Vec<String>> {
+        let mut keys = vec![];
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let key = path
+                    .strip_prefix(&self.root)
+                    .expect("not under root")
+                    .to_string_lossy()
+                    .replace(std::path::MAIN_SEPARATOR, "/");
+                if key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Keeps blocks in S3, GCS, Azure or anything else supported by the [`object_store`] crate.
+#[cfg(feature = "object-store")]
+pub struct ObjectStoreBackend {
+    store: std::sync::Arc<dyn object_store::ObjectStore>,
+}
+
+#[cfg(feature = "object-store")]
+impl ObjectStoreBackend {
+    pub fn new(store: std::sync::Arc<dyn object_store::ObjectStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[cfg(feature = "object-store")]
+fn object_store_err(err: object_store::Error) -> crate::encryptedfs::FsError {
+    io::Error::other(err).into()
+}
+
+/// Object store encodes characters like `|` from the encrypted names, decode them back to our keys.
+#[cfg(feature = "object-store")]
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if let (b'%', Some(hex)) = (b, tail.get(..2)) {
+            if let Ok(decoded) = u8::from_str_radix(&String::from_utf8_lossy(hex), 16) {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+        }
+        bytes.push(b);
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+#[cfg(feature = "object-store")]
+#[async_trait]
+impl StorageBackend for ObjectStoreBackend {
+    async fn get_block(&self, key: &str) -> FsResult<Option<Vec<u8>>> {
+        match self.store.get(&object_store::path::Path::from(key)).await {
+            Ok(res) => Ok(Some(res.bytes().await.map_err(object_store_err)?.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(object_store_err(err)),
+        }
+    }
+
+    async fn put_block(&self, key: &str, data: &[u8]) -> FsResult<()> {
+        self.store
+            .put(&object_store::path::Path::from(key), data.to_vec().into())
+            .await
+            .map_err(object_store_err)?;
+        Ok(())
+    }
+
+    async fn delete_block(&self, key: &str) -> FsResult<()> {
+        match self
+            .store
+            .delete(&object_store::path::Path::from(key))
+            .await
+        {
+            Err(err) if !matches!(err, object_store::Error::NotFound { .. }) => {
+                Err(object_store_err(err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> FsResult<Vec<String>> {
+        use futures_util::TryStreamExt;
+
+        // object store prefixes match whole path segments, filter the rest ourselves
+        let parent = prefix.rsplit_once('/').map(|(parent, _)| parent);
+        let parent = parent.map(object_store::path::Path::from);
+        let keys: Vec<_> = self
+            .store
+            .list(parent.as_ref())
+            .map_ok(|meta| percent_decode(meta.location.as_ref()))
+            .try_collect()
+            .await
+            .map_err(object_store_err)?;
+        Ok(keys
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect())
+    }
+}
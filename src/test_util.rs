@@ -0,0 +1,342 @@
+//! Helpers for testing code built on rencfs, like with `proptest`, enabled by the `test-util` feature.
+//!
+//! [`TestVault`] is a vault in a temporary dir, removed when it's dropped. Point `TMPDIR` to a `tmpfs` to keep it
+//! in memory. [`TestVault::assert_round_trip`] and [`TestVault::run_ops`] check that what's read back is what was
+//! written, catching mistakes in the offsets math around the blocks.
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn round_trip(offset in 0..10_000_u64, data in vec(any::<u8>(), 0..10_000)) {
+//!         tokio::runtime::Runtime::new().unwrap().block_on(async {
+//!             let vault = TestVault::builder().build().await.unwrap();
+//!             vault.assert_round_trip(offset, &data).await;
+//!         });
+//!     }
+//! }
+//! ```
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rand::Rng;
+use shush_rs::SecretString;
+use tempfile::TempDir;
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileType, FsOptions, FsResult, PasswordProvider, ROOT_INODE,
+};
+
+/// What [`TestVault::run_ops`] does with a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Write { offset: u64, data: Vec<u8> },
+    Read { offset: u64, len: usize },
+    SetLen(u64),
+}
+
+/// Builds a [`TestVault`].
+pub struct TestVaultBuilder {
+    cipher: Cipher,
+    options: FsOptions,
+}
+
+impl TestVaultBuilder {
+    #[must_use]
+    pub const fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    #[must_use]
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn options(mut self, options: FsOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn build(self) -> FsResult<TestVault> {
+        let dir = tempfile::tempdir()?;
+        let fs = EncryptedFs::new_with_options(
+            dir.path().to_path_buf(),
+            Box::new(TestPasswordProvider),
+            self.cipher,
+            false,
+            self.options,
+        )
+        .await?;
+        Ok(TestVault {
+            fs: Some(fs),
+            dir,
+            cipher: self.cipher,
+        })
+    }
+}
+
+struct TestPasswordProvider;
+
+impl PasswordProvider for TestPasswordProvider {
+    fn get_password(&self) -> Option<SecretString> {
+        Some(SecretString::from_str("password").unwrap())
+    }
+}
+
+/// A vault in a temporary dir with a fixed password, removed when dropped.
+pub struct TestVault {
+    // `None` only if opening it again failed
+    fs: Option<Arc<EncryptedFs>>,
+    dir: TempDir,
+    cipher: Cipher,
+}
+
+impl TestVault {
+    #[must_use]
+    pub fn builder() -> TestVaultBuilder {
+        TestVaultBuilder {
+            cipher: Cipher::ChaCha20Poly1305,
+            options: FsOptions::default(),
+        }
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn fs(&self) -> &Arc<EncryptedFs> {
+        self.fs.as_ref().expect("the vault failed to open again")
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Opens the data dir again with `options`, like when mounting it again, or after a crash as nothing pending is
+    /// written before. The [`EncryptedFs`] we had is dropped first, so it doesn't keep the data dir busy.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn reopen(&mut self, read_only: bool, options: FsOptions) -> FsResult<()> {
+        self.fs = None;
+        self.fs = Some(
+            EncryptedFs::new_with_options(
+                self.dir.path().to_path_buf(),
+                Box::new(TestPasswordProvider),
+                self.cipher,
+                read_only,
+                options,
+            )
+            .await?,
+        );
+        Ok(())
+    }
+
+    /// Creates an empty file in the root dir, returns its inode.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_file(&self, name: &str) -> FsResult<u64> {
+        let (fh, attr) = self
+            .fs()
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str(name).unwrap(),
+                CreateFileAttr {
+                    kind: FileType::RegularFile,
+                    perm: 0o644,
+                    uid: 0,
+                    gid: 0,
+                    rdev: 0,
+                    flags: 0,
+                },
+                false,
+                false,
+            )
+            .await?;
+        self.fs().release(fh).await?;
+        Ok(attr.ino)
+    }
+
+    /// Writes all of `data` at `offset`, with as many writes as needed, and flushes it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn write_all(&self, ino: u64, offset: u64, data: &[u8]) -> FsResult<()> {
+        let fh = self.fs().open(ino, false, true).await?;
+        let mut written = 0;
+        while written < data.len() {
+            written += self
+                .fs()
+                .write(ino, offset + written as u64, &data[written..], fh)
+                .await?;
+        }
+        self.fs().release(fh).await
+    }
+
+    /// Reads up to `len` bytes from `offset`, less only at the end of the file.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read(&self, ino: u64, offset: u64, len: usize) -> FsResult<Vec<u8>> {
+        let fh = self.fs().open(ino, true, false).await?;
+        let mut buf = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            let n = self
+                .fs()
+                .read(ino, offset + read as u64, &mut buf[read..], fh)
+                .await?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        self.fs().release(fh).await?;
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    /// Reads the whole content of the file.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read_all(&self, ino: u64) -> FsResult<Vec<u8>> {
+        let size = self.fs().get_attr(ino).await?.size;
+        self.read(ino, 0, size as usize).await
+    }
+
+    /// Writes `data` at `offset` in a new file and asserts that it reads back as zeros up to `offset` followed by
+    /// `data`.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn assert_round_trip(&self, offset: u64, data: &[u8]) {
+        let ino = self
+            .create_file(&format!("round-trip-{}", rand::random::<u64>()))
+            .await
+            .unwrap();
+        self.write_all(ino, offset, data).await.unwrap();
+        let mut expected = vec![0; offset as usize];
+        expected.extend_from_slice(data);
+        if data.is_empty() {
+            expected.clear();
+        }
+        assert_eq!(
+            expected.len() as u64,
+            self.fs().get_attr(ino).await.unwrap().size
+        );
+        assert_eq!(expected, self.read_all(ino).await.unwrap());
+        assert_eq!(data, &self.read(ino, offset, data.len()).await.unwrap()[..]);
+    }
+
+    /// Runs `ops` on a new file and on a `Vec` and asserts after each that the file has the same content.
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn run_ops(&self, ops: &[Op]) {
+        let ino = self
+            .create_file(&format!("ops-{}", rand::random::<u64>()))
+            .await
+            .unwrap();
+        let mut model = vec![];
+        for (i, op) in ops.iter().enumerate() {
+            match op {
+                Op::Write { offset, data } => {
+                    self.write_all(ino, *offset, data).await.unwrap();
+                    if !data.is_empty() {
+                        let end = *offset as usize + data.len();
+                        if model.len() < end {
+                            model.resize(end, 0);
+                        }
+                        model[*offset as usize..end].copy_from_slice(data);
+                    }
+                }
+                Op::Read { offset, len } => {
+                    let start = (*offset as usize).min(model.len());
+                    let end = (start + len).min(model.len());
+                    assert_eq!(
+                        model[start..end],
+                        self.read(ino, *offset, *len).await.unwrap(),
+                        "op {i}: {op:?}"
+                    );
+                }
+                Op::SetLen(len) => {
+                    self.fs().set_len(ino, *len).await.unwrap();
+                    model.resize(*len as usize, 0);
+                }
+            }
+            assert_eq!(
+                model.len() as u64,
+                self.fs().get_attr(ino).await.unwrap().size,
+                "op {i}: {op:?}"
+            );
+        }
+        assert_eq!(model, self.read_all(ino).await.unwrap());
+    }
+}
+
+/// `count` random operations on a file up to about `max_len` bytes, for [`TestVault::run_ops`]. Offsets and lengths
+/// are often around multiples of the block size, where the mistakes usually are.
+pub fn random_ops(rng: &mut impl Rng, count: usize, max_len: u64, block_size: u64) -> Vec<Op> {
+    let offset = |rng: &mut dyn rand::RngCore| {
+        if rng.gen_bool(0.5) {
+            let block = rng.gen_range(0..=max_len / block_size);
+            (block * block_size)
+                .saturating_add_signed(rng.gen_range(-2..=2))
+                .min(max_len)
+        } else {
+            rng.gen_range(0..=max_len)
+        }
+    };
+    (0..count)
+        .map(|_| match rng.gen_range(0..10) {
+            0..=4 => {
+                let offset = offset(rng);
+                let len = offset_len(rng, max_len - offset, block_size);
+                let mut data = vec![0; len];
+                rng.fill(&mut data[..]);
+                Op::Write { offset, data }
+            }
+            5..=8 => {
+                let offset = offset(rng);
+                Op::Read {
+                    offset,
+                    len: offset_len(rng, max_len - offset + 1, block_size),
+                }
+            }
+            _ => Op::SetLen(offset(rng)),
+        })
+        .collect()
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn offset_len(rng: &mut impl Rng, max: u64, block_size: u64) -> usize {
+    let len = if rng.gen_bool(0.3) {
+        block_size.saturating_add_signed(rng.gen_range(-1..=1))
+    } else {
+        rng.gen_range(0..=max)
+    };
+    len.min(max) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let vault = TestVault::builder().build().await.unwrap();
+        let block_size = vault.fs().block_size() as u64;
+        for (offset, len) in [
+            (0, 0),
+            (0, 1),
+            (0, block_size),
+            (block_size - 1, 2),
+            (block_size, block_size * 2 + 1),
+            (block_size * 3 + 7, 42),
+        ] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            vault.assert_round_trip(offset, &data).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_random_ops() {
+        let vault = TestVault::builder().build().await.unwrap();
+        let block_size = vault.fs().block_size() as u64;
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let ops = random_ops(&mut rng, 100, block_size * 5, block_size);
+        vault.run_ops(&ops).await;
+    }
+}